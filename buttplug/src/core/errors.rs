@@ -134,6 +134,10 @@ pub enum ButtplugDeviceError {
   DeviceFeatureCountMismatch(u32, u32),
   /// Device only has {0} features, but was given an index of {1}
   DeviceFeatureIndexError(u32, u32),
+  /// Device only has {available} features, but was given an index of {requested}
+  IndexOutOfRange { requested: u32, available: usize },
+  /// {0} has 0 commands, will not do anything.
+  EmptyCommand(ButtplugDeviceMessageType),
   /// Device only has {0} sensors, but was given an index of {1}
   DeviceSensorIndexError(u32, u32),
   /// Device connection error: {0}
@@ -12,6 +12,7 @@ use std::{
 };
 
 use core::hash::Hash;
+use strum::IntoEnumIterator;
 
 // We need this array to be exposed in our WASM FFI, but the only way to do that
 // is to expose it at the declaration level. Therefore, we use the WASM feature
@@ -27,7 +28,7 @@ use core::hash::Hash;
 /// context. These names are used in [Device Configuration](crate::server::device::configuration)
 /// and the [Device Configuration File](crate::util::device_configuration), and are expected to
 /// de/serialize to lowercase versions of their names.
-#[derive(EnumString, Clone, Debug, PartialEq, Eq, Hash, Display, Copy)]
+#[derive(EnumString, EnumIter, Clone, Debug, PartialEq, Eq, Hash, Display, Copy)]
 #[strum(serialize_all = "lowercase")]
 pub enum Endpoint {
   /// Expect to take commands, when multiple receive endpoints may be available
@@ -125,6 +126,13 @@ pub enum Endpoint {
   Generic31,
 }
 
+impl Endpoint {
+  /// Every endpoint name, in declaration order.
+  pub fn all() -> impl Iterator<Item = Endpoint> {
+    Endpoint::iter()
+  }
+}
+
 // Implement to/from string serialization for Endpoint struct
 impl Serialize for Endpoint {
   fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
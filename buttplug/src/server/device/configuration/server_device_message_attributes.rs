@@ -343,42 +343,68 @@ pub struct ServerDeviceMessageAttributesBuilder {
 }
 
 impl ServerDeviceMessageAttributesBuilder {
-  pub fn scalar_cmd(&mut self, attrs: &[ServerGenericDeviceMessageAttributes]) -> &Self {
+  pub fn scalar_cmd(&mut self, attrs: &[ServerGenericDeviceMessageAttributes]) -> &mut Self {
     self.attrs.scalar_cmd = Some(attrs.to_vec());
     self
   }
 
-  pub fn rotate_cmd(&mut self, attrs: &[ServerGenericDeviceMessageAttributes]) -> &Self {
+  pub fn rotate_cmd(&mut self, attrs: &[ServerGenericDeviceMessageAttributes]) -> &mut Self {
     self.attrs.rotate_cmd = Some(attrs.to_vec());
     self
   }
 
-  pub fn linear_cmd(&mut self, attrs: &[ServerGenericDeviceMessageAttributes]) -> &Self {
+  pub fn linear_cmd(&mut self, attrs: &[ServerGenericDeviceMessageAttributes]) -> &mut Self {
     self.attrs.linear_cmd = Some(attrs.to_vec());
     self
   }
 
-  pub fn sensor_read_cmd(&mut self, attrs: &[SensorDeviceMessageAttributes]) -> &Self {
+  /// Shorthand for [scalar_cmd](Self::scalar_cmd) with `count` identical vibrators, each with a
+  /// step range of 0 to `step_count`.
+  pub fn vibrators(&mut self, count: u32, step_count: u32) -> &mut Self {
+    self.scalar_cmd(&uniform_attributes(
+      count,
+      step_count,
+      ActuatorType::Vibrate,
+    ))
+  }
+
+  /// Shorthand for [rotate_cmd](Self::rotate_cmd) with `count` identical rotators, each with a step
+  /// range of 0 to `step_count`.
+  pub fn rotators(&mut self, count: u32, step_count: u32) -> &mut Self {
+    self.rotate_cmd(&uniform_attributes(count, step_count, ActuatorType::Rotate))
+  }
+
+  /// Shorthand for [linear_cmd](Self::linear_cmd) with `count` identical linear actuators, each
+  /// with a step range of 0 to `step_count`.
+  pub fn linears(&mut self, count: u32, step_count: u32) -> &mut Self {
+    self.linear_cmd(&uniform_attributes(
+      count,
+      step_count,
+      ActuatorType::Position,
+    ))
+  }
+
+  pub fn sensor_read_cmd(&mut self, attrs: &[SensorDeviceMessageAttributes]) -> &mut Self {
     self.attrs.sensor_read_cmd = Some(attrs.to_vec());
     self
   }
 
-  pub fn sensor_subscribe_cmd(&mut self, attrs: &[SensorDeviceMessageAttributes]) -> &Self {
+  pub fn sensor_subscribe_cmd(&mut self, attrs: &[SensorDeviceMessageAttributes]) -> &mut Self {
     self.attrs.sensor_subscribe_cmd = Some(attrs.to_vec());
     self
   }
 
-  pub fn raw_read_cmd(&mut self, endpoints: &[Endpoint]) -> &Self {
+  pub fn raw_read_cmd(&mut self, endpoints: &[Endpoint]) -> &mut Self {
     self.attrs.raw_read_cmd = Some(RawDeviceMessageAttributes::new(endpoints));
     self
   }
 
-  pub fn raw_write_cmd(&mut self, endpoints: &[Endpoint]) -> &Self {
+  pub fn raw_write_cmd(&mut self, endpoints: &[Endpoint]) -> &mut Self {
     self.attrs.raw_write_cmd = Some(RawDeviceMessageAttributes::new(endpoints));
     self
   }
 
-  pub fn raw_subscribe_cmd(&mut self, endpoints: &[Endpoint]) -> &Self {
+  pub fn raw_subscribe_cmd(&mut self, endpoints: &[Endpoint]) -> &mut Self {
     self.attrs.raw_subscribe_cmd = Some(RawDeviceMessageAttributes::new(endpoints));
     self
   }
@@ -388,6 +414,21 @@ impl ServerDeviceMessageAttributesBuilder {
   }
 }
 
+fn uniform_attributes(
+  count: u32,
+  step_count: u32,
+  actuator_type: ActuatorType,
+) -> Vec<ServerGenericDeviceMessageAttributes> {
+  vec![
+    ServerGenericDeviceMessageAttributes::new(
+      "N/A",
+      &RangeInclusive::new(0, step_count),
+      actuator_type,
+    );
+    count as usize
+  ]
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Getters, Setters)]
 pub struct ServerGenericDeviceMessageAttributes {
   #[getset(get = "pub")]
@@ -431,6 +472,19 @@ impl TryFrom<DeviceFeature> for ServerGenericDeviceMessageAttributes {
 }
 
 impl ServerGenericDeviceMessageAttributes {
+  pub fn new(
+    feature_descriptor: &str,
+    step_range: &RangeInclusive<u32>,
+    actuator_type: ActuatorType,
+  ) -> Self {
+    Self {
+      feature_descriptor: feature_descriptor.to_owned(),
+      actuator_type,
+      step_range: step_range.clone(),
+      step_limit: step_range.clone(),
+    }
+  }
+
   pub fn step_count(&self) -> u32 {
     self.step_limit.end() - self.step_limit.start()
   }
@@ -475,4 +529,27 @@ mod test {
       device_feature_2.try_into().unwrap();
     assert_eq!(vibrate_attributes_2.step_count(), 4);
   }
+
+  #[test]
+  pub fn test_uniform_attributes_builder() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .rotators(1, 10)
+      .linears(3, 100)
+      .finish();
+    let scalars = attributes.scalar_cmd().as_ref().unwrap();
+    assert_eq!(scalars.len(), 2);
+    assert!(scalars
+      .iter()
+      .all(|attr| *attr.actuator_type() == ActuatorType::Vibrate && attr.step_count() == 20));
+    let rotators = attributes.rotate_cmd().as_ref().unwrap();
+    assert_eq!(rotators.len(), 1);
+    assert_eq!(*rotators[0].actuator_type(), ActuatorType::Rotate);
+    assert_eq!(rotators[0].step_count(), 10);
+    let linears = attributes.linear_cmd().as_ref().unwrap();
+    assert_eq!(linears.len(), 3);
+    assert!(linears
+      .iter()
+      .all(|attr| *attr.actuator_type() == ActuatorType::Position && attr.step_count() == 100));
+  }
 }
@@ -353,6 +353,10 @@ impl PartialEq for SerialSpecifier {
 #[getset(get = "pub", set = "pub")]
 pub struct WebsocketSpecifier {
   name: String,
+  /// True if the device connected over a TLS (wss://) session. This describes the connection, not
+  /// the device, so it is not taken into account when matching specifiers.
+  #[serde(default)]
+  secure: bool,
 }
 
 impl PartialEq for WebsocketSpecifier {
@@ -366,6 +370,31 @@ impl PartialEq for WebsocketSpecifier {
 
 impl WebsocketSpecifier {
   pub fn new(name: &str) -> WebsocketSpecifier {
+    Self {
+      name: name.to_owned(),
+      secure: false,
+    }
+  }
+}
+
+/// Specifier for TCP Server Device Manager devices
+///
+/// Like the websocket device manager, the TCP server device manager is network based, so the only
+/// info we have is a device name provided as part of the connection handshake.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Getters, Setters, MutGetters)]
+#[getset(get = "pub", set = "pub")]
+pub struct TcpSpecifier {
+  name: String,
+}
+
+impl PartialEq for TcpSpecifier {
+  fn eq(&self, other: &Self) -> bool {
+    self.name == other.name
+  }
+}
+
+impl TcpSpecifier {
+  pub fn new(name: &str) -> TcpSpecifier {
     Self {
       name: name.to_owned(),
     }
@@ -392,6 +421,8 @@ pub enum ProtocolCommunicationSpecifier {
   LovenseConnectService(LovenseConnectServiceSpecifier),
   #[serde(rename = "websocket")]
   Websocket(WebsocketSpecifier),
+  #[serde(rename = "tcp")]
+  Tcp(TcpSpecifier),
 }
 
 impl PartialEq for ProtocolCommunicationSpecifier {
@@ -404,6 +435,7 @@ impl PartialEq for ProtocolCommunicationSpecifier {
       (HID(self_spec), HID(other_spec)) => self_spec == other_spec,
       (XInput(self_spec), XInput(other_spec)) => self_spec == other_spec,
       (Websocket(self_spec), Websocket(other_spec)) => self_spec == other_spec,
+      (Tcp(self_spec), Tcp(other_spec)) => self_spec == other_spec,
       (LovenseConnectService(self_spec), LovenseConnectService(other_spec)) => {
         self_spec == other_spec
       }
@@ -0,0 +1,222 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Notification rate limiting for any [HardwareInternal] implementation.
+//!
+//! Some endpoints (battery, RSSI, pressure sensors) can notify dozens of times a second, which
+//! mostly ends up as event spam further up the stack. [CoalescingHardware] wraps another hardware
+//! implementation and passes on at most one notification per endpoint per interval. Notifications
+//! that arrive too soon are held, and only the latest one is sent once the interval is up, so the
+//! newest value is never lost.
+
+use super::{
+  HardwareEvent,
+  HardwareInternal,
+  HardwareReadCmd,
+  HardwareReading,
+  HardwareSubscribeCmd,
+  HardwareUnsubscribeCmd,
+  HardwareWriteCmd,
+  NotificationDecoder,
+};
+use crate::{
+  core::{errors::ButtplugDeviceError, message::Endpoint},
+  util::{async_manager, sleep},
+};
+use futures::{
+  future::{self, BoxFuture},
+  FutureExt,
+};
+use instant::Instant;
+use std::{collections::HashMap, time::Duration};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+/// [HardwareInternal] wrapper that limits how often notifications are passed on for each endpoint.
+/// All commands go straight through to the wrapped hardware.
+pub struct CoalescingHardware {
+  inner: Box<dyn HardwareInternal>,
+  event_sender: broadcast::Sender<HardwareEvent>,
+  cancellation_token: CancellationToken,
+}
+
+impl CoalescingHardware {
+  /// Wrap `inner`, passing on at most one notification per endpoint every `min_interval`.
+  pub fn new(inner: Box<dyn HardwareInternal>, min_interval: Duration) -> Self {
+    let (event_sender, _) = broadcast::channel(256);
+    let cancellation_token = CancellationToken::new();
+    async_manager::spawn(coalesce_notifications(
+      inner.event_stream(),
+      event_sender.clone(),
+      min_interval,
+      cancellation_token.child_token(),
+    ));
+    Self {
+      inner,
+      event_sender,
+      cancellation_token,
+    }
+  }
+}
+
+impl Drop for CoalescingHardware {
+  fn drop(&mut self) {
+    self.cancellation_token.cancel();
+  }
+}
+
+// Per endpoint, when we last passed on a notification and the newest one held back since then.
+type EndpointState = (Instant, Option<(String, Vec<u8>, Option<String>)>);
+
+fn flush_pending(
+  endpoints: &mut HashMap<Endpoint, EndpointState>,
+  event_sender: &broadcast::Sender<HardwareEvent>,
+  min_interval: Duration,
+  force: bool,
+) {
+  let now = Instant::now();
+  for (endpoint, (last_sent, pending)) in endpoints.iter_mut() {
+    if !force && now.saturating_duration_since(*last_sent) < min_interval {
+      continue;
+    }
+    if let Some((address, data, identifier)) = pending.take() {
+      // If no one is listening, ignore output.
+      let _ = event_sender.send(HardwareEvent::Notification(
+        address, *endpoint, data, identifier,
+      ));
+      *last_sent = now;
+    }
+  }
+}
+
+async fn coalesce_notifications(
+  mut inner_receiver: broadcast::Receiver<HardwareEvent>,
+  event_sender: broadcast::Sender<HardwareEvent>,
+  min_interval: Duration,
+  token: CancellationToken,
+) {
+  let mut endpoints: HashMap<Endpoint, EndpointState> = HashMap::new();
+  loop {
+    let flush_wait = endpoints
+      .values()
+      .filter(|(_, pending)| pending.is_some())
+      .map(|(last_sent, _)| (*last_sent + min_interval).saturating_duration_since(Instant::now()))
+      .min();
+    let flush = async move {
+      match flush_wait {
+        Some(wait) => sleep(wait).await,
+        None => future::pending::<()>().await,
+      }
+    };
+    let event = select! {
+      event = inner_receiver.recv().fuse() => event,
+      _ = flush.fuse() => {
+        flush_pending(&mut endpoints, &event_sender, min_interval, false);
+        continue;
+      },
+      _ = token.cancelled().fuse() => break,
+    };
+    match event {
+      Ok(HardwareEvent::Notification(address, endpoint, data, identifier)) => {
+        let now = Instant::now();
+        match endpoints.get_mut(&endpoint) {
+          Some((last_sent, pending))
+            if now.saturating_duration_since(*last_sent) < min_interval =>
+          {
+            *pending = Some((address, data, identifier));
+          }
+          _ => {
+            let _ = event_sender.send(HardwareEvent::Notification(
+              address, endpoint, data, identifier,
+            ));
+            endpoints.insert(endpoint, (now, None));
+          }
+        }
+      }
+      Ok(HardwareEvent::Disconnected(address, reason)) => {
+        // Anything held back still happened before the disconnect, so get it out first.
+        flush_pending(&mut endpoints, &event_sender, min_interval, true);
+        let _ = event_sender.send(HardwareEvent::Disconnected(address, reason));
+      }
+      Ok(event) => {
+        let _ = event_sender.send(event);
+      }
+      Err(broadcast::error::RecvError::Lagged(count)) => {
+        warn!(
+          "Notification coalescer fell behind, {} events skipped.",
+          count
+        );
+      }
+      Err(broadcast::error::RecvError::Closed) => break,
+    }
+  }
+  debug!("Exiting notification coalescing task.");
+}
+
+impl HardwareInternal for CoalescingHardware {
+  fn event_stream(&self) -> broadcast::Receiver<HardwareEvent> {
+    self.event_sender.subscribe()
+  }
+
+  fn disconnect(&self) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    self.inner.disconnect()
+  }
+
+  fn read_value(
+    &self,
+    msg: &HardwareReadCmd,
+  ) -> BoxFuture<'static, Result<HardwareReading, ButtplugDeviceError>> {
+    self.inner.read_value(msg)
+  }
+
+  fn write_value(
+    &self,
+    msg: &HardwareWriteCmd,
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    self.inner.write_value(msg)
+  }
+
+  fn write_values(
+    &self,
+    msgs: &[HardwareWriteCmd],
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    self.inner.write_values(msgs)
+  }
+
+  fn subscribe(
+    &self,
+    msg: &HardwareSubscribeCmd,
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    self.inner.subscribe(msg)
+  }
+
+  fn unsubscribe(
+    &self,
+    msg: &HardwareUnsubscribeCmd,
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    self.inner.unsubscribe(msg)
+  }
+
+  fn subscribe_all(
+    &self,
+    endpoints: &[Endpoint],
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    self.inner.subscribe_all(endpoints)
+  }
+
+  fn subscribe_with_decoder(
+    &self,
+    msg: &HardwareSubscribeCmd,
+    decoder: NotificationDecoder,
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    self.inner.subscribe_with_decoder(msg, decoder)
+  }
+
+  fn flush(&self) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    self.inner.flush()
+  }
+}
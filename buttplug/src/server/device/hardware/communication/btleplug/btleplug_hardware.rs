@@ -11,6 +11,7 @@ use crate::{
   server::device::{
     configuration::{BluetoothLESpecifier, ProtocolCommunicationSpecifier},
     hardware::{
+      DisconnectReason,
       Hardware,
       HardwareConnector,
       HardwareEvent,
@@ -273,6 +274,7 @@ impl<T: Peripheral + 'static> BtlePlugHardware<T> {
                 format!("{:?}", address),
                 endpoint,
                 notification.value,
+                None,
               )) {
                 error!(
                   "Cannot send notification, device object disappeared: {:?}",
@@ -292,7 +294,8 @@ impl<T: Peripheral + 'static> BtlePlugHardware<T> {
                 if event_stream_clone.receiver_count() != 0 {
                   if let Err(err) = event_stream_clone
                   .send(HardwareEvent::Disconnected(
-                    format!("{:?}", address)
+                    format!("{:?}", address),
+                    DisconnectReason::ReadError
                   )) {
                     error!(
                       "Cannot send notification, device object disappeared: {:?}",
@@ -441,6 +444,9 @@ impl<T: Peripheral + 'static> HardwareInternal for BtlePlugHardware<T> {
     &self,
     msg: &HardwareSubscribeCmd,
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    if let Err(err) = msg.check_no_prefix_filter("Bluetooth LE") {
+      return future::ready(Err(err)).boxed();
+    }
     let endpoint = msg.endpoint;
     if self.subscribed_endpoints.contains(&endpoint) {
       debug!(
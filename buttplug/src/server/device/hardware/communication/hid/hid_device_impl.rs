@@ -18,9 +18,17 @@ use crate::{
       HardwareWriteCmd,
     },
   },
+  util::async_manager,
 };
 use async_trait::async_trait;
-use futures::{future::BoxFuture, AsyncWriteExt};
+use futures::{
+  future::{self, BoxFuture},
+  AsyncRead,
+  AsyncReadExt,
+  AsyncWrite,
+  AsyncWriteExt,
+  FutureExt,
+};
 use hidapi::{DeviceInfo, HidApi};
 use std::{
   fmt::{self, Debug},
@@ -30,6 +38,10 @@ use std::{
   },
 };
 use tokio::sync::{broadcast, Mutex};
+use tokio_util::sync::CancellationToken;
+
+// hidapi hands back input reports in 64 byte chunks.
+const HID_REPORT_SIZE: usize = 64;
 
 pub struct HidHardwareConnector {
   hid_instance: Arc<HidApi>,
@@ -71,7 +83,10 @@ impl HardwareConnector for HidHardwareConnector {
 
   async fn connect(&mut self) -> Result<Box<dyn HardwareSpecializer>, ButtplugDeviceError> {
     let device = self.device_info.open_device(&self.hid_instance).unwrap();
-    let device_impl_internal = HIDDeviceImpl::new(HidAsyncDevice::new(device).unwrap());
+    let device_impl_internal = HIDDeviceImpl::new(
+      self.device_info.serial_number().unwrap(),
+      HidAsyncDevice::new(device).unwrap(),
+    );
     info!(
       "New HID device created: {}",
       self.device_info.product_string().unwrap()
@@ -87,19 +102,39 @@ impl HardwareConnector for HidHardwareConnector {
 }
 
 pub struct HIDDeviceImpl {
+  address: String,
   connected: Arc<AtomicBool>,
   device_event_sender: broadcast::Sender<HardwareEvent>,
-  device: Arc<Mutex<HidAsyncDevice>>,
+  reader: Arc<Mutex<Box<dyn AsyncRead + Send + Unpin>>>,
+  writer: Arc<Mutex<Box<dyn AsyncWrite + Send + Unpin>>>,
+  subscribe_token: Arc<Mutex<Option<CancellationToken>>>,
 }
 
 impl HIDDeviceImpl {
-  pub fn new(device: HidAsyncDevice) -> Self {
+  /// Takes anything that reads input reports and writes output reports, which is usually a
+  /// [HidAsyncDevice], but can be swapped out for testing.
+  pub fn new<D>(address: &str, device: D) -> Self
+  where
+    D: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+  {
     let (device_event_sender, _) = broadcast::channel(256);
+    // Split the device so a pending input report read doesn't hold up writes.
+    let (reader, writer) = device.split();
     Self {
-      device: Arc::new(Mutex::new(device)),
+      address: address.to_owned(),
+      reader: Arc::new(Mutex::new(Box::new(reader))),
+      writer: Arc::new(Mutex::new(Box::new(writer))),
       connected: Arc::new(AtomicBool::new(true)),
       device_event_sender,
+      subscribe_token: Arc::new(Mutex::new(None)),
+    }
+  }
+
+  fn check_endpoint(endpoint: Endpoint) -> Result<(), ButtplugDeviceError> {
+    if endpoint != Endpoint::Rx {
+      return Err(ButtplugDeviceError::InvalidEndpoint(endpoint));
     }
+    Ok(())
   }
 }
 
@@ -110,8 +145,12 @@ impl HardwareInternal for HIDDeviceImpl {
 
   fn disconnect(&self) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
     let connected = self.connected.clone();
+    let subscribe_token = self.subscribe_token.clone();
     Box::pin(async move {
       connected.store(false, Ordering::SeqCst);
+      if let Some(token) = subscribe_token.lock().await.take() {
+        token.cancel();
+      }
       Ok(())
     })
   }
@@ -127,10 +166,10 @@ impl HardwareInternal for HIDDeviceImpl {
     &self,
     msg: &HardwareWriteCmd,
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
-    let device = self.device.clone();
+    let writer = self.writer.clone();
     let data = msg.data.clone();
     Box::pin(async move {
-      device.lock().await.write(&data).await.map_err(|e| {
+      writer.lock().await.write(&data).await.map_err(|e| {
         ButtplugDeviceError::DeviceCommunicationError(format!(
           "Cannot write to HID Device: {:?}.",
           e
@@ -142,15 +181,199 @@ impl HardwareInternal for HIDDeviceImpl {
 
   fn subscribe(
     &self,
-    _msg: &HardwareSubscribeCmd,
+    msg: &HardwareSubscribeCmd,
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
-    unimplemented!();
+    if let Err(err) = Self::check_endpoint(msg.endpoint()) {
+      return future::ready(Err(err)).boxed();
+    }
+    if let Err(err) = msg.check_no_prefix_filter("HID") {
+      return future::ready(Err(err)).boxed();
+    }
+    let endpoint = msg.endpoint();
+    let reader = self.reader.clone();
+    let event_sender = self.device_event_sender.clone();
+    let address = self.address.clone();
+    let subscribe_token = self.subscribe_token.clone();
+    async move {
+      let mut subscribe_token = subscribe_token.lock().await;
+      if subscribe_token.is_some() {
+        error!("Endpoint already subscribed somehow!");
+        return Ok(());
+      }
+      let token = CancellationToken::new();
+      *subscribe_token = Some(token.clone());
+      async_manager::spawn(async move {
+        // Only one subscription runs at a time, so we can hold the reader until we're cancelled.
+        let mut reader = reader.lock().await;
+        let mut buf = [0u8; HID_REPORT_SIZE];
+        loop {
+          // Finish the read before looking at the buffer, so its borrow has ended.
+          let result = select! {
+            result = reader.read(&mut buf).fuse() => result,
+            _ = token.cancelled().fuse() => break,
+          };
+          match result {
+            Ok(0) | Err(_) => break,
+            Ok(len) => {
+              // We don't really care if there's no one to send the event to here.
+              let _ = event_sender.send(HardwareEvent::Notification(
+                address.clone(),
+                endpoint,
+                buf[..len].to_vec(),
+                None,
+              ));
+            }
+          }
+        }
+        info!("HID input report task for {} exiting", address);
+      });
+      Ok(())
+    }
+    .boxed()
   }
 
   fn unsubscribe(
     &self,
-    _msg: &HardwareUnsubscribeCmd,
+    msg: &HardwareUnsubscribeCmd,
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
-    unimplemented!();
+    if let Err(err) = Self::check_endpoint(msg.endpoint()) {
+      return future::ready(Err(err)).boxed();
+    }
+    let subscribe_token = self.subscribe_token.clone();
+    async move {
+      if let Some(token) = subscribe_token.lock().await.take() {
+        token.cancel();
+        Ok(())
+      } else {
+        Err(ButtplugDeviceError::DeviceCommunicationError(
+          "Device not subscribed.".to_owned(),
+        ))
+      }
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use futures::{
+    channel::mpsc,
+    stream::{StreamExt, TryStreamExt},
+    task::{Context, Poll},
+  };
+  use std::{io, pin::Pin};
+
+  // Stands in for a HidAsyncDevice. Each item sent on the input channel comes back as one input
+  // report read, and written output reports are collected for inspection.
+  struct MockHidDevice {
+    input: Pin<Box<dyn AsyncRead + Send>>,
+    output: std::sync::Arc<std::sync::Mutex<Vec<Vec<u8>>>>,
+  }
+
+  impl AsyncRead for MockHidDevice {
+    fn poll_read(
+      mut self: Pin<&mut Self>,
+      cx: &mut Context<'_>,
+      buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+      self.input.as_mut().poll_read(cx, buf)
+    }
+  }
+
+  impl AsyncWrite for MockHidDevice {
+    fn poll_write(
+      self: Pin<&mut Self>,
+      _cx: &mut Context<'_>,
+      buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+      self
+        .output
+        .lock()
+        .expect("Test, assuming infallible")
+        .push(buf.to_vec());
+      Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+      Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+      Poll::Ready(Ok(()))
+    }
+  }
+
+  fn setup_mock_device() -> (
+    HIDDeviceImpl,
+    mpsc::UnboundedSender<Vec<u8>>,
+    std::sync::Arc<std::sync::Mutex<Vec<Vec<u8>>>>,
+  ) {
+    let (input_sender, input_receiver) = mpsc::unbounded::<Vec<u8>>();
+    let output = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let device = MockHidDevice {
+      input: Box::pin(input_receiver.map(Ok::<_, io::Error>).into_async_read()),
+      output: output.clone(),
+    };
+    (
+      HIDDeviceImpl::new("test-address", device),
+      input_sender,
+      output,
+    )
+  }
+
+  #[tokio::test]
+  async fn test_hid_write_output_report() {
+    let (device, _input, output) = setup_mock_device();
+    device
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![0, 1, 2], false))
+      .await
+      .expect("Test, assuming infallible");
+    assert_eq!(
+      *output.lock().expect("Test, assuming infallible"),
+      vec![vec![0, 1, 2]]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_hid_subscribe_input_reports() {
+    let (device, input, _output) = setup_mock_device();
+    let mut event_receiver = device.event_stream();
+    device
+      .subscribe(&HardwareSubscribeCmd::new(Endpoint::Rx))
+      .await
+      .expect("Test, assuming infallible");
+    for report in [vec![1, 2, 3], vec![4, 5]] {
+      input
+        .unbounded_send(report.clone())
+        .expect("Test, assuming infallible");
+      match event_receiver.recv().await {
+        Ok(HardwareEvent::Notification(address, endpoint, data, _)) => {
+          assert_eq!(address, "test-address");
+          assert_eq!(endpoint, Endpoint::Rx);
+          assert_eq!(data, report);
+        }
+        event => panic!("Expected notification, got {:?}", event),
+      }
+    }
+    device
+      .unsubscribe(&HardwareUnsubscribeCmd::new(Endpoint::Rx))
+      .await
+      .expect("Test, assuming infallible");
+    assert!(device
+      .unsubscribe(&HardwareUnsubscribeCmd::new(Endpoint::Rx))
+      .await
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn test_hid_subscribe_invalid_endpoint() {
+    let (device, _input, _output) = setup_mock_device();
+    assert!(matches!(
+      device
+        .subscribe(&HardwareSubscribeCmd::new(Endpoint::Tx))
+        .await,
+      Err(ButtplugDeviceError::InvalidEndpoint(Endpoint::Tx))
+    ));
   }
 }
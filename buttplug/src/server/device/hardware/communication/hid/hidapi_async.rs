@@ -32,6 +32,9 @@ pub enum HidAsyncDeviceError {
   Spawn(#[from] SpawnError),
 }
 
+// How long the read thread blocks on the device before letting go of it.
+const READ_TIMEOUT_MS: i32 = 100;
+
 enum ReadState {
   Idle,
   Busy,
@@ -111,8 +114,9 @@ impl HidAsyncDevice {
           match device.lock() {
             Ok(guard) => {
               let mut buf = [0u8; 64];
-              //match guard.read_timeout(&mut buf[..], 1000) {
-              match guard.read(&mut buf[..]) {
+              // Time out regularly so the device lock is released and writes can get through
+              // while we wait on input reports.
+              match guard.read_timeout(&mut buf[..], READ_TIMEOUT_MS) {
                 Err(_) => {
                   //error!("hidapi failed: {}", e);
                   drop(data_tx);
@@ -273,8 +277,9 @@ impl AsyncRead for HidAsyncDevice {
           let vec = match this.data_rx.try_recv() {
             Ok(Some(vec)) => vec,
             Ok(None) => {
-              // end of stream?
-              return Poll::Pending;
+              // Read timed out without a report, ask the read thread for another one.
+              this.rstate = ReadState::Idle;
+              continue;
             }
             Err(e) => match e {
               mpsc::TryRecvError::Disconnected => {
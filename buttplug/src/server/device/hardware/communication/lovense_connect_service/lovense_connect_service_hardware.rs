@@ -11,6 +11,7 @@ use crate::{
   server::device::{
     configuration::{LovenseConnectServiceSpecifier, ProtocolCommunicationSpecifier},
     hardware::{
+      DisconnectReason,
       GenericHardwareSpecializer,
       Hardware,
       HardwareConnector,
@@ -103,7 +104,10 @@ impl LovenseServiceHardware {
                 continue;
               }
               if !toy.connected {
-                let _ = sender_clone.send(HardwareEvent::Disconnected(toy_id.clone()));
+                let _ = sender_clone.send(HardwareEvent::Disconnected(
+                  toy_id.clone(),
+                  DisconnectReason::ReadError,
+                ));
                 info!("Exiting lovense service device connection check loop.");
                 break;
               }
@@ -112,7 +116,10 @@ impl LovenseServiceHardware {
             }
           }
           None => {
-            let _ = sender_clone.send(HardwareEvent::Disconnected(toy_id.clone()));
+            let _ = sender_clone.send(HardwareEvent::Disconnected(
+              toy_id.clone(),
+              DisconnectReason::ReadError,
+            ));
             info!("Exiting lovense service device connection check loop.");
             break;
           }
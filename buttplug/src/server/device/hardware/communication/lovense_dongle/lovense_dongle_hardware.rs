@@ -17,6 +17,7 @@ use crate::{
   server::device::{
     configuration::{BluetoothLESpecifier, ProtocolCommunicationSpecifier},
     hardware::{
+      DisconnectReason,
       GenericHardwareSpecializer,
       Hardware,
       HardwareConnector,
@@ -141,6 +142,7 @@ impl LovenseDongleHardware {
             address_clone.clone(),
             Endpoint::Rx,
             data_str.into_bytes(),
+            None,
           ))
           .is_err()
         {
@@ -153,7 +155,10 @@ impl LovenseDongleHardware {
       }
       info!("Lovense dongle device disconnected",);
       if device_event_sender_clone
-        .send(HardwareEvent::Disconnected(address_clone.clone()))
+        .send(HardwareEvent::Disconnected(
+          address_clone.clone(),
+          DisconnectReason::ReadError,
+        ))
         .is_err()
       {
         error!("Device Manager no longer alive, cannot send removed event.");
@@ -8,6 +8,8 @@
 // Network DCMs work on all platforms
 #[cfg(feature = "lovense-connect-service-manager")]
 pub mod lovense_connect_service;
+#[cfg(feature = "tcp-server-manager")]
+pub mod tcp_server;
 #[cfg(feature = "websocket-server-manager")]
 pub mod websocket_server;
 
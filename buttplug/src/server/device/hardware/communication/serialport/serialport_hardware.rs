@@ -268,6 +268,16 @@ impl SerialPortHardware {
       thread_cancellation_token: token,
     })
   }
+
+  // Serial ports only have one line in each direction, so reads and subscriptions go through Rx
+  // and writes go through Tx.
+  fn check_endpoint(expected: Endpoint, endpoint: Endpoint) -> Result<(), ButtplugDeviceError> {
+    if endpoint == expected {
+      Ok(())
+    } else {
+      Err(ButtplugDeviceError::InvalidEndpoint(endpoint))
+    }
+  }
 }
 
 impl HardwareInternal for SerialPortHardware {
@@ -286,9 +296,12 @@ impl HardwareInternal for SerialPortHardware {
 
   fn read_value(
     &self,
-    _msg: &HardwareReadCmd,
+    msg: &HardwareReadCmd,
   ) -> BoxFuture<'static, Result<HardwareReading, ButtplugDeviceError>> {
-    // TODO Should check endpoint validity and length requirements
+    // TODO Should check length requirements
+    if let Err(err) = Self::check_endpoint(Endpoint::Rx, msg.endpoint()) {
+      return future::ready(Err(err)).boxed();
+    }
     let receiver = self.port_receiver.clone();
     async move {
       let mut recv_mut = receiver.lock().await;
@@ -310,7 +323,9 @@ impl HardwareInternal for SerialPortHardware {
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
     let sender = self.port_sender.clone();
     let data = msg.data.clone();
-    // TODO Should check endpoint validity
+    if let Err(err) = Self::check_endpoint(Endpoint::Tx, msg.endpoint()) {
+      return future::ready(Err(err)).boxed();
+    }
     async move {
       if sender.send(data).await.is_err() {
         warn!("Tasks should exist if we get here, but may not if we're shutting down");
@@ -322,9 +337,14 @@ impl HardwareInternal for SerialPortHardware {
 
   fn subscribe(
     &self,
-    _msg: &HardwareSubscribeCmd,
+    msg: &HardwareSubscribeCmd,
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
-    // TODO Should check endpoint validity
+    if let Err(err) = Self::check_endpoint(Endpoint::Rx, msg.endpoint()) {
+      return future::ready(Err(err)).boxed();
+    }
+    if let Err(err) = msg.check_no_prefix_filter("Serial port") {
+      return future::ready(Err(err)).boxed();
+    }
     let data_receiver = self.port_receiver.clone();
     let event_sender = self.device_event_sender.clone();
     let address = self.address.clone();
@@ -343,6 +363,7 @@ impl HardwareInternal for SerialPortHardware {
                   address.clone(),
                   Endpoint::Tx,
                   data,
+                  None,
                 ))
                 .expect("As long as we're subscribed we should have a listener");
             }
@@ -374,3 +395,17 @@ impl Drop for SerialPortHardware {
     self.thread_cancellation_token.cancel();
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_serial_port_hardware_check_endpoint() {
+    assert!(SerialPortHardware::check_endpoint(Endpoint::Tx, Endpoint::Tx).is_ok());
+    assert!(matches!(
+      SerialPortHardware::check_endpoint(Endpoint::Rx, Endpoint::Tx),
+      Err(ButtplugDeviceError::InvalidEndpoint(Endpoint::Tx))
+    ));
+  }
+}
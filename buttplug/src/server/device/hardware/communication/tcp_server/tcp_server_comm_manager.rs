@@ -0,0 +1,201 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::tcp_server_hardware::{TcpFraming, TcpServerHardwareConnector};
+use crate::{
+  core::ButtplugResultFuture,
+  server::device::hardware::communication::{
+    HardwareCommunicationManager,
+    HardwareCommunicationManagerBuilder,
+    HardwareCommunicationManagerEvent,
+  },
+  util::async_manager,
+};
+use futures::FutureExt;
+use getset::{CopyGetters, Getters};
+use serde::{Deserialize, Serialize};
+use tokio::{io::BufReader, net::TcpListener, sync::mpsc::Sender};
+use tokio_util::sync::CancellationToken;
+
+// Packet format received from external devices, as the first frame on a new connection.
+#[derive(Serialize, Deserialize, Debug, Clone, Getters, CopyGetters)]
+pub struct TcpServerDeviceCommManagerInitInfo {
+  #[getset(get = "pub")]
+  identifier: String,
+  #[getset(get = "pub")]
+  address: String,
+  #[getset(get_copy = "pub")]
+  version: u32,
+}
+
+#[derive(Clone)]
+pub struct TcpServerDeviceCommunicationManagerBuilder {
+  listen_on_all_interfaces: bool,
+  server_port: u16,
+  framing: TcpFraming,
+}
+
+impl Default for TcpServerDeviceCommunicationManagerBuilder {
+  fn default() -> Self {
+    Self {
+      listen_on_all_interfaces: false,
+      server_port: 54818,
+      framing: TcpFraming::default(),
+    }
+  }
+}
+
+impl TcpServerDeviceCommunicationManagerBuilder {
+  pub fn listen_on_all_interfaces(mut self, should_listen: bool) -> Self {
+    self.listen_on_all_interfaces = should_listen;
+    self
+  }
+
+  pub fn server_port(mut self, port: u16) -> Self {
+    self.server_port = port;
+    self
+  }
+
+  pub fn framing(mut self, framing: TcpFraming) -> Self {
+    self.framing = framing;
+    self
+  }
+}
+
+impl HardwareCommunicationManagerBuilder for TcpServerDeviceCommunicationManagerBuilder {
+  fn finish(
+    &mut self,
+    sender: Sender<HardwareCommunicationManagerEvent>,
+  ) -> Box<dyn HardwareCommunicationManager> {
+    Box::new(TcpServerDeviceCommunicationManager::new(
+      sender,
+      self.server_port,
+      self.listen_on_all_interfaces,
+      self.framing,
+    ))
+  }
+}
+
+pub struct TcpServerDeviceCommunicationManager {
+  server_cancellation_token: CancellationToken,
+}
+
+impl TcpServerDeviceCommunicationManager {
+  fn new(
+    sender: Sender<HardwareCommunicationManagerEvent>,
+    port: u16,
+    listen_on_all_interfaces: bool,
+    framing: TcpFraming,
+  ) -> Self {
+    trace!("TCP server port created.");
+    let server_cancellation_token = CancellationToken::new();
+    let child_token = server_cancellation_token.child_token();
+    async_manager::spawn(async move {
+      let base_addr = if listen_on_all_interfaces {
+        "0.0.0.0"
+      } else {
+        "127.0.0.1"
+      };
+
+      let addr = format!("{}:{}", base_addr, port);
+      debug!("Trying to listen on {}", addr);
+      let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+          error!("Cannot bind TCP server to {}: {:?}.", addr, err);
+          return;
+        }
+      };
+      debug!("Listening on: {}", addr);
+      loop {
+        select! {
+          listener_result = listener.accept().fuse() => {
+            let stream = if let Ok((stream, _)) = listener_result {
+              stream
+            } else {
+              error!("Cannot bind TCP server comm manager to address {}.", addr);
+              return;
+            };
+            info!("Got connection");
+            // As with websocket devices, we know nothing about the device until it sends us its
+            // info packet, so wait for that before telling the device manager about it.
+            let sender_clone = sender.clone();
+            tokio::spawn(async move {
+              let mut stream = BufReader::new(stream);
+              // TODO Implement a receive timeout here so we don't wait forever
+              let info_packet: TcpServerDeviceCommManagerInitInfo =
+                match framing.read_frame(&mut stream).await {
+                  Ok(frame) => {
+                    if let Ok(packet) = serde_json::from_slice(&frame) {
+                      packet
+                    } else {
+                      error!("Did not receive a valid JSON info packet as the first frame, disconnecting.");
+                      return;
+                    }
+                  }
+                  Err(err) => {
+                    error!("Did not receive info frame as first frame, dropping connection: {}", err);
+                    return;
+                  }
+                };
+              let connector = TcpServerHardwareConnector::new(
+                info_packet.clone(),
+                stream,
+                framing,
+                CancellationToken::new(),
+              );
+              if sender_clone
+                .send(HardwareCommunicationManagerEvent::DeviceFound {
+                  name: format!("TCP Device {}", info_packet.identifier),
+                  address: info_packet.address.clone(),
+                  creator: Box::new(connector),
+                })
+                .await
+                .is_err()
+              {
+                error!("Device manager disappeared, exiting.");
+              }
+            });
+          },
+          _ = child_token.cancelled().fuse() => {
+            info!("Task token cancelled, assuming TCP server comm manager shutdown.");
+            break;
+          }
+        }
+      }
+    });
+    Self {
+      server_cancellation_token,
+    }
+  }
+}
+
+impl HardwareCommunicationManager for TcpServerDeviceCommunicationManager {
+  fn name(&self) -> &'static str {
+    "TcpServerCommunicationManager"
+  }
+
+  fn start_scanning(&mut self) -> ButtplugResultFuture {
+    debug!("TCP server manager scanning for devices.");
+    async move { Ok(()) }.boxed()
+  }
+
+  fn stop_scanning(&mut self) -> ButtplugResultFuture {
+    async move { Ok(()) }.boxed()
+  }
+
+  // No restrictions since this is network not hardware.
+  fn can_scan(&self) -> bool {
+    true
+  }
+}
+
+impl Drop for TcpServerDeviceCommunicationManager {
+  fn drop(&mut self) {
+    self.server_cancellation_token.cancel();
+  }
+}
@@ -0,0 +1,726 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::tcp_server_comm_manager::TcpServerDeviceCommManagerInitInfo;
+use crate::{
+  core::{errors::ButtplugDeviceError, message::Endpoint},
+  server::device::{
+    configuration::{ProtocolCommunicationSpecifier, TcpSpecifier},
+    hardware::{
+      DisconnectReason,
+      GenericHardwareSpecializer,
+      Hardware,
+      HardwareConnector,
+      HardwareEvent,
+      HardwareInternal,
+      HardwareReadCmd,
+      HardwareReading,
+      HardwareSpecializer,
+      HardwareSubscribeCmd,
+      HardwareUnsubscribeCmd,
+      HardwareWriteCmd,
+    },
+  },
+  util::async_manager,
+};
+use async_trait::async_trait;
+use futures::{
+  future::{self, BoxFuture},
+  stream,
+  FutureExt,
+  StreamExt,
+};
+use std::{
+  fmt::{self, Debug},
+  io,
+  sync::Arc,
+  time::Duration,
+};
+use tokio::{
+  io::{
+    AsyncBufRead,
+    AsyncBufReadExt,
+    AsyncRead,
+    AsyncReadExt,
+    AsyncWrite,
+    AsyncWriteExt,
+    BufReader,
+  },
+  sync::{
+    broadcast,
+    mpsc::{channel, Receiver, Sender},
+    Mutex,
+  },
+  time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+
+// Used when a read command doesn't specify its own timeout.
+const DEFAULT_READ_TIMEOUT_MS: u32 = 1000;
+// Length prefixes come straight off the wire, so don't trust them to size our buffers.
+const MAX_FRAME_LENGTH: u32 = 1024 * 1024;
+// As with websocket devices, the connection itself is the only thing we can talk to.
+const TCP_ENDPOINTS: [Endpoint; 2] = [Endpoint::Rx, Endpoint::Tx];
+
+/// How frames are delimited on a TCP device connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TcpFraming {
+  /// Each frame is preceded by its length, as a big endian u32.
+  #[default]
+  LengthPrefixed,
+  /// Each frame is terminated by a newline, which is not part of the frame data.
+  NewlineDelimited,
+}
+
+impl TcpFraming {
+  pub(super) async fn read_frame<R>(&self, reader: &mut R) -> io::Result<Vec<u8>>
+  where
+    R: AsyncBufRead + Unpin,
+  {
+    match self {
+      TcpFraming::LengthPrefixed => {
+        let length = reader.read_u32().await?;
+        if length > MAX_FRAME_LENGTH {
+          return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Frame length {} is over the maximum frame length.", length),
+          ));
+        }
+        let mut frame = vec![0; length as usize];
+        reader.read_exact(&mut frame).await?;
+        Ok(frame)
+      }
+      TcpFraming::NewlineDelimited => {
+        let mut frame = vec![];
+        // Lines get the same cap as length prefixed frames, plus room for the newline.
+        (&mut *reader)
+          .take(MAX_FRAME_LENGTH as u64 + 1)
+          .read_until(b'\n', &mut frame)
+          .await?;
+        if frame.last() == Some(&b'\n') {
+          frame.pop();
+          return Ok(frame);
+        }
+        // No newline within the cap, so the line is too long, unless we ran out of data first.
+        if frame.len() > MAX_FRAME_LENGTH as usize {
+          return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Line is over the maximum frame length.",
+          ));
+        }
+        // A connection closing partway through a line loses that line.
+        Err(io::ErrorKind::UnexpectedEof.into())
+      }
+    }
+  }
+
+  /// Checks that `data` can be sent as a single frame. A newline inside a newline delimited frame
+  /// would be read as the end of it on the other side.
+  pub(super) fn check_frame(&self, data: &[u8]) -> io::Result<()> {
+    if *self == TcpFraming::NewlineDelimited && data.contains(&b'\n') {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Newline delimited frames cannot contain newlines.",
+      ));
+    }
+    Ok(())
+  }
+
+  pub(super) async fn write_frame<W>(&self, writer: &mut W, data: &[u8]) -> io::Result<()>
+  where
+    W: AsyncWrite + Unpin,
+  {
+    self.check_frame(data)?;
+    match self {
+      TcpFraming::LengthPrefixed => {
+        writer.write_u32(data.len() as u32).await?;
+        writer.write_all(data).await?;
+      }
+      TcpFraming::NewlineDelimited => {
+        writer.write_all(data).await?;
+        writer.write_all(b"\n").await?;
+      }
+    }
+    writer.flush().await
+  }
+}
+
+async fn run_connection_loop<S>(
+  address: &str,
+  event_sender: broadcast::Sender<HardwareEvent>,
+  stream: BufReader<S>,
+  framing: TcpFraming,
+  mut request_receiver: Receiver<Vec<u8>>,
+  response_sender: broadcast::Sender<Vec<u8>>,
+  cancellation_token: CancellationToken,
+) where
+  S: AsyncRead + AsyncWrite + Unpin,
+{
+  info!("Starting TCP server connection event loop.");
+
+  let (reader, mut writer) = tokio::io::split(stream);
+  // Reading a frame can take several reads, so keep the read in progress alive across loop
+  // iterations instead of restarting it every time something else wakes us up.
+  let frames = stream::unfold(BufReader::new(reader), move |mut reader| async move {
+    let frame = framing.read_frame(&mut reader).await;
+    Some((frame, reader))
+  });
+  futures::pin_mut!(frames);
+
+  // There's no ping/pong here, so a failed read or write is how we find out the device is gone.
+  loop {
+    select! {
+      _ = cancellation_token.cancelled().fuse() => {
+        info!("TCP device connection shut down, disconnecting.");
+        while request_receiver.try_recv().is_ok() {}
+        let _ = event_sender.send(HardwareEvent::Disconnected(
+          address.to_owned(),
+          DisconnectReason::OwnerDropped,
+        ));
+        break;
+      }
+      request = request_receiver.recv().fuse() => {
+        if let Some(data) = request {
+          if let Err(err) = framing.write_frame(&mut writer, &data).await {
+            error!("Cannot send value to TCP device, considering connection closed: {}", err);
+            let _ = event_sender.send(HardwareEvent::Disconnected(
+              address.to_owned(),
+              DisconnectReason::WriteError,
+            ));
+            break;
+          }
+        } else {
+          info!("TCP server connector owner dropped, disconnecting TCP connection.");
+          break;
+        }
+      }
+      frame = frames.next().fuse() => match frame {
+        Some(Ok(data)) => {
+          // If no one is listening, ignore output.
+          let _ = response_sender.send(data);
+        }
+        Some(Err(err)) => {
+          info!("Cannot read from TCP device, considering connection closed: {}", err);
+          // Running out of data between frames is how a device closing its end shows up.
+          let reason = if err.kind() == io::ErrorKind::UnexpectedEof {
+            DisconnectReason::ClientClose
+          } else {
+            DisconnectReason::ReadError
+          };
+          let _ = event_sender.send(HardwareEvent::Disconnected(address.to_owned(), reason));
+          break;
+        }
+        None => {
+          // Our frame stream never ends on its own, but treat it as a close anyways.
+          let _ = event_sender.send(HardwareEvent::Disconnected(
+            address.to_owned(),
+            DisconnectReason::ClientClose,
+          ));
+          break;
+        }
+      }
+    }
+  }
+
+  if let Err(err) = writer.shutdown().await {
+    debug!("Error shutting down TCP connection: {}", err);
+  }
+  debug!("Exiting TCP Server Device control loop.");
+}
+
+impl Debug for TcpServerHardwareConnector {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("TcpServerHardwareConnector")
+      .field("info", &self.info)
+      .finish()
+  }
+}
+
+pub struct TcpServerHardwareConnector {
+  info: TcpServerDeviceCommManagerInitInfo,
+  framing: TcpFraming,
+  outgoing_sender: Sender<Vec<u8>>,
+  incoming_broadcaster: broadcast::Sender<Vec<u8>>,
+  device_event_sender: broadcast::Sender<HardwareEvent>,
+  cancellation_token: CancellationToken,
+}
+
+impl TcpServerHardwareConnector {
+  /// Spawns the connection loop for an accepted TCP connection. The stream is taken as a
+  /// [BufReader], since the comm manager has already read the info frame through one, and may have
+  /// buffered data past it. Cancelling `cancellation_token` shuts the loop down.
+  pub fn new<S>(
+    info: TcpServerDeviceCommManagerInitInfo,
+    stream: BufReader<S>,
+    framing: TcpFraming,
+    cancellation_token: CancellationToken,
+  ) -> Self
+  where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+  {
+    let (outgoing_sender, outgoing_receiver) = channel(256);
+    let (incoming_broadcaster, _) = broadcast::channel(256);
+    let incoming_broadcaster_clone = incoming_broadcaster.clone();
+    let (device_event_sender, _) = broadcast::channel(256);
+    let device_event_sender_clone = device_event_sender.clone();
+    let address = info.address().clone();
+    let cancellation_token_clone = cancellation_token.clone();
+    tokio::spawn(async move {
+      run_connection_loop(
+        &address,
+        device_event_sender_clone,
+        stream,
+        framing,
+        outgoing_receiver,
+        incoming_broadcaster_clone,
+        cancellation_token_clone,
+      )
+      .await;
+    });
+    Self {
+      info,
+      framing,
+      outgoing_sender,
+      incoming_broadcaster,
+      device_event_sender,
+      cancellation_token,
+    }
+  }
+}
+
+#[async_trait]
+impl HardwareConnector for TcpServerHardwareConnector {
+  fn specifier(&self) -> ProtocolCommunicationSpecifier {
+    ProtocolCommunicationSpecifier::Tcp(TcpSpecifier::new(self.info.identifier()))
+  }
+
+  async fn connect(&mut self) -> Result<Box<dyn HardwareSpecializer>, ButtplugDeviceError> {
+    let hardware_internal = TcpServerHardware::new(
+      self.device_event_sender.clone(),
+      self.info.clone(),
+      self.framing,
+      self.outgoing_sender.clone(),
+      self.incoming_broadcaster.clone(),
+      self.cancellation_token.clone(),
+    );
+    let hardware = Hardware::new(
+      self.info.identifier(),
+      self.info.address(),
+      &TCP_ENDPOINTS,
+      Box::new(hardware_internal),
+    );
+    Ok(Box::new(GenericHardwareSpecializer::new(hardware)))
+  }
+}
+
+pub struct TcpServerHardware {
+  info: TcpServerDeviceCommManagerInitInfo,
+  framing: TcpFraming,
+  outgoing_sender: Sender<Vec<u8>>,
+  incoming_broadcaster: broadcast::Sender<Vec<u8>>,
+  device_event_sender: broadcast::Sender<HardwareEvent>,
+  connection_token: CancellationToken,
+  subscribe_token: Arc<Mutex<Option<CancellationToken>>>,
+}
+
+impl TcpServerHardware {
+  pub fn new(
+    device_event_sender: broadcast::Sender<HardwareEvent>,
+    info: TcpServerDeviceCommManagerInitInfo,
+    framing: TcpFraming,
+    outgoing_sender: Sender<Vec<u8>>,
+    incoming_broadcaster: broadcast::Sender<Vec<u8>>,
+    connection_token: CancellationToken,
+  ) -> Self {
+    Self {
+      info,
+      framing,
+      outgoing_sender,
+      incoming_broadcaster,
+      device_event_sender,
+      connection_token,
+      subscribe_token: Arc::new(Mutex::new(None)),
+    }
+  }
+
+  fn check_endpoint(endpoint: Endpoint) -> Result<(), ButtplugDeviceError> {
+    if TCP_ENDPOINTS.contains(&endpoint) {
+      Ok(())
+    } else {
+      Err(ButtplugDeviceError::InvalidEndpoint(endpoint))
+    }
+  }
+}
+
+impl HardwareInternal for TcpServerHardware {
+  fn event_stream(&self) -> broadcast::Receiver<HardwareEvent> {
+    self.device_event_sender.subscribe()
+  }
+
+  fn disconnect(&self) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    self.connection_token.cancel();
+    future::ready(Ok(())).boxed()
+  }
+
+  fn read_value(
+    &self,
+    msg: &HardwareReadCmd,
+  ) -> BoxFuture<'static, Result<HardwareReading, ButtplugDeviceError>> {
+    if let Err(err) = Self::check_endpoint(msg.endpoint()) {
+      return future::ready(Err(err)).boxed();
+    }
+    // Unlike websocket devices there's no request format we can assume, so a read is just the
+    // next frame the device sends.
+    let mut data_receiver = self.incoming_broadcaster.subscribe();
+    let endpoint = msg.endpoint();
+    let timeout_ms = if msg.timeout_ms() == 0 {
+      DEFAULT_READ_TIMEOUT_MS
+    } else {
+      msg.timeout_ms()
+    };
+    async move {
+      match timeout(
+        Duration::from_millis(timeout_ms as u64),
+        data_receiver.recv(),
+      )
+      .await
+      {
+        Ok(Ok(data)) => Ok(HardwareReading::new(endpoint, &data)),
+        Ok(Err(err)) => Err(ButtplugDeviceError::DeviceCommunicationError(format!(
+          "Could not read value from TCP device: {}",
+          err
+        ))),
+        Err(_) => Err(ButtplugDeviceError::DeviceCommunicationError(format!(
+          "TCP device did not send anything within {}ms.",
+          timeout_ms
+        ))),
+      }
+    }
+    .boxed()
+  }
+
+  fn write_value(
+    &self,
+    msg: &HardwareWriteCmd,
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    if let Err(err) = Self::check_endpoint(msg.endpoint()) {
+      return future::ready(Err(err)).boxed();
+    }
+    // Catch frames we can't send here, rather than having the connection loop drop the
+    // connection over them.
+    if let Err(err) = self.framing.check_frame(&msg.data) {
+      return future::ready(Err(ButtplugDeviceError::DeviceCommunicationError(format!(
+        "Could not write value to TCP device: {}",
+        err
+      ))))
+      .boxed();
+    }
+    let sender = self.outgoing_sender.clone();
+    let data = msg.data.clone();
+    async move {
+      sender.send(data).await.map_err(|err| {
+        ButtplugDeviceError::DeviceCommunicationError(format!(
+          "Could not write value to TCP device: {}",
+          err
+        ))
+      })
+    }
+    .boxed()
+  }
+
+  fn subscribe(
+    &self,
+    msg: &HardwareSubscribeCmd,
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    if let Err(err) = Self::check_endpoint(msg.endpoint()) {
+      return future::ready(Err(err)).boxed();
+    }
+    if let Err(err) = msg.check_no_prefix_filter("TCP") {
+      return future::ready(Err(err)).boxed();
+    }
+    let endpoint = msg.endpoint();
+    let mut data_receiver = self.incoming_broadcaster.subscribe();
+    let event_sender = self.device_event_sender.clone();
+    let address = self.info.address().clone();
+    let identifier = self.info.identifier().clone();
+    let subscribe_token = self.subscribe_token.clone();
+    async move {
+      let mut subscribe_token = subscribe_token.lock().await;
+      if subscribe_token.is_some() {
+        error!("Endpoint already subscribed somehow!");
+        return Ok(());
+      }
+      let token = CancellationToken::new();
+      *subscribe_token = Some(token.clone());
+      async_manager::spawn(async move {
+        loop {
+          select! {
+            result = data_receiver.recv().fuse() => {
+              match result {
+                Ok(data) => {
+                  // We don't really care if there's no one to send the event to here.
+                  let _ = event_sender
+                    .send(HardwareEvent::Notification(
+                      address.clone(),
+                      endpoint,
+                      data,
+                      Some(identifier.clone()),
+                    ));
+                },
+                Err(_) => break,
+              }
+            },
+            _ = token.cancelled().fuse() => {
+              break;
+            }
+          }
+        }
+        info!("Data channel closed, ending TCP server device listener task");
+      });
+      Ok(())
+    }
+    .boxed()
+  }
+
+  fn subscribe_all(
+    &self,
+    endpoints: &[Endpoint],
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    if let Some(err) = endpoints
+      .iter()
+      .find_map(|endpoint| Self::check_endpoint(*endpoint).err())
+    {
+      return future::ready(Err(err)).boxed();
+    }
+    // There's only the one subscription to be had, so only make it once.
+    match endpoints.first() {
+      Some(endpoint) => self.subscribe(&HardwareSubscribeCmd::new(*endpoint)),
+      None => future::ready(Ok(())).boxed(),
+    }
+  }
+
+  fn unsubscribe(
+    &self,
+    msg: &HardwareUnsubscribeCmd,
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    if let Err(err) = Self::check_endpoint(msg.endpoint()) {
+      return future::ready(Err(err)).boxed();
+    }
+    let subscribe_token = self.subscribe_token.clone();
+    async move {
+      if let Some(token) = subscribe_token.lock().await.take() {
+        token.cancel();
+        Ok(())
+      } else {
+        Err(ButtplugDeviceError::DeviceCommunicationError(
+          "Device not subscribed.".to_owned(),
+        ))
+      }
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use tokio::net::{TcpListener, TcpStream};
+
+  // Accepts a single connection and echoes everything it receives back, until the client goes away.
+  async fn start_echo_server() -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0")
+      .await
+      .expect("Test, assuming infallible");
+    let addr = listener.local_addr().expect("Test, assuming infallible");
+    tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.expect("Test, assuming infallible");
+      let (mut reader, mut writer) = stream.split();
+      let _ = tokio::io::copy(&mut reader, &mut writer).await;
+    });
+    TcpStream::connect(addr)
+      .await
+      .expect("Test, assuming infallible")
+  }
+
+  async fn setup_test_hardware(stream: TcpStream, framing: TcpFraming) -> Hardware {
+    let info: TcpServerDeviceCommManagerInitInfo = serde_json::from_str(
+      r#"{"identifier": "test-device", "address": "test-address", "version": 0}"#,
+    )
+    .expect("Test, assuming infallible");
+    let mut connector = TcpServerHardwareConnector::new(
+      info,
+      BufReader::new(stream),
+      framing,
+      CancellationToken::new(),
+    );
+    connector
+      .connect()
+      .await
+      .expect("Test, assuming infallible")
+      .specialize(&[])
+      .await
+      .expect("Test, assuming infallible")
+  }
+
+  #[tokio::test]
+  async fn test_tcp_server_hardware_length_prefixed_echo() {
+    let hardware = setup_test_hardware(start_echo_server().await, TcpFraming::LengthPrefixed).await;
+    let mut event_receiver = hardware.event_stream();
+    hardware
+      .subscribe(&HardwareSubscribeCmd::new(Endpoint::Rx))
+      .await
+      .expect("Test, assuming infallible");
+    hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![1, 2, 3], false))
+      .await
+      .expect("Test, assuming infallible");
+    match event_receiver.recv().await {
+      Ok(HardwareEvent::Notification(address, endpoint, data, identifier)) => {
+        assert_eq!(address, "test-address");
+        assert_eq!(identifier, Some("test-device".to_owned()));
+        assert_eq!(endpoint, Endpoint::Rx);
+        assert_eq!(data, vec![1, 2, 3]);
+      }
+      event => panic!("Expected notification, got {:?}", event),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_tcp_server_hardware_newline_delimited_read() {
+    let hardware =
+      setup_test_hardware(start_echo_server().await, TcpFraming::NewlineDelimited).await;
+    let read = hardware.read_value(&HardwareReadCmd::new(Endpoint::Rx, 0, 500));
+    hardware
+      .write_value(&HardwareWriteCmd::new(
+        Endpoint::Tx,
+        b"hello".to_vec(),
+        false,
+      ))
+      .await
+      .expect("Test, assuming infallible");
+    let reading = read.await.expect("Test, assuming infallible");
+    assert_eq!(*reading.data(), b"hello".to_vec());
+  }
+
+  #[tokio::test]
+  async fn test_tcp_server_hardware_disconnect_on_close() {
+    let listener = TcpListener::bind("127.0.0.1:0")
+      .await
+      .expect("Test, assuming infallible");
+    let addr = listener.local_addr().expect("Test, assuming infallible");
+    let stream = TcpStream::connect(addr)
+      .await
+      .expect("Test, assuming infallible");
+    let (server_stream, _) = listener.accept().await.expect("Test, assuming infallible");
+    let hardware = setup_test_hardware(stream, TcpFraming::LengthPrefixed).await;
+    let mut event_receiver = hardware.event_stream();
+    drop(server_stream);
+    assert!(matches!(
+      event_receiver.recv().await,
+      Ok(HardwareEvent::Disconnected(address, DisconnectReason::ClientClose))
+        if address == "test-address"
+    ));
+  }
+
+  #[tokio::test]
+  async fn test_tcp_server_hardware_newline_delimited_write_rejects_newlines() {
+    let hardware =
+      setup_test_hardware(start_echo_server().await, TcpFraming::NewlineDelimited).await;
+    assert!(matches!(
+      hardware
+        .write_value(&HardwareWriteCmd::new(
+          Endpoint::Tx,
+          b"hello\nworld".to_vec(),
+          false,
+        ))
+        .await,
+      Err(ButtplugDeviceError::DeviceCommunicationError(_))
+    ));
+    // The connection is still usable afterwards.
+    let read = hardware.read_value(&HardwareReadCmd::new(Endpoint::Rx, 0, 500));
+    hardware
+      .write_value(&HardwareWriteCmd::new(
+        Endpoint::Tx,
+        b"hello".to_vec(),
+        false,
+      ))
+      .await
+      .expect("Test, assuming infallible");
+    let reading = read.await.expect("Test, assuming infallible");
+    assert_eq!(*reading.data(), b"hello".to_vec());
+    // Writing the frame directly fails without writing anything.
+    let mut written = vec![];
+    assert_eq!(
+      TcpFraming::NewlineDelimited
+        .write_frame(&mut written, b"a\nb")
+        .await
+        .expect_err("Test, assuming infallible")
+        .kind(),
+      io::ErrorKind::InvalidInput
+    );
+    assert!(written.is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_tcp_server_hardware_newline_delimited_max_frame_length() {
+    let mut line = vec![b'a'; MAX_FRAME_LENGTH as usize];
+    line.push(b'\n');
+    let frame = TcpFraming::NewlineDelimited
+      .read_frame(&mut BufReader::new(line.as_slice()))
+      .await
+      .expect("Test, assuming infallible");
+    assert_eq!(frame.len(), MAX_FRAME_LENGTH as usize);
+    // One byte over, and we stop reading instead of buffering until a newline shows up.
+    let line = vec![b'a'; MAX_FRAME_LENGTH as usize + 2];
+    assert_eq!(
+      TcpFraming::NewlineDelimited
+        .read_frame(&mut BufReader::new(line.as_slice()))
+        .await
+        .expect_err("Test, assuming infallible")
+        .kind(),
+      io::ErrorKind::InvalidData
+    );
+  }
+
+  #[tokio::test]
+  async fn test_tcp_server_hardware_subscribe_all() {
+    let hardware = setup_test_hardware(start_echo_server().await, TcpFraming::LengthPrefixed).await;
+    let mut event_receiver = hardware.event_stream();
+    hardware
+      .subscribe_all()
+      .await
+      .expect("Test, assuming infallible");
+    // If the frame were reported for both endpoints, the second notification would be a repeat.
+    for data in [vec![1], vec![2]] {
+      hardware
+        .write_value(&HardwareWriteCmd::new(Endpoint::Tx, data.clone(), false))
+        .await
+        .expect("Test, assuming infallible");
+      match event_receiver.recv().await {
+        Ok(HardwareEvent::Notification(_, endpoint, notification, _)) => {
+          assert_eq!(endpoint, Endpoint::Rx);
+          assert_eq!(notification, data);
+        }
+        event => panic!("Expected notification, got {:?}", event),
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn test_tcp_server_hardware_subscribe_prefix_filter_unsupported() {
+    let hardware = setup_test_hardware(start_echo_server().await, TcpFraming::LengthPrefixed).await;
+    assert!(matches!(
+      hardware
+        .subscribe(&HardwareSubscribeCmd::new_with_prefix_filter(
+          Endpoint::Rx,
+          &[0xaa],
+        ))
+        .await,
+      Err(ButtplugDeviceError::UnhandledCommand(_))
+    ));
+  }
+}
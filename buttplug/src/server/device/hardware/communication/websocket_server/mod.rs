@@ -6,4 +6,6 @@
 // for full license information.
 
 pub mod websocket_server_comm_manager;
+pub mod websocket_server_discovery;
+pub mod websocket_server_framing;
 pub mod websocket_server_hardware;
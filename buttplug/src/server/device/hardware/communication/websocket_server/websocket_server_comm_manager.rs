@@ -5,7 +5,16 @@
 // Licensed under the BSD 3-Clause license. See LICENSE file in the project root
 // for full license information.
 
-use super::websocket_server_hardware::WebsocketServerHardwareConnector;
+use super::{
+  websocket_server_discovery::{run_discovery, DiscoveredDevices, WebsocketDiscoveryConfig},
+  websocket_server_framing::{Framing, NoFraming},
+  websocket_server_hardware::{
+    try_reconnect,
+    try_replace,
+    ReconnectHandle,
+    WebsocketServerHardwareConnector,
+  },
+};
 use crate::{
   core::ButtplugResultFuture,
   server::device::hardware::communication::{
@@ -15,10 +24,24 @@ use crate::{
   },
   util::async_manager,
 };
+use dashmap::DashMap;
 use futures::{FutureExt, StreamExt};
-use getset::{CopyGetters, Getters};
+use getset::{CopyGetters, Getters, Setters};
 use serde::{Deserialize, Serialize};
-use tokio::{net::TcpListener, sync::mpsc::Sender};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+  io::{AsyncRead, AsyncWrite},
+  net::TcpListener,
+  sync::mpsc::Sender,
+};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+use tokio_tungstenite::{
+  tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue},
+  },
+  WebSocketStream,
+};
 use tokio_util::sync::CancellationToken;
 
 // Packet format received from external devices.
@@ -30,12 +53,135 @@ pub struct WebsocketServerDeviceCommManagerInitInfo {
   address: String,
   #[getset(get_copy = "pub")]
   version: u32,
+  /// Where the device announced itself from, if it replied to a discovery beacon before connecting.
+  #[serde(skip)]
+  #[getset(get_copy = "pub")]
+  discovered_from: Option<SocketAddr>,
+  /// Subprotocol agreed on during the websocket handshake, if the device offered one the server
+  /// accepts.
+  #[serde(skip)]
+  #[getset(get = "pub")]
+  subprotocol: Option<String>,
+}
+
+/// How long a device whose connection dropped is given to reconnect before it's considered gone.
+/// The wait is split into attempts, each twice as long as the last, up to the max delay.
+#[derive(Debug, Clone, Copy, CopyGetters, Setters)]
+#[getset(get_copy = "pub", set = "pub")]
+pub struct WebsocketReconnectConfig {
+  /// How long the first attempt waits.
+  base_delay: Duration,
+  /// Longest any single attempt waits.
+  max_delay: Duration,
+  /// How many attempts to make before giving up on the device.
+  max_attempts: u32,
+}
+
+impl Default for WebsocketReconnectConfig {
+  fn default() -> Self {
+    Self {
+      base_delay: Duration::from_millis(500),
+      max_delay: Duration::from_millis(8000),
+      max_attempts: 5,
+    }
+  }
+}
+
+/// Settings for each websocket device connection accepted by the comm manager.
+#[derive(Debug, Clone, CopyGetters, Setters)]
+#[getset(get_copy = "pub", set = "pub")]
+pub struct WebsocketServerDeviceConnectionConfig {
+  /// How long to wait between pings to the device.
+  ping_interval: Duration,
+  /// How many ping intervals can pass without a pong before the device is considered disconnected.
+  max_missed_pongs: u32,
+  /// If true, only pongs echoing the payload of the last ping sent count toward liveness. Pongs
+  /// with any other payload (e.g. injected by something between us and the device) are ignored.
+  match_pong_payload: bool,
+  /// How long a write can wait on a congested connection before failing. Waits forever if unset.
+  write_timeout: Option<Duration>,
+  /// Largest text or binary frame, in bytes, that will be passed on from the device. Larger frames
+  /// are dropped.
+  max_frame_size: usize,
+  /// If set, a tighter limit than max_frame_size for text frames. Devices are expected to send
+  /// binary, so text usually only comes from something misbehaving. Tungstenite has already
+  /// checked the text is valid UTF-8 by the time this is applied.
+  max_text_frame_size: Option<usize>,
+  /// How many oversize frames a device can send before it's disconnected. Never disconnects if
+  /// unset.
+  max_oversize_frames: Option<u32>,
+  /// If set, a device that sends nothing at all (not even pongs) for this long is disconnected.
+  /// Catches half open connections without waiting out the ping checks.
+  idle_timeout: Option<Duration>,
+  /// If set, a device whose connection drops (as opposed to closing it, or being disconnected by
+  /// us) stays around while it has a chance to reconnect from the same address.
+  reconnect: Option<WebsocketReconnectConfig>,
+  /// If true, an empty binary frame from the device is taken as it disconnecting, for bridges that
+  /// signal disconnects that way instead of closing the websocket.
+  empty_frame_disconnect: bool,
+  /// If true, a write to an endpoint is dropped when its data is identical to the last write to
+  /// that endpoint. Catches protocols that turn different commands into the same bytes. Off by
+  /// default, as some devices need identical writes repeated.
+  dedup_writes: bool,
+  /// Framing applied to data written to and received from the device.
+  #[getset(skip)]
+  framing: Arc<dyn Framing>,
+  /// If set, written to the device when its hardware is dropped without being disconnected first
+  /// (e.g. if the task owning it panics), so a device doesn't keep running with nothing left to
+  /// stop it. Usually the protocol's stop command. Best effort, as it's skipped if the outgoing
+  /// queue is full.
+  #[getset(skip)]
+  drop_stop_frame: Option<Vec<u8>>,
+}
+
+impl WebsocketServerDeviceConnectionConfig {
+  pub fn framing(&self) -> &Arc<dyn Framing> {
+    &self.framing
+  }
+
+  pub fn set_framing(&mut self, framing: Arc<dyn Framing>) -> &mut Self {
+    self.framing = framing;
+    self
+  }
+
+  pub fn drop_stop_frame(&self) -> &Option<Vec<u8>> {
+    &self.drop_stop_frame
+  }
+
+  pub fn set_drop_stop_frame(&mut self, drop_stop_frame: Option<Vec<u8>>) -> &mut Self {
+    self.drop_stop_frame = drop_stop_frame;
+    self
+  }
+}
+
+impl Default for WebsocketServerDeviceConnectionConfig {
+  fn default() -> Self {
+    Self {
+      ping_interval: Duration::from_millis(10000),
+      max_missed_pongs: 0,
+      match_pong_payload: false,
+      write_timeout: None,
+      max_frame_size: 1024 * 1024,
+      max_text_frame_size: None,
+      max_oversize_frames: None,
+      idle_timeout: None,
+      reconnect: None,
+      empty_frame_disconnect: false,
+      dedup_writes: false,
+      framing: Arc::new(NoFraming),
+      drop_stop_frame: None,
+    }
+  }
 }
 
 #[derive(Clone)]
 pub struct WebsocketServerDeviceCommunicationManagerBuilder {
   listen_on_all_interfaces: bool,
   server_port: u16,
+  connection_config: WebsocketServerDeviceConnectionConfig,
+  tls_config: Option<Arc<ServerConfig>>,
+  discovery_config: Option<WebsocketDiscoveryConfig>,
+  accepted_subprotocols: Vec<String>,
 }
 
 impl Default for WebsocketServerDeviceCommunicationManagerBuilder {
@@ -43,6 +189,10 @@ impl Default for WebsocketServerDeviceCommunicationManagerBuilder {
     Self {
       listen_on_all_interfaces: false,
       server_port: 54817,
+      connection_config: WebsocketServerDeviceConnectionConfig::default(),
+      tls_config: None,
+      discovery_config: None,
+      accepted_subprotocols: vec![],
     }
   }
 }
@@ -57,6 +207,94 @@ impl WebsocketServerDeviceCommunicationManagerBuilder {
     self.server_port = port;
     self
   }
+
+  pub fn ping_interval(mut self, interval: Duration) -> Self {
+    self.connection_config.ping_interval = interval;
+    self
+  }
+
+  pub fn max_missed_pongs(mut self, max_missed_pongs: u32) -> Self {
+    self.connection_config.max_missed_pongs = max_missed_pongs;
+    self
+  }
+
+  pub fn match_pong_payload(mut self, match_pong_payload: bool) -> Self {
+    self.connection_config.match_pong_payload = match_pong_payload;
+    self
+  }
+
+  pub fn write_timeout(mut self, write_timeout: Duration) -> Self {
+    self.connection_config.write_timeout = Some(write_timeout);
+    self
+  }
+
+  pub fn max_frame_size(mut self, max_frame_size: usize) -> Self {
+    self.connection_config.max_frame_size = max_frame_size;
+    self
+  }
+
+  pub fn max_text_frame_size(mut self, max_text_frame_size: usize) -> Self {
+    self.connection_config.max_text_frame_size = Some(max_text_frame_size);
+    self
+  }
+
+  pub fn max_oversize_frames(mut self, max_oversize_frames: u32) -> Self {
+    self.connection_config.max_oversize_frames = Some(max_oversize_frames);
+    self
+  }
+
+  pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+    self.connection_config.idle_timeout = Some(idle_timeout);
+    self
+  }
+
+  pub fn reconnect(mut self, config: WebsocketReconnectConfig) -> Self {
+    self.connection_config.reconnect = Some(config);
+    self
+  }
+
+  pub fn empty_frame_disconnect(mut self, empty_frame_disconnect: bool) -> Self {
+    self.connection_config.empty_frame_disconnect = empty_frame_disconnect;
+    self
+  }
+
+  pub fn dedup_writes(mut self, dedup_writes: bool) -> Self {
+    self.connection_config.dedup_writes = dedup_writes;
+    self
+  }
+
+  pub fn framing(mut self, framing: Arc<dyn Framing>) -> Self {
+    self.connection_config.framing = framing;
+    self
+  }
+
+  pub fn drop_stop_frame(mut self, drop_stop_frame: Vec<u8>) -> Self {
+    self.connection_config.drop_stop_frame = Some(drop_stop_frame);
+    self
+  }
+
+  /// Accept device connections over TLS (wss://) using the given server configuration. Plain
+  /// websocket connections will no longer be accepted.
+  pub fn tls_config(mut self, config: Arc<ServerConfig>) -> Self {
+    self.tls_config = Some(config);
+    self
+  }
+
+  /// Broadcast UDP beacons advertising the server port, so devices on the LAN can find it without
+  /// configuration. Off by default.
+  pub fn discovery(mut self, config: WebsocketDiscoveryConfig) -> Self {
+    self.discovery_config = Some(config);
+    self
+  }
+
+  /// Websocket subprotocols the server will agree to. During the handshake, the first protocol the
+  /// device offers that's in this list is picked, and can be read from the device's hardware
+  /// afterward. Devices that offer nothing, or nothing in the list, still connect without
+  /// a subprotocol.
+  pub fn accepted_subprotocols(mut self, subprotocols: Vec<String>) -> Self {
+    self.accepted_subprotocols = subprotocols;
+    self
+  }
 }
 
 impl HardwareCommunicationManagerBuilder for WebsocketServerDeviceCommunicationManagerBuilder {
@@ -68,10 +306,153 @@ impl HardwareCommunicationManagerBuilder for WebsocketServerDeviceCommunicationM
       sender,
       self.server_port,
       self.listen_on_all_interfaces,
+      self.connection_config.clone(),
+      self.tls_config.clone(),
+      self.discovery_config.clone(),
+      self.accepted_subprotocols.clone(),
     ))
   }
 }
 
+// Live connections, keyed by device address. Each entry holds the token used to shut the connection
+// down, a token that fires once it has actually finished, and the handle for passing it a new
+// stream.
+type ConnectionMap = Arc<DashMap<String, (CancellationToken, CancellationToken, ReconnectHandle)>>;
+
+// Run the websocket handshake, picking the first subprotocol offered by the device that's also in
+// `accepted`, in the device's order of preference.
+//
+// The handshake callback's signature is fixed by tungstenite, so its large error type can't be
+// boxed.
+#[allow(clippy::result_large_err)]
+async fn accept_websocket<S>(
+  stream: S,
+  accepted: &[String],
+) -> Result<(WebSocketStream<S>, Option<String>), tokio_tungstenite::tungstenite::Error>
+where
+  S: AsyncRead + AsyncWrite + Unpin,
+{
+  let mut subprotocol = None;
+  let ws_stream = tokio_tungstenite::accept_hdr_async(
+    stream,
+    |request: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+      let offered = request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim);
+      for protocol in offered {
+        if accepted.iter().any(|x| x == protocol) {
+          if let Ok(value) = HeaderValue::from_str(protocol) {
+            response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, value);
+            subprotocol = Some(protocol.to_owned());
+          }
+          break;
+        }
+      }
+      Ok(response)
+    },
+  )
+  .await?;
+  Ok((ws_stream, subprotocol))
+}
+
+async fn handle_device_connection<S, F>(
+  mut ws_stream: WebSocketStream<S>,
+  subprotocol: Option<String>,
+  sender: Sender<HardwareCommunicationManagerEvent>,
+  connections: ConnectionMap,
+  discovered: DiscoveredDevices,
+  create_connector: F,
+) where
+  S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+  F: FnOnce(
+    WebsocketServerDeviceCommManagerInitInfo,
+    WebSocketStream<S>,
+    CancellationToken,
+  ) -> WebsocketServerHardwareConnector,
+{
+  // TODO Implement a receive timeout here so we don't wait forever
+  if let Some(Ok(tokio_tungstenite::tungstenite::Message::Text(info_message))) =
+    ws_stream.next().await
+  {
+    let mut info_packet: WebsocketServerDeviceCommManagerInitInfo =
+      if let Ok(packet) = serde_json::from_str(&info_message) {
+        packet
+      } else {
+        error!("Did not receive a valid JSON info packet as the first packet, disconnecting.");
+        if let Err(err) = ws_stream.close(None).await {
+          error!("Error closing connection: {}", err);
+        }
+        return;
+      };
+    info_packet.discovered_from = discovered.get(info_packet.address()).map(|source| *source);
+    info_packet.subprotocol = subprotocol;
+    // If the device's previous connection dropped and its connector is waiting on it, pick up
+    // where we left off instead of announcing a new device. If a device reconnects before we've
+    // noticed its old connection dropped, the new connection replaces the old one in the same
+    // connector, so the device carries on as well.
+    let reconnect_handle = connections
+      .get(info_packet.address())
+      .map(|entry| entry.2.clone());
+    if let Some(handle) = reconnect_handle {
+      match try_reconnect(&handle, ws_stream) {
+        Ok(()) => {
+          info!(
+            "Websocket device at {} reconnected to its existing connection.",
+            info_packet.address()
+          );
+          return;
+        }
+        Err(returned_stream) => ws_stream = *returned_stream,
+      }
+      match try_replace(&handle, ws_stream) {
+        Ok(()) => {
+          info!(
+            "Websocket device at {} reconnected, replacing previous connection.",
+            info_packet.address()
+          );
+          return;
+        }
+        Err(returned_stream) => ws_stream = *returned_stream,
+      }
+    }
+    // Otherwise whatever connector had the address is done with it, or on its way out. Make sure
+    // its connection loop has fully exited (emitting its Disconnected event) before the new device
+    // is announced, so the device manager always sees the disconnect first.
+    if let Some((_, (old_token, old_closed_token, _))) = connections.remove(info_packet.address()) {
+      old_token.cancel();
+      old_closed_token.cancelled().await;
+    }
+    let address = info_packet.address().clone();
+    let connection_token = CancellationToken::new();
+    let connector = create_connector(info_packet.clone(), ws_stream, connection_token.clone());
+    connections.insert(
+      address,
+      (
+        connection_token,
+        connector.connection_closed_token(),
+        connector.reconnect_handle(),
+      ),
+    );
+    if sender
+      .send(HardwareCommunicationManagerEvent::DeviceFound {
+        name: format!("Websocket Device {}", info_packet.identifier),
+        address: info_packet.address.clone(),
+        creator: Box::new(connector),
+      })
+      .await
+      .is_err()
+    {
+      error!("Device manager disappeared, exiting.");
+    }
+  } else {
+    error!("Did not receive info message as first packet, dropping connection.");
+  }
+}
+
 pub struct WebsocketServerDeviceCommunicationManager {
   server_cancellation_token: CancellationToken,
 }
@@ -81,10 +462,24 @@ impl WebsocketServerDeviceCommunicationManager {
     sender: Sender<HardwareCommunicationManagerEvent>,
     port: u16,
     listen_on_all_interfaces: bool,
+    connection_config: WebsocketServerDeviceConnectionConfig,
+    tls_config: Option<Arc<ServerConfig>>,
+    discovery_config: Option<WebsocketDiscoveryConfig>,
+    accepted_subprotocols: Vec<String>,
   ) -> Self {
     trace!("Websocket server port created.");
     let server_cancellation_token = CancellationToken::new();
     let child_token = server_cancellation_token.child_token();
+    let tls_acceptor = tls_config.map(TlsAcceptor::from);
+    let discovered: DiscoveredDevices = Arc::new(DashMap::new());
+    if let Some(discovery_config) = discovery_config {
+      async_manager::spawn(run_discovery(
+        discovery_config,
+        port,
+        discovered.clone(),
+        child_token.clone(),
+      ));
+    }
     async_manager::spawn(async move {
       let base_addr = if listen_on_all_interfaces {
         "0.0.0.0"
@@ -105,6 +500,7 @@ impl WebsocketServerDeviceCommunicationManager {
         }
       };
       debug!("Listening on: {}", addr);
+      let connections: ConnectionMap = Arc::new(DashMap::new());
       loop {
         select! {
           listener_result = listener.accept().fuse() => {
@@ -115,50 +511,64 @@ impl WebsocketServerDeviceCommunicationManager {
               return;
             };
             info!("Got connection");
-            let ws_fut = tokio_tungstenite::accept_async(stream);
-            let mut ws_stream = match ws_fut.await {
-              Ok(ws_stream) => ws_stream,
-              Err(err) => {
-                error!("Cannot accept socket: {}", err);
-                continue;
-              }
-            };
             // Websockets are different from the rest of the communication managers, in that we have no
             // information about the device type when we create the connection, and therefore have to
             // wait for the first packet. We'll have to pass our device event sender off to the newly
             // created event loop, so that it can fire once the info packet is received.
             let sender_clone = sender.clone();
+            let connection_config = connection_config.clone();
+            let connections = connections.clone();
+            let discovered = discovered.clone();
+            let tls_acceptor = tls_acceptor.clone();
+            let accepted_subprotocols = accepted_subprotocols.clone();
             tokio::spawn(async move {
-              // TODO Implement a receive timeout here so we don't wait forever
-              if let Some(Ok(tokio_tungstenite::tungstenite::Message::Text(info_message))) =
-                ws_stream.next().await
-              {
-                let info_packet: WebsocketServerDeviceCommManagerInitInfo =
-                  if let Ok(packet) = serde_json::from_str(&info_message) {
-                    packet
-                  } else {
-                    error!("Did not receive a valid JSON info packet as the first packet, disconnecting.");
-                    if let Err(err) = ws_stream.close(None).await {
-                      error!("Error closing connection: {}", err);
-                    }
+              if let Some(acceptor) = tls_acceptor {
+                let tls_stream = match acceptor.accept(stream).await {
+                  Ok(tls_stream) => tls_stream,
+                  Err(err) => {
+                    error!("Cannot establish TLS session: {}", err);
+                    return;
+                  }
+                };
+                let accepted = accept_websocket(tls_stream, &accepted_subprotocols).await;
+                let (ws_stream, subprotocol) = match accepted {
+                  Ok(accepted) => accepted,
+                  Err(err) => {
+                    error!("Cannot accept socket: {}", err);
                     return;
-                  };
-                if sender_clone
-                  .send(HardwareCommunicationManagerEvent::DeviceFound {
-                    name: format!("Websocket Device {}", info_packet.identifier),
-                    address: info_packet.address.clone(),
-                    creator: Box::new(WebsocketServerHardwareConnector::new(
-                      info_packet,
-                      ws_stream,
-                    )),
-                  })
-                  .await
-                  .is_err()
-                {
-                  error!("Device manager disappeared, exiting.");
-                }
+                  }
+                };
+                handle_device_connection(
+                  ws_stream,
+                  subprotocol,
+                  sender_clone,
+                  connections,
+                  discovered,
+                  |info, ws_stream, token| {
+                    WebsocketServerHardwareConnector::new_tls(info, ws_stream, connection_config, token)
+                  },
+                )
+                .await;
               } else {
-                error!("Did not receive info message as first packet, dropping connection.");
+                let accepted = accept_websocket(stream, &accepted_subprotocols).await;
+                let (ws_stream, subprotocol) = match accepted {
+                  Ok(accepted) => accepted,
+                  Err(err) => {
+                    error!("Cannot accept socket: {}", err);
+                    return;
+                  }
+                };
+                handle_device_connection(
+                  ws_stream,
+                  subprotocol,
+                  sender_clone,
+                  connections,
+                  discovered,
+                  |info, ws_stream, token| {
+                    WebsocketServerHardwareConnector::new(info, ws_stream, connection_config, token)
+                  },
+                )
+                .await;
               }
             });
           },
@@ -200,3 +610,162 @@ impl Drop for WebsocketServerDeviceCommunicationManager {
     self.server_cancellation_token.cancel();
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    core::message::Endpoint,
+    server::device::hardware::{HardwareEvent, HardwareWriteCmd},
+  };
+  use futures::SinkExt;
+  use std::sync::Mutex;
+  use tokio::{io::duplex, sync::mpsc};
+  use tokio_tungstenite::tungstenite::{client::IntoClientRequest, protocol::Role, Message};
+
+  #[tokio::test]
+  async fn test_websocket_server_subprotocol_negotiation() {
+    let (server_stream, client_stream) = duplex(4096);
+    let client_task = tokio::spawn(async move {
+      let mut request = "ws://localhost/"
+        .into_client_request()
+        .expect("Test, assuming infallible");
+      request.headers_mut().insert(
+        SEC_WEBSOCKET_PROTOCOL,
+        HeaderValue::from_static("buttplug-binary, buttplug-json"),
+      );
+      let (mut client_ws, response) = tokio_tungstenite::client_async(request, client_stream)
+        .await
+        .expect("Test, assuming infallible");
+      assert_eq!(
+        response.headers().get(SEC_WEBSOCKET_PROTOCOL),
+        Some(&HeaderValue::from_static("buttplug-json"))
+      );
+      client_ws
+        .send(Message::Text(
+          r#"{"identifier": "test-device", "address": "test-address", "version": 0}"#.to_owned(),
+        ))
+        .await
+        .expect("Test, assuming infallible");
+      client_ws
+    });
+
+    let (ws_stream, subprotocol) = accept_websocket(
+      server_stream,
+      &["buttplug-json".to_owned(), "buttplug-v2".to_owned()],
+    )
+    .await
+    .expect("Test, assuming infallible");
+    assert_eq!(subprotocol, Some("buttplug-json".to_owned()));
+
+    let (sender, mut receiver) = mpsc::channel(1);
+    let recorded = Arc::new(Mutex::new(None));
+    let recorded_clone = recorded.clone();
+    handle_device_connection(
+      ws_stream,
+      subprotocol,
+      sender,
+      Arc::new(DashMap::new()),
+      Arc::new(DashMap::new()),
+      move |info, ws_stream, token| {
+        let connector = WebsocketServerHardwareConnector::new(
+          info,
+          ws_stream,
+          WebsocketServerDeviceConnectionConfig::default(),
+          token,
+        );
+        *recorded_clone.lock().expect("Test, assuming infallible") = connector.subprotocol();
+        connector
+      },
+    )
+    .await;
+    assert!(matches!(
+      receiver.recv().await,
+      Some(HardwareCommunicationManagerEvent::DeviceFound { .. })
+    ));
+    assert_eq!(
+      *recorded.lock().expect("Test, assuming infallible"),
+      Some("buttplug-json".to_owned())
+    );
+    let _client_ws = client_task.await.expect("Test, assuming infallible");
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_connection_replaced_keeps_device() {
+    let (sender, mut receiver) = mpsc::channel(2);
+    let connections: ConnectionMap = Arc::new(DashMap::new());
+    // Connects a device at the same address each time, handing back its side of the connection.
+    let connect = || {
+      let sender = sender.clone();
+      let connections = connections.clone();
+      async move {
+        let (server_stream, client_stream) = duplex(4096);
+        let server_ws = WebSocketStream::from_raw_socket(server_stream, Role::Server, None).await;
+        let mut client_ws =
+          WebSocketStream::from_raw_socket(client_stream, Role::Client, None).await;
+        client_ws
+          .send(Message::Text(
+            r#"{"identifier": "test-device", "address": "test-address", "version": 0}"#.to_owned(),
+          ))
+          .await
+          .expect("Test, assuming infallible");
+        handle_device_connection(
+          server_ws,
+          None,
+          sender,
+          connections,
+          Arc::new(DashMap::new()),
+          |info, ws_stream, token| {
+            WebsocketServerHardwareConnector::new(
+              info,
+              ws_stream,
+              WebsocketServerDeviceConnectionConfig::default(),
+              token,
+            )
+          },
+        )
+        .await;
+        client_ws
+      }
+    };
+
+    let mut first_client_ws = connect().await;
+    let Some(HardwareCommunicationManagerEvent::DeviceFound { mut creator, .. }) =
+      receiver.recv().await
+    else {
+      panic!("Expected device to be found");
+    };
+    let hardware = creator
+      .connect()
+      .await
+      .expect("Test, assuming infallible")
+      .specialize(&[])
+      .await
+      .expect("Test, assuming infallible");
+    let mut event_receiver = hardware.event_stream();
+
+    // The new connection takes over the existing device rather than being announced as a new one.
+    let mut second_client_ws = connect().await;
+    assert!(matches!(
+      first_client_ws.next().await,
+      Some(Ok(Message::Close(_)))
+    ));
+    assert!(matches!(
+      event_receiver.recv().await,
+      Ok(HardwareEvent::Reconnecting(_))
+    ));
+    assert!(matches!(
+      event_receiver.recv().await,
+      Ok(HardwareEvent::Reconnected(_))
+    ));
+    assert!(receiver.try_recv().is_err());
+    hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![1, 2, 3], false))
+      .await
+      .expect("Test, assuming infallible");
+    assert!(matches!(
+      second_client_ws.next().await,
+      Some(Ok(Message::Binary(data))) if data == vec![1, 2, 3]
+    ));
+  }
+}
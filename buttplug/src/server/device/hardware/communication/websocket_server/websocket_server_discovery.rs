@@ -0,0 +1,198 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! UDP discovery for the websocket server device backend.
+//!
+//! While discovery is running, the server broadcasts a [WebsocketDiscoveryBeacon] on the LAN every
+//! few seconds, so app-based devices can find the websocket port without any configuration. Devices
+//! reply to the beacon with the same JSON info packet they send once connected. Replies are stored
+//! by device address, so the connection can be matched back to where it was discovered from.
+
+use super::websocket_server_comm_manager::WebsocketServerDeviceCommManagerInitInfo;
+use dashmap::DashMap;
+use futures::FutureExt;
+use getset::{CopyGetters, Setters};
+use serde::{Deserialize, Serialize};
+use std::{
+  net::{IpAddr, Ipv4Addr, SocketAddr},
+  sync::Arc,
+  time::Duration,
+};
+use tokio::{net::UdpSocket, time::interval};
+use tokio_util::sync::CancellationToken;
+
+// Largest announce packet we'll accept. Info packets are tiny JSON objects, so this is plenty.
+const MAX_ANNOUNCE_SIZE: usize = 2048;
+
+/// Addresses devices announced themselves from, keyed by the device address in their info packet.
+pub(super) type DiscoveredDevices = Arc<DashMap<String, SocketAddr>>;
+
+/// Settings for UDP discovery beacons.
+#[derive(Debug, Clone, CopyGetters, Setters)]
+#[getset(get_copy = "pub", set = "pub")]
+pub struct WebsocketDiscoveryConfig {
+  /// UDP port devices listen on for beacons.
+  port: u16,
+  /// Address beacons are sent to. Defaults to the IPv4 limited broadcast address.
+  broadcast_address: IpAddr,
+  /// How long to wait between beacons.
+  beacon_interval: Duration,
+}
+
+impl Default for WebsocketDiscoveryConfig {
+  fn default() -> Self {
+    Self {
+      port: 54819,
+      broadcast_address: IpAddr::V4(Ipv4Addr::BROADCAST),
+      beacon_interval: Duration::from_millis(5000),
+    }
+  }
+}
+
+/// Packet broadcast by the server, telling devices which port the websocket server is on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, CopyGetters)]
+#[getset(get_copy = "pub")]
+pub struct WebsocketDiscoveryBeacon {
+  server_port: u16,
+}
+
+impl WebsocketDiscoveryBeacon {
+  pub fn new(server_port: u16) -> Self {
+    Self { server_port }
+  }
+}
+
+/// Broadcast beacons and record device announce replies until the token is cancelled.
+pub(super) async fn run_discovery(
+  config: WebsocketDiscoveryConfig,
+  server_port: u16,
+  discovered: DiscoveredDevices,
+  token: CancellationToken,
+) {
+  let socket = match UdpSocket::bind("0.0.0.0:0").await {
+    Ok(socket) => socket,
+    Err(err) => {
+      error!("Cannot bind websocket discovery socket: {:?}", err);
+      return;
+    }
+  };
+  if let Err(err) = socket.set_broadcast(true) {
+    error!(
+      "Cannot enable broadcast on websocket discovery socket: {:?}",
+      err
+    );
+    return;
+  }
+  let beacon = serde_json::to_vec(&WebsocketDiscoveryBeacon::new(server_port))
+    .expect("Beacon is always serializable");
+  let beacon_address = SocketAddr::new(config.broadcast_address(), config.port());
+  let mut beacon_timer = interval(config.beacon_interval());
+  let mut buf = [0u8; MAX_ANNOUNCE_SIZE];
+  loop {
+    // Finish the receive before looking at the buffer, so its borrow has ended.
+    let result = select! {
+      _ = beacon_timer.tick().fuse() => {
+        if let Err(err) = socket.send_to(&beacon, beacon_address).await {
+          warn!("Cannot send websocket discovery beacon to {}: {:?}", beacon_address, err);
+        }
+        continue;
+      },
+      result = socket.recv_from(&mut buf).fuse() => result,
+      _ = token.cancelled().fuse() => break,
+    };
+    match result {
+      Ok((len, source)) => {
+        match serde_json::from_slice::<WebsocketServerDeviceCommManagerInitInfo>(&buf[..len]) {
+          Ok(info) => {
+            debug!(
+              "Websocket device {} announced from {}",
+              info.address(),
+              source
+            );
+            discovered.insert(info.address().clone(), source);
+          }
+          Err(err) => {
+            debug!(
+              "Ignoring invalid discovery announce from {}: {:?}",
+              source, err
+            );
+          }
+        }
+      }
+      Err(err) => {
+        error!("Websocket discovery socket failed: {:?}", err);
+        break;
+      }
+    }
+  }
+  info!("Exiting websocket discovery loop.");
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use tokio::time::timeout;
+
+  #[tokio::test]
+  async fn test_websocket_discovery_beacon_and_announce() {
+    // Stands in for a device listening for beacons.
+    let device_socket = UdpSocket::bind("127.0.0.1:0")
+      .await
+      .expect("Test, assuming infallible");
+    let device_port = device_socket
+      .local_addr()
+      .expect("Test, assuming infallible")
+      .port();
+    let mut config = WebsocketDiscoveryConfig::default();
+    config
+      .set_port(device_port)
+      .set_broadcast_address(IpAddr::V4(Ipv4Addr::LOCALHOST))
+      .set_beacon_interval(Duration::from_millis(50));
+    let discovered: DiscoveredDevices = Arc::new(DashMap::new());
+    let token = CancellationToken::new();
+    tokio::spawn(run_discovery(
+      config,
+      54817,
+      discovered.clone(),
+      token.clone(),
+    ));
+
+    let mut buf = [0u8; MAX_ANNOUNCE_SIZE];
+    let (len, server_address) = timeout(Duration::from_secs(5), device_socket.recv_from(&mut buf))
+      .await
+      .expect("Test, assuming infallible")
+      .expect("Test, assuming infallible");
+    let beacon: WebsocketDiscoveryBeacon =
+      serde_json::from_slice(&buf[..len]).expect("Test, assuming infallible");
+    assert_eq!(beacon.server_port(), 54817);
+
+    device_socket
+      .send_to(
+        br#"{"identifier": "test-device", "address": "test-address", "version": 0}"#,
+        server_address,
+      )
+      .await
+      .expect("Test, assuming infallible");
+    let device_address = device_socket
+      .local_addr()
+      .expect("Test, assuming infallible");
+    timeout(Duration::from_secs(5), async {
+      while !discovered.contains_key("test-address") {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+    })
+    .await
+    .expect("Test, assuming infallible");
+    assert_eq!(
+      *discovered
+        .get("test-address")
+        .expect("Test, assuming infallible"),
+      device_address
+    );
+    token.cancel();
+  }
+}
@@ -0,0 +1,71 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Framing for data sent over websocket device connections.
+//!
+//! By default, each write to a websocket device goes out as one bare binary frame, and each frame
+//! the device sends is passed on as is. Some bridges wrap every logical command in their own
+//! framing on top of that (a length prefix, a delimiter, etc). A [Framing] implementation handles
+//! that wrapping, so protocols can keep dealing with plain command data.
+
+use crate::core::errors::ButtplugDeviceError;
+use std::fmt::Debug;
+
+/// Wraps outgoing data and unwraps incoming data for a websocket device connection.
+pub trait Framing: Debug + Send + Sync {
+  /// Frame data being written to the device.
+  fn encode(&self, data: &[u8]) -> Vec<u8>;
+  /// Unwrap a frame received from the device. Frames that can't be unwrapped are dropped by the
+  /// connection.
+  fn decode(&self, frame: &[u8]) -> Result<Vec<u8>, ButtplugDeviceError>;
+}
+
+/// Sends and receives data as is. This is the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoFraming;
+
+impl Framing for NoFraming {
+  fn encode(&self, data: &[u8]) -> Vec<u8> {
+    data.to_vec()
+  }
+
+  fn decode(&self, frame: &[u8]) -> Result<Vec<u8>, ButtplugDeviceError> {
+    Ok(frame.to_vec())
+  }
+}
+
+/// Prefixes each frame with the length of its data, as a big endian u32.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LengthPrefixedFraming;
+
+impl Framing for LengthPrefixedFraming {
+  fn encode(&self, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 4);
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(data);
+    frame
+  }
+
+  fn decode(&self, frame: &[u8]) -> Result<Vec<u8>, ButtplugDeviceError> {
+    if frame.len() < 4 {
+      return Err(ButtplugDeviceError::DeviceCommunicationError(format!(
+        "Frame of {} bytes is too short to hold a length prefix.",
+        frame.len()
+      )));
+    }
+    let (prefix, data) = frame.split_at(4);
+    let length = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+    if length != data.len() {
+      return Err(ButtplugDeviceError::DeviceCommunicationError(format!(
+        "Frame length prefix of {} does not match the {} bytes of data in the frame.",
+        length,
+        data.len()
+      )));
+    }
+    Ok(data.to_vec())
+  }
+}
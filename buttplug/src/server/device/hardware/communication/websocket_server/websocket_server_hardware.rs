@@ -5,12 +5,20 @@
 // Licensed under the BSD 3-Clause license. See LICENSE file in the project root
 // for full license information.
 
-use super::websocket_server_comm_manager::WebsocketServerDeviceCommManagerInitInfo;
+use super::{
+  websocket_server_comm_manager::{
+    WebsocketReconnectConfig,
+    WebsocketServerDeviceCommManagerInitInfo,
+    WebsocketServerDeviceConnectionConfig,
+  },
+  websocket_server_framing::Framing,
+};
 use crate::{
   core::{errors::ButtplugDeviceError, message::Endpoint},
   server::device::{
     configuration::{ProtocolCommunicationSpecifier, WebsocketSpecifier},
     hardware::{
+      DisconnectReason,
       GenericHardwareSpecializer,
       Hardware,
       HardwareConnector,
@@ -22,6 +30,7 @@ use crate::{
       HardwareSubscribeCmd,
       HardwareUnsubscribeCmd,
       HardwareWriteCmd,
+      NotificationDecoder,
     },
   },
   util::async_manager,
@@ -33,89 +42,481 @@ use futures::{
   SinkExt,
   StreamExt,
 };
+use getset::CopyGetters;
+use serde::{Deserialize, Serialize};
 use std::{
+  any::Any,
+  collections::HashMap,
   fmt::{self, Debug},
   sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
     Arc,
   },
-  time::Duration,
+  time::{Duration, Instant},
 };
 use tokio::{
-  net::TcpStream,
+  io::{AsyncRead, AsyncWrite},
   sync::{
     broadcast,
     mpsc::{channel, Receiver, Sender},
-    Mutex,
+    oneshot,
   },
-  time::sleep,
+  time::{sleep, timeout},
 };
+use tokio_rustls::server::TlsStream;
 use tokio_util::sync::CancellationToken;
+use tracing_futures::Instrument;
+
+// Used when a read command doesn't specify its own timeout.
+const DEFAULT_READ_TIMEOUT_MS: u32 = 1000;
+// How long to wait for the client to answer our close frame when we shut a connection down.
+const CLOSE_HANDSHAKE_TIMEOUT_MS: u64 = 1000;
+// How many writes a write monitor can fall behind before it starts missing them.
+const WRITE_MONITOR_CAPACITY: usize = 256;
+// All websocket devices are presented with the same endpoints, since the connection itself is the
+// only thing we can talk to.
+const WEBSOCKET_ENDPOINTS: [Endpoint; 2] = [Endpoint::Rx, Endpoint::Tx];
+
+/// Snapshot of the traffic and ping/pong health of a websocket device connection.
+#[derive(Debug, Clone, Copy, Default, CopyGetters)]
+#[getset(get_copy = "pub")]
+pub struct ConnectionStats {
+  /// Payload bytes sent to the device.
+  bytes_sent: u64,
+  /// Payload bytes received from the device, from both text and binary frames.
+  bytes_received: u64,
+  pings_sent: u64,
+  pongs_received: u64,
+  /// When the most recent pong arrived, if one has arrived yet.
+  last_pong_instant: Option<Instant>,
+}
+
+// Live counters behind [ConnectionStats], updated by the connection loop.
+#[derive(Debug, Default)]
+struct ConnectionStatsTracker {
+  bytes_sent: AtomicU64,
+  bytes_received: AtomicU64,
+  pings_sent: AtomicU64,
+  pongs_received: AtomicU64,
+  last_pong_instant: std::sync::Mutex<Option<Instant>>,
+}
+
+impl ConnectionStatsTracker {
+  fn record_pong(&self) {
+    self.pongs_received.fetch_add(1, Ordering::Relaxed);
+    *self
+      .last_pong_instant
+      .lock()
+      .expect("Lock is never held across a panic") = Some(Instant::now());
+  }
+
+  fn snapshot(&self) -> ConnectionStats {
+    ConnectionStats {
+      bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+      bytes_received: self.bytes_received.load(Ordering::Relaxed),
+      pings_sent: self.pings_sent.load(Ordering::Relaxed),
+      pongs_received: self.pongs_received.load(Ordering::Relaxed),
+      last_pong_instant: *self
+        .last_pong_instant
+        .lock()
+        .expect("Lock is never held across a panic"),
+    }
+  }
+}
+
+/// Messages queued by the hardware for the connection loop to send.
+#[derive(Debug)]
+pub enum OutgoingMessage {
+  /// Data to send to the device.
+  Data(Vec<u8>),
+  /// Answered once everything queued ahead of it has been sent.
+  Flush(oneshot::Sender<()>),
+}
+
+// Last data queued for each endpoint, shared by a connection and the hardware writing to it.
+type LastWrites = Arc<std::sync::Mutex<HashMap<Endpoint, Vec<u8>>>>;
+
+/// Handles shared between a connection loop and the connector/hardware it serves.
+#[derive(Debug, Clone, Default)]
+pub struct WebsocketServerConnectionHandle {
+  // Cancelled to shut the connection loop down.
+  cancellation_token: CancellationToken,
+  // Cancelled once the connection loop has fully exited.
+  closed_token: CancellationToken,
+  // Whether a task spawned by the connector is running the connection, and so will cancel
+  // closed_token when it's done. Nothing does that for detached connections.
+  closed_by_task: bool,
+  stats: Arc<ConnectionStatsTracker>,
+  // Most recent text or binary frame from the device, as it arrived, before any unframing.
+  last_frame: Arc<std::sync::Mutex<Option<Vec<u8>>>>,
+  // Reads waiting on a reply from the device, by request id.
+  pending_reads: Arc<std::sync::Mutex<HashMap<u32, oneshot::Sender<Vec<u8>>>>>,
+  next_read_id: Arc<AtomicU32>,
+  // Last data written to each endpoint, for hardware dropping duplicate writes. Only good for as
+  // long as the device is on the same stream, as a device coming back may not be in the same state.
+  last_writes: LastWrites,
+}
+
+impl WebsocketServerConnectionHandle {
+  fn clear_last_writes(&self) {
+    self
+      .last_writes
+      .lock()
+      .expect("Lock is never held across a panic")
+      .clear();
+  }
+
+  // Hand a read reply to the read waiting on it. Returns false if the text isn't a read reply, or
+  // nothing is waiting on it, in which case it should be treated like any other frame.
+  fn complete_read(&self, text: &str) -> bool {
+    let Ok(reply) = serde_json::from_str::<WebsocketReadReply>(text) else {
+      return false;
+    };
+    let Some(reply_sender) = self
+      .pending_reads
+      .lock()
+      .expect("Lock is never held across a panic")
+      .remove(&reply.id)
+    else {
+      return false;
+    };
+    // The read may have timed out just now, which is fine.
+    let _ = reply_sender.send(reply.data);
+    true
+  }
+
+  fn record_frame(&self, frame: &[u8]) {
+    *self
+      .last_frame
+      .lock()
+      .expect("Lock is never held across a panic") = Some(frame.to_vec());
+  }
+}
+
+/// The connection loop's side of the channels behind a [WebsocketServerHardware]. Normally owned by
+/// the task [WebsocketServerHardwareConnector] spawns, but handed out by
+/// [WebsocketServerHardware::new_detached] so tests can run [run_connection_loop] themselves, or
+/// stand in for it entirely.
+pub(crate) struct DetachedConnection {
+  pub(crate) event_sender: broadcast::Sender<HardwareEvent>,
+  pub(crate) outgoing_receiver: Receiver<OutgoingMessage>,
+  pub(crate) incoming_broadcaster: broadcast::Sender<Vec<u8>>,
+  pub(crate) connection: WebsocketServerConnectionHandle,
+}
+
+impl DetachedConnection {
+  // Returns the sender for outgoing messages alongside, as the only end the loop doesn't keep.
+  fn new(cancellation_token: CancellationToken) -> (Sender<OutgoingMessage>, Self) {
+    let (outgoing_sender, outgoing_receiver) = channel(256);
+    let (incoming_broadcaster, _) = broadcast::channel(256);
+    let (event_sender, _) = broadcast::channel(256);
+    let connection = WebsocketServerConnectionHandle {
+      cancellation_token,
+      ..Default::default()
+    };
+    (
+      outgoing_sender,
+      Self {
+        event_sender,
+        outgoing_receiver,
+        incoming_broadcaster,
+        connection,
+      },
+    )
+  }
+}
+
+/// Read request sent to a websocket device for [HardwareInternal::read_value]. It goes out as JSON
+/// in a binary frame, through the connection's [Framing] like any other write, e.g.
+///
+/// ```json
+/// {"id": 3, "endpoint": "rx", "length": 4, "timeout_ms": 500}
+/// ```
+///
+/// `id` is picked by the hardware and goes up with each read on the connection, wrapping at
+/// u32::MAX. The other fields are the [HardwareReadCmd] as given, so a timeout of 0 means the
+/// default of 1000ms. A length of 0 takes whatever the device sends. Otherwise the device has to
+/// send back exactly that many bytes.
+#[derive(Serialize)]
+struct WebsocketReadRequest<'a> {
+  id: u32,
+  #[serde(flatten)]
+  cmd: &'a HardwareReadCmd,
+}
+
+/// The device's answer to a [WebsocketReadRequest]: a text frame holding JSON with the request's
+/// id and the data read, as an array of bytes, e.g.
+///
+/// ```json
+/// {"id": 3, "data": [1, 2, 3, 4]}
+/// ```
+///
+/// The id is how the reply is told apart from notifications and from replies to other reads.
+/// Replies are only looked for in text frames, and aren't unframed first. Text frames that don't
+/// parse as a reply, or whose id no read is waiting on (e.g. because it already timed out), are
+/// passed on like any other notification.
+#[derive(Deserialize)]
+struct WebsocketReadReply {
+  id: u32,
+  data: Vec<u8>,
+}
+
+// Removes a read from the pending reads once it's done, however it finishes (including its future
+// being dropped), so replies that show up late aren't held on to.
+struct PendingRead {
+  pending_reads: Arc<std::sync::Mutex<HashMap<u32, oneshot::Sender<Vec<u8>>>>>,
+  id: u32,
+}
+
+impl Drop for PendingRead {
+  fn drop(&mut self) {
+    self
+      .pending_reads
+      .lock()
+      .expect("Lock is never held across a panic")
+      .remove(&self.id);
+  }
+}
 
-async fn run_connection_loop(
+// Why a connection loop stopped. Only dropped connections are worth waiting on a reconnect for,
+// everything else was on purpose. Closed connections have already sent their disconnect event,
+// dropped ones leave it to whoever runs the loop, as it depends on whether the device reconnects.
+// Replaced connections don't send one at all, as the device carries on over the new stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConnectionLoopExit {
+  Closed,
+  Dropped(DisconnectReason),
+  Replaced,
+}
+
+// Unwrap a frame from the device and pass it on to anything reading or subscribed.
+fn broadcast_frame(
   address: &str,
-  event_sender: broadcast::Sender<HardwareEvent>,
-  ws_stream: tokio_tungstenite::WebSocketStream<TcpStream>,
-  mut request_receiver: Receiver<Vec<u8>>,
-  response_sender: broadcast::Sender<Vec<u8>>,
+  framing: &dyn Framing,
+  response_sender: &broadcast::Sender<Vec<u8>>,
+  frame: &[u8],
 ) {
-  info!("Starting websocket server connection event loop.");
+  match framing.decode(frame) {
+    Ok(data) => {
+      // If no one is listening, ignore output.
+      let _ = response_sender.send(data);
+    }
+    Err(err) => warn!("Dropping malformed frame from {}: {}", address, err),
+  }
+}
+
+// Cancelling `replace_token` stops the loop for another stream to take over, without a disconnect
+// event, where cancelling the connection's own token shuts the device down.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn run_connection_loop<S>(
+  address: &str,
+  event_sender: &broadcast::Sender<HardwareEvent>,
+  ws_stream: tokio_tungstenite::WebSocketStream<S>,
+  request_receiver: &mut Receiver<OutgoingMessage>,
+  response_sender: &broadcast::Sender<Vec<u8>>,
+  config: &WebsocketServerDeviceConnectionConfig,
+  connection: &WebsocketServerConnectionHandle,
+  replace_token: &CancellationToken,
+) -> ConnectionLoopExit
+where
+  S: AsyncRead + AsyncWrite + Unpin,
+{
+  let stats = &connection.stats;
+  info!(
+    state = "connected",
+    "Starting websocket server connection event loop."
+  );
 
   let (mut websocket_server_sender, mut websocket_server_receiver) = ws_stream.split();
+  // A device on a new stream may have lost whatever state our last writes left it in.
+  connection.clear_last_writes();
 
-  // Start pong count at 1, so we'll clear it after sending our first ping.
-  let mut pong_count = 1u32;
+  // Start as if we've gotten a pong, so we won't count a miss before sending our first ping.
+  let mut pong_received = true;
+  // Every ping interval that passes without a pong uses up one of these. Any pong refills it.
+  let mut missed_pongs_remaining = config.max_missed_pongs();
+  // Each ping carries a new payload, so pongs can be matched to the ping they answer.
+  let mut ping_count = 0u32;
+  let mut last_ping_payload = vec![];
+  // Only set when we're the side closing the connection, in which case the client still owes us a
+  // close frame.
+  let mut await_close_ack = false;
+  let mut oversize_frames = 0u32;
+  let mut last_frame_instant = Instant::now();
 
-  loop {
+  let (exit, reason) = loop {
+    let idle_wait = config
+      .idle_timeout()
+      .map(|idle_timeout| idle_timeout.saturating_sub(last_frame_instant.elapsed()));
+    let idle = async move {
+      match idle_wait {
+        Some(wait) => sleep(wait).await,
+        None => future::pending::<()>().await,
+      }
+    };
     select! {
-      _ = sleep(Duration::from_millis(10000)).fuse() => {
-        if pong_count == 0 {
+      _ = connection.cancellation_token.cancelled().fuse() => {
+        info!("Websocket device connection shut down, disconnecting.");
+        // Anything still queued was meant for this connection, so don't let it leak into whatever
+        // replaces us.
+        while request_receiver.try_recv().is_ok() {}
+        let _ = event_sender.send(HardwareEvent::Disconnected(
+          address.to_owned(),
+          DisconnectReason::OwnerDropped
+        ));
+        await_close_ack = true;
+        break (ConnectionLoopExit::Closed, "shut down");
+      }
+      _ = replace_token.cancelled().fuse() => {
+        info!("Websocket device connection replaced, handing over to the new connection.");
+        // As with shutting down, queued data was meant for the old connection. The device moved
+        // on from this connection already, so don't wait on it to acknowledge the close either.
+        while request_receiver.try_recv().is_ok() {}
+        break (ConnectionLoopExit::Replaced, "replaced");
+      }
+      _ = sleep(config.ping_interval()).fuse() => {
+        if pong_received {
+          missed_pongs_remaining = config.max_missed_pongs();
+        } else if missed_pongs_remaining == 0 {
           error!("No pongs received, considering connection closed.");
-          break;
+          break (ConnectionLoopExit::Dropped(DisconnectReason::PingTimeout), "ping timeout");
+        } else {
+          missed_pongs_remaining -= 1;
+          warn!(
+            "No pong received in the last ping interval, {} more misses allowed.",
+            missed_pongs_remaining
+          );
         }
-        pong_count = 0;
+        pong_received = false;
+        ping_count = ping_count.wrapping_add(1);
+        last_ping_payload = ping_count.to_be_bytes().to_vec();
         if websocket_server_sender
-          .send(tokio_tungstenite::tungstenite::Message::Ping(vec!(0)))
+          .send(tokio_tungstenite::tungstenite::Message::Ping(last_ping_payload.clone()))
           .await
           .is_err() {
           error!("Cannot send ping to client, considering connection closed.");
-          break;
+          break (ConnectionLoopExit::Dropped(DisconnectReason::WriteError), "ping failed");
         }
+        stats.pings_sent.fetch_add(1, Ordering::Relaxed);
+        trace!(state = "ping sent", "Sent ping to websocket device.");
+      }
+      _ = idle.fuse() => {
+        warn!("Nothing received from websocket device {}, considering connection idle.", address);
+        let _ = event_sender.send(HardwareEvent::Disconnected(
+          address.to_owned(),
+          DisconnectReason::IdleTimeout
+        ));
+        break (ConnectionLoopExit::Closed, "idle timeout");
       }
-      ws_msg = request_receiver.recv().fuse() => {
-        if let Some(binary_msg) = ws_msg {
+      ws_msg = request_receiver.recv().fuse() => match ws_msg {
+        Some(OutgoingMessage::Data(binary_msg)) => {
+          let len = binary_msg.len() as u64;
           if websocket_server_sender
             .send(tokio_tungstenite::tungstenite::Message::Binary(binary_msg))
             .await
             .is_err() {
             error!("Cannot send binary value to client, considering connection closed.");
-            break;
+            break (ConnectionLoopExit::Dropped(DisconnectReason::WriteError), "write failed");
           }
-        } else {
+          stats.bytes_sent.fetch_add(len, Ordering::Relaxed);
+        }
+        Some(OutgoingMessage::Flush(ack)) => {
+          // Messages are handled in order, so everything queued before this is already out.
+          if websocket_server_sender.flush().await.is_err() {
+            error!("Cannot flush data to client, considering connection closed.");
+            break (ConnectionLoopExit::Dropped(DisconnectReason::WriteError), "flush failed");
+          }
+          // The flusher may have given up waiting, which is fine.
+          let _ = ack.send(());
+        }
+        None => {
           info!("Websocket server connector owner dropped, disconnecting websocket connection.");
-          break;
+          let _ = event_sender.send(HardwareEvent::Disconnected(
+            address.to_owned(),
+            DisconnectReason::OwnerDropped
+          ));
+          break (ConnectionLoopExit::Closed, "hardware dropped");
         }
-      }
+      },
       websocket_server_msg = websocket_server_receiver.next().fuse() => match websocket_server_msg {
         Some(ws_data) => {
           match ws_data {
             Ok(msg) => {
+              // A pong that doesn't answer our last ping says nothing about whether the device is
+              // still there, so it doesn't count as activity either.
+              let unmatched_pong = match &msg {
+                tokio_tungstenite::tungstenite::Message::Pong(payload) => {
+                  config.match_pong_payload() && *payload != last_ping_payload
+                }
+                _ => false,
+              };
+              if !unmatched_pong {
+                last_frame_instant = Instant::now();
+              }
               match msg {
                 tokio_tungstenite::tungstenite::Message::Text(text_msg) => {
-                  // If someone accidentally packs text, politely turn it into binary for them.
-                  let _ = response_sender.send(text_msg.as_bytes().to_vec());
+                  stats.bytes_received.fetch_add(text_msg.len() as u64, Ordering::Relaxed);
+                  let max_text_frame_size = config
+                    .max_text_frame_size()
+                    .map_or(config.max_frame_size(), |max| max.min(config.max_frame_size()));
+                  if text_msg.len() > max_text_frame_size {
+                    warn!(
+                      "Dropping {} byte text frame from {}, larger than max text size of {}.",
+                      text_msg.len(),
+                      address,
+                      max_text_frame_size
+                    );
+                    oversize_frames += 1;
+                  } else {
+                    connection.record_frame(text_msg.as_bytes());
+                    // Read replies go to the read waiting on them, not to subscribers.
+                    if !connection.complete_read(&text_msg) {
+                      // If someone accidentally packs text, politely turn it into binary for them.
+                      broadcast_frame(
+                        address,
+                        config.framing().as_ref(),
+                        response_sender,
+                        text_msg.as_bytes(),
+                      );
+                    }
+                  }
                 }
                 tokio_tungstenite::tungstenite::Message::Binary(binary_msg) => {
-                  // If no one is listening, ignore output.
-                  let _ = response_sender.send(binary_msg);
+                  if binary_msg.is_empty() && config.empty_frame_disconnect() {
+                    info!("Websocket device {} sent an empty frame, disconnecting.", address);
+                    let _ = event_sender.send(HardwareEvent::Disconnected(
+                      address.to_owned(),
+                      DisconnectReason::ClientClose
+                    ));
+                    break (ConnectionLoopExit::Closed, "empty frame from device");
+                  }
+                  stats.bytes_received.fetch_add(binary_msg.len() as u64, Ordering::Relaxed);
+                  if binary_msg.len() > config.max_frame_size() {
+                    warn!(
+                      "Dropping {} byte binary frame from {}, larger than max frame size of {}.",
+                      binary_msg.len(),
+                      address,
+                      config.max_frame_size()
+                    );
+                    oversize_frames += 1;
+                  } else {
+                    connection.record_frame(&binary_msg);
+                    broadcast_frame(
+                      address,
+                      config.framing().as_ref(),
+                      response_sender,
+                      &binary_msg,
+                    );
+                  }
                 }
                 tokio_tungstenite::tungstenite::Message::Close(_) => {
                   // Drop the error if no one receives the message, we're breaking anyways.
                   let _ = event_sender
                     .send(HardwareEvent::Disconnected(
-                      address.to_owned()
+                      address.to_owned(),
+                      DisconnectReason::ClientClose
                     ));
-                  break;
+                  break (ConnectionLoopExit::Closed, "closed by device");
                 }
                 tokio_tungstenite::tungstenite::Message::Ping(_) => {
                   // noop
@@ -126,29 +527,179 @@ async fn run_connection_loop(
                   continue;
                 }
                 tokio_tungstenite::tungstenite::Message::Pong(_) => {
-                  pong_count += 1;
+                  if unmatched_pong {
+                    warn!("Ignoring pong from {} that doesn't match the last ping sent.", address);
+                    continue;
+                  }
+                  pong_received = true;
+                  stats.record_pong();
+                  trace!(state = "pong received", "Received pong from websocket device.");
                   continue;
                 }
               }
             },
             Err(err) => {
               error!("Error from websocket server, assuming disconnection: {:?}", err);
-              break;
+              break (ConnectionLoopExit::Dropped(DisconnectReason::ReadError), "websocket error");
             }
           }
         },
         None => {
           error!("Websocket channel closed, breaking");
-          break;
+          break (ConnectionLoopExit::Dropped(DisconnectReason::ReadError), "stream ended");
         }
       }
     }
-  }
+    if let Some(max_oversize_frames) = config.max_oversize_frames() {
+      if oversize_frames > max_oversize_frames {
+        error!(
+          "Device {} sent too many oversize frames, disconnecting.",
+          address
+        );
+        let _ = event_sender.send(HardwareEvent::Disconnected(
+          address.to_owned(),
+          DisconnectReason::InvalidData,
+        ));
+        await_close_ack = true;
+        break (ConnectionLoopExit::Closed, "too many oversize frames");
+      }
+    }
+  };
 
   if let Err(e) = websocket_server_sender.close().await {
     error!("Error closing websocket: {}", e);
+  } else if await_close_ack {
+    let close_ack = async {
+      while let Some(Ok(msg)) = websocket_server_receiver.next().await {
+        if let tokio_tungstenite::tungstenite::Message::Close(_) = msg {
+          break;
+        }
+      }
+    };
+    if timeout(Duration::from_millis(CLOSE_HANDSHAKE_TIMEOUT_MS), close_ack)
+      .await
+      .is_err()
+    {
+      warn!("Websocket client did not acknowledge close, dropping connection.");
+    }
+  }
+  info!(
+    state = "disconnected",
+    reason,
+    dropped = matches!(exit, ConnectionLoopExit::Dropped(_)),
+    "Exiting Websocket Server Device control loop."
+  );
+  // Nothing written from here on reaches the device through this stream, and whatever the device
+  // does reach us through next (a reconnect, or a connection replacing this one) starts over.
+  connection.clear_last_writes();
+  exit
+}
+
+// Hands streams from devices reconnecting at the same address over to the connector the address
+// belongs to, either while it waits on a dropped connection, or to replace the one it's running.
+struct Reconnector<S> {
+  awaiting: AtomicBool,
+  // Stops the connection loop that's running, if there is one, so a new stream can replace it.
+  replace_token: std::sync::Mutex<Option<CancellationToken>>,
+  sender: Sender<tokio_tungstenite::WebSocketStream<S>>,
+}
+
+impl<S> Reconnector<S> {
+  fn set_replace_token(&self, replace_token: Option<CancellationToken>) {
+    *self
+      .replace_token
+      .lock()
+      .expect("Lock is never held across a panic") = replace_token;
+  }
+}
+
+/// Type erased [Reconnector], so the comm manager can track them without knowing the stream type.
+pub(super) type ReconnectHandle = Arc<dyn Any + Send + Sync>;
+
+/// Give a newly connected device's stream to the connector its address previously belonged to, if
+/// that connector is waiting on a reconnection. Hands the stream back otherwise, boxed, as
+/// websocket streams are too big to pass around in errors.
+pub(super) fn try_reconnect<S>(
+  handle: &ReconnectHandle,
+  ws_stream: tokio_tungstenite::WebSocketStream<S>,
+) -> Result<(), Box<tokio_tungstenite::WebSocketStream<S>>>
+where
+  S: Send + 'static,
+{
+  match handle.downcast_ref::<Reconnector<S>>() {
+    Some(reconnector) if reconnector.awaiting.load(Ordering::SeqCst) => reconnector
+      .sender
+      .try_send(ws_stream)
+      .map_err(|err| Box::new(err.into_inner())),
+    _ => Err(Box::new(ws_stream)),
+  }
+}
+
+/// Give a newly connected device's stream to the connector its address already belongs to, to
+/// replace the connection that connector is running. The connector's hardware carries on over the
+/// new stream, with [HardwareEvent::Reconnecting] and [HardwareEvent::Reconnected] sent around the
+/// swap instead of a disconnect, and keeps the info it was first connected with. Hands the stream
+/// back if the connector isn't running a connection, e.g. because it has already shut down.
+pub(super) fn try_replace<S>(
+  handle: &ReconnectHandle,
+  ws_stream: tokio_tungstenite::WebSocketStream<S>,
+) -> Result<(), Box<tokio_tungstenite::WebSocketStream<S>>>
+where
+  S: Send + 'static,
+{
+  let Some(reconnector) = handle.downcast_ref::<Reconnector<S>>() else {
+    return Err(Box::new(ws_stream));
+  };
+  let replace_token = reconnector
+    .replace_token
+    .lock()
+    .expect("Lock is never held across a panic");
+  let Some(replace_token) = replace_token.as_ref() else {
+    return Err(Box::new(ws_stream));
+  };
+  reconnector
+    .sender
+    .try_send(ws_stream)
+    .map_err(|err| Box::new(err.into_inner()))?;
+  replace_token.cancel();
+  Ok(())
+}
+
+// Waits for the device to reconnect, giving it a longer window each attempt. Returns None if the
+// device doesn't come back, or we're shut down while waiting.
+async fn wait_for_reconnect<S>(
+  address: &str,
+  reconnector: &Reconnector<S>,
+  receiver: &mut Receiver<tokio_tungstenite::WebSocketStream<S>>,
+  config: WebsocketReconnectConfig,
+  cancellation_token: &CancellationToken,
+) -> Option<tokio_tungstenite::WebSocketStream<S>> {
+  reconnector.awaiting.store(true, Ordering::SeqCst);
+  let mut delay = config.base_delay();
+  let mut ws_stream = None;
+  for attempt in 1..=config.max_attempts() {
+    info!(
+      state = "reconnecting",
+      "Waiting {}ms for websocket device {} to reconnect (attempt {} of {}).",
+      delay.as_millis(),
+      address,
+      attempt,
+      config.max_attempts()
+    );
+    let result = select! {
+      ws_stream = receiver.recv().fuse() => ws_stream,
+      _ = sleep(delay).fuse() => {
+        delay = (delay * 2).min(config.max_delay());
+        continue;
+      },
+      _ = cancellation_token.cancelled().fuse() => None,
+    };
+    ws_stream = result;
+    break;
   }
-  debug!("Exiting Websocket Server Device control loop.");
+  reconnector.awaiting.store(false, Ordering::SeqCst);
+  // A stream may have been handed over just as we gave up.
+  ws_stream.or_else(|| receiver.try_recv().ok())
 }
 
 impl Debug for WebsocketServerHardwareConnector {
@@ -161,89 +712,518 @@ impl Debug for WebsocketServerHardwareConnector {
 
 pub struct WebsocketServerHardwareConnector {
   info: WebsocketServerDeviceCommManagerInitInfo,
-  outgoing_sender: Sender<Vec<u8>>,
+  outgoing_sender: Sender<OutgoingMessage>,
   incoming_broadcaster: broadcast::Sender<Vec<u8>>,
   device_event_sender: broadcast::Sender<HardwareEvent>,
+  connection: WebsocketServerConnectionHandle,
+  write_timeout: Option<Duration>,
+  framing: Arc<dyn Framing>,
+  drop_stop_frame: Option<Vec<u8>>,
+  dedup_writes: bool,
+  secure: bool,
+  reconnect_handle: ReconnectHandle,
 }
 
 impl WebsocketServerHardwareConnector {
-  pub fn new(
+  /// Spawns the connection loop for an accepted websocket. Cancelling `cancellation_token` shuts
+  /// the loop down, dropping any queued outgoing data and emitting
+  /// [HardwareEvent::Disconnected] before the loop exits.
+  pub fn new<S>(
     info: WebsocketServerDeviceCommManagerInitInfo,
-    ws_stream: tokio_tungstenite::WebSocketStream<TcpStream>,
-  ) -> Self {
-    let (outgoing_sender, outgoing_receiver) = channel(256);
-    let (incoming_broadcaster, _) = broadcast::channel(256);
-    let incoming_broadcaster_clone = incoming_broadcaster.clone();
-    let (device_event_sender, _) = broadcast::channel(256);
-    let device_event_sender_clone = device_event_sender.clone();
+    ws_stream: tokio_tungstenite::WebSocketStream<S>,
+    config: WebsocketServerDeviceConnectionConfig,
+    cancellation_token: CancellationToken,
+  ) -> Self
+  where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+  {
+    Self::spawn(info, ws_stream, config, cancellation_token, false)
+  }
+
+  /// Same as [WebsocketServerHardwareConnector::new], for websockets running over a TLS session
+  /// (wss://). The device specifier produced by the connector will be marked as secure.
+  pub fn new_tls<S>(
+    info: WebsocketServerDeviceCommManagerInitInfo,
+    ws_stream: tokio_tungstenite::WebSocketStream<TlsStream<S>>,
+    config: WebsocketServerDeviceConnectionConfig,
+    cancellation_token: CancellationToken,
+  ) -> Self
+  where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+  {
+    Self::spawn(info, ws_stream, config, cancellation_token, true)
+  }
+
+  fn spawn<S>(
+    info: WebsocketServerDeviceCommManagerInitInfo,
+    ws_stream: tokio_tungstenite::WebSocketStream<S>,
+    config: WebsocketServerDeviceConnectionConfig,
+    cancellation_token: CancellationToken,
+    secure: bool,
+  ) -> Self
+  where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+  {
+    let (outgoing_sender, mut detached) = DetachedConnection::new(cancellation_token);
+    detached.connection.closed_by_task = true;
+    let incoming_broadcaster = detached.incoming_broadcaster.clone();
+    let device_event_sender = detached.event_sender.clone();
+    let connection = detached.connection.clone();
     let address = info.address().clone();
-    tokio::spawn(async move {
-      run_connection_loop(
-        &address,
-        device_event_sender_clone,
-        ws_stream,
-        outgoing_receiver,
-        incoming_broadcaster_clone,
-      )
-      .await;
+    let write_timeout = config.write_timeout();
+    let framing = config.framing().clone();
+    let drop_stop_frame = config.drop_stop_frame().clone();
+    let dedup_writes = config.dedup_writes();
+    let (reconnect_sender, mut reconnect_receiver) =
+      channel::<tokio_tungstenite::WebSocketStream<S>>(1);
+    // Set up front, so the connection can be replaced as soon as the connector exists.
+    let mut replace_token = CancellationToken::new();
+    let reconnector = Arc::new(Reconnector::<S> {
+      awaiting: AtomicBool::new(false),
+      replace_token: std::sync::Mutex::new(Some(replace_token.clone())),
+      sender: reconnect_sender,
     });
+    let reconnector_clone = reconnector.clone();
+    let reconnect_handle: ReconnectHandle = reconnector;
+    // Everything logged by the connection, including across reconnects, is tagged with the device.
+    let span = info_span!(
+      "websocket device connection",
+      address = tracing::field::display(info.address()),
+      identifier = tracing::field::display(info.identifier())
+    );
+    tokio::spawn(
+      async move {
+        let DetachedConnection {
+          event_sender,
+          mut outgoing_receiver,
+          incoming_broadcaster,
+          connection,
+        } = detached;
+        let mut ws_stream = ws_stream;
+        loop {
+          let exit = run_connection_loop(
+            &address,
+            &event_sender,
+            ws_stream,
+            &mut outgoing_receiver,
+            &incoming_broadcaster,
+            &config,
+            &connection,
+            &replace_token,
+          )
+          .await;
+          reconnector_clone.set_replace_token(None);
+          let reason = match exit {
+            ConnectionLoopExit::Closed => break,
+            ConnectionLoopExit::Dropped(reason) => reason,
+            // Replacements are queued before the loop is told to stop, so this is only for if
+            // something else took the stream in the meantime.
+            ConnectionLoopExit::Replaced => DisconnectReason::OwnerDropped,
+          };
+          // Replacing a connection keeps the device, the same as it coming back after dropping. A
+          // replacement may also land just as the connection dropped on its own.
+          ws_stream = match (reconnect_receiver.try_recv(), config.reconnect()) {
+            (Ok(ws_stream), _) => {
+              let _ = event_sender.send(HardwareEvent::Reconnecting(address.clone()));
+              info!("Websocket device {} connection replaced.", address);
+              ws_stream
+            }
+            // Keep the device alive across dropped connections, as long as it keeps coming back.
+            (Err(_), Some(reconnect_config)) => {
+              let _ = event_sender.send(HardwareEvent::Reconnecting(address.clone()));
+              match wait_for_reconnect(
+                &address,
+                &reconnector_clone,
+                &mut reconnect_receiver,
+                reconnect_config,
+                &connection.cancellation_token,
+              )
+              .await
+              {
+                Some(ws_stream) => ws_stream,
+                None => {
+                  warn!(
+                    "Websocket device {} did not reconnect, disconnecting.",
+                    address
+                  );
+                  let _ = event_sender.send(HardwareEvent::Disconnected(address.clone(), reason));
+                  break;
+                }
+              }
+            }
+            (Err(_), None) => {
+              let _ = event_sender.send(HardwareEvent::Disconnected(address.clone(), reason));
+              break;
+            }
+          };
+          info!("Websocket device {} reconnected.", address);
+          let _ = event_sender.send(HardwareEvent::Reconnected(address.clone()));
+          replace_token = CancellationToken::new();
+          reconnector_clone.set_replace_token(Some(replace_token.clone()));
+        }
+        connection.closed_token.cancel();
+      }
+      .instrument(span),
+    );
     Self {
       info,
       outgoing_sender,
       incoming_broadcaster,
       device_event_sender,
+      connection,
+      write_timeout,
+      framing,
+      drop_stop_frame,
+      dedup_writes,
+      secure,
+      reconnect_handle,
     }
   }
+
+  /// Handle for passing reconnected or replacement streams to this connector.
+  pub(super) fn reconnect_handle(&self) -> ReconnectHandle {
+    self.reconnect_handle.clone()
+  }
+
+  /// Token that is cancelled once the connection loop has fully exited.
+  pub fn connection_closed_token(&self) -> CancellationToken {
+    self.connection.closed_token.clone()
+  }
+
+  /// Subprotocol agreed on with the device during the websocket handshake, if any.
+  pub fn subprotocol(&self) -> Option<String> {
+    self.info.subprotocol().clone()
+  }
+
+  fn create_hardware(&self) -> WebsocketServerHardware {
+    WebsocketServerHardware::new(
+      self.device_event_sender.clone(),
+      self.info.clone(),
+      self.outgoing_sender.clone(),
+      self.incoming_broadcaster.clone(),
+      self.connection.clone(),
+      self.write_timeout,
+      self.framing.clone(),
+      self.drop_stop_frame.clone(),
+      self.dedup_writes,
+    )
+  }
 }
 
 #[async_trait]
 impl HardwareConnector for WebsocketServerHardwareConnector {
   fn specifier(&self) -> ProtocolCommunicationSpecifier {
-    ProtocolCommunicationSpecifier::Websocket(WebsocketSpecifier::new(self.info.identifier()))
+    let mut specifier = WebsocketSpecifier::new(self.info.identifier());
+    specifier.set_secure(self.secure);
+    ProtocolCommunicationSpecifier::Websocket(specifier)
   }
 
   async fn connect(&mut self) -> Result<Box<dyn HardwareSpecializer>, ButtplugDeviceError> {
-    let hardware_internal = WebsocketServerHardware::new(
-      self.device_event_sender.clone(),
-      self.info.clone(),
-      self.outgoing_sender.clone(),
-      self.incoming_broadcaster.clone(),
-    );
+    let hardware_internal = self.create_hardware();
     let hardware = Hardware::new(
       self.info.identifier(),
       self.info.address(),
-      &[Endpoint::Rx, Endpoint::Tx],
+      &WEBSOCKET_ENDPOINTS,
       Box::new(hardware_internal),
     );
     Ok(Box::new(GenericHardwareSpecializer::new(hardware)))
   }
 }
 
+// A subscribed endpoint, and which data it wants passed on.
+struct Subscription {
+  endpoint: Endpoint,
+  prefix_filter: Option<Vec<u8>>,
+  decoder: Option<NotificationDecoder>,
+}
+
+// The device only has the one stream of data, so a single task passes it on, handing each frame to
+// every subscription whose prefix filter matches it, in the order they were made. Subscriptions with
+// filters that don't overlap sort frames into endpoints, and an unfiltered one sees everything.
+#[derive(Default)]
+struct Subscriptions {
+  subscriptions: Vec<Subscription>,
+  // Shuts down the forwarding task, which runs while anything is subscribed.
+  forwarding_token: Option<CancellationToken>,
+}
+
+impl Subscriptions {
+  fn route(&self, data: &[u8]) -> Vec<(Endpoint, Option<NotificationDecoder>)> {
+    self
+      .subscriptions
+      .iter()
+      .filter(|subscription| {
+        subscription
+          .prefix_filter
+          .as_ref()
+          .is_none_or(|prefix| data.starts_with(prefix))
+      })
+      .map(|subscription| (subscription.endpoint, subscription.decoder.clone()))
+      .collect()
+  }
+}
+
+// Passes data received from the device on as notifications, until cancelled. See Subscriptions.
+async fn forward_notifications(
+  mut data_receiver: broadcast::Receiver<Vec<u8>>,
+  subscriptions: Arc<std::sync::Mutex<Subscriptions>>,
+  event_sender: broadcast::Sender<HardwareEvent>,
+  address: String,
+  identifier: String,
+  token: CancellationToken,
+) {
+  loop {
+    select! {
+      result = data_receiver.recv().fuse() => {
+        match result {
+          Ok(data) => {
+            debug!("Got websocket data! {:?}", data);
+            let routes = subscriptions
+              .lock()
+              .expect("Lock is never held across a panic")
+              .route(&data);
+            if routes.is_empty() {
+              trace!("Dropping websocket data that doesn't match any subscription filter.");
+              continue;
+            }
+            for (endpoint, decoder) in routes {
+              let data = match &decoder {
+                Some(decoder) => match decoder(data.as_slice()) {
+                  Ok(decoded) => decoded,
+                  Err(err) => {
+                    warn!(
+                      "Dropping websocket data for {} that failed to decode: {:?}",
+                      endpoint, err
+                    );
+                    continue;
+                  }
+                },
+                None => data.clone(),
+              };
+              // We don't really care if there's no one to send the error to here.
+              let _ = event_sender
+                .send(HardwareEvent::Notification(
+                  address.clone(),
+                  endpoint,
+                  data,
+                  Some(identifier.clone()),
+                ));
+            }
+          },
+          Err(broadcast::error::RecvError::Lagged(count)) => {
+            warn!(
+              "Subscriptions on {} fell behind, {} notifications dropped.",
+              address, count
+            );
+            // There's no telling which endpoints the dropped data was for, so tell all of them.
+            let endpoints: Vec<Endpoint> = subscriptions
+              .lock()
+              .expect("Lock is never held across a panic")
+              .subscriptions
+              .iter()
+              .map(|subscription| subscription.endpoint)
+              .collect();
+            for endpoint in endpoints {
+              let _ = event_sender.send(HardwareEvent::NotificationsDropped(
+                address.clone(),
+                endpoint,
+                count,
+              ));
+            }
+          },
+          Err(broadcast::error::RecvError::Closed) => break,
+        }
+      },
+      _ = token.cancelled().fuse() => {
+        break;
+      }
+    }
+  }
+  info!("Data channel closed, ending websocket server device listener task");
+}
+
 pub struct WebsocketServerHardware {
   connected: Arc<AtomicBool>,
-  subscribed: Arc<AtomicBool>,
-  subscribe_token: Arc<Mutex<Option<CancellationToken>>>,
+  subscriptions: Arc<std::sync::Mutex<Subscriptions>>,
   info: WebsocketServerDeviceCommManagerInitInfo,
-  outgoing_sender: Sender<Vec<u8>>,
+  outgoing_sender: Sender<OutgoingMessage>,
   incoming_broadcaster: broadcast::Sender<Vec<u8>>,
   device_event_sender: broadcast::Sender<HardwareEvent>,
+  connection: WebsocketServerConnectionHandle,
+  write_timeout: Option<Duration>,
+  framing: Arc<dyn Framing>,
+  drop_stop_frame: Option<Vec<u8>>,
+  // The connection's last write to each endpoint, if duplicate writes are being dropped.
+  last_writes: Option<LastWrites>,
+  write_monitor: broadcast::Sender<(Endpoint, Vec<u8>)>,
 }
 
 impl WebsocketServerHardware {
+  #[allow(clippy::too_many_arguments)]
   pub fn new(
     device_event_sender: broadcast::Sender<HardwareEvent>,
     info: WebsocketServerDeviceCommManagerInitInfo,
-    outgoing_sender: Sender<Vec<u8>>,
+    outgoing_sender: Sender<OutgoingMessage>,
     incoming_broadcaster: broadcast::Sender<Vec<u8>>,
+    connection: WebsocketServerConnectionHandle,
+    write_timeout: Option<Duration>,
+    framing: Arc<dyn Framing>,
+    drop_stop_frame: Option<Vec<u8>>,
+    dedup_writes: bool,
   ) -> Self {
     Self {
+      last_writes: dedup_writes.then(|| connection.last_writes.clone()),
+      connection,
+      write_timeout,
+      framing,
+      drop_stop_frame,
+      write_monitor: broadcast::channel(WRITE_MONITOR_CAPACITY).0,
       connected: Arc::new(AtomicBool::new(true)),
       info,
       outgoing_sender,
       incoming_broadcaster,
       device_event_sender,
-      subscribed: Arc::new(AtomicBool::new(false)),
-      subscribe_token: Arc::new(Mutex::new(None)),
+      subscriptions: Arc::new(std::sync::Mutex::new(Subscriptions::default())),
+    }
+  }
+
+  /// Create hardware without a connection loop behind it. Whatever the hardware sends comes out of
+  /// the returned [DetachedConnection], and whatever is sent into it reaches the hardware, so tests
+  /// can check the hardware's channel wiring without a socket, or pass the ends to
+  /// [run_connection_loop] along with a stream of their own.
+  #[cfg(test)]
+  pub(crate) fn new_detached(
+    info: WebsocketServerDeviceCommManagerInitInfo,
+    config: &WebsocketServerDeviceConnectionConfig,
+  ) -> (Self, DetachedConnection) {
+    let (outgoing_sender, detached) = DetachedConnection::new(CancellationToken::new());
+    let hardware = Self::new(
+      detached.event_sender.clone(),
+      info,
+      outgoing_sender,
+      detached.incoming_broadcaster.clone(),
+      detached.connection.clone(),
+      config.write_timeout(),
+      config.framing().clone(),
+      config.drop_stop_frame().clone(),
+      config.dedup_writes(),
+    );
+    (hardware, detached)
+  }
+
+  fn check_endpoint(endpoint: Endpoint) -> Result<(), ButtplugDeviceError> {
+    if WEBSOCKET_ENDPOINTS.contains(&endpoint) {
+      Ok(())
+    } else {
+      Err(ButtplugDeviceError::InvalidEndpoint(endpoint))
+    }
+  }
+
+  // Adds a subscription, starting the forwarding task if it's the first one.
+  fn subscribe_internal(
+    &self,
+    msg: &HardwareSubscribeCmd,
+    decoder: Option<NotificationDecoder>,
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    if let Err(err) = Self::check_endpoint(msg.endpoint()) {
+      return future::ready(Err(err)).boxed();
+    }
+    let subscription = Subscription {
+      endpoint: msg.endpoint(),
+      prefix_filter: msg.prefix_filter().clone(),
+      decoder,
+    };
+    let data_receiver = self.incoming_broadcaster.subscribe();
+    let event_sender = self.device_event_sender.clone();
+    let address = self.info.address().clone();
+    let identifier = self.info.identifier().clone();
+    let subscriptions = self.subscriptions.clone();
+    async move {
+      let mut locked_subscriptions = subscriptions
+        .lock()
+        .expect("Lock is never held across a panic");
+      let endpoint = subscription.endpoint;
+      if locked_subscriptions
+        .subscriptions
+        .iter()
+        .any(|existing| existing.endpoint == endpoint)
+      {
+        error!("Endpoint {} already subscribed somehow!", endpoint);
+        return Ok(());
+      }
+      locked_subscriptions.subscriptions.push(subscription);
+      if locked_subscriptions.forwarding_token.is_none() {
+        let token = CancellationToken::new();
+        locked_subscriptions.forwarding_token = Some(token.clone());
+        async_manager::spawn(forward_notifications(
+          data_receiver,
+          subscriptions.clone(),
+          event_sender,
+          address,
+          identifier,
+          token,
+        ));
+      }
+      Ok(())
+    }
+    .boxed()
+  }
+
+  /// Subprotocol agreed on with the device during the websocket handshake, if any.
+  pub fn subprotocol(&self) -> Option<String> {
+    self.info.subprotocol().clone()
+  }
+
+  /// Current traffic and ping/pong statistics for the device connection.
+  pub fn stats(&self) -> ConnectionStats {
+    self.connection.stats.snapshot()
+  }
+
+  /// The most recent text or binary frame received from the device, exactly as it arrived, for
+  /// debugging or one-off reads that don't warrant a subscription. Frames dropped for being over
+  /// the max frame size aren't kept. None if the device hasn't sent anything yet.
+  pub fn last_received_frame(&self) -> Option<Vec<u8>> {
+    self
+      .connection
+      .last_frame
+      .lock()
+      .expect("Lock is never held across a panic")
+      .clone()
+  }
+
+  /// Tap on everything written to the device, for debugging tools that show what's being sent.
+  /// Each write comes through as its endpoint and data, before any framing is applied, once it's
+  /// been queued for the device. Writes dropped as duplicates or that fail to queue don't show up,
+  /// as they're never sent. Writes are only copied to the monitor while something is subscribed.
+  pub fn write_monitor(&self) -> broadcast::Receiver<(Endpoint, Vec<u8>)> {
+    self.write_monitor.subscribe()
+  }
+
+  /// Number of live receivers for this device's events, so callers can tell if anything is still
+  /// listening before tearing the device down.
+  pub fn event_listener_count(&self) -> usize {
+    self.device_event_sender.receiver_count()
+  }
+}
+
+impl Drop for WebsocketServerHardware {
+  fn drop(&mut self) {
+    // Nothing to stop if we disconnected on purpose, or the connection is already gone.
+    if !self.connected.load(Ordering::SeqCst) || self.connection.closed_token.is_cancelled() {
+      return;
+    }
+    if let Some(stop_frame) = &self.drop_stop_frame {
+      // We can't wait for room in the queue here, so this is best effort.
+      if let Err(err) = self
+        .outgoing_sender
+        .try_send(OutgoingMessage::Data(self.framing.encode(stop_frame)))
+      {
+        warn!(
+          "Could not send stop frame to dropped websocket device {}: {}",
+          self.info.address(),
+          err
+        );
+      }
     }
   }
 }
@@ -255,36 +1235,211 @@ impl HardwareInternal for WebsocketServerHardware {
 
   fn disconnect(&self) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
     let connected = self.connected.clone();
+    let connection = self.connection.clone();
     async move {
       connected.store(false, Ordering::SeqCst);
+      connection.cancellation_token.cancel();
+      if connection.closed_by_task {
+        // Have the connection loop run the close handshake, and wait until it's done with it.
+        connection.closed_token.cancelled().await;
+      } else {
+        // Whoever is running a detached connection is left to finish it up.
+        connection.closed_token.cancel();
+      }
       Ok(())
     }
     .boxed()
   }
 
+  // Reads are request/response. We send the read command to the device as JSON with a request id,
+  // and wait for a text frame carrying the same id back, see WebsocketReadRequest and
+  // WebsocketReadReply for the wire format.
   fn read_value(
     &self,
-    _msg: &HardwareReadCmd,
+    msg: &HardwareReadCmd,
   ) -> BoxFuture<'static, Result<HardwareReading, ButtplugDeviceError>> {
-    future::ready(Err(ButtplugDeviceError::UnhandledCommand(
-      "Websocket Hardware does not support read".to_owned(),
-    )))
+    if let Err(err) = Self::check_endpoint(msg.endpoint()) {
+      return future::ready(Err(err)).boxed();
+    }
+    let id = self.connection.next_read_id.fetch_add(1, Ordering::Relaxed);
+    let request = match serde_json::to_vec(&WebsocketReadRequest { id, cmd: msg }) {
+      Ok(request) => OutgoingMessage::Data(self.framing.encode(&request)),
+      Err(err) => {
+        return future::ready(Err(ButtplugDeviceError::DeviceCommunicationError(format!(
+          "Could not serialize read request for websocket device: {}",
+          err
+        ))))
+        .boxed()
+      }
+    };
+    // Register before sending the request, so we can't miss a fast response.
+    let (reply_sender, reply_receiver) = oneshot::channel();
+    self
+      .connection
+      .pending_reads
+      .lock()
+      .expect("Lock is never held across a panic")
+      .insert(id, reply_sender);
+    let pending_read = PendingRead {
+      pending_reads: self.connection.pending_reads.clone(),
+      id,
+    };
+    let sender = self.outgoing_sender.clone();
+    let endpoint = msg.endpoint();
+    let length = msg.length() as usize;
+    let timeout_ms = if msg.timeout_ms() == 0 {
+      DEFAULT_READ_TIMEOUT_MS
+    } else {
+      msg.timeout_ms()
+    };
+    async move {
+      let _pending_read = pending_read;
+      sender.send(request).await.map_err(|err| {
+        ButtplugDeviceError::DeviceCommunicationError(format!(
+          "Could not send read request to websocket device: {}",
+          err
+        ))
+      })?;
+      let data = match timeout(Duration::from_millis(timeout_ms as u64), reply_receiver).await {
+        Ok(Ok(data)) => data,
+        Ok(Err(_)) => {
+          return Err(ButtplugDeviceError::DeviceNotConnected(
+            "Websocket device disconnected before answering read.".to_owned(),
+          ))
+        }
+        Err(_) => {
+          return Err(ButtplugDeviceError::DeviceCommunicationError(format!(
+            "Websocket device did not respond to read within {}ms.",
+            timeout_ms
+          )))
+        }
+      };
+      // A length of 0 takes whatever the device sends. Otherwise the reply has to be exactly what
+      // was asked for, as a protocol reading a fixed size value can't do anything sensible with a
+      // partial one, or guess which part of a longer one it wanted.
+      if length != 0 && data.len() != length {
+        return Err(ButtplugDeviceError::DeviceCommunicationError(format!(
+          "Websocket device sent {} bytes for a {} byte read from {:?}.",
+          data.len(),
+          length,
+          endpoint
+        )));
+      }
+      Ok(HardwareReading::new(endpoint, &data))
+    }
     .boxed()
   }
 
+  // Writes go out in the order they're queued, which is when the returned future resolves, not when
+  // write_value is called. Awaiting each write before starting the next keeps them in order. Writes
+  // started concurrently (e.g. from different tasks) are queued in whatever order they get room in
+  // the channel, so callers that care about their relative order need to sequence them.
   fn write_value(
     &self,
     msg: &HardwareWriteCmd,
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    if let Err(err) = Self::check_endpoint(msg.endpoint()) {
+      return future::ready(Err(err)).boxed();
+    }
+    let endpoint = msg.endpoint();
+    if let Some(last_writes) = &self.last_writes {
+      if last_writes
+        .lock()
+        .expect("Lock is never held across a panic")
+        .get(&endpoint)
+        == Some(&msg.data)
+      {
+        trace!("Dropping write to {} identical to the last one.", endpoint);
+        return future::ready(Ok(())).boxed();
+      }
+    }
+    let monitor = (self.write_monitor.receiver_count() != 0)
+      .then(|| (self.write_monitor.clone(), msg.data.clone()));
+    // Only recorded once the write is queued, so a write that's never polled or fails to go out
+    // doesn't hold back a retry.
+    let last_write = self
+      .last_writes
+      .clone()
+      .map(|last_writes| (last_writes, msg.data.clone()));
+    let sender = self.outgoing_sender.clone();
+    let data = OutgoingMessage::Data(self.framing.encode(&msg.data));
+    let write_timeout = self.write_timeout;
+    async move {
+      let result = if let Some(write_timeout) = write_timeout {
+        sender
+          .send_timeout(data, write_timeout)
+          .await
+          .map_err(|err| {
+            ButtplugDeviceError::DeviceCommunicationError(format!(
+              "Could not write value to websocket device: {}",
+              err
+            ))
+          })
+      } else {
+        sender.send(data).await.map_err(|err| {
+          ButtplugDeviceError::DeviceCommunicationError(format!(
+            "Could not write value to websocket device: {}",
+            err
+          ))
+        })
+      };
+      if result.is_ok() {
+        if let Some((last_writes, data)) = last_write {
+          last_writes
+            .lock()
+            .expect("Lock is never held across a panic")
+            .insert(endpoint, data);
+        }
+        if let Some((write_monitor, monitor_data)) = monitor {
+          // If the monitor went away in the meantime, ignore output.
+          let _ = write_monitor.send((endpoint, monitor_data));
+        }
+      }
+      result
+    }
+    .boxed()
+  }
+
+  fn write_values(
+    &self,
+    msgs: &[HardwareWriteCmd],
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    // Every endpoint shares the one connection, so there's nothing to route. Check all endpoints up
+    // front though, so a bad one doesn't leave the device with only part of a command.
+    if let Some(err) = msgs
+      .iter()
+      .find_map(|msg| Self::check_endpoint(msg.endpoint()).err())
+    {
+      return future::ready(Err(err)).boxed();
+    }
+    let write_futs: Vec<_> = msgs.iter().map(|msg| self.write_value(msg)).collect();
+    async move {
+      for write_fut in write_futs {
+        write_fut.await?;
+      }
+      Ok(())
+    }
+    .boxed()
+  }
+
+  fn flush(&self) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
     let sender = self.outgoing_sender.clone();
-    let data = msg.data.clone();
-    // TODO Should check endpoint validity
     async move {
-      sender.send(data).await.map_err(|err| {
-        ButtplugDeviceError::DeviceCommunicationError(format!(
-          "Could not write value to websocket device: {}",
-          err
-        ))
+      let (ack_sender, ack_receiver) = oneshot::channel();
+      sender
+        .send(OutgoingMessage::Flush(ack_sender))
+        .await
+        .map_err(|err| {
+          ButtplugDeviceError::DeviceCommunicationError(format!(
+            "Could not flush websocket device: {}",
+            err
+          ))
+        })?;
+      // The connection loop drops the ack if it shuts down before getting to the flush.
+      ack_receiver.await.map_err(|_| {
+        ButtplugDeviceError::DeviceNotConnected(
+          "Websocket device disconnected before writes were flushed.".to_owned(),
+        )
       })
     }
     .boxed()
@@ -292,47 +1447,38 @@ impl HardwareInternal for WebsocketServerHardware {
 
   fn subscribe(
     &self,
-    _msg: &HardwareSubscribeCmd,
+    msg: &HardwareSubscribeCmd,
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    self.subscribe_internal(msg, None)
+  }
+
+  fn subscribe_with_decoder(
+    &self,
+    msg: &HardwareSubscribeCmd,
+    decoder: NotificationDecoder,
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    self.subscribe_internal(msg, Some(decoder))
+  }
+
+  fn subscribe_all(
+    &self,
+    endpoints: &[Endpoint],
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
-    if self.subscribed.load(Ordering::SeqCst) {
-      error!("Endpoint already subscribed somehow!");
-      return future::ready(Ok(())).boxed();
+    // Check all endpoints up front, so a bad one doesn't leave the device partly subscribed.
+    if let Some(err) = endpoints
+      .iter()
+      .find_map(|endpoint| Self::check_endpoint(*endpoint).err())
+    {
+      return future::ready(Err(err)).boxed();
     }
-    // TODO Should check endpoint validity
-    let mut data_receiver = self.incoming_broadcaster.subscribe();
-    let event_sender = self.device_event_sender.clone();
-    let address = self.info.address().clone();
-    let subscribed = self.subscribed.clone();
-    let subscribed_token = self.subscribe_token.clone();
+    let subscribe_futs: Vec<_> = endpoints
+      .iter()
+      .map(|endpoint| self.subscribe_internal(&HardwareSubscribeCmd::new(*endpoint), None))
+      .collect();
     async move {
-      subscribed.store(true, Ordering::SeqCst);
-      let token = CancellationToken::new();
-      *(subscribed_token.lock().await) = Some(token.child_token());
-      async_manager::spawn(async move {
-        loop {
-          select! {
-            result = data_receiver.recv().fuse() => {
-              match result {
-                Ok(data) => {
-                  debug!("Got websocket data! {:?}", data);
-                  // We don't really care if there's no one to send the error to here.
-                  let _ = event_sender
-                    .send(HardwareEvent::Notification(
-                      address.clone(),
-                      Endpoint::Tx,
-                      data,
-                    ));
-                },
-                Err(_) => break,
-              }
-            },
-            _ = token.cancelled().fuse() => {
-              break;
-            }
-          }
-        }
-        info!("Data channel closed, ending websocket server device listener task");
-      });
+      for subscribe_fut in subscribe_futs {
+        subscribe_fut.await?;
+      }
       Ok(())
     }
     .boxed()
@@ -340,25 +1486,1557 @@ impl HardwareInternal for WebsocketServerHardware {
 
   fn unsubscribe(
     &self,
-    _msg: &HardwareUnsubscribeCmd,
+    msg: &HardwareUnsubscribeCmd,
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
-    if self.subscribed.load(Ordering::SeqCst) {
-      let subscribed = self.subscribed.clone();
-      let subscribed_token = self.subscribe_token.clone();
-      async move {
-        subscribed.store(false, Ordering::SeqCst);
-        let token = (subscribed_token.lock().await)
-          .take()
-          .expect("If we were subscribed, we'll have a token.");
-        token.cancel();
-        Ok(())
-      }
-      .boxed()
-    } else {
-      future::ready(Err(ButtplugDeviceError::DeviceCommunicationError(
-        "Device not subscribed.".to_owned(),
-      )))
-      .boxed()
+    if let Err(err) = Self::check_endpoint(msg.endpoint()) {
+      return future::ready(Err(err)).boxed();
+    }
+    let endpoint = msg.endpoint();
+    let subscriptions = self.subscriptions.clone();
+    async move {
+      let mut subscriptions = subscriptions
+        .lock()
+        .expect("Lock is never held across a panic");
+      let Some(index) = subscriptions
+        .subscriptions
+        .iter()
+        .position(|subscription| subscription.endpoint == endpoint)
+      else {
+        return Err(ButtplugDeviceError::DeviceCommunicationError(format!(
+          "Device not subscribed to endpoint {}.",
+          endpoint
+        )));
+      };
+      subscriptions.subscriptions.remove(index);
+      if subscriptions.subscriptions.is_empty() {
+        if let Some(token) = subscriptions.forwarding_token.take() {
+          token.cancel();
+        }
+      }
+      Ok(())
+    }
+    .boxed()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{
+    super::websocket_server_framing::{LengthPrefixedFraming, NoFraming},
+    *,
+  };
+  use tokio::io::{duplex, DuplexStream};
+  use tokio_tungstenite::{
+    tungstenite::{protocol::Role, Message},
+    WebSocketStream,
+  };
+  use tracing_subscriber::fmt::MakeWriter;
+
+  async fn setup_test_connector(
+    cancellation_token: CancellationToken,
+  ) -> (
+    WebsocketServerHardwareConnector,
+    WebSocketStream<DuplexStream>,
+  ) {
+    setup_test_connector_with_config(
+      cancellation_token,
+      WebsocketServerDeviceConnectionConfig::default(),
+    )
+    .await
+  }
+
+  async fn setup_test_connector_with_config(
+    cancellation_token: CancellationToken,
+    config: WebsocketServerDeviceConnectionConfig,
+  ) -> (
+    WebsocketServerHardwareConnector,
+    WebSocketStream<DuplexStream>,
+  ) {
+    let (server_stream, client_stream) = duplex(4096);
+    let server_ws = WebSocketStream::from_raw_socket(server_stream, Role::Server, None).await;
+    let client_ws = WebSocketStream::from_raw_socket(client_stream, Role::Client, None).await;
+    let info: WebsocketServerDeviceCommManagerInitInfo = serde_json::from_str(
+      r#"{"identifier": "test-device", "address": "test-address", "version": 0}"#,
+    )
+    .expect("Test, assuming infallible");
+    let connector =
+      WebsocketServerHardwareConnector::new(info, server_ws, config, cancellation_token);
+    (connector, client_ws)
+  }
+
+  async fn setup_test_hardware() -> (Hardware, WebSocketStream<DuplexStream>) {
+    let (mut connector, client_ws) = setup_test_connector(CancellationToken::new()).await;
+    let hardware = connector
+      .connect()
+      .await
+      .expect("Test, assuming infallible")
+      .specialize(&[])
+      .await
+      .expect("Test, assuming infallible");
+    (hardware, client_ws)
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_connection_shut_down() {
+    let token = CancellationToken::new();
+    let (mut connector, mut client_ws) = setup_test_connector(token.child_token()).await;
+    let closed_token = connector.connection_closed_token();
+    let hardware = connector
+      .connect()
+      .await
+      .expect("Test, assuming infallible")
+      .specialize(&[])
+      .await
+      .expect("Test, assuming infallible");
+    let mut event_receiver = hardware.event_stream();
+
+    // This is what the comm manager does with a finished connection when a new one shows up with
+    // the same address.
+    token.cancel();
+    closed_token.cancelled().await;
+    assert!(matches!(
+      event_receiver.recv().await,
+      Ok(HardwareEvent::Disconnected(address, DisconnectReason::OwnerDropped))
+        if address == "test-address"
+    ));
+    assert!(matches!(
+      client_ws.next().await,
+      Some(Ok(Message::Close(_)))
+    ));
+    assert!(hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![0], false))
+      .await
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_connection_replaced() {
+    let (mut connector, mut client_ws) = setup_test_connector(CancellationToken::new()).await;
+    let reconnect_handle = connector.reconnect_handle();
+    let hardware = connector
+      .connect()
+      .await
+      .expect("Test, assuming infallible")
+      .specialize(&[])
+      .await
+      .expect("Test, assuming infallible");
+    let mut event_receiver = hardware.event_stream();
+    hardware
+      .subscribe(&HardwareSubscribeCmd::new(Endpoint::Rx))
+      .await
+      .expect("Test, assuming infallible");
+
+    let (server_stream, client_stream) = duplex(4096);
+    let server_ws = WebSocketStream::from_raw_socket(server_stream, Role::Server, None).await;
+    let mut new_client_ws =
+      WebSocketStream::from_raw_socket(client_stream, Role::Client, None).await;
+    assert!(try_replace(&reconnect_handle, server_ws).is_ok());
+
+    // The first connection loop is gone, and the device carries on without disconnecting.
+    assert!(matches!(
+      client_ws.next().await,
+      Some(Ok(Message::Close(_)))
+    ));
+    assert!(matches!(
+      event_receiver.recv().await,
+      Ok(HardwareEvent::Reconnecting(address)) if address == "test-address"
+    ));
+    assert!(matches!(
+      event_receiver.recv().await,
+      Ok(HardwareEvent::Reconnected(address)) if address == "test-address"
+    ));
+    hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![1, 2, 3], false))
+      .await
+      .expect("Test, assuming infallible");
+    assert!(matches!(
+      new_client_ws.next().await,
+      Some(Ok(Message::Binary(data))) if data == vec![1, 2, 3]
+    ));
+    new_client_ws
+      .send(Message::Binary(vec![4, 5, 6]))
+      .await
+      .expect("Test, assuming infallible");
+    assert!(matches!(
+      event_receiver.recv().await,
+      Ok(HardwareEvent::Notification(_, Endpoint::Rx, data, _)) if data == vec![4, 5, 6]
+    ));
+  }
+
+  // Reads a request off the client side, checks it's the read we expect, and returns its id.
+  async fn receive_read_request(
+    client_ws: &mut WebSocketStream<DuplexStream>,
+    expected: &HardwareReadCmd,
+  ) -> u64 {
+    match client_ws.next().await {
+      Some(Ok(Message::Binary(request))) => {
+        let request: serde_json::Value =
+          serde_json::from_slice(&request).expect("Test, assuming infallible");
+        let read_cmd: HardwareReadCmd =
+          serde_json::from_value(request.clone()).expect("Test, assuming infallible");
+        assert_eq!(read_cmd, *expected);
+        request["id"].as_u64().expect("Test, assuming infallible")
+      }
+      msg => panic!("Expected read request, got {:?}", msg),
+    }
+  }
+
+  async fn send_read_reply(client_ws: &mut WebSocketStream<DuplexStream>, id: u64, data: &[u8]) {
+    client_ws
+      .send(Message::Text(
+        serde_json::json!({ "id": id, "data": data }).to_string(),
+      ))
+      .await
+      .expect("Test, assuming infallible");
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_read() {
+    let (hardware, mut client_ws) = setup_test_hardware().await;
+    let client_task = tokio::spawn(async move {
+      let id =
+        receive_read_request(&mut client_ws, &HardwareReadCmd::new(Endpoint::Rx, 3, 500)).await;
+      // Notifications, and replies to reads nobody made, aren't taken as the reply.
+      client_ws
+        .send(Message::Binary(vec![9, 9, 9]))
+        .await
+        .expect("Test, assuming infallible");
+      send_read_reply(&mut client_ws, id + 1, &[8, 8, 8]).await;
+      send_read_reply(&mut client_ws, id, &[1, 2, 3]).await;
+      client_ws
+    });
+    let reading = hardware
+      .read_value(&HardwareReadCmd::new(Endpoint::Rx, 3, 500))
+      .await
+      .expect("Test, assuming infallible");
+    assert_eq!(*reading.endpoint(), Endpoint::Rx);
+    assert_eq!(*reading.data(), vec![1, 2, 3]);
+    client_task.await.expect("Test, assuming infallible");
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_read_length() {
+    let (hardware, mut client_ws) = setup_test_hardware().await;
+    let client_task = tokio::spawn(async move {
+      for reply in [vec![1, 2, 3, 4], vec![1, 2, 3, 4, 5, 6], vec![7, 8]] {
+        let id =
+          receive_read_request(&mut client_ws, &HardwareReadCmd::new(Endpoint::Rx, 4, 500)).await;
+        send_read_reply(&mut client_ws, id, &reply).await;
+      }
+      client_ws
+    });
+    let reading = hardware
+      .read_value(&HardwareReadCmd::new(Endpoint::Rx, 4, 500))
+      .await
+      .expect("Test, assuming infallible");
+    assert_eq!(*reading.data(), vec![1, 2, 3, 4]);
+    // Replies that aren't the requested length are errors, rather than being cut down or passed
+    // along short.
+    for _ in 0..2 {
+      assert!(matches!(
+        hardware
+          .read_value(&HardwareReadCmd::new(Endpoint::Rx, 4, 500))
+          .await,
+        Err(ButtplugDeviceError::DeviceCommunicationError(_))
+      ));
+    }
+    client_task.await.expect("Test, assuming infallible");
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_write_monitor() {
+    let mut config = WebsocketServerDeviceConnectionConfig::default();
+    config.set_framing(Arc::new(LengthPrefixedFraming));
+    let (connector, mut client_ws) =
+      setup_test_connector_with_config(CancellationToken::new(), config).await;
+    let hardware = connector.create_hardware();
+    // Nothing is kept for monitors that aren't around yet.
+    hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![1], false))
+      .await
+      .expect("Test, assuming infallible");
+    let mut monitor = hardware.write_monitor();
+    hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![2, 3], false))
+      .await
+      .expect("Test, assuming infallible");
+    // The monitor sees the data as written, without framing.
+    assert_eq!(
+      monitor.recv().await.expect("Test, assuming infallible"),
+      (Endpoint::Tx, vec![2, 3])
+    );
+    assert!(monitor.try_recv().is_err());
+    for expected in [vec![0, 0, 0, 1, 1], vec![0, 0, 0, 2, 2, 3]] {
+      assert_eq!(
+        client_ws
+          .next()
+          .await
+          .expect("Test, assuming infallible")
+          .ok(),
+        Some(Message::Binary(expected))
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_write_monitor_failed_write() {
+    let info: WebsocketServerDeviceCommManagerInitInfo = serde_json::from_str(
+      r#"{"identifier": "test-device", "address": "test-address", "version": 0}"#,
+    )
+    .expect("Test, assuming infallible");
+    let config = WebsocketServerDeviceConnectionConfig::default();
+    let (hardware, detached) = WebsocketServerHardware::new_detached(info, &config);
+    let mut monitor = hardware.write_monitor();
+    // With nothing left to take writes, they fail, and never reach the monitor.
+    drop(detached);
+    assert!(hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![1], false))
+      .await
+      .is_err());
+    assert!(monitor.try_recv().is_err());
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_invalid_endpoint() {
+    let (hardware, _client_ws) = setup_test_hardware().await;
+    assert_eq!(
+      hardware
+        .write_value(&HardwareWriteCmd::new(Endpoint::Generic0, vec![0], false))
+        .await,
+      Err(ButtplugDeviceError::InvalidEndpoint(Endpoint::Generic0))
+    );
+    assert_eq!(
+      hardware
+        .subscribe(&HardwareSubscribeCmd::new(Endpoint::Generic0))
+        .await,
+      Err(ButtplugDeviceError::InvalidEndpoint(Endpoint::Generic0))
+    );
+    assert_eq!(
+      hardware
+        .unsubscribe(&HardwareUnsubscribeCmd::new(Endpoint::Generic0))
+        .await,
+      Err(ButtplugDeviceError::InvalidEndpoint(Endpoint::Generic0))
+    );
+    assert!(hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![0], false))
+      .await
+      .is_ok());
+  }
+
+  // Collects formatted log output, so tests can check what was logged.
+  #[derive(Clone, Default)]
+  struct LogCapture(Arc<std::sync::Mutex<Vec<u8>>>);
+
+  impl std::io::Write for LogCapture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      self
+        .0
+        .lock()
+        .expect("Test, assuming infallible")
+        .extend_from_slice(buf);
+      Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  impl MakeWriter<'_> for LogCapture {
+    type Writer = LogCapture;
+    fn make_writer(&self) -> Self::Writer {
+      self.clone()
+    }
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_log_span() {
+    let capture = LogCapture::default();
+    let subscriber = tracing_subscriber::fmt()
+      .with_writer(capture.clone())
+      .with_ansi(false)
+      .with_max_level(tracing::Level::TRACE)
+      .finish();
+    // Tests run on a single threaded runtime, so this covers the connection task too.
+    let _guard = tracing::subscriber::set_default(subscriber);
+    let (hardware, _client_ws) = setup_test_hardware().await;
+    hardware
+      .disconnect()
+      .await
+      .expect("Test, assuming infallible");
+    let logs = String::from_utf8(capture.0.lock().expect("Test, assuming infallible").clone())
+      .expect("Test, assuming infallible");
+    for state in ["connected", "disconnected"] {
+      let line = logs
+        .lines()
+        .find(|line| line.contains(&format!("state=\"{}\"", state)))
+        .unwrap_or_else(|| panic!("No {} event in logs:\n{}", state, logs));
+      assert!(line.contains("address=test-address"));
+      assert!(line.contains("identifier=test-device"));
+    }
+    assert!(logs.contains("reason=\"shut down\""));
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_flush() {
+    let (hardware, mut client_ws) = setup_test_hardware().await;
+    hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![1, 2, 3], false))
+      .await
+      .expect("Test, assuming infallible");
+    hardware.flush().await.expect("Test, assuming infallible");
+    // Once flushed, the data is already waiting on the socket.
+    assert!(matches!(
+      client_ws.next().now_or_never(),
+      Some(Some(Ok(Message::Binary(data)))) if data == vec![1, 2, 3]
+    ));
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_write_ordering() {
+    const WRITE_COUNT: u32 = 1000;
+    let (hardware, mut client_ws) = setup_test_hardware().await;
+    // Read while writing, since the writes won't all fit in the socket buffer.
+    let client_task = tokio::spawn(async move {
+      let mut received = vec![];
+      while received.len() < WRITE_COUNT as usize {
+        match client_ws.next().await {
+          Some(Ok(Message::Binary(data))) => received.push(data),
+          msg => panic!("Expected binary frame, got {:?}", msg),
+        }
+      }
+      received
+    });
+    for i in 0..WRITE_COUNT {
+      hardware
+        .write_value(&HardwareWriteCmd::new(
+          Endpoint::Tx,
+          i.to_be_bytes().to_vec(),
+          false,
+        ))
+        .await
+        .expect("Test, assuming infallible");
+    }
+    let received = client_task.await.expect("Test, assuming infallible");
+    let expected: Vec<Vec<u8>> = (0..WRITE_COUNT).map(|i| i.to_be_bytes().to_vec()).collect();
+    assert_eq!(received, expected);
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_drop_stop_frame() {
+    let mut config = WebsocketServerDeviceConnectionConfig::default();
+    config.set_drop_stop_frame(Some(vec![0xff, 0x00]));
+    let (mut connector, mut client_ws) =
+      setup_test_connector_with_config(CancellationToken::new(), config).await;
+    let hardware = connector
+      .connect()
+      .await
+      .expect("Test, assuming infallible")
+      .specialize(&[])
+      .await
+      .expect("Test, assuming infallible");
+    hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![1], false))
+      .await
+      .expect("Test, assuming infallible");
+    drop(hardware);
+    for expected in [vec![1], vec![0xff, 0x00]] {
+      match client_ws.next().await {
+        Some(Ok(Message::Binary(data))) => assert_eq!(data, expected),
+        msg => panic!("Expected binary frame, got {:?}", msg),
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_dedup_writes() {
+    let mut config = WebsocketServerDeviceConnectionConfig::default();
+    config.set_dedup_writes(true);
+    let (mut connector, mut client_ws) =
+      setup_test_connector_with_config(CancellationToken::new(), config).await;
+    let hardware = connector
+      .connect()
+      .await
+      .expect("Test, assuming infallible")
+      .specialize(&[])
+      .await
+      .expect("Test, assuming infallible");
+    // Repeats are only dropped per endpoint, and only when they match the write right before them.
+    for (endpoint, data) in [
+      (Endpoint::Tx, vec![1]),
+      (Endpoint::Tx, vec![1]),
+      (Endpoint::Rx, vec![1]),
+      (Endpoint::Tx, vec![2]),
+      (Endpoint::Tx, vec![2]),
+      (Endpoint::Tx, vec![1]),
+    ] {
+      hardware
+        .write_value(&HardwareWriteCmd::new(endpoint, data, false))
+        .await
+        .expect("Test, assuming infallible");
+    }
+    // A write that's dropped before it's queued doesn't count as the last one.
+    drop(hardware.write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![3], false)));
+    hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![3], false))
+      .await
+      .expect("Test, assuming infallible");
+    for expected in [vec![1], vec![1], vec![2], vec![1], vec![3]] {
+      match client_ws.next().await {
+        Some(Ok(Message::Binary(data))) => assert_eq!(data, expected),
+        msg => panic!("Expected binary frame, got {:?}", msg),
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_endpoints() {
+    let (hardware, _client_ws) = setup_test_hardware().await;
+    assert_eq!(hardware.endpoints(), &[Endpoint::Rx, Endpoint::Tx]);
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_read_timeout() {
+    // Hold on to the client side so the connection stays up, but never answer.
+    let (hardware, _client_ws) = setup_test_hardware().await;
+    assert!(matches!(
+      hardware
+        .read_value(&HardwareReadCmd::new(Endpoint::Rx, 3, 50))
+        .await,
+      Err(ButtplugDeviceError::DeviceCommunicationError(_))
+    ));
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_text_notification() {
+    let (hardware, mut client_ws) = setup_test_hardware().await;
+    let mut event_receiver = hardware.event_stream();
+    hardware
+      .subscribe(&HardwareSubscribeCmd::new(Endpoint::Rx))
+      .await
+      .expect("Test, assuming infallible");
+    client_ws
+      .send(Message::Text(r#"{"battery": 50}"#.to_owned()))
+      .await
+      .expect("Test, assuming infallible");
+    match event_receiver.recv().await {
+      Ok(HardwareEvent::Notification(address, endpoint, data, identifier)) => {
+        assert_eq!(address, "test-address");
+        assert_eq!(identifier, Some("test-device".to_owned()));
+        assert_eq!(endpoint, Endpoint::Rx);
+        assert_eq!(data, br#"{"battery": 50}"#.to_vec());
+      }
+      event => panic!("Expected notification, got {:?}", event),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_disconnect_sends_close() {
+    let (hardware, mut client_ws) = setup_test_hardware().await;
+    let client_task = tokio::spawn(async move {
+      assert!(matches!(
+        client_ws.next().await,
+        Some(Ok(Message::Close(_)))
+      ));
+      // Polling again flushes our half of the close handshake back to the server.
+      assert!(client_ws.next().await.is_none());
+    });
+    hardware
+      .disconnect()
+      .await
+      .expect("Test, assuming infallible");
+    client_task.await.expect("Test, assuming infallible");
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_write_timeout() {
+    // Nothing ever drains this channel, so once it's full every write has to wait.
+    let (outgoing_sender, _outgoing_receiver) = channel(1);
+    let (incoming_broadcaster, _) = broadcast::channel(1);
+    let (device_event_sender, _) = broadcast::channel(1);
+    let info: WebsocketServerDeviceCommManagerInitInfo = serde_json::from_str(
+      r#"{"identifier": "test-device", "address": "test-address", "version": 0}"#,
+    )
+    .expect("Test, assuming infallible");
+    let hardware = WebsocketServerHardware::new(
+      device_event_sender,
+      info,
+      outgoing_sender,
+      incoming_broadcaster,
+      WebsocketServerConnectionHandle::default(),
+      Some(Duration::from_millis(50)),
+      Arc::new(NoFraming),
+      None,
+      false,
+    );
+    assert!(hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![0], false))
+      .await
+      .is_ok());
+    assert!(matches!(
+      hardware
+        .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![1], false))
+        .await,
+      Err(ButtplugDeviceError::DeviceCommunicationError(_))
+    ));
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_stats() {
+    let (connector, mut client_ws) = setup_test_connector(CancellationToken::new()).await;
+    let hardware = connector.create_hardware();
+    assert_eq!(hardware.stats().bytes_sent(), 0);
+    for _ in 0..3 {
+      hardware
+        .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![0, 1], false))
+        .await
+        .expect("Test, assuming infallible");
+      assert!(matches!(
+        client_ws.next().await,
+        Some(Ok(Message::Binary(_)))
+      ));
+    }
+    // The client has seen all three frames, so the loop has finished sending them.
+    assert_eq!(hardware.stats().bytes_sent(), 6);
+    client_ws
+      .send(Message::Binary(vec![0, 1, 2, 3]))
+      .await
+      .expect("Test, assuming infallible");
+    while hardware.stats().bytes_received() == 0 {
+      tokio::task::yield_now().await;
+    }
+    assert_eq!(hardware.stats().bytes_received(), 4);
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_last_received_frame() {
+    let (connector, mut client_ws) = setup_test_connector(CancellationToken::new()).await;
+    let hardware = connector.create_hardware();
+    assert_eq!(hardware.last_received_frame(), None);
+    for frame in [vec![1, 2], vec![3, 4, 5]] {
+      client_ws
+        .send(Message::Binary(frame))
+        .await
+        .expect("Test, assuming infallible");
+    }
+    while hardware.last_received_frame() != Some(vec![3, 4, 5]) {
+      tokio::task::yield_now().await;
+    }
+    client_ws
+      .send(Message::Text("hi".to_owned()))
+      .await
+      .expect("Test, assuming infallible");
+    while hardware.last_received_frame() != Some(b"hi".to_vec()) {
+      tokio::task::yield_now().await;
+    }
+  }
+
+  // Pulls the next notification off the event stream, as (endpoint, data).
+  async fn next_notification(
+    event_receiver: &mut broadcast::Receiver<HardwareEvent>,
+  ) -> (Endpoint, Vec<u8>) {
+    match event_receiver.recv().await {
+      Ok(HardwareEvent::Notification(_, endpoint, data, _)) => (endpoint, data),
+      event => panic!("Expected notification, got {:?}", event),
     }
   }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_per_endpoint_subscriptions() {
+    let (hardware, mut client_ws) = setup_test_hardware().await;
+    let mut event_receiver = hardware.event_stream();
+    for endpoint in [Endpoint::Rx, Endpoint::Tx] {
+      hardware
+        .subscribe(&HardwareSubscribeCmd::new(endpoint))
+        .await
+        .expect("Test, assuming infallible");
+    }
+    // Each frame goes to both subscriptions, tagged with their endpoints, in the order they were
+    // made.
+    for frame in [vec![1], vec![2]] {
+      client_ws
+        .send(Message::Binary(frame.clone()))
+        .await
+        .expect("Test, assuming infallible");
+      for endpoint in [Endpoint::Rx, Endpoint::Tx] {
+        assert_eq!(
+          next_notification(&mut event_receiver).await,
+          (endpoint, frame.clone())
+        );
+      }
+    }
+
+    // Unsubscribing from one endpoint leaves the other one running.
+    hardware
+      .unsubscribe(&HardwareUnsubscribeCmd::new(Endpoint::Rx))
+      .await
+      .expect("Test, assuming infallible");
+    assert!(hardware
+      .unsubscribe(&HardwareUnsubscribeCmd::new(Endpoint::Rx))
+      .await
+      .is_err());
+    client_ws
+      .send(Message::Binary(vec![3]))
+      .await
+      .expect("Test, assuming infallible");
+    assert_eq!(
+      next_notification(&mut event_receiver).await,
+      (Endpoint::Tx, vec![3])
+    );
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_prefix_filters_route_endpoints() {
+    let (hardware, mut client_ws) = setup_test_hardware().await;
+    let mut event_receiver = hardware.event_stream();
+    hardware
+      .subscribe(&HardwareSubscribeCmd::new_with_prefix_filter(
+        Endpoint::Rx,
+        &[0xaa],
+      ))
+      .await
+      .expect("Test, assuming infallible");
+    hardware
+      .subscribe(&HardwareSubscribeCmd::new(Endpoint::Tx))
+      .await
+      .expect("Test, assuming infallible");
+    for frame in [vec![0xaa, 0x01], vec![0xbb, 0x02], vec![0xaa, 0x03]] {
+      client_ws
+        .send(Message::Binary(frame))
+        .await
+        .expect("Test, assuming infallible");
+    }
+    // Frames go to every subscription they match, so the unfiltered one sees everything.
+    for expected in [
+      (Endpoint::Rx, vec![0xaa, 0x01]),
+      (Endpoint::Tx, vec![0xaa, 0x01]),
+      (Endpoint::Tx, vec![0xbb, 0x02]),
+      (Endpoint::Rx, vec![0xaa, 0x03]),
+      (Endpoint::Tx, vec![0xaa, 0x03]),
+    ] {
+      assert_eq!(next_notification(&mut event_receiver).await, expected);
+    }
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_subscribe_all() {
+    let (mut connector, mut client_ws) = setup_test_connector(CancellationToken::new()).await;
+    // Asking for every endpoint there is fails without subscribing to anything.
+    let websocket_hardware = connector.create_hardware();
+    let all_endpoints: Vec<Endpoint> = Endpoint::all().collect();
+    assert!(matches!(
+      websocket_hardware.subscribe_all(&all_endpoints).await,
+      Err(ButtplugDeviceError::InvalidEndpoint(_))
+    ));
+    assert!(websocket_hardware
+      .unsubscribe(&HardwareUnsubscribeCmd::new(Endpoint::Rx))
+      .await
+      .is_err());
+
+    let hardware = connector
+      .connect()
+      .await
+      .expect("Test, assuming infallible")
+      .specialize(&[])
+      .await
+      .expect("Test, assuming infallible");
+    let mut event_receiver = hardware.event_stream();
+    hardware
+      .subscribe_all()
+      .await
+      .expect("Test, assuming infallible");
+    client_ws
+      .send(Message::Binary(vec![1]))
+      .await
+      .expect("Test, assuming infallible");
+    // Every endpoint is subscribed, so each frame is reported once for each of them.
+    for endpoint in [Endpoint::Rx, Endpoint::Tx] {
+      assert_eq!(
+        next_notification(&mut event_receiver).await,
+        (endpoint, vec![1])
+      );
+    }
+    // They're separate subscriptions, so one can be dropped and the other keeps going.
+    hardware
+      .unsubscribe(&HardwareUnsubscribeCmd::new(Endpoint::Rx))
+      .await
+      .expect("Test, assuming infallible");
+    client_ws
+      .send(Message::Binary(vec![2]))
+      .await
+      .expect("Test, assuming infallible");
+    assert_eq!(
+      next_notification(&mut event_receiver).await,
+      (Endpoint::Tx, vec![2])
+    );
+    hardware
+      .unsubscribe(&HardwareUnsubscribeCmd::new(Endpoint::Tx))
+      .await
+      .expect("Test, assuming infallible");
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_subscription_lag() {
+    // A tiny notification buffer, so the subscription can't keep up.
+    let (outgoing_sender, _outgoing_receiver) = channel(1);
+    let (incoming_broadcaster, _) = broadcast::channel(2);
+    let (device_event_sender, _) = broadcast::channel(16);
+    let info: WebsocketServerDeviceCommManagerInitInfo = serde_json::from_str(
+      r#"{"identifier": "test-device", "address": "test-address", "version": 0}"#,
+    )
+    .expect("Test, assuming infallible");
+    let hardware = WebsocketServerHardware::new(
+      device_event_sender,
+      info,
+      outgoing_sender,
+      incoming_broadcaster.clone(),
+      WebsocketServerConnectionHandle::default(),
+      None,
+      Arc::new(NoFraming),
+      None,
+      false,
+    );
+    let mut event_receiver = hardware.event_stream();
+    hardware
+      .subscribe(&HardwareSubscribeCmd::new(Endpoint::Rx))
+      .await
+      .expect("Test, assuming infallible");
+    // Nothing else gets to run until we await, so the buffer overruns.
+    for data in 0..5u8 {
+      incoming_broadcaster
+        .send(vec![data])
+        .expect("Test, assuming infallible");
+    }
+    assert!(matches!(
+      event_receiver.recv().await,
+      Ok(HardwareEvent::NotificationsDropped(address, Endpoint::Rx, 3)) if address == "test-address"
+    ));
+    // The subscription carries on from what's still buffered.
+    for expected in [vec![3], vec![4]] {
+      match event_receiver.recv().await {
+        Ok(HardwareEvent::Notification(_, Endpoint::Rx, data, _)) => assert_eq!(data, expected),
+        event => panic!("Expected notification, got {:?}", event),
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_detached() {
+    let info: WebsocketServerDeviceCommManagerInitInfo = serde_json::from_str(
+      r#"{"identifier": "test-device", "address": "test-address", "version": 0}"#,
+    )
+    .expect("Test, assuming infallible");
+    let config = WebsocketServerDeviceConnectionConfig::default();
+    let (hardware, mut detached) = WebsocketServerHardware::new_detached(info, &config);
+    let mut event_receiver = hardware.event_stream();
+
+    // With nothing driving the channels, writes can be picked up straight off the outgoing queue.
+    hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![1, 2], false))
+      .await
+      .expect("Test, assuming infallible");
+    match detached.outgoing_receiver.recv().await {
+      Some(OutgoingMessage::Data(data)) => assert_eq!(data, vec![1, 2]),
+      msg => panic!("Expected data, got {:?}", msg),
+    }
+
+    // Then run the connection loop over a stream fed by hand.
+    let (server_stream, client_stream) = duplex(4096);
+    let server_ws = WebSocketStream::from_raw_socket(server_stream, Role::Server, None).await;
+    let mut client_ws = WebSocketStream::from_raw_socket(client_stream, Role::Client, None).await;
+    let loop_task = tokio::spawn(async move {
+      run_connection_loop(
+        "test-address",
+        &detached.event_sender,
+        server_ws,
+        &mut detached.outgoing_receiver,
+        &detached.incoming_broadcaster,
+        &config,
+        &detached.connection,
+        &CancellationToken::new(),
+      )
+      .await
+    });
+    hardware
+      .subscribe(&HardwareSubscribeCmd::new(Endpoint::Rx))
+      .await
+      .expect("Test, assuming infallible");
+    hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![3], false))
+      .await
+      .expect("Test, assuming infallible");
+    assert_eq!(
+      client_ws
+        .next()
+        .await
+        .expect("Test, assuming infallible")
+        .ok(),
+      Some(Message::Binary(vec![3]))
+    );
+    client_ws
+      .send(Message::Binary(vec![4, 5]))
+      .await
+      .expect("Test, assuming infallible");
+    match event_receiver.recv().await {
+      Ok(HardwareEvent::Notification(address, Endpoint::Rx, data, _)) => {
+        assert_eq!(address, "test-address");
+        assert_eq!(data, vec![4, 5]);
+      }
+      event => panic!("Expected notification, got {:?}", event),
+    }
+    client_ws
+      .close(None)
+      .await
+      .expect("Test, assuming infallible");
+    assert!(matches!(
+      event_receiver.recv().await,
+      Ok(HardwareEvent::Disconnected(address, DisconnectReason::ClientClose))
+        if address == "test-address"
+    ));
+    assert_eq!(
+      loop_task.await.expect("Test, assuming infallible"),
+      ConnectionLoopExit::Closed
+    );
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_subscribe_prefix_filter() {
+    let (hardware, mut client_ws) = setup_test_hardware().await;
+    let mut event_receiver = hardware.event_stream();
+    hardware
+      .subscribe(&HardwareSubscribeCmd::new_with_prefix_filter(
+        Endpoint::Rx,
+        &[0xaa, 0x01],
+      ))
+      .await
+      .expect("Test, assuming infallible");
+    for frame in [
+      vec![0xff, 0x00],
+      vec![0xaa, 0x01, 0x05],
+      vec![0xaa],
+      vec![0xaa, 0x02, 0x05],
+      vec![0xaa, 0x01, 0x06],
+    ] {
+      client_ws
+        .send(Message::Binary(frame))
+        .await
+        .expect("Test, assuming infallible");
+    }
+    for expected in [vec![0xaa, 0x01, 0x05], vec![0xaa, 0x01, 0x06]] {
+      match event_receiver.recv().await {
+        Ok(HardwareEvent::Notification(_, endpoint, data, _)) => {
+          assert_eq!(endpoint, Endpoint::Rx);
+          assert_eq!(data, expected);
+        }
+        event => panic!("Expected notification, got {:?}", event),
+      }
+    }
+    assert!(event_receiver.try_recv().is_err());
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_write_values() {
+    let (hardware, mut client_ws) = setup_test_hardware().await;
+    hardware
+      .write_values(&[
+        HardwareWriteCmd::new(Endpoint::Tx, vec![1, 2], false),
+        HardwareWriteCmd::new(Endpoint::Rx, vec![3, 4], false),
+      ])
+      .await
+      .expect("Test, assuming infallible");
+    for expected in [vec![1, 2], vec![3, 4]] {
+      match client_ws.next().await {
+        Some(Ok(Message::Binary(data))) => assert_eq!(data, expected),
+        msg => panic!("Expected binary frame, got {:?}", msg),
+      }
+    }
+    // Nothing goes out if any endpoint is invalid.
+    assert!(matches!(
+      hardware
+        .write_values(&[
+          HardwareWriteCmd::new(Endpoint::Tx, vec![5], false),
+          HardwareWriteCmd::new(Endpoint::Firmware, vec![6], false),
+        ])
+        .await,
+      Err(ButtplugDeviceError::InvalidEndpoint(Endpoint::Firmware))
+    ));
+    hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![7], false))
+      .await
+      .expect("Test, assuming infallible");
+    match client_ws.next().await {
+      Some(Ok(Message::Binary(data))) => assert_eq!(data, vec![7]),
+      msg => panic!("Expected binary frame, got {:?}", msg),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_subscribe_with_decoder() {
+    let (hardware, mut client_ws) = setup_test_hardware().await;
+    let mut event_receiver = hardware.event_stream();
+    // Strips a 2 byte header, rejecting frames too short to have one.
+    let decoder: NotificationDecoder = Arc::new(|data: &[u8]| {
+      if data.len() < 2 {
+        return Err(ButtplugDeviceError::DeviceCommunicationError(
+          "Frame too short for header".to_owned(),
+        ));
+      }
+      Ok(data[2..].to_vec())
+    });
+    hardware
+      .subscribe_with_decoder(&HardwareSubscribeCmd::new(Endpoint::Rx), decoder)
+      .await
+      .expect("Test, assuming infallible");
+    for frame in [
+      vec![0x01, 0x02, 0x05, 0x06],
+      vec![0x01],
+      vec![0x01, 0x02, 0x07],
+    ] {
+      client_ws
+        .send(Message::Binary(frame))
+        .await
+        .expect("Test, assuming infallible");
+    }
+    for expected in [vec![0x05, 0x06], vec![0x07]] {
+      match event_receiver.recv().await {
+        Ok(HardwareEvent::Notification(_, endpoint, data, _)) => {
+          assert_eq!(endpoint, Endpoint::Rx);
+          assert_eq!(data, expected);
+        }
+        event => panic!("Expected notification, got {:?}", event),
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_read_via_subscription() {
+    let (hardware, mut client_ws) = setup_test_hardware().await;
+    let read = hardware.read_via_subscription(Endpoint::Rx, Duration::from_secs(5));
+    let client_task = tokio::spawn(async move {
+      client_ws
+        .send(Message::Binary(vec![4, 5, 6]))
+        .await
+        .expect("Test, assuming infallible");
+      client_ws
+    });
+    let reading = read.await.expect("Test, assuming infallible");
+    assert_eq!(*reading.endpoint(), Endpoint::Rx);
+    assert_eq!(*reading.data(), vec![4, 5, 6]);
+    let _client_ws = client_task.await.expect("Test, assuming infallible");
+    // The temporary subscription is cleaned up afterward.
+    assert!(hardware
+      .unsubscribe(&HardwareUnsubscribeCmd::new(Endpoint::Rx))
+      .await
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_read_via_subscription_timeout() {
+    let (hardware, _client_ws) = setup_test_hardware().await;
+    assert!(matches!(
+      hardware
+        .read_via_subscription(Endpoint::Rx, Duration::from_millis(50))
+        .await,
+      Err(ButtplugDeviceError::DeviceCommunicationError(_))
+    ));
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_event_listener_count() {
+    let (connector, _client_ws) = setup_test_connector(CancellationToken::new()).await;
+    let hardware = connector.create_hardware();
+    assert_eq!(hardware.event_listener_count(), 0);
+    let first_receiver = hardware.event_stream();
+    let _second_receiver = hardware.event_stream();
+    assert_eq!(hardware.event_listener_count(), 2);
+    drop(first_receiver);
+    assert_eq!(hardware.event_listener_count(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_ping_timeout() {
+    let mut config = WebsocketServerDeviceConnectionConfig::default();
+    config.set_ping_interval(Duration::from_millis(50));
+    // The client never reads, so it never answers the pings it's sent.
+    let (mut connector, _client_ws) =
+      setup_test_connector_with_config(CancellationToken::new(), config).await;
+    let closed_token = connector.connection_closed_token();
+    let hardware = connector
+      .connect()
+      .await
+      .expect("Test, assuming infallible")
+      .specialize(&[])
+      .await
+      .expect("Test, assuming infallible");
+    let mut event_receiver = hardware.event_stream();
+    assert!(matches!(
+      timeout(Duration::from_secs(5), event_receiver.recv()).await,
+      Ok(Ok(HardwareEvent::Disconnected(address, DisconnectReason::PingTimeout)))
+        if address == "test-address"
+    ));
+    timeout(Duration::from_secs(5), closed_token.cancelled())
+      .await
+      .expect("Test, assuming infallible");
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_idle_timeout() {
+    let mut config = WebsocketServerDeviceConnectionConfig::default();
+    config.set_idle_timeout(Some(Duration::from_millis(100)));
+    // Keep the client side open, but never send anything on it.
+    let (mut connector, _client_ws) =
+      setup_test_connector_with_config(CancellationToken::new(), config).await;
+    let closed_token = connector.connection_closed_token();
+    let hardware = connector
+      .connect()
+      .await
+      .expect("Test, assuming infallible")
+      .specialize(&[])
+      .await
+      .expect("Test, assuming infallible");
+    let mut event_receiver = hardware.event_stream();
+    assert!(matches!(
+      timeout(Duration::from_secs(5), event_receiver.recv()).await,
+      Ok(Ok(HardwareEvent::Disconnected(address, DisconnectReason::IdleTimeout)))
+        if address == "test-address"
+    ));
+    timeout(Duration::from_secs(5), closed_token.cancelled())
+      .await
+      .expect("Test, assuming infallible");
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_match_pong_payload() {
+    let mut config = WebsocketServerDeviceConnectionConfig::default();
+    config.set_ping_interval(Duration::from_millis(50));
+    config.set_match_pong_payload(true);
+    let (connector, mut client_ws) =
+      setup_test_connector_with_config(CancellationToken::new(), config).await;
+    let closed_token = connector.connection_closed_token();
+    let hardware = connector.create_hardware();
+    async fn next_ping(client_ws: &mut WebSocketStream<DuplexStream>) -> Vec<u8> {
+      loop {
+        match client_ws.next().await {
+          Some(Ok(Message::Ping(payload))) => return payload,
+          Some(Ok(_)) => continue,
+          msg => panic!("Expected ping, got {:?}", msg),
+        }
+      }
+    }
+
+    // Answering with the ping's payload keeps the connection alive.
+    let first_payload = next_ping(&mut client_ws).await;
+    client_ws
+      .send(Message::Pong(first_payload.clone()))
+      .await
+      .expect("Test, assuming infallible");
+    let second_payload = next_ping(&mut client_ws).await;
+    assert_ne!(first_payload, second_payload);
+    assert_eq!(hardware.stats().pongs_received(), 1);
+
+    // A pong with some other payload isn't counted, so the next ping interval finds nothing
+    // answered the last ping and drops the connection.
+    client_ws
+      .send(Message::Pong(first_payload))
+      .await
+      .expect("Test, assuming infallible");
+    timeout(Duration::from_secs(5), closed_token.cancelled())
+      .await
+      .expect("Test, assuming infallible");
+    assert_eq!(hardware.stats().pongs_received(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_empty_frame_disconnect() {
+    let mut config = WebsocketServerDeviceConnectionConfig::default();
+    config.set_empty_frame_disconnect(true);
+    let (mut connector, mut client_ws) =
+      setup_test_connector_with_config(CancellationToken::new(), config).await;
+    let closed_token = connector.connection_closed_token();
+    let hardware = connector
+      .connect()
+      .await
+      .expect("Test, assuming infallible")
+      .specialize(&[])
+      .await
+      .expect("Test, assuming infallible");
+    let mut event_receiver = hardware.event_stream();
+    client_ws
+      .send(Message::Binary(vec![]))
+      .await
+      .expect("Test, assuming infallible");
+    assert!(matches!(
+      timeout(Duration::from_secs(5), event_receiver.recv()).await,
+      Ok(Ok(HardwareEvent::Disconnected(address, DisconnectReason::ClientClose)))
+        if address == "test-address"
+    ));
+    timeout(Duration::from_secs(5), closed_token.cancelled())
+      .await
+      .expect("Test, assuming infallible");
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_drops_oversize_text_frames() {
+    let mut config = WebsocketServerDeviceConnectionConfig::default();
+    config.set_max_text_frame_size(Some(4));
+    let (mut connector, mut client_ws) =
+      setup_test_connector_with_config(CancellationToken::new(), config).await;
+    let hardware = connector
+      .connect()
+      .await
+      .expect("Test, assuming infallible")
+      .specialize(&[])
+      .await
+      .expect("Test, assuming infallible");
+    let mut event_receiver = hardware.event_stream();
+    hardware
+      .subscribe(&HardwareSubscribeCmd::new(Endpoint::Rx))
+      .await
+      .expect("Test, assuming infallible");
+    client_ws
+      .send(Message::Text("x".repeat(64 * 1024)))
+      .await
+      .expect("Test, assuming infallible");
+    // Binary frames are still held to max_frame_size only.
+    client_ws
+      .send(Message::Binary(vec![1, 2, 3, 4, 5]))
+      .await
+      .expect("Test, assuming infallible");
+    client_ws
+      .send(Message::Text("1234".to_owned()))
+      .await
+      .expect("Test, assuming infallible");
+    match event_receiver.recv().await {
+      Ok(HardwareEvent::Notification(_, _, data, _)) => assert_eq!(data, vec![1, 2, 3, 4, 5]),
+      event => panic!("Expected notification, got {:?}", event),
+    }
+    match event_receiver.recv().await {
+      Ok(HardwareEvent::Notification(_, _, data, _)) => assert_eq!(data, b"1234".to_vec()),
+      event => panic!("Expected notification, got {:?}", event),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_drops_oversize_frames() {
+    let mut config = WebsocketServerDeviceConnectionConfig::default();
+    config
+      .set_max_frame_size(4)
+      .set_max_oversize_frames(Some(1));
+    let (mut connector, mut client_ws) =
+      setup_test_connector_with_config(CancellationToken::new(), config).await;
+    let closed_token = connector.connection_closed_token();
+    let hardware = connector
+      .connect()
+      .await
+      .expect("Test, assuming infallible")
+      .specialize(&[])
+      .await
+      .expect("Test, assuming infallible");
+    let mut event_receiver = hardware.event_stream();
+    hardware
+      .subscribe(&HardwareSubscribeCmd::new(Endpoint::Rx))
+      .await
+      .expect("Test, assuming infallible");
+    client_ws
+      .send(Message::Binary(vec![0; 5]))
+      .await
+      .expect("Test, assuming infallible");
+    client_ws
+      .send(Message::Binary(vec![1, 2, 3, 4]))
+      .await
+      .expect("Test, assuming infallible");
+    // The oversize frame never shows up, only the one after it.
+    match event_receiver.recv().await {
+      Ok(HardwareEvent::Notification(_, _, data, _)) => assert_eq!(data, vec![1, 2, 3, 4]),
+      event => panic!("Expected notification, got {:?}", event),
+    }
+
+    // One more goes past the allowed count, which closes the connection.
+    client_ws
+      .send(Message::Text("12345".to_owned()))
+      .await
+      .expect("Test, assuming infallible");
+    assert!(matches!(
+      event_receiver.recv().await,
+      Ok(HardwareEvent::Disconnected(
+        _,
+        DisconnectReason::InvalidData
+      ))
+    ));
+    timeout(Duration::from_secs(5), closed_token.cancelled())
+      .await
+      .expect("Test, assuming infallible");
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_coalesced_notifications() {
+    use crate::server::device::hardware::coalescing_hardware::CoalescingHardware;
+
+    let (connector, mut client_ws) = setup_test_connector(CancellationToken::new()).await;
+    let hardware = CoalescingHardware::new(
+      Box::new(connector.create_hardware()),
+      Duration::from_millis(200),
+    );
+    let mut event_receiver = hardware.event_stream();
+    hardware
+      .subscribe(&HardwareSubscribeCmd::new(Endpoint::Rx))
+      .await
+      .expect("Test, assuming infallible");
+    let start = Instant::now();
+    for value in 1..=3 {
+      client_ws
+        .send(Message::Binary(vec![value]))
+        .await
+        .expect("Test, assuming infallible");
+    }
+    // The first notification goes out right away, the second is replaced by the third, which only
+    // goes out once the interval is up.
+    for expected in [1, 3] {
+      match event_receiver.recv().await {
+        Ok(HardwareEvent::Notification(_, Endpoint::Rx, data, _)) => {
+          assert_eq!(data, vec![expected])
+        }
+        event => panic!("Expected notification, got {:?}", event),
+      }
+    }
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    assert!(timeout(Duration::from_millis(300), event_receiver.recv())
+      .await
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_reconnect() {
+    let mut reconnect_config = WebsocketReconnectConfig::default();
+    reconnect_config
+      .set_base_delay(Duration::from_millis(100))
+      .set_max_attempts(20);
+    let mut config = WebsocketServerDeviceConnectionConfig::default();
+    config.set_reconnect(Some(reconnect_config));
+    let (mut connector, client_ws) =
+      setup_test_connector_with_config(CancellationToken::new(), config).await;
+    let reconnect_handle = connector.reconnect_handle();
+    let hardware = connector
+      .connect()
+      .await
+      .expect("Test, assuming infallible")
+      .specialize(&[])
+      .await
+      .expect("Test, assuming infallible");
+    let mut event_receiver = hardware.event_stream();
+    hardware
+      .subscribe(&HardwareSubscribeCmd::new(Endpoint::Rx))
+      .await
+      .expect("Test, assuming infallible");
+
+    // Drop the client without a close handshake, like a device losing its network.
+    drop(client_ws);
+    assert!(matches!(
+      event_receiver.recv().await,
+      Ok(HardwareEvent::Reconnecting(_))
+    ));
+
+    let (server_stream, client_stream) = duplex(4096);
+    let mut server_ws = WebSocketStream::from_raw_socket(server_stream, Role::Server, None).await;
+    let mut client_ws = WebSocketStream::from_raw_socket(client_stream, Role::Client, None).await;
+    // The connector may not be listening for the new stream quite yet.
+    loop {
+      match try_reconnect(&reconnect_handle, server_ws) {
+        Ok(()) => break,
+        Err(returned_stream) => server_ws = *returned_stream,
+      }
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(matches!(
+      event_receiver.recv().await,
+      Ok(HardwareEvent::Reconnected(_))
+    ));
+
+    // The existing hardware and its subscription carry on over the new connection.
+    client_ws
+      .send(Message::Binary(vec![7, 8, 9]))
+      .await
+      .expect("Test, assuming infallible");
+    match event_receiver.recv().await {
+      Ok(HardwareEvent::Notification(_, Endpoint::Rx, data, _)) => assert_eq!(data, vec![7, 8, 9]),
+      event => panic!("Expected notification, got {:?}", event),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_detached_disconnect() {
+    let info: WebsocketServerDeviceCommManagerInitInfo = serde_json::from_str(
+      r#"{"identifier": "test-device", "address": "test-address", "version": 0}"#,
+    )
+    .expect("Test, assuming infallible");
+    let config = WebsocketServerDeviceConnectionConfig::default();
+    let (hardware, detached) = WebsocketServerHardware::new_detached(info, &config);
+    // There's no connection loop to wait on, so this can't hang.
+    timeout(Duration::from_millis(500), hardware.disconnect())
+      .await
+      .expect("Test, assuming infallible")
+      .expect("Test, assuming infallible");
+    assert!(detached.connection.cancellation_token.is_cancelled());
+    assert!(detached.connection.closed_token.is_cancelled());
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_dedup_writes_reset_on_reconnect() {
+    let mut reconnect_config = WebsocketReconnectConfig::default();
+    reconnect_config
+      .set_base_delay(Duration::from_millis(100))
+      .set_max_attempts(20);
+    let mut config = WebsocketServerDeviceConnectionConfig::default();
+    config
+      .set_reconnect(Some(reconnect_config))
+      .set_dedup_writes(true);
+    let (mut connector, mut client_ws) =
+      setup_test_connector_with_config(CancellationToken::new(), config).await;
+    let reconnect_handle = connector.reconnect_handle();
+    let hardware = connector
+      .connect()
+      .await
+      .expect("Test, assuming infallible")
+      .specialize(&[])
+      .await
+      .expect("Test, assuming infallible");
+    let mut event_receiver = hardware.event_stream();
+    hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![1], false))
+      .await
+      .expect("Test, assuming infallible");
+    assert_eq!(
+      client_ws
+        .next()
+        .await
+        .expect("Test, assuming infallible")
+        .ok(),
+      Some(Message::Binary(vec![1]))
+    );
+
+    drop(client_ws);
+    assert!(matches!(
+      event_receiver.recv().await,
+      Ok(HardwareEvent::Reconnecting(_))
+    ));
+    let (server_stream, client_stream) = duplex(4096);
+    let mut server_ws = WebSocketStream::from_raw_socket(server_stream, Role::Server, None).await;
+    let mut client_ws = WebSocketStream::from_raw_socket(client_stream, Role::Client, None).await;
+    loop {
+      match try_reconnect(&reconnect_handle, server_ws) {
+        Ok(()) => break,
+        Err(returned_stream) => server_ws = *returned_stream,
+      }
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(matches!(
+      event_receiver.recv().await,
+      Ok(HardwareEvent::Reconnected(_))
+    ));
+
+    // The reconnected device never saw the first write, so the same write goes out again.
+    hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![1], false))
+      .await
+      .expect("Test, assuming infallible");
+    assert_eq!(
+      client_ws
+        .next()
+        .await
+        .expect("Test, assuming infallible")
+        .ok(),
+      Some(Message::Binary(vec![1]))
+    );
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_dedup_writes_reset_on_loop_exit() {
+    let info: WebsocketServerDeviceCommManagerInitInfo = serde_json::from_str(
+      r#"{"identifier": "test-device", "address": "test-address", "version": 0}"#,
+    )
+    .expect("Test, assuming infallible");
+    let mut config = WebsocketServerDeviceConnectionConfig::default();
+    config.set_dedup_writes(true);
+    let (hardware, mut detached) = WebsocketServerHardware::new_detached(info, &config);
+    hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![1], false))
+      .await
+      .expect("Test, assuming infallible");
+    assert!(detached.outgoing_receiver.recv().await.is_some());
+
+    // Run a connection that gets shut down.
+    let (server_stream, _client_stream) = duplex(4096);
+    let server_ws = WebSocketStream::from_raw_socket(server_stream, Role::Server, None).await;
+    detached.connection.cancellation_token.cancel();
+    assert_eq!(
+      run_connection_loop(
+        "test-address",
+        &detached.event_sender,
+        server_ws,
+        &mut detached.outgoing_receiver,
+        &detached.incoming_broadcaster,
+        &config,
+        &detached.connection,
+        &CancellationToken::new(),
+      )
+      .await,
+      ConnectionLoopExit::Closed
+    );
+    hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![1], false))
+      .await
+      .expect("Test, assuming infallible");
+    match detached.outgoing_receiver.recv().await {
+      Some(OutgoingMessage::Data(data)) => assert_eq!(data, vec![1]),
+      msg => panic!("Expected data, got {:?}", msg),
+    }
+  }
+
+  // Writes data through the hardware, checks it shows up at the client framed as expected, then
+  // sends the frame back and checks it comes out of the hardware unframed.
+  async fn check_framing_round_trip(framing: Arc<dyn Framing>, expected_frame: Vec<u8>) {
+    let mut config = WebsocketServerDeviceConnectionConfig::default();
+    config.set_framing(framing);
+    let (mut connector, mut client_ws) =
+      setup_test_connector_with_config(CancellationToken::new(), config).await;
+    let hardware = connector
+      .connect()
+      .await
+      .expect("Test, assuming infallible")
+      .specialize(&[])
+      .await
+      .expect("Test, assuming infallible");
+    let mut event_receiver = hardware.event_stream();
+    hardware
+      .subscribe(&HardwareSubscribeCmd::new(Endpoint::Rx))
+      .await
+      .expect("Test, assuming infallible");
+    hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![1, 2, 3], false))
+      .await
+      .expect("Test, assuming infallible");
+    let frame = match client_ws.next().await {
+      Some(Ok(Message::Binary(frame))) => frame,
+      msg => panic!("Expected binary frame, got {:?}", msg),
+    };
+    assert_eq!(frame, expected_frame);
+    client_ws
+      .send(Message::Binary(frame))
+      .await
+      .expect("Test, assuming infallible");
+    match event_receiver.recv().await {
+      Ok(HardwareEvent::Notification(_, Endpoint::Rx, data, _)) => assert_eq!(data, vec![1, 2, 3]),
+      event => panic!("Expected notification, got {:?}", event),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_no_framing() {
+    check_framing_round_trip(Arc::new(NoFraming), vec![1, 2, 3]).await;
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_hardware_length_prefixed_framing() {
+    check_framing_round_trip(Arc::new(LengthPrefixedFraming), vec![0, 0, 0, 3, 1, 2, 3]).await;
+  }
 }
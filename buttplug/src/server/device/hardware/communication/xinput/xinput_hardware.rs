@@ -12,6 +12,7 @@ use crate::{
   server::device::{
     configuration::{ProtocolCommunicationSpecifier, XInputSpecifier},
     hardware::{
+      DisconnectReason,
       GenericHardwareSpecializer,
       Hardware,
       HardwareConnector,
@@ -55,7 +56,10 @@ async fn check_gamepad_connectivity(
     if handle.get_state(index as u32).is_err() {
       info!("XInput gamepad {} has disconnected.", index);
       // If this fails, we don't care because we're exiting anyways.
-      let _ = sender.send(HardwareEvent::Disconnected(create_address(index)));
+      let _ = sender.send(HardwareEvent::Disconnected(
+        create_address(index),
+        DisconnectReason::ReadError,
+      ));
       return;
     }
     tokio::select! {
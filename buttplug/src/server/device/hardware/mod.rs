@@ -1,4 +1,7 @@
+pub mod coalescing_hardware;
 pub mod communication;
+#[cfg(any(test, feature = "testing"))]
+pub mod test_hardware;
 
 use std::{fmt::Debug, sync::Arc, time::Duration};
 
@@ -8,9 +11,10 @@ use crate::{
     message::{Endpoint, RawReadCmd, RawReading, RawSubscribeCmd, RawUnsubscribeCmd, RawWriteCmd},
   },
   server::device::configuration::ProtocolCommunicationSpecifier,
+  util::sleep,
 };
 use async_trait::async_trait;
-use futures::future::BoxFuture;
+use futures::future::{self, BoxFuture};
 use futures_util::FutureExt;
 use getset::{CopyGetters, Getters};
 use instant::Instant;
@@ -102,25 +106,63 @@ impl From<RawWriteCmd> for HardwareWriteCmd {
 /// While usually related to notify/indicate characteristics on Bluetooth LE devices, can be used
 /// with any read endpoint to signal that any information received should be automatically passed to
 /// the protocol implementation.
-#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize, CopyGetters)]
-#[getset(get_copy = "pub")]
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize, Getters, CopyGetters)]
 pub struct HardwareSubscribeCmd {
   /// Endpoint to subscribe to notifications from.
+  #[getset(get_copy = "pub")]
   endpoint: Endpoint,
+  /// If set, only notifications starting with these bytes are passed on, so protocols aren't woken
+  /// up for heartbeats and other noise. Currently only applied by the websocket server backend,
+  /// other backends fail filtered subscriptions with [ButtplugDeviceError::UnhandledCommand].
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  #[getset(get = "pub")]
+  prefix_filter: Option<Vec<u8>>,
 }
 
 impl HardwareSubscribeCmd {
   /// Create a new DeviceSubscribeCmd instance
   pub fn new(endpoint: Endpoint) -> Self {
-    Self { endpoint }
+    Self {
+      endpoint,
+      prefix_filter: None,
+    }
+  }
+
+  /// Create a new DeviceSubscribeCmd instance that only passes on notifications starting with
+  /// `prefix`.
+  pub fn new_with_prefix_filter(endpoint: Endpoint, prefix: &[u8]) -> Self {
+    Self {
+      endpoint,
+      prefix_filter: Some(prefix.to_vec()),
+    }
+  }
+
+  // For backends that don't apply prefix filters, so a filtered subscription fails instead of
+  // quietly passing everything on.
+  pub(crate) fn check_no_prefix_filter(
+    &self,
+    hardware_name: &str,
+  ) -> Result<(), ButtplugDeviceError> {
+    if self.prefix_filter.is_some() {
+      Err(ButtplugDeviceError::UnhandledCommand(format!(
+        "{} hardware does not support subscription prefix filters",
+        hardware_name
+      )))
+    } else {
+      Ok(())
+    }
   }
 }
 
+/// Turns notification data from a subscription into what gets passed on, for protocols whose devices
+/// wrap their data in simple framing. Notifications it returns an error for are dropped. See
+/// [HardwareInternal::subscribe_with_decoder].
+pub type NotificationDecoder =
+  Arc<dyn Fn(&[u8]) -> Result<Vec<u8>, ButtplugDeviceError> + Send + Sync>;
+
 impl From<RawSubscribeCmd> for HardwareSubscribeCmd {
   fn from(msg: RawSubscribeCmd) -> Self {
-    Self {
-      endpoint: msg.endpoint(),
-    }
+    Self::new(msg.endpoint())
   }
 }
 
@@ -219,13 +261,45 @@ impl From<HardwareReading> for RawReading {
   }
 }
 
+/// Why a device disconnected, carried by [HardwareEvent::Disconnected].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+  /// The device, or whatever is talking for it, ended the connection on purpose.
+  ClientClose,
+  /// Reading from the device failed, or the connection to it was lost.
+  ReadError,
+  /// Sending data to the device failed.
+  WriteError,
+  /// The device stopped answering liveness checks.
+  PingTimeout,
+  /// Nothing was received from the device for longer than it's allowed to stay quiet.
+  IdleTimeout,
+  /// We ended the connection, because the hardware was disconnected or dropped, or the connection
+  /// was shut down.
+  OwnerDropped,
+  /// We ended the connection because the device kept sending data it isn't allowed to.
+  InvalidData,
+}
+
 /// Events that can be emitted from a [Hardware](crate::device::Hardware).
 #[derive(Debug, Clone)]
 pub enum HardwareEvent {
-  /// Device received data
-  Notification(String, Endpoint, Vec<u8>),
-  /// Device disconnected
-  Disconnected(String),
+  /// Device received data. Fields are the device address, the endpoint the data came in on, the
+  /// data itself, and the device identifier when the hardware knows one (e.g. the identifier a
+  /// websocket or TCP device sent in its info packet), so subscribers listening to several devices
+  /// can tell them apart.
+  Notification(String, Endpoint, Vec<u8>, Option<String>),
+  /// Device disconnected. Fields are the device address and why it disconnected.
+  Disconnected(String, DisconnectReason),
+  /// Device connection dropped, and the hardware is waiting for the device to come back before
+  /// giving up on it
+  Reconnecting(String),
+  /// Device came back after its connection dropped
+  Reconnected(String),
+  /// A subscription fell too far behind and notifications were lost. Fields are the device
+  /// address, the subscribed endpoint, and how many notifications were dropped. Protocols that
+  /// parse a stream spread over several notifications should resynchronize.
+  NotificationsDropped(String, Endpoint, u64),
 }
 
 /// Hardware implementation and communication portion of a
@@ -284,9 +358,10 @@ impl Hardware {
     &self.address
   }
 
-  /// Returns the device endpoint list
-  pub fn endpoints(&self) -> Vec<Endpoint> {
-    self.endpoints.clone()
+  /// Returns the endpoints the device was set up with, so protocols can check what the hardware
+  /// supports instead of assuming.
+  pub fn endpoints(&self) -> &[Endpoint] {
+    &self.endpoints
   }
 
   /// Returns a receiver for any events the device may emit.
@@ -321,6 +396,16 @@ impl Hardware {
     self.internal_impl.read_value(msg)
   }
 
+  /// Read a value from the device by waiting on the next notification from an endpoint. See
+  /// [HardwareInternal::read_via_subscription].
+  pub fn read_via_subscription(
+    &self,
+    endpoint: Endpoint,
+    timeout: Duration,
+  ) -> BoxFuture<'static, Result<HardwareReading, ButtplugDeviceError>> {
+    self.internal_impl.read_via_subscription(endpoint, timeout)
+  }
+
   /// Write a value to the device
   pub fn write_value(
     &self,
@@ -339,6 +424,25 @@ impl Hardware {
     }
   }
 
+  /// Write values to the device, in order, stopping at the first that fails. See
+  /// [HardwareInternal::write_values].
+  pub fn write_values(
+    &self,
+    msgs: &[HardwareWriteCmd],
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    let write_fut = self.internal_impl.write_values(msgs);
+    if self.requires_keepalive {
+      let last_write_time = self.last_write_time.clone();
+      async move {
+        *last_write_time.write().await = Instant::now();
+        write_fut.await
+      }
+      .boxed()
+    } else {
+      write_fut
+    }
+  }
+
   /// Subscribe to a device endpoint, if it exists
   pub fn subscribe(
     &self,
@@ -347,6 +451,22 @@ impl Hardware {
     self.internal_impl.subscribe(msg)
   }
 
+  /// Subscribe to every endpoint the device was set up with. See
+  /// [HardwareInternal::subscribe_all].
+  pub fn subscribe_all(&self) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    self.internal_impl.subscribe_all(&self.endpoints)
+  }
+
+  /// Subscribe to a device endpoint, decoding each notification before it's passed on. See
+  /// [HardwareInternal::subscribe_with_decoder].
+  pub fn subscribe_with_decoder(
+    &self,
+    msg: &HardwareSubscribeCmd,
+    decoder: NotificationDecoder,
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    self.internal_impl.subscribe_with_decoder(msg, decoder)
+  }
+
   /// Unsubscribe from a device endpoint, if it exists
   pub fn unsubscribe(
     &self,
@@ -354,6 +474,12 @@ impl Hardware {
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
     self.internal_impl.unsubscribe(msg)
   }
+
+  /// Wait until all writes queued so far have been sent to the device. See
+  /// [HardwareInternal::flush].
+  pub fn flush(&self) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    self.internal_impl.flush()
+  }
 }
 
 /// Internal representation of device implementations
@@ -377,6 +503,21 @@ pub trait HardwareInternal: Sync + Send {
     &self,
     msg: &HardwareWriteCmd,
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>>;
+  /// Write several values to the device, for devices that need the same command on more than one
+  /// endpoint. Writes go out in order, and the first error is returned without attempting the rest.
+  fn write_values(
+    &self,
+    msgs: &[HardwareWriteCmd],
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    let write_futs: Vec<_> = msgs.iter().map(|msg| self.write_value(msg)).collect();
+    async move {
+      for write_fut in write_futs {
+        write_fut.await?;
+      }
+      Ok(())
+    }
+    .boxed()
+  }
   /// Subscribe to a device endpoint, if it exists
   fn subscribe(
     &self,
@@ -387,6 +528,96 @@ pub trait HardwareInternal: Sync + Send {
     &self,
     msg: &HardwareUnsubscribeCmd,
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>>;
+  /// Subscribe to each of `endpoints`, so protocols that want notifications from everything don't
+  /// have to subscribe one endpoint at a time. Subscriptions happen in order, and the first error
+  /// is returned without attempting the rest. Backends where every endpoint shares one stream of
+  /// data report each notification once per subscribed endpoint, except for TCP, which only has
+  /// the one subscription to give and so subscribes once, to the first endpoint.
+  fn subscribe_all(
+    &self,
+    endpoints: &[Endpoint],
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    let subscribe_futs: Vec<_> = endpoints
+      .iter()
+      .map(|endpoint| self.subscribe(&HardwareSubscribeCmd::new(*endpoint)))
+      .collect();
+    async move {
+      for subscribe_fut in subscribe_futs {
+        subscribe_fut.await?;
+      }
+      Ok(())
+    }
+    .boxed()
+  }
+  /// Subscribe to a device endpoint, running each notification through `decoder` before it's
+  /// passed on. Notifications that fail to decode are logged and dropped, so protocols only see
+  /// payloads they can use. Only supported by some backends, so this fails by default.
+  fn subscribe_with_decoder(
+    &self,
+    _msg: &HardwareSubscribeCmd,
+    _decoder: NotificationDecoder,
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    future::ready(Err(ButtplugDeviceError::UnhandledCommand(
+      "Hardware does not support notification decoders.".to_owned(),
+    )))
+    .boxed()
+  }
+  /// Resolves once all writes queued before the call have actually been sent to the device. Only
+  /// needed for hardware that queues writes, which is why this resolves immediately by default.
+  fn flush(&self) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    future::ready(Ok(())).boxed()
+  }
+  /// Read from an endpoint by subscribing to it, waiting for the next notification, then
+  /// unsubscribing. Gives transports that can only notify (like websocket devices) the same read
+  /// path as everything else. Should not be used on an endpoint that is already subscribed to, as it
+  /// will be unsubscribed afterward.
+  fn read_via_subscription(
+    &self,
+    endpoint: Endpoint,
+    timeout: Duration,
+  ) -> BoxFuture<'static, Result<HardwareReading, ButtplugDeviceError>> {
+    // Listen before subscribing, so we can't miss a notification that shows up right away.
+    let mut event_receiver = self.event_stream();
+    let subscribe_fut = self.subscribe(&HardwareSubscribeCmd::new(endpoint));
+    let unsubscribe_fut = self.unsubscribe(&HardwareUnsubscribeCmd::new(endpoint));
+    async move {
+      subscribe_fut.await?;
+      let wait_for_notification = async {
+        loop {
+          match event_receiver.recv().await {
+            Ok(HardwareEvent::Notification(_, notification_endpoint, data, _))
+              if notification_endpoint == endpoint =>
+            {
+              return Ok(HardwareReading::new(endpoint, &data));
+            }
+            Ok(HardwareEvent::Disconnected(..)) | Err(broadcast::error::RecvError::Closed) => {
+              return Err(ButtplugDeviceError::DeviceNotConnected(
+                "Device disconnected while waiting for a reading.".to_owned(),
+              ));
+            }
+            // Notifications for other endpoints, or missed events, aren't what we're after.
+            _ => continue,
+          }
+        }
+      };
+      let result = select! {
+        result = wait_for_notification.fuse() => result,
+        _ = sleep(timeout).fuse() => Err(ButtplugDeviceError::DeviceCommunicationError(format!(
+          "Timed out waiting for a notification on {} after {}ms.",
+          endpoint,
+          timeout.as_millis()
+        ))),
+      };
+      if let Err(err) = unsubscribe_fut.await {
+        warn!(
+          "Error unsubscribing from {} after reading: {:?}",
+          endpoint, err
+        );
+      }
+      result
+    }
+    .boxed()
+  }
 }
 
 #[async_trait]
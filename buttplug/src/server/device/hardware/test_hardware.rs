@@ -0,0 +1,275 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! In memory [HardwareInternal] implementation for tests.
+//!
+//! [TestHardware] doesn't talk to anything. It records every write it's given, hands back readings
+//! queued by the test, and passes on whatever events the test injects. This makes it possible to
+//! check a protocol's byte output, or run the command manager end to end, without a real device
+//! or socket. Clones share the same state, so a test can keep a clone around after boxing another
+//! into a [Hardware](super::Hardware).
+//!
+//! Only built for this crate's tests, or with the `testing` feature.
+
+use super::{
+  DisconnectReason,
+  HardwareEvent,
+  HardwareInternal,
+  HardwareReadCmd,
+  HardwareReading,
+  HardwareSubscribeCmd,
+  HardwareUnsubscribeCmd,
+  HardwareWriteCmd,
+};
+use crate::core::errors::ButtplugDeviceError;
+use futures::{
+  future::{self, BoxFuture},
+  FutureExt,
+};
+use std::{
+  collections::VecDeque,
+  sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+
+/// Fake hardware that records writes and replays queued readings.
+#[derive(Clone)]
+pub struct TestHardware {
+  address: String,
+  event_sender: broadcast::Sender<HardwareEvent>,
+  writes: Arc<Mutex<Vec<HardwareWriteCmd>>>,
+  readings: Arc<Mutex<VecDeque<HardwareReading>>>,
+}
+
+impl TestHardware {
+  pub fn new(address: &str) -> Self {
+    let (event_sender, _) = broadcast::channel(256);
+    Self {
+      address: address.to_owned(),
+      event_sender,
+      writes: Arc::new(Mutex::new(vec![])),
+      readings: Arc::new(Mutex::new(VecDeque::new())),
+    }
+  }
+
+  /// Every write sent to the hardware so far, oldest first.
+  pub fn writes(&self) -> Vec<HardwareWriteCmd> {
+    self.writes.lock().expect("Lock is never poisoned").clone()
+  }
+
+  /// Just the data of every write sent to the hardware so far, oldest first.
+  pub fn written_data(&self) -> Vec<Vec<u8>> {
+    self
+      .writes
+      .lock()
+      .expect("Lock is never poisoned")
+      .iter()
+      .map(|x| x.data().clone())
+      .collect()
+  }
+
+  pub fn clear_writes(&self) {
+    self.writes.lock().expect("Lock is never poisoned").clear();
+  }
+
+  /// Queue a reading to be returned by the next read_value call. Readings are returned in the
+  /// order they're queued, whatever endpoint is read from.
+  pub fn queue_reading(&self, reading: HardwareReading) {
+    self
+      .readings
+      .lock()
+      .expect("Lock is never poisoned")
+      .push_back(reading);
+  }
+
+  /// Send an event to everything listening on the hardware's event stream.
+  pub fn inject_event(&self, event: HardwareEvent) {
+    // If no one is listening, ignore output.
+    let _ = self.event_sender.send(event);
+  }
+}
+
+impl HardwareInternal for TestHardware {
+  fn event_stream(&self) -> broadcast::Receiver<HardwareEvent> {
+    self.event_sender.subscribe()
+  }
+
+  fn disconnect(&self) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    self.inject_event(HardwareEvent::Disconnected(
+      self.address.clone(),
+      DisconnectReason::OwnerDropped,
+    ));
+    future::ready(Ok(())).boxed()
+  }
+
+  fn read_value(
+    &self,
+    msg: &HardwareReadCmd,
+  ) -> BoxFuture<'static, Result<HardwareReading, ButtplugDeviceError>> {
+    let reading = self
+      .readings
+      .lock()
+      .expect("Lock is never poisoned")
+      .pop_front()
+      .ok_or_else(|| {
+        ButtplugDeviceError::DeviceCommunicationError(format!(
+          "No reading queued for read from {:?}.",
+          msg.endpoint()
+        ))
+      });
+    future::ready(reading).boxed()
+  }
+
+  fn write_value(
+    &self,
+    msg: &HardwareWriteCmd,
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    self
+      .writes
+      .lock()
+      .expect("Lock is never poisoned")
+      .push(msg.clone());
+    future::ready(Ok(())).boxed()
+  }
+
+  fn subscribe(
+    &self,
+    _msg: &HardwareSubscribeCmd,
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    future::ready(Ok(())).boxed()
+  }
+
+  fn unsubscribe(
+    &self,
+    _msg: &HardwareUnsubscribeCmd,
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    future::ready(Ok(())).boxed()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::TestHardware;
+  use crate::{
+    core::message::{ActuatorType, Endpoint, ScalarCmd, ScalarSubcommand},
+    server::device::{
+      configuration::{ProtocolDeviceAttributes, ServerDeviceMessageAttributesBuilder},
+      hardware::{
+        DisconnectReason,
+        Hardware,
+        HardwareEvent,
+        HardwareReadCmd,
+        HardwareReading,
+        HardwareWriteCmd,
+      },
+      protocol::{aneros::Aneros, generic_command_manager::GenericCommandManager, ProtocolHandler},
+    },
+  };
+
+  #[tokio::test]
+  async fn test_test_hardware_protocol_output() {
+    let test_hardware = TestHardware::new("test-address");
+    let hardware = Hardware::new(
+      "Test",
+      "test-address",
+      &[Endpoint::Tx],
+      Box::new(test_hardware.clone()),
+    );
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 127)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Aneros", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let commands = mgr
+      .update_scalar(
+        &ScalarCmd::new(
+          0,
+          vec![
+            ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
+            ScalarSubcommand::new(1, 1.0, ActuatorType::Vibrate),
+          ],
+        ),
+        false,
+      )
+      .expect("Test, assuming infallible");
+    for command in Aneros::default()
+      .handle_scalar_cmd(&commands)
+      .expect("Test, assuming infallible")
+    {
+      hardware
+        .parse_message(&command)
+        .await
+        .expect("Test, assuming infallible");
+    }
+    assert_eq!(
+      test_hardware.written_data(),
+      vec![vec![0xF1, 64], vec![0xF2, 127]]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_test_hardware_write_values() {
+    let test_hardware = TestHardware::new("test-address");
+    let hardware = Hardware::new(
+      "Test",
+      "test-address",
+      &[Endpoint::Tx, Endpoint::Firmware],
+      Box::new(test_hardware.clone()),
+    );
+    let writes = vec![
+      HardwareWriteCmd::new(Endpoint::Tx, vec![1, 2], false),
+      HardwareWriteCmd::new(Endpoint::Firmware, vec![1, 2], true),
+    ];
+    hardware
+      .write_values(&writes)
+      .await
+      .expect("Test, assuming infallible");
+    assert_eq!(test_hardware.writes(), writes);
+  }
+
+  #[tokio::test]
+  async fn test_test_hardware_readings_and_events() {
+    let test_hardware = TestHardware::new("test-address");
+    let hardware = Hardware::new(
+      "Test",
+      "test-address",
+      &[Endpoint::Rx],
+      Box::new(test_hardware.clone()),
+    );
+    assert!(hardware
+      .read_value(&HardwareReadCmd::new(Endpoint::Rx, 1, 0))
+      .await
+      .is_err());
+    test_hardware.queue_reading(HardwareReading::new(Endpoint::Rx, &[1, 2]));
+    let reading = hardware
+      .read_value(&HardwareReadCmd::new(Endpoint::Rx, 2, 0))
+      .await
+      .expect("Test, assuming infallible");
+    assert_eq!(reading.data(), &vec![1, 2]);
+
+    let mut event_receiver = hardware.event_stream();
+    test_hardware.inject_event(HardwareEvent::Notification(
+      "test-address".to_owned(),
+      Endpoint::Rx,
+      vec![3],
+      None,
+    ));
+    assert!(matches!(
+      event_receiver.recv().await,
+      Ok(HardwareEvent::Notification(_, Endpoint::Rx, data, None)) if data == vec![3]
+    ));
+    hardware
+      .disconnect()
+      .await
+      .expect("Test, assuming infallible");
+    assert!(matches!(
+      event_receiver.recv().await,
+      Ok(HardwareEvent::Disconnected(address, DisconnectReason::OwnerDropped))
+        if address == "test-address"
+    ));
+  }
+}
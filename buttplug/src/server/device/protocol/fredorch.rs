@@ -122,7 +122,7 @@ impl ProtocolInitializer for FredorchInitializer {
     // expect 0, 1, 0, 1, 1 on connect
     select! {
       event = event_receiver.recv().fuse() => {
-        if let Ok(HardwareEvent::Notification(_, _, n)) = event {
+        if let Ok(HardwareEvent::Notification(_, _, n, _)) = event {
           debug!("Fredorch: wake up - received {:?}", n);
         } else {
           return Err(
@@ -149,7 +149,7 @@ impl ProtocolInitializer for FredorchInitializer {
 
       select! {
         event = event_receiver.recv().fuse() => {
-          if let Ok(HardwareEvent::Notification(_, _, n)) = event {
+          if let Ok(HardwareEvent::Notification(_, _, n, _)) = event {
             debug!("Fredorch: {} - received {:?}", data.0, n);
           } else {
             return Err(
@@ -81,7 +81,7 @@ impl ProtocolInitializer for FredorchRotaryInitializer {
 
       select! {
         event = event_receiver.recv().fuse() => {
-          if let Ok(HardwareEvent::Notification(_, _, n)) = event {
+          if let Ok(HardwareEvent::Notification(_, _, n, _)) = event {
             debug!("FredorchRotary: {} - received {:?}", data.0, n);
           } else {
             return Err(
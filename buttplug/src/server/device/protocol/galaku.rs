@@ -175,7 +175,7 @@ impl ProtocolHandler for Galaku {
         .await?;
       while let Ok(event) = device_notification_receiver.recv().await {
         return match event {
-          HardwareEvent::Notification(_, endpoint, data) => {
+          HardwareEvent::Notification(_, endpoint, data, _) => {
             if endpoint != Endpoint::RxBLEBattery {
               continue;
             }
@@ -187,10 +187,13 @@ impl ProtocolHandler for Galaku {
             );
             Ok(battery_reading.into())
           }
-          HardwareEvent::Disconnected(_) => Err(ButtplugDeviceError::ProtocolSpecificError(
+          HardwareEvent::Disconnected(..) => Err(ButtplugDeviceError::ProtocolSpecificError(
             "Galaku".to_owned(),
             "Galaku Device disconnected while getting Battery info.".to_owned(),
           )),
+          HardwareEvent::Reconnecting(_)
+          | HardwareEvent::Reconnected(_)
+          | HardwareEvent::NotificationsDropped(..) => continue,
         };
       }
       Err(ButtplugDeviceError::ProtocolSpecificError(
@@ -11,20 +11,147 @@ use crate::{
     message::{
       ActuatorType,
       ButtplugDeviceCommandMessageUnion,
+      ButtplugDeviceMessage,
+      ButtplugDeviceMessageType,
       LinearCmd,
       RotateCmd,
       RotationSubcommand,
       ScalarCmd,
       ScalarSubcommand,
+      SensorType,
+      VectorSubcommand,
+      VibrateCmd,
+      VibrateSubcommand,
     },
   },
-  server::device::configuration::{ProtocolDeviceAttributes, ServerGenericDeviceMessageAttributes},
+  server::device::{
+    configuration::{
+      ProtocolDeviceAttributes,
+      ServerDeviceMessageAttributes,
+      ServerGenericDeviceMessageAttributes,
+    },
+    hardware::HardwareEvent,
+  },
+  util::sleep,
 };
 use getset::Getters;
+use instant::Instant;
+use serde::{Deserialize, Serialize};
 use std::{
+  collections::VecDeque,
+  fmt::{self, Debug},
   ops::RangeInclusive,
-  sync::atomic::{AtomicBool, AtomicU32, Ordering::SeqCst},
+  sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering::SeqCst},
+    Mutex,
+  },
+  time::Duration,
 };
+use tokio::sync::broadcast;
+
+// Longest move we'll pass on to a linear actuator, in milliseconds, unless configured otherwise.
+const DEFAULT_MAX_LINEAR_DURATION_MS: u32 = 60000;
+// Stroke durations used when emulating scalar speeds on linear actuators, in milliseconds. The
+// slowest speed above 0.0 takes the longest, full speed takes the shortest.
+const EMULATED_STROKE_MAX_DURATION_MS: u32 = 1000;
+const EMULATED_STROKE_MIN_DURATION_MS: u32 = 200;
+// How many queued vibration commands are kept, unless configured otherwise.
+const DEFAULT_VIBRATION_QUEUE_DEPTH: usize = 16;
+
+/// How float command values are turned into integer device steps.
+///
+/// The default is [Ceil](RoundingMode::Ceil), not [Round](RoundingMode::Round). Speeds have always
+/// been rounded up, and the expected output in the protocol tests (along with what users are used
+/// to from their devices) depends on it, so switching the default would change the bytes sent to
+/// every device. Managers that want round to nearest can ask for it through
+/// [GenericCommandManagerBuilder::rounding_mode] or
+/// [GenericCommandManager::set_rounding_mode].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+  Floor,
+  Round,
+  // When calculating speeds, round up by default. This follows how we calculated things in
+  // buttplug-js and buttplug-csharp, so it's more for history than anything, but it's what users
+  // will expect.
+  #[default]
+  Ceil,
+}
+
+impl RoundingMode {
+  fn apply(&self, value: f64) -> u32 {
+    match self {
+      RoundingMode::Floor => value.floor() as u32,
+      RoundingMode::Round => value.round() as u32,
+      RoundingMode::Ceil => value.ceil() as u32,
+    }
+  }
+}
+
+// Scalar updates held back by update_scalar_coalesced. Windows are numbered, so the call waiting to
+// close one can tell whether it's still the one to do it.
+#[derive(Default)]
+struct CoalesceState {
+  window_count: u64,
+  open_window: Option<CoalesceWindow>,
+}
+
+struct CoalesceWindow {
+  id: u64,
+  device_index: u32,
+  closes_at: Instant,
+  values: Vec<Option<ScalarSubcommand>>,
+}
+
+// Minimum time between commands emitted for one message type. Commands that come in sooner are
+// still stored, but held back until the interval is up.
+struct RateLimit {
+  min_interval: Duration,
+  last_emitted: Mutex<Option<Instant>>,
+  // Set when something was held back, so the next command that goes out carries every actuator's
+  // latest value instead of only what changed in that command.
+  held_back: AtomicBool,
+}
+
+impl RateLimit {
+  fn new(min_interval: Duration) -> Self {
+    Self {
+      min_interval,
+      last_emitted: Mutex::new(None),
+      held_back: AtomicBool::new(false),
+    }
+  }
+
+  // Returns None if a result should be held back. Otherwise returns whether anything was held back
+  // since the last emitted command, in which case every actuator needs to be sent.
+  fn check(&self, has_changes: bool) -> Option<bool> {
+    let mut last_emitted = self
+      .last_emitted
+      .lock()
+      .expect("Lock is never held across a panic");
+    let held_back = self.held_back.load(SeqCst);
+    if !has_changes && !held_back {
+      return Some(false);
+    }
+    if last_emitted.is_some_and(|last| last.elapsed() < self.min_interval) {
+      if has_changes {
+        self.held_back.store(true, SeqCst);
+      }
+      return None;
+    }
+    *last_emitted = Some(Instant::now());
+    self.held_back.store(false, SeqCst);
+    Some(held_back)
+  }
+
+  // Forget everything emitted or held back, so the next command goes straight out.
+  fn clear(&self) {
+    *self
+      .last_emitted
+      .lock()
+      .expect("Lock is never held across a panic") = None;
+    self.held_back.store(false, SeqCst);
+  }
+}
 
 #[derive(Getters)]
 #[getset(get = "pub")]
@@ -32,6 +159,7 @@ struct ScalarGenericCommand {
   actuator: ActuatorType,
   step_range: RangeInclusive<u32>,
   value: AtomicU32,
+  enabled: bool,
 }
 
 impl ScalarGenericCommand {
@@ -40,10 +168,56 @@ impl ScalarGenericCommand {
       actuator: *attributes.actuator_type(),
       step_range: attributes.step_limit().clone(),
       value: AtomicU32::new(0),
+      enabled: true,
     }
   }
 }
 
+/// The last command values a [GenericCommandManager] sent, which it uses to decide what needs to be
+/// sent next. Can be saved and restored, so a resumed session doesn't resend commands the device
+/// is already running.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Getters)]
+#[getset(get = "pub")]
+pub struct CommandManagerState {
+  sent_scalar: bool,
+  sent_rotation: bool,
+  sent_linear: bool,
+  /// Scalar steps, by feature index.
+  scalars: Vec<u32>,
+  /// Rotation speed steps and clockwise flags, by feature index.
+  rotations: Vec<(u32, bool)>,
+  /// Linear durations and position steps, by feature index.
+  linears: Vec<(u32, u32)>,
+}
+
+/// Vibration commands that step from the current speeds to a target, built by
+/// [GenericCommandManager::update_vibration_ramp]. Each command is paired with how long after the
+/// start of the ramp it should be sent.
+#[derive(Debug, Clone, Default, PartialEq, Getters)]
+#[getset(get = "pub")]
+pub struct RampPlan {
+  commands: Vec<(Duration, VibrateCmd)>,
+}
+
+impl RampPlan {
+  pub fn len(&self) -> usize {
+    self.commands.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.commands.is_empty()
+  }
+}
+
+impl IntoIterator for RampPlan {
+  type Item = (Duration, VibrateCmd);
+  type IntoIter = std::vec::IntoIter<(Duration, VibrateCmd)>;
+
+  fn into_iter(self) -> Self::IntoIter {
+    self.commands.into_iter()
+  }
+}
+
 // In order to make our lives easier, we make some assumptions about what's internally mutable in
 // the GenericCommandManager (GCM). Once the GCM is configured for a device, it won't change sizes,
 // because we don't support things like adding motors to devices randomly while Buttplug is running.
@@ -55,552 +229,5810 @@ impl ScalarGenericCommand {
 pub struct GenericCommandManager {
   sent_scalar: AtomicBool,
   sent_rotation: AtomicBool,
-  _sent_linear: bool,
+  sent_linear: AtomicBool,
   scalars: Vec<ScalarGenericCommand>,
   rotations: Vec<(AtomicU32, AtomicBool)>,
   rotation_step_ranges: Vec<RangeInclusive<u32>>,
-  _linears: Vec<(u32, u32)>,
-  _linear_step_counts: Vec<u32>,
+  rotation_enabled: Vec<bool>,
+  linears: Vec<(AtomicU32, AtomicU32)>,
+  linear_step_ranges: Vec<RangeInclusive<u32>>,
   stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  rounding_mode: RoundingMode,
+  preserve_nonzero_speed: bool,
+  max_linear_duration: u32,
+  command_keepalive: Option<Duration>,
+  last_command_instant: Mutex<Option<Instant>>,
+  inactivity_timeout: Option<Duration>,
+  last_update_instant: Mutex<Option<Instant>>,
+  vibration_queue: Mutex<VecDeque<VibrateCmd>>,
+  vibration_queue_depth: usize,
+  intensity_cap: f64,
+  max_step_delta: Option<u32>,
+  coalesce_window: Option<Duration>,
+  pending_scalars: Mutex<CoalesceState>,
+  linear_scalar_emulation: bool,
+  sensor_readings: Mutex<Vec<(SensorType, i32)>>,
+  scalar_rate_limit: Option<RateLimit>,
+  rotation_rate_limit: Option<RateLimit>,
+  linear_rate_limit: Option<RateLimit>,
+  dedup_enabled: bool,
+  stop_clockwise: bool,
+  speed_curve: f64,
+  step_inclusive: bool,
+  device_label: Option<String>,
 }
 
-impl GenericCommandManager {
-  pub fn new(attributes: &ProtocolDeviceAttributes) -> Self {
+// Shows what the manager last sent, with each actuator's step range for context, for
+// troubleshooting what a device is being told to do.
+impl Debug for GenericCommandManager {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let scalars: Vec<_> = self
+      .scalars
+      .iter()
+      .map(|x| {
+        (
+          *x.actuator(),
+          x.value().load(SeqCst),
+          x.step_range().clone(),
+        )
+      })
+      .collect();
+    let rotations: Vec<_> = self
+      .rotations
+      .iter()
+      .zip(self.rotation_step_ranges.iter())
+      .map(|((speed, clockwise), range)| {
+        (speed.load(SeqCst), clockwise.load(SeqCst), range.clone())
+      })
+      .collect();
+    let linears: Vec<_> = self
+      .linears
+      .iter()
+      .zip(self.linear_step_ranges.iter())
+      .map(|((duration, position), range)| {
+        (duration.load(SeqCst), position.load(SeqCst), range.clone())
+      })
+      .collect();
+    f.debug_struct("GenericCommandManager")
+      .field("device_label", &self.device_label)
+      .field("sent_scalar", &self.sent_scalar.load(SeqCst))
+      .field("sent_rotation", &self.sent_rotation.load(SeqCst))
+      .field("sent_linear", &self.sent_linear.load(SeqCst))
+      .field("scalars", &scalars)
+      .field("rotations", &rotations)
+      .field("linears", &linears)
+      .field("stop_command_count", &self.stop_commands.len())
+      .finish()
+  }
+}
+
+/// A scalar step from [GenericCommandManager::update_scalar_detailed], along with the 0.0-1.0 value
+/// the device will actually run at for it.
+pub type DetailedScalarStep = (ActuatorType, u32, f64);
+
+/// Output of [GenericCommandManager::update], by the kind of command that was applied. Each holds
+/// the same vec the matching update method returns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GenericCommandResult {
+  Scalar(Vec<Option<(ActuatorType, u32)>>),
+  Rotate(Vec<Option<(u32, bool)>>),
+  Linear(Vec<Option<(u32, u32)>>),
+}
+
+// Prefixes a device label to an error's message, so logs say which device it came from. Errors
+// without a message of their own become ProtocolRequirementErrors to make room for it.
+fn label_device_error(label: &Option<String>, err: ButtplugDeviceError) -> ButtplugDeviceError {
+  let Some(label) = label else {
+    return err;
+  };
+  match err {
+    ButtplugDeviceError::ProtocolRequirementError(msg) => {
+      ButtplugDeviceError::ProtocolRequirementError(format!("{}: {}", label, msg))
+    }
+    ButtplugDeviceError::DeviceConfigurationError(msg) => {
+      ButtplugDeviceError::DeviceConfigurationError(format!("{}: {}", label, msg))
+    }
+    ButtplugDeviceError::UnhandledCommand(msg) => {
+      ButtplugDeviceError::UnhandledCommand(format!("{}: {}", label, msg))
+    }
+    err => ButtplugDeviceError::ProtocolRequirementError(format!("{}: {}", label, err)),
+  }
+}
+
+/// Merge user overrides into a base device configuration, for building the attributes handed to a
+/// [GenericCommandManager]. Anything set in `overrides` wins, per message type, as with
+/// [ServerDeviceMessageAttributes::merge]. Overrides can change actuator step ranges but not how
+/// many actuators a device has, so a ScalarCmd, RotateCmd, or LinearCmd override with a different
+/// feature count than the base is an error.
+pub fn merge_attributes(
+  base: &ServerDeviceMessageAttributes,
+  overrides: &ServerDeviceMessageAttributes,
+) -> Result<ServerDeviceMessageAttributes, ButtplugError> {
+  for (msg_type, base_attrs, override_attrs) in [
+    (
+      ButtplugDeviceMessageType::ScalarCmd,
+      base.scalar_cmd(),
+      overrides.scalar_cmd(),
+    ),
+    (
+      ButtplugDeviceMessageType::RotateCmd,
+      base.rotate_cmd(),
+      overrides.rotate_cmd(),
+    ),
+    (
+      ButtplugDeviceMessageType::LinearCmd,
+      base.linear_cmd(),
+      overrides.linear_cmd(),
+    ),
+  ] {
+    if let (Some(base_attrs), Some(override_attrs)) = (base_attrs, override_attrs) {
+      if base_attrs.len() != override_attrs.len() {
+        return Err(
+          ButtplugDeviceError::DeviceConfigurationError(format!(
+            "{} override has {} features, but the device has {}.",
+            msg_type,
+            override_attrs.len(),
+            base_attrs.len()
+          ))
+          .into(),
+        );
+      }
+    }
+  }
+  Ok(base.merge(overrides))
+}
+
+/// Configures and creates [GenericCommandManager] instances.
+pub struct GenericCommandManagerBuilder<'a> {
+  attributes: &'a ProtocolDeviceAttributes,
+  /// If true, step ranges that only contain a single step are rejected along with inverted ones.
+  strict_step_counts: bool,
+  /// If true, configurations without any scalar, rotation, or linear actuators are rejected.
+  require_actuators: bool,
+  rounding_mode: RoundingMode,
+  min_nonzero_step: bool,
+  max_linear_duration: u32,
+  command_keepalive: Option<Duration>,
+  intensity_cap: f64,
+  step_inclusive: bool,
+  device_label: Option<String>,
+}
+
+impl<'a> GenericCommandManagerBuilder<'a> {
+  pub fn new(attributes: &'a ProtocolDeviceAttributes) -> Self {
+    Self {
+      attributes,
+      strict_step_counts: false,
+      require_actuators: false,
+      rounding_mode: RoundingMode::default(),
+      min_nonzero_step: false,
+      max_linear_duration: DEFAULT_MAX_LINEAR_DURATION_MS,
+      command_keepalive: None,
+      intensity_cap: 1.0,
+      step_inclusive: false,
+      device_label: None,
+    }
+  }
+
+  /// Reject feature configurations where a step range has no room for more than one step, instead
+  /// of only rejecting ranges that can't be converted at all.
+  pub fn strict_step_counts(&mut self, strict_step_counts: bool) -> &mut Self {
+    self.strict_step_counts = strict_step_counts;
+    self
+  }
+
+  /// Reject configurations that have nothing for the manager to drive, so devices that only have
+  /// sensors (or a broken configuration) fail at setup instead of on their first command. See
+  /// [GenericCommandManager::has_any_actuator].
+  pub fn require_actuators(&mut self, require_actuators: bool) -> &mut Self {
+    self.require_actuators = require_actuators;
+    self
+  }
+
+  pub fn rounding_mode(&mut self, rounding_mode: RoundingMode) -> &mut Self {
+    self.rounding_mode = rounding_mode;
+    self
+  }
+
+  /// See [GenericCommandManager::set_preserve_nonzero_speed].
+  pub fn min_nonzero_step(&mut self, min_nonzero_step: bool) -> &mut Self {
+    self.min_nonzero_step = min_nonzero_step;
+    self
+  }
+
+  /// See [GenericCommandManager::set_max_linear_duration].
+  pub fn max_linear_duration(&mut self, max_linear_duration: u32) -> &mut Self {
+    self.max_linear_duration = max_linear_duration;
+    self
+  }
+
+  /// See [GenericCommandManager::set_intensity_cap].
+  pub fn intensity_cap(&mut self, intensity_cap: f64) -> &mut Self {
+    self.intensity_cap = intensity_cap.clamp(0.0, 1.0);
+    self
+  }
+
+  /// See [GenericCommandManager::set_command_keepalive].
+  pub fn command_keepalive(&mut self, command_keepalive: Duration) -> &mut Self {
+    self.command_keepalive = Some(command_keepalive);
+    self
+  }
+
+  /// See [GenericCommandManager::set_step_inclusive].
+  pub fn step_inclusive(&mut self, step_inclusive: bool) -> &mut Self {
+    self.step_inclusive = step_inclusive;
+    self
+  }
+
+  /// See [GenericCommandManager::set_device_label].
+  pub fn device_label(&mut self, device_label: &str) -> &mut Self {
+    self.device_label = Some(device_label.to_owned());
+    self
+  }
+
+  /// Try to build a [GenericCommandManager] using the parameters given.
+  pub fn finish(&self) -> Result<GenericCommandManager, ButtplugDeviceError> {
+    let attributes = self.attributes;
+    // Every conversion assumes a step range is ordered, otherwise subtracting its bounds will
+    // underflow the first time we get a command. Catch bad configurations here instead.
+    let check_ranges = |msg_type: &str, attrs: &[ServerGenericDeviceMessageAttributes]| {
+      for (index, attr) in attrs.iter().enumerate() {
+        for range in [attr.step_range(), attr.step_limit()] {
+          if range.start() > range.end() {
+            return Err(label_device_error(
+              &self.device_label,
+              ButtplugDeviceError::DeviceConfigurationError(format!(
+                "{} feature {} has an invalid step range {:?}.",
+                msg_type, index, range
+              )),
+            ));
+          }
+          if self.strict_step_counts && range.start() == range.end() {
+            return Err(label_device_error(
+              &self.device_label,
+              ButtplugDeviceError::DeviceConfigurationError(format!(
+                "{} feature {} has a step range {:?} with only one step.",
+                msg_type, index, range
+              )),
+            ));
+          }
+        }
+      }
+      Ok(())
+    };
+
     let mut scalars = vec![];
     let mut rotations = vec![];
     let mut rotation_step_ranges = vec![];
     let mut linears = vec![];
-    let mut linear_step_counts = vec![];
-
-    let mut stop_commands = vec![];
+    let mut linear_step_ranges = vec![];
 
     if let Some(attrs) = attributes.message_attributes().scalar_cmd() {
-      let mut subcommands = vec![];
-      for (index, attr) in attrs.iter().enumerate() {
+      check_ranges("ScalarCmd", attrs)?;
+      for attr in attrs {
         scalars.push(ScalarGenericCommand::new(attr));
-        subcommands.push(ScalarSubcommand::new(
-          index as u32,
-          0.0,
-          *attr.actuator_type(),
-        ));
       }
-
-      stop_commands.push(ScalarCmd::new(0, subcommands).into());
     }
     if let Some(attrs) = attributes.message_attributes().rotate_cmd() {
+      check_ranges("RotateCmd", attrs)?;
       rotations.resize_with(attrs.len(), || (AtomicU32::new(0), AtomicBool::new(false)));
       for attr in attrs {
         rotation_step_ranges.push(attr.step_range().clone());
       }
-
-      // TODO Can we assume clockwise is false here? We might send extra
-      // messages on Lovense since it'll require both a speed and change
-      // direction command, but is that really a big deal? We can just
-      // have it ignore the direction difference on a 0.0 speed?
-      let mut subcommands = vec![];
-      for i in 0..rotations.len() {
-        subcommands.push(RotationSubcommand::new(i as u32, 0.0, false));
-      }
-      stop_commands.push(RotateCmd::new(0, subcommands).into());
     }
     if let Some(attrs) = attributes.message_attributes().linear_cmd() {
-      linears = vec![(0, 0); attrs.len()];
+      check_ranges("LinearCmd", attrs)?;
+      linears.resize_with(attrs.len(), || (AtomicU32::new(0), AtomicU32::new(0)));
       for attr in attrs {
-        linear_step_counts.push(attr.step_count());
+        linear_step_ranges.push(attr.step_limit().clone());
       }
     }
 
-    Self {
+    if self.require_actuators && scalars.is_empty() && rotations.is_empty() && linears.is_empty() {
+      return Err(label_device_error(
+        &self.device_label,
+        ButtplugDeviceError::DeviceConfigurationError(
+          "Device has no scalar, rotation, or linear actuators to command.".to_owned(),
+        ),
+      ));
+    }
+
+    let mut mgr = GenericCommandManager {
       sent_scalar: AtomicBool::new(false),
       sent_rotation: AtomicBool::new(false),
-      _sent_linear: false,
+      sent_linear: AtomicBool::new(false),
       scalars,
       rotations,
-      _linears: linears,
+      linears,
+      rotation_enabled: vec![true; rotation_step_ranges.len()],
       rotation_step_ranges,
-      _linear_step_counts: linear_step_counts,
-      stop_commands,
-    }
+      linear_step_ranges,
+      stop_commands: vec![],
+      rounding_mode: self.rounding_mode,
+      preserve_nonzero_speed: self.min_nonzero_step,
+      max_linear_duration: self.max_linear_duration,
+      command_keepalive: self.command_keepalive,
+      last_command_instant: Mutex::new(None),
+      inactivity_timeout: None,
+      last_update_instant: Mutex::new(None),
+      vibration_queue: Mutex::new(VecDeque::new()),
+      vibration_queue_depth: DEFAULT_VIBRATION_QUEUE_DEPTH,
+      intensity_cap: self.intensity_cap,
+      max_step_delta: None,
+      coalesce_window: None,
+      pending_scalars: Mutex::new(CoalesceState::default()),
+      linear_scalar_emulation: false,
+      sensor_readings: Mutex::new(vec![]),
+      scalar_rate_limit: None,
+      rotation_rate_limit: None,
+      linear_rate_limit: None,
+      dedup_enabled: true,
+      stop_clockwise: false,
+      speed_curve: 1.0,
+      step_inclusive: self.step_inclusive,
+      device_label: self.device_label.clone(),
+    };
+    mgr.rebuild_stop_commands();
+    Ok(mgr)
   }
+}
 
-  pub fn update_scalar(
-    &self,
-    msg: &ScalarCmd,
-    match_all: bool,
-  ) -> Result<Vec<Option<(ActuatorType, u32)>>, ButtplugError> {
-    // First, make sure this is a valid command, that contains at least one
-    // subcommand.
-    if msg.scalars().is_empty() {
-      return Err(
-        ButtplugDeviceError::ProtocolRequirementError(
-          "ScalarCmd has 0 commands, will not do anything.".to_owned(),
-        )
-        .into(),
-      );
-    }
+impl GenericCommandManager {
+  pub fn new(attributes: &ProtocolDeviceAttributes) -> Result<Self, ButtplugDeviceError> {
+    GenericCommandManagerBuilder::new(attributes).finish()
+  }
 
-    // Now we convert from the generic 0.0-1.0 range to the StepCount
-    // attribute given by the device config.
+  pub fn rounding_mode(&self) -> RoundingMode {
+    self.rounding_mode
+  }
 
-    // If we've already sent commands before, we should check against our
-    // old values. Otherwise, we should always send whatever command we're
-    // going to send.
-    let mut result: Vec<Option<(ActuatorType, u32)>> = vec![None; self.scalars.len()];
+  pub fn set_rounding_mode(&mut self, rounding_mode: RoundingMode) {
+    self.rounding_mode = rounding_mode;
+  }
 
-    for scalar_command in msg.scalars() {
-      let index = scalar_command.index() as usize;
-      // Since we're going to iterate here anyways, we do our index check
-      // here instead of in a filter above.
-      if index >= self.scalars.len() {
-        return Err(
-          ButtplugDeviceError::ProtocolRequirementError(format!(
-            "ScalarCmd has {} commands, device has {} features.",
-            msg.scalars().len(),
-            self.scalars.len()
-          ))
-          .into(),
-        );
-      }
+  pub fn preserve_nonzero_speed(&self) -> bool {
+    self.preserve_nonzero_speed
+  }
 
-      let range_start = self.scalars[index].step_range().start();
-      let range = self.scalars[index].step_range().end() - range_start;
-      let scalar_modifier = scalar_command.scalar() * range as f64;
-      let scalar = if scalar_modifier < 0.0001 {
-        0
-      } else {
-        // When calculating speeds, round up. This follows how we calculated
-        // things in buttplug-js and buttplug-csharp, so it's more for history
-        // than anything, but it's what users will expect.
-        (scalar_modifier + *range_start as f64).ceil() as u32
-      };
-      trace!(
-        "{:?} {} {} {}",
-        self.scalars[index].step_range(),
-        range,
-        scalar_modifier,
-        scalar
-      );
-      // If we've already sent commands, we don't want to send them again,
-      // because some of our communication busses are REALLY slow. Make sure
-      // these values get None in our return vector.
-      let current_scalar = self.scalars[index].value().load(SeqCst);
-      let sent_scalar = self.sent_scalar.load(SeqCst);
-      if !sent_scalar || scalar != current_scalar {
-        self.scalars[index].value().store(scalar, SeqCst);
-        result[index] = Some((*self.scalars[index].actuator(), scalar));
-      }
+  /// If set, any scalar or rotation speed above 0.0 will map to at least step 1 (or the bottom of
+  /// the step range, if that's higher), so very slow commands don't turn into stop commands. 0.0
+  /// still maps to 0.
+  pub fn set_preserve_nonzero_speed(&mut self, preserve_nonzero_speed: bool) {
+    self.preserve_nonzero_speed = preserve_nonzero_speed;
+  }
 
-      if !sent_scalar {
-        self.sent_scalar.store(true, SeqCst);
-      }
-    }
+  pub fn max_linear_duration(&self) -> u32 {
+    self.max_linear_duration
+  }
 
-    // If we have no changes to the device, just send back an empty command array. We have nothing
-    // to do.
-    if result.iter().all(|x| x.is_none()) {
-      result.clear();
-    } else if match_all {
-      // If we're in a match all situation, set up the array with all prior
-      // values before switching them out.
-      for (index, cmd) in self.scalars.iter().enumerate() {
-        if result[index].is_none() {
-          result[index] = Some((*cmd.actuator(), cmd.value.load(SeqCst)));
-        }
-      }
-    }
+  /// Longest duration, in milliseconds, passed on for a linear move. Longer moves are clamped to
+  /// this. Defaults to 60 seconds.
+  pub fn set_max_linear_duration(&mut self, max_linear_duration: u32) {
+    self.max_linear_duration = max_linear_duration;
+  }
 
-    // Return the command vector for the protocol to turn into proprietary commands
-    Ok(result)
+  pub fn intensity_cap(&self) -> f64 {
+    self.intensity_cap
   }
 
-  // Test method
-  #[cfg(test)]
-  pub(super) fn scalars(&self) -> Vec<Option<(ActuatorType, u32)>> {
-    self
-      .scalars
-      .iter()
-      .map(|x| Some((*x.actuator(), x.value().load(SeqCst))))
-      .collect()
+  /// Scale every scalar and rotation speed by `cap` (0.0-1.0, clamped) before it's converted to a
+  /// step, so no command goes over that fraction of the device's range. A 1.0 request reaches
+  /// exactly the capped step. Linear positions aren't affected. Defaults to 1.0.
+  pub fn set_intensity_cap(&mut self, cap: f64) {
+    self.intensity_cap = cap.clamp(0.0, 1.0);
   }
 
-  pub fn update_rotation(
-    &self,
-    msg: &RotateCmd,
-    match_all: bool,
-  ) -> Result<Vec<Option<(u32, bool)>>, ButtplugError> {
-    // First, make sure this is a valid command, that contains at least one
-    // command.
-    if msg.rotations().is_empty() {
-      return Err(
-        ButtplugDeviceError::ProtocolRequirementError(
-          "RotateCmd has 0 commands, will not do anything.".to_owned(),
-        )
-        .into(),
+  pub fn speed_curve(&self) -> f64 {
+    self.speed_curve
+  }
+
+  /// Raise every scalar and rotation speed to the power of `gamma` before it's converted to a step.
+  /// Below 1.0 gives more resolution at the low end, where many motors barely move, and above 1.0
+  /// gives less. Applied before the intensity cap. Gammas that aren't positive and finite are
+  /// ignored, leaving the curve linear. Defaults to 1.0.
+  pub fn set_speed_curve(&mut self, gamma: f64) {
+    if gamma > 0.0 && gamma.is_finite() {
+      self.speed_curve = gamma;
+    } else {
+      warn!(
+        "Speed curve gamma {} is invalid, using a linear curve.",
+        gamma
       );
+      self.speed_curve = 1.0;
     }
+  }
 
-    // Now we convert from the generic 0.0-1.0 range to the StepCount
-    // attribute given by the device config.
+  pub fn step_inclusive(&self) -> bool {
+    self.step_inclusive
+  }
 
-    // If we've already sent commands before, we should check against our
-    // old values. Otherwise, we should always send whatever command we're
-    // going to send.
-    let mut result: Vec<Option<(u32, bool)>> = vec![None; self.rotations.len()];
-    for rotate_command in msg.rotations() {
-      let index = rotate_command.index() as usize;
-      // Since we're going to iterate here anyways, we do our index check
-      // here instead of in a filter above.
-      if index >= self.rotations.len() {
-        return Err(
-          ButtplugDeviceError::ProtocolRequirementError(format!(
-            "RotateCmd has {} commands, device has {} rotators.",
-            msg.rotations().len(),
-            self.rotations.len()
-          ))
-          .into(),
-        );
-      }
+  /// Choose how the top of a step range is read. By default (exclusive) the end of the range is the
+  /// highest step, so a 0-20 range has 21 steps and 1.0 becomes step 20. Some devices advertise a
+  /// step count instead, where 20 means steps 0-19. With step_inclusive set, the end of the range
+  /// is treated as that count, making the highest step one below it, so 1.0 on a 0-20 range
+  /// becomes step 19. Applies to every actuator, and to reading stored steps back as values.
+  pub fn set_step_inclusive(&mut self, step_inclusive: bool) {
+    self.step_inclusive = step_inclusive;
+  }
 
-      // When calculating speeds, round up. This follows how we calculated
-      // things in buttplug-js and buttplug-csharp, so it's more for history
-      // than anything, but it's what users will expect.
-      let range = self.rotation_step_ranges[index].end() - self.rotation_step_ranges[index].start();
-      let speed_modifier = rotate_command.speed() * range as f64;
-      let speed = if speed_modifier < 0.0001 {
-        0
-      } else {
-        // When calculating speeds, round up. This follows how we calculated
-        // things in buttplug-js and buttplug-csharp, so it's more for history
-        // than anything, but it's what users will expect.
-        (speed_modifier + *self.rotation_step_ranges[index].start() as f64).ceil() as u32
-      };
-      let clockwise = rotate_command.clockwise();
-      // If we've already sent commands, we don't want to send them again,
-      // because some of our communication busses are REALLY slow. Make sure
-      // these values get None in our return vector.
-      let sent_rotation = self.sent_rotation.load(SeqCst);
-      if !sent_rotation
-        || speed != self.rotations[index].0.load(SeqCst)
-        || clockwise != self.rotations[index].1.load(SeqCst)
-      {
-        self.rotations[index].0.store(speed, SeqCst);
-        self.rotations[index].1.store(clockwise, SeqCst);
-        result[index] = Some((speed, clockwise));
-      }
-      if !sent_rotation {
-        self.sent_rotation.store(true, SeqCst);
-      }
-    }
+  pub fn device_label(&self) -> Option<&str> {
+    self.device_label.as_deref()
+  }
 
-    // If we're in a match all situation, set up the array with all prior
-    // values before switching them out.
-    if match_all && !result.iter().all(|x| x.is_none()) {
-      for (index, rotation) in self.rotations.iter().enumerate() {
-        if result[index].is_none() {
-          result[index] = Some((rotation.0.load(SeqCst), rotation.1.load(SeqCst)));
-        }
-      }
+  /// Name the device this manager belongs to, e.g. with its name and address. When set, the label
+  /// is put at the start of every error the manager returns, so logs from setups with several
+  /// devices say which one an error came from. Errors that only carry numbers or types (index
+  /// errors and the like) are returned as ProtocolRequirementErrors with the label and the original
+  /// message.
+  pub fn set_device_label(&mut self, device_label: Option<String>) {
+    self.device_label = device_label;
+  }
+
+  fn label_device_error(&self, err: ButtplugDeviceError) -> ButtplugDeviceError {
+    label_device_error(&self.device_label, err)
+  }
+
+  fn label_error(&self, err: ButtplugError) -> ButtplugError {
+    match err {
+      ButtplugError::ButtplugDeviceError(err) => self.label_device_error(err).into(),
+      err => err,
     }
+  }
 
-    // Return the command vector for the protocol to turn into proprietary commands
-    Ok(result)
+  pub fn max_step_delta(&self) -> Option<u32> {
+    self.max_step_delta
   }
 
-  pub fn _update_linear(&self, _msg: &LinearCmd) -> Result<Option<Vec<(u32, u32)>>, ButtplugError> {
-    // First, make sure this is a valid command, that doesn't contain an
-    // index we can't reach.
+  /// Limit how many steps a scalar actuator can speed up by in a single command. Larger jumps are
+  /// clamped, and the clamped value is what's cached, so repeating the command keeps ramping until
+  /// the target is reached. Slowing down isn't limited. 0 removes the limit.
+  pub fn set_max_step_delta(&mut self, delta: u32) {
+    self.max_step_delta = if delta == 0 { None } else { Some(delta) };
+  }
 
-    // If we've already sent commands before, we should check against our
-    // old values. Otherwise, we should always send whatever command we're
-    // going to send.
+  pub fn linear_scalar_emulation(&self) -> bool {
+    self.linear_scalar_emulation
+  }
+
+  /// Allow [update_scalar_as_linear](Self::update_scalar_as_linear), so apps that only send
+  /// vibration commands can still drive linear-only devices. Off by default.
+  pub fn set_linear_scalar_emulation(&mut self, linear_scalar_emulation: bool) {
+    self.linear_scalar_emulation = linear_scalar_emulation;
+  }
+
+  pub fn coalesce_window(&self) -> Option<Duration> {
+    self.coalesce_window
+  }
+
+  /// How long [update_scalar_coalesced](Self::update_scalar_coalesced) holds updates before
+  /// sending the latest ones, so rapid slider changes don't flood slow devices. None (the default)
+  /// sends every update right away.
+  pub fn set_coalesce_window(&mut self, window: Option<Duration>) {
+    self.coalesce_window = window;
+  }
+
+  pub fn command_keepalive(&self) -> Option<Duration> {
+    self.command_keepalive
+  }
+
+  /// If set, once more than this much time has passed since the last command we emitted, the next
+  /// command is sent even if it matches the cached values. Some devices stop on their own if they
+  /// don't hear from us for a while, at which point our cache no longer matches the hardware and
+  /// repeating the last command would otherwise be suppressed.
+  pub fn set_command_keepalive(&mut self, command_keepalive: Option<Duration>) {
+    self.command_keepalive = command_keepalive;
+  }
+
+  pub fn inactivity_timeout(&self) -> Option<Duration> {
+    self.inactivity_timeout
+  }
+
+  /// If set, [poll_watchdog](Self::poll_watchdog) produces stop commands once this much time has
+  /// passed without a scalar, rotation, or linear update, so a device isn't left running after
+  /// whatever was controlling it goes quiet. Updates count whether or not they emit anything.
+  pub fn set_inactivity_timeout(&mut self, timeout: Option<Duration>) {
+    self.inactivity_timeout = timeout;
+  }
+
+  /// Check the [inactivity timeout](Self::set_inactivity_timeout) against `now`, returning the
+  /// [stop commands](Self::stop_commands) if it has passed since the last update. Fires once per
+  /// period of inactivity, and not at all if nothing is running. Nothing is scheduled by the
+  /// manager, so this needs to be called regularly by whatever owns it.
+  pub fn poll_watchdog(&self, now: Instant) -> Option<Vec<ButtplugDeviceCommandMessageUnion>> {
+    let timeout = self.inactivity_timeout?;
+    let mut last_update = self
+      .last_update_instant
+      .lock()
+      .expect("Lock is never held across a panic");
+    if now.duration_since((*last_update)?) < timeout {
+      return None;
+    }
+    *last_update = None;
+    if !self.actuators_running() {
+      return None;
+    }
+    debug!(
+      "No updates in {:?}, stopping device{}.",
+      timeout,
+      self
+        .device_label
+        .as_ref()
+        .map(|label| format!(" {}", label))
+        .unwrap_or_default()
+    );
+    Some(self.stop_commands())
+  }
+
+  pub fn vibration_queue_depth(&self) -> usize {
+    self.vibration_queue_depth
+  }
+
+  /// Set how many commands [enqueue_vibration](Self::enqueue_vibration) holds on to. Once full,
+  /// the oldest queued command is dropped to make room. Always at least 1.
+  pub fn set_vibration_queue_depth(&mut self, depth: usize) {
+    self.vibration_queue_depth = depth.max(1);
+    let mut queue = self
+      .vibration_queue
+      .lock()
+      .expect("Lock is never held across a panic");
+    while queue.len() > self.vibration_queue_depth {
+      queue.pop_front();
+    }
+  }
+
+  /// Queue a vibration command to be applied later by [next_queued](Self::next_queued), for
+  /// integrators that lay out a ramp ahead of time and hand it out one step per tick. Commands are
+  /// checked as they're queued, so a bad one is rejected here instead of when its turn comes.
+  pub fn enqueue_vibration(&self, msg: VibrateCmd) -> Result<(), ButtplugError> {
+    self.validate_command(&msg.clone().into())?;
+    let mut queue = self
+      .vibration_queue
+      .lock()
+      .expect("Lock is never held across a panic");
+    if queue.len() >= self.vibration_queue_depth {
+      debug!("Vibration queue full, dropping oldest queued command.");
+      queue.pop_front();
+    }
+    queue.push_back(msg);
+    Ok(())
+  }
+
+  /// Number of vibration commands waiting in the queue.
+  pub fn queued_vibration_count(&self) -> usize {
+    self
+      .vibration_queue
+      .lock()
+      .expect("Lock is never held across a panic")
+      .len()
+  }
+
+  /// Apply the oldest queued vibration command, returning None if the queue is empty. Goes through
+  /// the same path (and deduplication) as [update](Self::update), and returns the steps to send by
+  /// vibrator index, only filled in for vibrators that changed. Like
+  /// [update_scalar](Self::update_scalar), the vec is empty if nothing changed.
+  pub fn next_queued(&self) -> Option<Vec<Option<u32>>> {
+    let msg = self
+      .vibration_queue
+      .lock()
+      .expect("Lock is never held across a panic")
+      .pop_front()?;
+    let result = match self.update(&msg.into(), false) {
+      Ok(GenericCommandResult::Scalar(result)) => result,
+      Ok(_) => unreachable!("Vibration commands always produce scalar results"),
+      Err(err) => {
+        // Only possible if the configuration changed after the command was queued.
+        warn!("Dropping queued vibration command: {:?}", err);
+        return Some(vec![]);
+      }
+    };
+    if result.is_empty() {
+      return Some(vec![]);
+    }
+    Some(
+      self
+        .vibrator_indexes()
+        .into_iter()
+        .map(|index| result[index as usize].map(|(_, step)| step))
+        .collect(),
+    )
+  }
+
+  fn mark_update(&self) {
+    *self
+      .last_update_instant
+      .lock()
+      .expect("Lock is never held across a panic") = Some(Instant::now());
+  }
+
+  // True if any scalar or rotation actuator was last sent something other than a stop. Linear
+  // actuators stop on their own once they reach a position.
+  fn actuators_running(&self) -> bool {
+    self.scalars.iter().any(|x| x.value().load(SeqCst) != 0)
+      || self
+        .rotations
+        .iter()
+        .any(|(speed, _)| speed.load(SeqCst) != 0)
+  }
+
+  /// Limit how often commands are emitted for a message type, for hardware that can't take updates
+  /// quickly. Updates that come in before the interval is up are stored but emit nothing. The first
+  /// update after the interval then emits the latest value for every actuator of that type, even
+  /// if it matches what's stored. VibrateCmd and ScalarCmd share a limit, as they drive the same
+  /// actuators. A zero interval removes the limit.
+  pub fn set_rate_limit(
+    &mut self,
+    msg_type: ButtplugDeviceMessageType,
+    min_interval: Duration,
+  ) -> Result<(), ButtplugDeviceError> {
+    let limit = if min_interval.is_zero() {
+      None
+    } else {
+      Some(RateLimit::new(min_interval))
+    };
+    match msg_type {
+      ButtplugDeviceMessageType::ScalarCmd | ButtplugDeviceMessageType::VibrateCmd => {
+        self.scalar_rate_limit = limit
+      }
+      ButtplugDeviceMessageType::RotateCmd => self.rotation_rate_limit = limit,
+      ButtplugDeviceMessageType::LinearCmd => self.linear_rate_limit = limit,
+      _ => return Err(self.label_device_error(ButtplugDeviceError::MessageNotSupported(msg_type))),
+    }
+    Ok(())
+  }
+
+  pub fn rate_limit(&self, msg_type: ButtplugDeviceMessageType) -> Option<Duration> {
+    let limit = match msg_type {
+      ButtplugDeviceMessageType::ScalarCmd | ButtplugDeviceMessageType::VibrateCmd => {
+        self.scalar_rate_limit.as_ref()
+      }
+      ButtplugDeviceMessageType::RotateCmd => self.rotation_rate_limit.as_ref(),
+      ButtplugDeviceMessageType::LinearCmd => self.linear_rate_limit.as_ref(),
+      _ => None,
+    };
+    limit.map(|limit| limit.min_interval)
+  }
+
+  // Test method
+  #[cfg(test)]
+  fn expire_rate_limits(&self) {
+    for limit in [
+      &self.scalar_rate_limit,
+      &self.rotation_rate_limit,
+      &self.linear_rate_limit,
+    ]
+    .into_iter()
+    .flatten()
+    {
+      *limit
+        .last_emitted
+        .lock()
+        .expect("Lock is never held across a panic") = None;
+    }
+  }
+
+  pub fn dedup_enabled(&self) -> bool {
+    self.dedup_enabled
+  }
+
+  /// Turn off deduplication against stored values, so every update emits a value for every
+  /// actuator of its type. Deduplication keeps slow buses from being flooded, but on lossy
+  /// transports a single lost command could otherwise leave a device stuck until the value changes.
+  /// On by default.
+  pub fn set_dedup_enabled(&mut self, dedup_enabled: bool) {
+    self.dedup_enabled = dedup_enabled;
+  }
+
+  pub fn stop_clockwise(&self) -> bool {
+    self.stop_clockwise
+  }
+
+  /// Set the direction used in rotation stop commands for rotators that haven't been sent anything
+  /// yet. Once a rotator has been commanded, its stop keeps the last direction it was sent, so
+  /// stopping never flips it. Defaults to false (counterclockwise).
+  pub fn set_stop_clockwise(&mut self, stop_clockwise: bool) {
+    self.stop_clockwise = stop_clockwise;
+    self.rebuild_stop_commands();
+  }
+
+  // Direction to put in a stop command for a rotator, see set_stop_clockwise.
+  fn rotation_stop_direction(&self, index: usize) -> bool {
+    if self.sent_rotation.load(SeqCst) {
+      self.rotations[index].1.load(SeqCst)
+    } else {
+      self.stop_clockwise
+    }
+  }
+
+  // Stored rotation stops only hold the default direction, so swap in each rotator's current stop
+  // direction on the way out.
+  fn refresh_stop_command(
+    &self,
+    command: &ButtplugDeviceCommandMessageUnion,
+  ) -> ButtplugDeviceCommandMessageUnion {
+    match command {
+      ButtplugDeviceCommandMessageUnion::RotateCmd(_) => {
+        let subcommands = (0..self.rotations.len())
+          .map(|index| {
+            RotationSubcommand::new(index as u32, 0.0, self.rotation_stop_direction(index))
+          })
+          .collect();
+        RotateCmd::new(0, subcommands).into()
+      }
+      command => command.clone(),
+    }
+  }
+
+  // True if commands need to go out whether or not they match what's stored.
+  fn resend_required(&self) -> bool {
+    !self.dedup_enabled || self.keepalive_expired()
+  }
+
+  // True if the keepalive interval has passed since the last command we emitted, meaning cached
+  // values can't be trusted for deduplication.
+  fn keepalive_expired(&self) -> bool {
+    let Some(keepalive) = self.command_keepalive else {
+      return false;
+    };
+    self
+      .last_command_instant
+      .lock()
+      .expect("Lock is never held across a panic")
+      .is_some_and(|last| last.elapsed() > keepalive)
+  }
+
+  fn mark_command_emitted(&self) {
+    *self
+      .last_command_instant
+      .lock()
+      .expect("Lock is never held across a panic") = Some(Instant::now());
+  }
+
+  // Test method
+  #[cfg(test)]
+  fn set_last_command_instant(&self, instant: Instant) {
+    *self
+      .last_command_instant
+      .lock()
+      .expect("Lock is never held across a panic") = Some(instant);
+  }
+
+  // Number of steps above the start of a range that commands can reach, see set_step_inclusive.
+  fn step_span(&self, range: &RangeInclusive<u32>) -> u32 {
+    let span = range.end().saturating_sub(*range.start());
+    if self.step_inclusive {
+      span.saturating_sub(1)
+    } else {
+      span
+    }
+  }
+
+  // Convert from the generic 0.0-1.0 range to a step in the range given by the device config.
+  // Anything close enough to 0.0 becomes zero_step, so scalars and rotations can stop cleanly.
+  fn convert_to_step(&self, value: f64, range: &RangeInclusive<u32>, zero_step: u32) -> u32 {
+    let modifier = value * self.step_span(range) as f64;
+    if modifier < 0.0001 {
+      zero_step
+    } else {
+      self.rounding_mode.apply(modifier + *range.start() as f64)
+    }
+  }
+
+  // Speeds use 0 as their stop value, which small values may round down to. Speeds are curved and
+  // scaled by the intensity cap first, so the top of the 0.0-1.0 range lands on the capped step.
+  //
+  // Binary (on/off) actuators, with a single step above their stop, are on for any speed above 0.0.
+  // Otherwise they'd need at least 0.5 to round up to on, which isn't what anyone asking for a low
+  // speed on an on/off toy expects.
+  fn convert_speed_to_step(&self, value: f64, range: &RangeInclusive<u32>) -> u32 {
+    let scaled_value = value.powf(self.speed_curve) * self.intensity_cap;
+    let top_step = range.start() + self.step_span(range);
+    if top_step - range.start() == 1 && scaled_value > 0.0 {
+      return top_step;
+    }
+    let step = self.convert_to_step(scaled_value, range, 0);
+    if step == 0 && value > 0.0 && self.preserve_nonzero_speed {
+      // Features configured without any steps to move through have nowhere to go but 0.
+      (*range.start()).max(1).min(top_step)
+    } else {
+      step
+    }
+  }
+
+  pub fn scalar_count(&self) -> usize {
+    self.scalars.len()
+  }
+
+  pub fn rotator_count(&self) -> usize {
+    self.rotations.len()
+  }
+
+  pub fn linear_actuator_count(&self) -> usize {
+    self.linears.len()
+  }
+
+  /// True if any actuator was last sent something other than a stop: a nonzero scalar or rotation
+  /// step, or any linear move. Linear actuators can't tell us when they finish a stroke, so one
+  /// that has been moved counts as active until the manager is [reset](Self::reset).
+  pub fn is_active(&self) -> bool {
+    self.scalars.iter().any(|x| x.value().load(SeqCst) != 0)
+      || self
+        .rotations
+        .iter()
+        .any(|(speed, _)| speed.load(SeqCst) != 0)
+      || self
+        .linears
+        .iter()
+        .any(|(duration, position)| duration.load(SeqCst) != 0 || position.load(SeqCst) != 0)
+  }
+
+  /// True if the manager has at least one scalar, rotation, or linear actuator. Without one, every
+  /// update fails and there are no stop commands, so callers may want to reject the device up
+  /// front instead.
+  pub fn has_any_actuator(&self) -> bool {
+    !self.scalars.is_empty() || !self.rotations.is_empty() || !self.linears.is_empty()
+  }
+
+  /// True if at least one scalar actuator is a vibrator.
+  pub fn has_vibrators(&self) -> bool {
+    self
+      .scalars
+      .iter()
+      .any(|x| *x.actuator() == ActuatorType::Vibrate)
+  }
+
+  /// True if the device has at least one rotator. A RotateCmd attribute listing no features
+  /// counts as having none.
+  pub fn has_rotators(&self) -> bool {
+    !self.rotations.is_empty()
+  }
+
+  /// Command message types the manager can generate commands for, based on which actuators it
+  /// has, sorted in [ButtplugDeviceMessageType] order. VibrateCmd and SingleMotorVibrateCmd are
+  /// only listed if at least one scalar actuator is a vibrator. StopDeviceCmd is left out, as it's
+  /// answered with [stop_commands](Self::stop_commands) rather than an update.
+  pub fn supported_message_types(&self) -> Vec<ButtplugDeviceMessageType> {
+    let mut message_types = vec![];
+    if !self.scalars.is_empty() {
+      message_types.push(ButtplugDeviceMessageType::ScalarCmd);
+    }
+    if self.has_vibrators() {
+      message_types.push(ButtplugDeviceMessageType::VibrateCmd);
+      message_types.push(ButtplugDeviceMessageType::SingleMotorVibrateCmd);
+    }
+    if self.has_rotators() {
+      message_types.push(ButtplugDeviceMessageType::RotateCmd);
+    }
+    if !self.linears.is_empty() {
+      message_types.push(ButtplugDeviceMessageType::LinearCmd);
+    }
+    message_types.sort();
+    message_types
+  }
+
+  pub fn scalar_step_range(&self, index: usize) -> Option<&RangeInclusive<u32>> {
+    self.scalars.get(index).map(|x| x.step_range())
+  }
+
+  pub fn rotation_step_ranges(&self) -> &[RangeInclusive<u32>] {
+    &self.rotation_step_ranges
+  }
+
+  pub fn linear_step_ranges(&self) -> &[RangeInclusive<u32>] {
+    &self.linear_step_ranges
+  }
+
+  /// Mute or unmute a single actuator. A disabled actuator is always driven at 0, whatever speed
+  /// commands ask for, but is still included in stop commands. Indexes follow the message type, so
+  /// a VibrateCmd index only counts vibrators.
+  pub fn set_actuator_enabled(
+    &mut self,
+    msg_type: ButtplugDeviceMessageType,
+    index: u32,
+    enabled: bool,
+  ) -> Result<(), ButtplugDeviceError> {
+    let result = self.apply_actuator_enabled(msg_type, index, enabled);
+    result.map_err(|err| self.label_device_error(err))
+  }
+
+  fn apply_actuator_enabled(
+    &mut self,
+    msg_type: ButtplugDeviceMessageType,
+    index: u32,
+    enabled: bool,
+  ) -> Result<(), ButtplugDeviceError> {
+    let flag = match msg_type {
+      ButtplugDeviceMessageType::ScalarCmd => {
+        let count = self.scalars.len() as u32;
+        self
+          .scalars
+          .get_mut(index as usize)
+          .map(|x| &mut x.enabled)
+          .ok_or(ButtplugDeviceError::DeviceFeatureIndexError(count, index))?
+      }
+      ButtplugDeviceMessageType::VibrateCmd => {
+        let count = self
+          .scalars
+          .iter()
+          .filter(|x| *x.actuator() == ActuatorType::Vibrate)
+          .count() as u32;
+        self
+          .scalars
+          .iter_mut()
+          .filter(|x| *x.actuator() == ActuatorType::Vibrate)
+          .nth(index as usize)
+          .map(|x| &mut x.enabled)
+          .ok_or(ButtplugDeviceError::DeviceFeatureIndexError(count, index))?
+      }
+      ButtplugDeviceMessageType::RotateCmd => {
+        let count = self.rotation_enabled.len() as u32;
+        self
+          .rotation_enabled
+          .get_mut(index as usize)
+          .ok_or(ButtplugDeviceError::DeviceFeatureIndexError(count, index))?
+      }
+      _ => return Err(ButtplugDeviceError::MessageNotSupported(msg_type)),
+    };
+    *flag = enabled;
+    Ok(())
+  }
+
+  /// Change how many steps an actuator has above the start of its step range, for devices that only
+  /// report their usable range after connecting. Indexes follow the message type, as with
+  /// [set_actuator_enabled](Self::set_actuator_enabled). The stored value is clamped to the new
+  /// range, and the next command for the message type is sent in full instead of being
+  /// deduplicated against values that were sent on the old scale.
+  pub fn set_step_count(
+    &mut self,
+    msg_type: ButtplugDeviceMessageType,
+    index: u32,
+    count: u32,
+  ) -> Result<(), ButtplugDeviceError> {
+    let result = self.apply_step_count(msg_type, index, count);
+    result.map_err(|err| self.label_device_error(err))
+  }
+
+  fn apply_step_count(
+    &mut self,
+    msg_type: ButtplugDeviceMessageType,
+    index: u32,
+    count: u32,
+  ) -> Result<(), ButtplugDeviceError> {
+    let (range, value, sent) = match msg_type {
+      ButtplugDeviceMessageType::ScalarCmd => {
+        let feature_count = self.scalars.len() as u32;
+        let scalar = self.scalars.get_mut(index as usize).ok_or(
+          ButtplugDeviceError::DeviceFeatureIndexError(feature_count, index),
+        )?;
+        (&mut scalar.step_range, &scalar.value, &self.sent_scalar)
+      }
+      ButtplugDeviceMessageType::VibrateCmd => {
+        let feature_count = self
+          .scalars
+          .iter()
+          .filter(|x| *x.actuator() == ActuatorType::Vibrate)
+          .count() as u32;
+        let scalar = self
+          .scalars
+          .iter_mut()
+          .filter(|x| *x.actuator() == ActuatorType::Vibrate)
+          .nth(index as usize)
+          .ok_or(ButtplugDeviceError::DeviceFeatureIndexError(
+            feature_count,
+            index,
+          ))?;
+        (&mut scalar.step_range, &scalar.value, &self.sent_scalar)
+      }
+      ButtplugDeviceMessageType::RotateCmd => {
+        let feature_count = self.rotations.len() as u32;
+        match (
+          self.rotation_step_ranges.get_mut(index as usize),
+          self.rotations.get(index as usize),
+        ) {
+          (Some(range), Some((speed, _))) => (range, speed, &self.sent_rotation),
+          _ => {
+            return Err(ButtplugDeviceError::DeviceFeatureIndexError(
+              feature_count,
+              index,
+            ))
+          }
+        }
+      }
+      ButtplugDeviceMessageType::LinearCmd => {
+        let feature_count = self.linears.len() as u32;
+        match (
+          self.linear_step_ranges.get_mut(index as usize),
+          self.linears.get(index as usize),
+        ) {
+          (Some(range), Some((_, position))) => (range, position, &self.sent_linear),
+          _ => {
+            return Err(ButtplugDeviceError::DeviceFeatureIndexError(
+              feature_count,
+              index,
+            ))
+          }
+        }
+      }
+      _ => return Err(ButtplugDeviceError::MessageNotSupported(msg_type)),
+    };
+    let end = range.start().checked_add(count).ok_or_else(|| {
+      ButtplugDeviceError::DeviceConfigurationError(format!(
+        "{} feature {} can't have {} steps above step {}.",
+        msg_type,
+        index,
+        count,
+        range.start()
+      ))
+    })?;
+    *range = RangeInclusive::new(*range.start(), end);
+    value.fetch_min(end, SeqCst);
+    sent.store(false, SeqCst);
+    Ok(())
+  }
+
+  // The spec doesn't allow a command to address the same feature twice. If we let it through,
+  // whichever subcommand came last would silently win.
+  fn check_duplicate_indexes(
+    msg_type: ButtplugDeviceMessageType,
+    indexes: impl Iterator<Item = u32>,
+  ) -> Result<(), ButtplugError> {
+    let mut seen = vec![];
+    for index in indexes {
+      if seen.contains(&index) {
+        return Err(
+          ButtplugDeviceError::ProtocolRequirementError(format!(
+            "{} has more than one subcommand for index {}.",
+            msg_type, index
+          ))
+          .into(),
+        );
+      }
+      seen.push(index);
+    }
+    Ok(())
+  }
+
+  // Check all values and indexes before storing anything, so a bad subcommand can't leave us with
+  // half updated state.
+  fn check_scalar_cmd(&self, msg: &ScalarCmd) -> Result<(), ButtplugError> {
+    // First, make sure this is a valid command, that contains at least one
+    // subcommand.
+    if msg.scalars().is_empty() {
+      return Err(ButtplugDeviceError::EmptyCommand(ButtplugDeviceMessageType::ScalarCmd).into());
+    }
+
+    for cmd in msg.scalars() {
+      if !(0.0..=1.0).contains(&cmd.scalar()) {
+        return Err(
+          ButtplugDeviceError::ProtocolRequirementError(format!(
+            "ScalarCmd value {} for index {} is invalid, should be between 0.0 and 1.0.",
+            cmd.scalar(),
+            cmd.index()
+          ))
+          .into(),
+        );
+      }
+      if cmd.index() as usize >= self.scalars.len() {
+        return Err(
+          ButtplugDeviceError::IndexOutOfRange {
+            requested: cmd.index(),
+            available: self.scalars.len(),
+          }
+          .into(),
+        );
+      }
+    }
+    Self::check_duplicate_indexes(
+      ButtplugDeviceMessageType::ScalarCmd,
+      msg.scalars().iter().map(|x| x.index()),
+    )
+  }
+
+  // Same idea as check_scalar_cmd. VibrateCmd indexes only count vibrators.
+  fn check_vibrate_cmd(&self, msg: &VibrateCmd) -> Result<(), ButtplugError> {
+    if msg.speeds().is_empty() {
+      return Err(ButtplugDeviceError::EmptyCommand(ButtplugDeviceMessageType::VibrateCmd).into());
+    }
+    let vibrator_count = self
+      .scalars
+      .iter()
+      .filter(|x| *x.actuator() == ActuatorType::Vibrate)
+      .count();
+    for cmd in msg.speeds() {
+      if !(0.0..=1.0).contains(&cmd.speed()) {
+        return Err(
+          ButtplugDeviceError::ProtocolRequirementError(format!(
+            "VibrateCmd speed {} for index {} is invalid, should be between 0.0 and 1.0.",
+            cmd.speed(),
+            cmd.index()
+          ))
+          .into(),
+        );
+      }
+      if cmd.index() as usize >= vibrator_count {
+        return Err(
+          ButtplugDeviceError::IndexOutOfRange {
+            requested: cmd.index(),
+            available: vibrator_count,
+          }
+          .into(),
+        );
+      }
+    }
+    Self::check_duplicate_indexes(
+      ButtplugDeviceMessageType::VibrateCmd,
+      msg.speeds().iter().map(|x| x.index()),
+    )
+  }
+
+  fn check_rotate_cmd(&self, msg: &RotateCmd) -> Result<(), ButtplugError> {
+    if msg.rotations().is_empty() {
+      return Err(ButtplugDeviceError::EmptyCommand(ButtplugDeviceMessageType::RotateCmd).into());
+    }
+    for cmd in msg.rotations() {
+      if !(0.0..=1.0).contains(&cmd.speed()) {
+        return Err(
+          ButtplugDeviceError::ProtocolRequirementError(format!(
+            "RotateCmd speed {} for index {} is invalid, should be between 0.0 and 1.0.",
+            cmd.speed(),
+            cmd.index()
+          ))
+          .into(),
+        );
+      }
+      if cmd.index() as usize >= self.rotations.len() {
+        return Err(
+          ButtplugDeviceError::IndexOutOfRange {
+            requested: cmd.index(),
+            available: self.rotations.len(),
+          }
+          .into(),
+        );
+      }
+    }
+    Self::check_duplicate_indexes(
+      ButtplugDeviceMessageType::RotateCmd,
+      msg.rotations().iter().map(|x| x.index()),
+    )
+  }
+
+  fn check_linear_cmd(&self, msg: &LinearCmd) -> Result<(), ButtplugError> {
+    if msg.vectors().is_empty() {
+      return Err(ButtplugDeviceError::EmptyCommand(ButtplugDeviceMessageType::LinearCmd).into());
+    }
+    for cmd in msg.vectors() {
+      if cmd.duration() == 0 {
+        return Err(
+          ButtplugDeviceError::ProtocolRequirementError(format!(
+            "LinearCmd duration for index {} is 0, should be greater than 0.",
+            cmd.index()
+          ))
+          .into(),
+        );
+      }
+      if !(0.0..=1.0).contains(&cmd.position()) {
+        return Err(
+          ButtplugDeviceError::ProtocolRequirementError(format!(
+            "LinearCmd position {} for index {} is invalid, should be between 0.0 and 1.0.",
+            cmd.position(),
+            cmd.index()
+          ))
+          .into(),
+        );
+      }
+      if cmd.index() as usize >= self.linears.len() {
+        return Err(
+          ButtplugDeviceError::IndexOutOfRange {
+            requested: cmd.index(),
+            available: self.linears.len(),
+          }
+          .into(),
+        );
+      }
+    }
+    Self::check_duplicate_indexes(
+      ButtplugDeviceMessageType::LinearCmd,
+      msg.vectors().iter().map(|x| x.index()),
+    )
+  }
+
+  /// Check a command against what this manager's device can do, without changing any state, so
+  /// servers can reject bad commands before anything is sent. Covers the message type being
+  /// supported by the device at all, indexes being in range, and values being valid. Only actuator
+  /// commands are tracked by the manager, so anything else (raw, sensor, and old device specific
+  /// commands) can't be checked here, and returns UnhandledCommand.
+  pub fn validate_command(
+    &self,
+    msg: &ButtplugDeviceCommandMessageUnion,
+  ) -> Result<(), ButtplugError> {
+    self.check_command(msg).map_err(|err| self.label_error(err))
+  }
+
+  fn check_command(&self, msg: &ButtplugDeviceCommandMessageUnion) -> Result<(), ButtplugError> {
+    let not_supported = |msg_type| -> Result<(), ButtplugError> {
+      Err(ButtplugDeviceError::MessageNotSupported(msg_type).into())
+    };
+    match msg {
+      ButtplugDeviceCommandMessageUnion::ScalarCmd(msg) => {
+        if self.scalars.is_empty() {
+          return not_supported(ButtplugDeviceMessageType::ScalarCmd);
+        }
+        self.check_scalar_cmd(msg)
+      }
+      ButtplugDeviceCommandMessageUnion::VibrateCmd(msg) => {
+        if !self
+          .scalars
+          .iter()
+          .any(|x| *x.actuator() == ActuatorType::Vibrate)
+        {
+          return not_supported(ButtplugDeviceMessageType::VibrateCmd);
+        }
+        self.check_vibrate_cmd(msg)
+      }
+      ButtplugDeviceCommandMessageUnion::SingleMotorVibrateCmd(msg) => {
+        // Sent to every vibrator, so there's no index to check.
+        if !self
+          .scalars
+          .iter()
+          .any(|x| *x.actuator() == ActuatorType::Vibrate)
+        {
+          return not_supported(ButtplugDeviceMessageType::VibrateCmd);
+        }
+        if !(0.0..=1.0).contains(&msg.speed()) {
+          return Err(
+            ButtplugDeviceError::ProtocolRequirementError(format!(
+              "SingleMotorVibrateCmd speed {} is invalid, should be between 0.0 and 1.0.",
+              msg.speed()
+            ))
+            .into(),
+          );
+        }
+        Ok(())
+      }
+      ButtplugDeviceCommandMessageUnion::RotateCmd(msg) => {
+        if self.rotations.is_empty() {
+          return not_supported(ButtplugDeviceMessageType::RotateCmd);
+        }
+        self.check_rotate_cmd(msg)
+      }
+      ButtplugDeviceCommandMessageUnion::LinearCmd(msg) => {
+        if self.linears.is_empty() {
+          return not_supported(ButtplugDeviceMessageType::LinearCmd);
+        }
+        self.check_linear_cmd(msg)
+      }
+      // Every device can be stopped.
+      ButtplugDeviceCommandMessageUnion::StopDeviceCmd(_) => Ok(()),
+      msg => Err(
+        ButtplugDeviceError::UnhandledCommand(format!(
+          "Generic command manager cannot validate {:?}",
+          msg
+        ))
+        .into(),
+      ),
+    }
+  }
+
+  /// Apply any generic command message, sending it to the matching update method. VibrateCmd and
+  /// SingleMotorVibrateCmd are converted to ScalarCmd over the device's vibrators first, the same
+  /// way the server does. StopDeviceCmd covers every actuator type, so use
+  /// [GenericCommandManager::stop_commands] for that instead.
+  pub fn update(
+    &self,
+    msg: &ButtplugDeviceCommandMessageUnion,
+    match_all: bool,
+  ) -> Result<GenericCommandResult, ButtplugError> {
+    self.validate_command(msg)?;
+    match msg {
+      ButtplugDeviceCommandMessageUnion::ScalarCmd(msg) => Ok(GenericCommandResult::Scalar(
+        self.update_scalar(msg, match_all)?,
+      )),
+      ButtplugDeviceCommandMessageUnion::VibrateCmd(msg) => {
+        let vibrators = self.vibrator_indexes();
+        let scalar_cmd = ScalarCmd::new(
+          msg.device_index(),
+          msg
+            .speeds()
+            .iter()
+            .map(|x| {
+              ScalarSubcommand::new(
+                vibrators[x.index() as usize],
+                x.speed(),
+                ActuatorType::Vibrate,
+              )
+            })
+            .collect(),
+        );
+        Ok(GenericCommandResult::Scalar(
+          self.update_scalar(&scalar_cmd, match_all)?,
+        ))
+      }
+      ButtplugDeviceCommandMessageUnion::SingleMotorVibrateCmd(msg) => {
+        let scalar_cmd = ScalarCmd::new(
+          msg.device_index(),
+          self
+            .vibrator_indexes()
+            .into_iter()
+            .map(|index| ScalarSubcommand::new(index, msg.speed(), ActuatorType::Vibrate))
+            .collect(),
+        );
+        Ok(GenericCommandResult::Scalar(
+          self.update_scalar(&scalar_cmd, match_all)?,
+        ))
+      }
+      ButtplugDeviceCommandMessageUnion::RotateCmd(msg) => Ok(GenericCommandResult::Rotate(
+        self.update_rotation(msg, match_all)?,
+      )),
+      ButtplugDeviceCommandMessageUnion::LinearCmd(msg) => Ok(GenericCommandResult::Linear(
+        self.update_linear(msg, match_all)?,
+      )),
+      msg => Err(
+        self
+          .label_device_error(ButtplugDeviceError::UnhandledCommand(format!(
+            "Generic command manager cannot apply {:?}",
+            msg
+          )))
+          .into(),
+      ),
+    }
+  }
+
+  // Scalar feature indexes of every vibrator, in order, so vibrator N is at index N.
+  fn vibrator_indexes(&self) -> Vec<u32> {
+    self
+      .scalars
+      .iter()
+      .enumerate()
+      .filter(|(_, x)| *x.actuator() == ActuatorType::Vibrate)
+      .map(|(i, _)| i as u32)
+      .collect()
+  }
+
+  // Disabled actuators always convert to a stop.
+  fn scalar_to_step(&self, index: usize, value: f64) -> u32 {
+    if *self.scalars[index].enabled() {
+      self.convert_speed_to_step(value, self.scalars[index].step_range())
+    } else {
+      0
+    }
+  }
+
+  // Increases are ramped, but decreases go through immediately so stopping is never delayed.
+  fn ramp_scalar_step(&self, index: usize, scalar: u32) -> u32 {
+    let current_scalar = self.scalars[index].value().load(SeqCst);
+    match self.max_step_delta {
+      Some(delta) if scalar > current_scalar => scalar.min(current_scalar.saturating_add(delta)),
+      _ => scalar,
+    }
+  }
+
+  /// Work out the steps a ScalarCmd would set every scalar actuator to, without storing anything or
+  /// deduplicating against what's been sent. Actuators the command doesn't mention keep their
+  /// current step. Useful for UI previews and validation.
+  pub fn preview_scalar(&self, msg: &ScalarCmd) -> Result<Vec<u32>, ButtplugError> {
+    self
+      .check_scalar_cmd(msg)
+      .map_err(|err| self.label_error(err))?;
+    let mut result: Vec<u32> = self
+      .scalars
+      .iter()
+      .map(|x| x.value().load(SeqCst))
+      .collect();
+    for scalar_command in msg.scalars() {
+      let index = scalar_command.index() as usize;
+      result[index] =
+        self.ramp_scalar_step(index, self.scalar_to_step(index, scalar_command.scalar()));
+    }
+    Ok(result)
+  }
+
+  /// Convert a ScalarCmd to steps, store them, and return the steps that need to be sent, by
+  /// feature index. Returns an empty vec if nothing changed.
+  ///
+  /// Without `match_all`, only actuators whose step changed are Some. With it, any change fills in
+  /// every actuator, for protocols that have to set all of them in one packet. Actuators the command
+  /// leaves out hold their last sent step, they are never zeroed, so a partial command only changes
+  /// what it mentions. Actuators that have never been commanded are at 0.
+  pub fn update_scalar(
+    &self,
+    msg: &ScalarCmd,
+    match_all: bool,
+  ) -> Result<Vec<Option<(ActuatorType, u32)>>, ButtplugError> {
+    self
+      .check_scalar_cmd(msg)
+      .map_err(|err| self.label_error(err))?;
+    self.mark_update();
+
+    // Now we convert from the generic 0.0-1.0 range to the StepCount
+    // attribute given by the device config.
+
+    // If we've already sent commands before, we should check against our
+    // old values. Otherwise, we should always send whatever command we're
+    // going to send.
+    let mut result: Vec<Option<(ActuatorType, u32)>> = vec![None; self.scalars.len()];
+    let sent_scalar = self.sent_scalar.load(SeqCst);
+    let resend = !sent_scalar || self.resend_required();
+
+    for scalar_command in msg.scalars() {
+      let index = scalar_command.index() as usize;
+      let scalar = self.scalar_to_step(index, scalar_command.scalar());
+      trace!(
+        "{:?} {} {}",
+        self.scalars[index].step_range(),
+        scalar_command.scalar(),
+        scalar
+      );
+      self.store_scalar_step(index, scalar, resend, &mut result);
+    }
+    self.mark_scalar_sent(sent_scalar, &result);
+
+    // Return the command vector for the protocol to turn into proprietary commands
+    Ok(self.finish_scalar_result(result, match_all))
+  }
+
+  /// Same as [update_scalar](Self::update_scalar), but if a [coalesce
+  /// window](Self::set_coalesce_window) is set, updates are held until the window closes and only
+  /// the latest value for each actuator is used. The call that opens the window resolves with the
+  /// combined result once it closes. Calls that land inside an open window are merged into it and
+  /// resolve immediately with an empty result, as there's nothing for them to send. If the opening
+  /// call is dropped before the window closes, the next call after it should have closed takes the
+  /// held values over, so they aren't lost or stuck. Without a window, this is the same as
+  /// update_scalar.
+  pub async fn update_scalar_coalesced(
+    &self,
+    msg: &ScalarCmd,
+    match_all: bool,
+  ) -> Result<Vec<Option<(ActuatorType, u32)>>, ButtplugError> {
+    let Some(window) = self.coalesce_window else {
+      return self.update_scalar(msg, match_all);
+    };
+    // Invalid commands are rejected up front, so they can't spoil the rest of the window.
+    self
+      .check_scalar_cmd(msg)
+      .map_err(|err| self.label_error(err))?;
+    let window_id = {
+      let mut state = self
+        .pending_scalars
+        .lock()
+        .expect("Lock is never held across a panic");
+      let now = Instant::now();
+      if let Some(open_window) = state
+        .open_window
+        .as_mut()
+        .filter(|open_window| open_window.closes_at > now)
+      {
+        for cmd in msg.scalars() {
+          open_window.values[cmd.index() as usize] = Some(cmd.clone());
+        }
+        return Ok(vec![]);
+      }
+      // Either nothing is open, or the window is past closing and whoever opened it is gone (or
+      // about to find out it's too late), so this call opens a new one, keeping anything held.
+      state.window_count += 1;
+      let id = state.window_count;
+      let scalar_count = self.scalars.len();
+      let open_window = state.open_window.get_or_insert_with(|| CoalesceWindow {
+        id,
+        device_index: msg.device_index(),
+        closes_at: now,
+        values: vec![None; scalar_count],
+      });
+      open_window.id = id;
+      open_window.device_index = msg.device_index();
+      open_window.closes_at = now + window;
+      for cmd in msg.scalars() {
+        open_window.values[cmd.index() as usize] = Some(cmd.clone());
+      }
+      id
+    };
+    sleep(window).await;
+    let closed_window = {
+      let mut state = self
+        .pending_scalars
+        .lock()
+        .expect("Lock is never held across a panic");
+      if state
+        .open_window
+        .as_ref()
+        .is_some_and(|open_window| open_window.id == window_id)
+      {
+        state.open_window.take()
+      } else {
+        None
+      }
+    };
+    // Taken over by a later call, or cleared by a reset.
+    let Some(closed_window) = closed_window else {
+      return Ok(vec![]);
+    };
+    self.update_scalar(
+      &ScalarCmd::new(
+        closed_window.device_index,
+        closed_window.values.into_iter().flatten().collect(),
+      ),
+      match_all,
+    )
+  }
+
+  /// Same as [update_scalar](Self::update_scalar), but takes values that are already device steps,
+  /// skipping the 0.0-1.0 conversion. Values are clamped to the top of each actuator's step range
+  /// (or the highest step allowed by the [intensity cap](Self::set_intensity_cap)),
+  /// and None leaves an actuator as is. Cached state is updated the same way as normalized commands,
+  /// so later commands of either kind are still deduplicated correctly.
+  pub fn update_scalar_raw(
+    &self,
+    values: &[Option<u32>],
+    match_all: bool,
+  ) -> Result<Vec<Option<(ActuatorType, u32)>>, ButtplugError> {
+    if values.len() > self.scalars.len() {
+      return Err(
+        self
+          .label_device_error(ButtplugDeviceError::DeviceFeatureCountMismatch(
+            self.scalars.len() as u32,
+            values.len() as u32,
+          ))
+          .into(),
+      );
+    }
+    self.mark_update();
+
+    let mut result: Vec<Option<(ActuatorType, u32)>> = vec![None; self.scalars.len()];
+    let sent_scalar = self.sent_scalar.load(SeqCst);
+    let resend = !sent_scalar || self.resend_required();
+    for (index, value) in values.iter().enumerate() {
+      if let Some(value) = value {
+        let scalar = (*value).min(self.scalar_to_step(index, 1.0));
+        self.store_scalar_step(index, scalar, resend, &mut result);
+      }
+    }
+    self.mark_scalar_sent(sent_scalar, &result);
+    Ok(self.finish_scalar_result(result, match_all))
+  }
+
+  fn store_scalar_step(
+    &self,
+    index: usize,
+    scalar: u32,
+    resend: bool,
+    result: &mut [Option<(ActuatorType, u32)>],
+  ) {
+    // If we've already sent commands, we don't want to send them again,
+    // because some of our communication busses are REALLY slow. Make sure
+    // these values get None in our return vector. `resend` is also set
+    // before the first command, so every subcommand in it goes out.
+    let current_scalar = self.scalars[index].value().load(SeqCst);
+    let scalar = self.ramp_scalar_step(index, scalar);
+    if resend || scalar != current_scalar {
+      self.scalars[index].value().store(scalar, SeqCst);
+      result[index] = Some((*self.scalars[index].actuator(), scalar));
+    }
+  }
+
+  // Only flip sent_scalar once the whole command has been stored, so it can't stop later
+  // subcommands of the first command from being sent.
+  fn mark_scalar_sent(&self, sent_scalar: bool, result: &[Option<(ActuatorType, u32)>]) {
+    if !sent_scalar && result.iter().any(Option::is_some) {
+      self.sent_scalar.store(true, SeqCst);
+    }
+  }
+
+  fn finish_scalar_result(
+    &self,
+    mut result: Vec<Option<(ActuatorType, u32)>>,
+    match_all: bool,
+  ) -> Vec<Option<(ActuatorType, u32)>> {
+    let held_back = match &self.scalar_rate_limit {
+      Some(limit) => match limit.check(result.iter().any(|x| x.is_some())) {
+        Some(held_back) => held_back,
+        None => {
+          result.clear();
+          return result;
+        }
+      },
+      None => false,
+    };
+    // If we have no changes to the device, just send back an empty command array. We have nothing
+    // to do.
+    if result.iter().all(|x| x.is_none()) && !held_back {
+      result.clear();
+      return result;
+    }
+    self.mark_command_emitted();
+    if match_all || held_back || !self.dedup_enabled {
+      // If we're in a match all situation, set up the array with all prior
+      // values before switching them out.
+      for (index, cmd) in self.scalars.iter().enumerate() {
+        if result[index].is_none() {
+          result[index] = Some((*cmd.actuator(), cmd.value.load(SeqCst)));
+        }
+      }
+    }
+    result
+  }
+
+  /// Same as [update_scalar](Self::update_scalar), but also returns the 0.0-1.0 value the device
+  /// will actually run at after being quantized to a step, so UIs can snap to reachable values.
+  pub fn update_scalar_detailed(
+    &self,
+    msg: &ScalarCmd,
+    match_all: bool,
+  ) -> Result<Vec<Option<DetailedScalarStep>>, ButtplugError> {
+    let result = self.update_scalar(msg, match_all)?;
+    Ok(
+      result
+        .iter()
+        .enumerate()
+        .map(|(index, cmd)| {
+          cmd.map(|(actuator, step)| {
+            (
+              actuator,
+              step,
+              self.convert_from_step(step, self.scalars[index].step_range()),
+            )
+          })
+        })
+        .collect(),
+    )
+  }
+
+  // Inverse of convert_to_step, for turning stored steps back into 0.0-1.0 values. 0 is always
+  // stopped, anything else is placed within the step range.
+  fn convert_from_step(&self, step: u32, range: &RangeInclusive<u32>) -> f64 {
+    let width = self.step_span(range);
+    if step == 0 {
+      0.0
+    } else if width == 0 {
+      1.0
+    } else {
+      (step.saturating_sub(*range.start()) as f64 / width as f64).min(1.0)
+    }
+  }
+
+  // Test method
+  #[cfg(test)]
+  pub(super) fn scalars(&self) -> Vec<Option<(ActuatorType, u32)>> {
+    self
+      .scalars
+      .iter()
+      .map(|x| Some((*x.actuator(), x.value().load(SeqCst))))
+      .collect()
+  }
+
+  /// Rotation version of [update_scalar](Self::update_scalar), returning speed steps and directions.
+  /// `match_all` works the same way, so rotators left out of a command hold their last speed and
+  /// direction.
+  pub fn update_rotation(
+    &self,
+    msg: &RotateCmd,
+    match_all: bool,
+  ) -> Result<Vec<Option<(u32, bool)>>, ButtplugError> {
+    self
+      .check_rotate_cmd(msg)
+      .map_err(|err| self.label_error(err))?;
+    self.mark_update();
 
     // Now we convert from the generic 0.0-1.0 range to the StepCount
     // attribute given by the device config.
 
-    // If we've already sent commands, we don't want to send them again,
-    // because some of our communication busses are REALLY slow. Make sure
-    // these values get None in our return vector.
+    // If we've already sent commands before, we should check against our
+    // old values. Otherwise, we should always send whatever command we're
+    // going to send.
+    let mut result: Vec<Option<(u32, bool)>> = vec![None; self.rotations.len()];
+    let resend = self.resend_required();
+    for rotate_command in msg.rotations() {
+      let index = rotate_command.index() as usize;
+      // Disabled rotators hold their last direction too, so they never produce new commands once
+      // they've stopped.
+      let (speed, clockwise) = if self.rotation_enabled[index] {
+        let speed =
+          self.convert_speed_to_step(rotate_command.speed(), &self.rotation_step_ranges[index]);
+        // Direction doesn't mean anything for a stopped rotator, so keep whatever we last sent
+        // instead of flipping it. Otherwise devices that take direction as a separate command
+        // (Lovense) would get a spurious direction change every time they're stopped.
+        let clockwise = if speed == 0 {
+          self.rotations[index].1.load(SeqCst)
+        } else {
+          rotate_command.clockwise()
+        };
+        (speed, clockwise)
+      } else {
+        (0, self.rotations[index].1.load(SeqCst))
+      };
+      // If we've already sent commands, we don't want to send them again,
+      // because some of our communication busses are REALLY slow. Make sure
+      // these values get None in our return vector.
+      let sent_rotation = self.sent_rotation.load(SeqCst);
+      if !sent_rotation
+        || resend
+        || speed != self.rotations[index].0.load(SeqCst)
+        || clockwise != self.rotations[index].1.load(SeqCst)
+      {
+        self.rotations[index].0.store(speed, SeqCst);
+        self.rotations[index].1.store(clockwise, SeqCst);
+        result[index] = Some((speed, clockwise));
+      }
+      if !sent_rotation {
+        self.sent_rotation.store(true, SeqCst);
+      }
+    }
+
+    let held_back = match &self.rotation_rate_limit {
+      Some(limit) => match limit.check(result.iter().any(|x| x.is_some())) {
+        Some(held_back) => held_back,
+        None => return Ok(vec![None; self.rotations.len()]),
+      },
+      None => false,
+    };
+
+    // If we're in a match all situation, set up the array with all prior
+    // values before switching them out.
+    let emitted = held_back || !result.iter().all(|x| x.is_none());
+    if emitted {
+      self.mark_command_emitted();
+    }
+    if (match_all || held_back || !self.dedup_enabled) && emitted {
+      for (index, rotation) in self.rotations.iter().enumerate() {
+        if result[index].is_none() {
+          result[index] = Some((rotation.0.load(SeqCst), rotation.1.load(SeqCst)));
+        }
+      }
+    }
+
+    // Return the command vector for the protocol to turn into proprietary commands
+    Ok(result)
+  }
+
+  pub fn update_linear(
+    &self,
+    msg: &LinearCmd,
+    match_all: bool,
+  ) -> Result<Vec<Option<(u32, u32)>>, ButtplugError> {
+    self
+      .check_linear_cmd(msg)
+      .map_err(|err| self.label_error(err))?;
+    self.mark_update();
+
+    // If we've already sent commands before, we should check against our
+    // old values. Otherwise, we should always send whatever command we're
+    // going to send.
+    let mut result: Vec<Option<(u32, u32)>> = vec![None; self.linears.len()];
+    let resend = self.resend_required();
+    for vector_command in msg.vectors() {
+      let index = vector_command.index() as usize;
+      // Positions map 0.0 to the bottom of the step range instead of 0, since
+      // there's no "off" for a position. Duration is passed through as is, in
+      // milliseconds.
+      let step_range = &self.linear_step_ranges[index];
+      let position =
+        self.convert_to_step(vector_command.position(), step_range, *step_range.start());
+      let mut duration = vector_command.duration();
+      if duration > self.max_linear_duration {
+        warn!(
+          "LinearCmd duration {}ms for index {} is over the maximum of {}ms, clamping.",
+          duration, index, self.max_linear_duration
+        );
+        duration = self.max_linear_duration;
+      }
+      // If we've already sent commands, we don't want to send them again,
+      // because some of our communication busses are REALLY slow. Make sure
+      // these values get None in our return vector.
+      let sent_linear = self.sent_linear.load(SeqCst);
+      if !sent_linear
+        || resend
+        || duration != self.linears[index].0.load(SeqCst)
+        || position != self.linears[index].1.load(SeqCst)
+      {
+        self.linears[index].0.store(duration, SeqCst);
+        self.linears[index].1.store(position, SeqCst);
+        result[index] = Some((duration, position));
+      }
+      if !sent_linear {
+        self.sent_linear.store(true, SeqCst);
+      }
+    }
+
+    let held_back = match &self.linear_rate_limit {
+      Some(limit) => match limit.check(result.iter().any(|x| x.is_some())) {
+        Some(held_back) => held_back,
+        None => return Ok(vec![None; self.linears.len()]),
+      },
+      None => false,
+    };
+
+    // If we're in a match all situation, set up the array with all prior
+    // values before switching them out.
+    let emitted = held_back || !result.iter().all(|x| x.is_none());
+    if emitted {
+      self.mark_command_emitted();
+    }
+    if (match_all || held_back || !self.dedup_enabled) && emitted {
+      for (index, linear) in self.linears.iter().enumerate() {
+        if result[index].is_none() {
+          result[index] = Some((linear.0.load(SeqCst), linear.1.load(SeqCst)));
+        }
+      }
+    }
+
+    // Return the command vector for the protocol to turn into proprietary commands
+    Ok(result)
+  }
+
+  /// Turn a ScalarCmd into one full stroke on each linear actuator with the same index, for devices
+  /// that only have linear actuators. Speed sets the stroke frequency. The returned commands move
+  /// to the top, then back to the bottom, and should be scheduled in order, each once the previous
+  /// one's duration has passed, repeating until the speed changes. 0.0 speeds are left out, so a
+  /// stop returns no commands. Requires [linear scalar
+  /// emulation](Self::set_linear_scalar_emulation) to be on.
+  pub fn update_scalar_as_linear(&self, msg: &ScalarCmd) -> Result<Vec<LinearCmd>, ButtplugError> {
+    if !self.linear_scalar_emulation {
+      return Err(
+        self
+          .label_device_error(ButtplugDeviceError::MessageNotSupported(
+            ButtplugDeviceMessageType::ScalarCmd,
+          ))
+          .into(),
+      );
+    }
+    if msg.scalars().is_empty() {
+      return Err(
+        self
+          .label_device_error(ButtplugDeviceError::EmptyCommand(
+            ButtplugDeviceMessageType::ScalarCmd,
+          ))
+          .into(),
+      );
+    }
+    let mut up_vectors = vec![];
+    let mut down_vectors = vec![];
+    for cmd in msg.scalars() {
+      if !(0.0..=1.0).contains(&cmd.scalar()) {
+        return Err(
+          self
+            .label_device_error(ButtplugDeviceError::ProtocolRequirementError(format!(
+              "ScalarCmd value {} for index {} is invalid, should be between 0.0 and 1.0.",
+              cmd.scalar(),
+              cmd.index()
+            )))
+            .into(),
+        );
+      }
+      if cmd.index() as usize >= self.linears.len() {
+        return Err(
+          self
+            .label_device_error(ButtplugDeviceError::IndexOutOfRange {
+              requested: cmd.index(),
+              available: self.linears.len(),
+            })
+            .into(),
+        );
+      }
+      if cmd.scalar() == 0.0 {
+        continue;
+      }
+      let duration = EMULATED_STROKE_MAX_DURATION_MS
+        - (cmd.scalar()
+          * (EMULATED_STROKE_MAX_DURATION_MS - EMULATED_STROKE_MIN_DURATION_MS) as f64)
+          .round() as u32;
+      up_vectors.push(VectorSubcommand::new(cmd.index(), duration, 1.0));
+      down_vectors.push(VectorSubcommand::new(cmd.index(), duration, 0.0));
+    }
+    if up_vectors.is_empty() {
+      return Ok(vec![]);
+    }
+    Ok(vec![
+      LinearCmd::new(0, up_vectors),
+      LinearCmd::new(0, down_vectors),
+    ])
+  }
+
+  pub fn stop_commands(&self) -> Vec<ButtplugDeviceCommandMessageUnion> {
+    self
+      .stop_commands
+      .iter()
+      .map(|x| self.refresh_stop_command(x))
+      .collect()
+  }
+
+  /// Stop only the scalar actuators that are currently running, for protocols that want to pause a
+  /// device with as few writes as possible. Returns a step of 0 for each running actuator and None
+  /// for ones that are already stopped, in the same shape as [update_scalar](Self::update_scalar),
+  /// and stores the stop as sent.
+  pub fn stop_delta(&self) -> Vec<Option<(ActuatorType, u32)>> {
+    let result: Vec<Option<(ActuatorType, u32)>> = self
+      .scalars
+      .iter()
+      .map(|scalar| {
+        if scalar.value().swap(0, SeqCst) != 0 {
+          Some((*scalar.actuator(), 0))
+        } else {
+          None
+        }
+      })
+      .collect();
+    if result.iter().any(|x| x.is_some()) {
+      self.mark_command_emitted();
+    }
+    result
+  }
+
+  /// Ramp every running scalar actuator down to a stop over `steps` increments, instead of cutting
+  /// power all at once. Returns one entry per increment, meant to be played at a fixed interval,
+  /// each holding the step to send by scalar index, or None for actuators that don't change in that
+  /// increment. Steps before the last stay within each actuator's step range, dropping towards the
+  /// bottom of it, and the last entry takes every running actuator to 0. Returns nothing if all
+  /// actuators are already stopped.
+  ///
+  /// Stored values follow the ramp, so they're left at 0 once this returns. If the ramp is cut
+  /// short, the next update should be sent with resend set.
+  pub fn soft_stop(&self, steps: u32) -> Vec<Vec<Option<u32>>> {
+    let steps = steps.max(1) as u64;
+    let starts: Vec<u64> = self
+      .scalars
+      .iter()
+      .map(|scalar| scalar.value().load(SeqCst) as u64)
+      .collect();
+    if starts.iter().all(|start| *start == 0) {
+      return vec![];
+    }
+    let ramp = (1..=steps)
+      .map(|increment| {
+        starts
+          .iter()
+          .zip(self.scalars.iter())
+          .map(|(start, scalar)| {
+            // Steps under the bottom of the range won't run the actuator, so only 0 goes below it.
+            let bottom = (*scalar.step_range().start() as u64).min(*start);
+            let step = if increment == steps {
+              0
+            } else {
+              (start - (start - bottom) * increment / steps) as u32
+            };
+            if scalar.value().swap(step, SeqCst) != step {
+              Some(step)
+            } else {
+              None
+            }
+          })
+          .collect()
+      })
+      .collect();
+    self.mark_command_emitted();
+    ramp
+  }
+
+  /// Plan a ramp of the vibrators from their current speeds to `target` (indexed by vibrator, with
+  /// vibrators past the end of it left alone) over `duration`. The ramp moves one device step at a
+  /// time for whichever vibrator has furthest to go, with the others moving in proportion, so no
+  /// command repeats a speed a vibrator is already at and there are never more commands than the
+  /// device can tell apart. Stopped vibrators ramp from, and stopping ones ramp to, the bottom of
+  /// their step range, as steps under it won't run them. Commands are spread evenly across the
+  /// duration, the last one landing on the target at the end of it.
+  ///
+  /// Nothing is stored. Each command should be passed to [update](Self::update) when its time
+  /// comes, which also means a ramp can be abandoned at any point by sending something else.
+  pub fn update_vibration_ramp(
+    &self,
+    target: &[f64],
+    duration: Duration,
+  ) -> Result<RampPlan, ButtplugError> {
+    let target_cmd = VibrateCmd::new(
+      0,
+      target
+        .iter()
+        .enumerate()
+        .map(|(index, speed)| VibrateSubcommand::new(index as u32, *speed))
+        .collect(),
+    );
+    self.check_vibrate_cmd(&target_cmd)?;
+    let vibrator_indexes = self.vibrator_indexes();
+    // For each vibrator that has to move: its vibrator index, scalar index, and start and end
+    // steps, with stops moved up to the bottom of the step range for interpolating between.
+    let ramps: Vec<(usize, usize, i64, i64)> = target
+      .iter()
+      .enumerate()
+      .filter_map(|(vibrator, speed)| {
+        let index = vibrator_indexes[vibrator] as usize;
+        let start = self.scalars[index].value().load(SeqCst);
+        let end = self.scalar_to_step(index, *speed);
+        let range = self.scalars[index].step_range();
+        let in_range = |step: u32| {
+          if step == 0 {
+            *range.start()
+          } else {
+            step.clamp(*range.start(), *range.end())
+          }
+        };
+        (start != end).then(|| {
+          (
+            vibrator,
+            index,
+            in_range(start) as i64,
+            in_range(end) as i64,
+          )
+        })
+      })
+      .collect();
+    // Going between a stop and the bottom of the range is still a change, even with no steps
+    // between them.
+    let increments = ramps
+      .iter()
+      .map(|(_, _, start, end)| (end - start).unsigned_abs())
+      .max()
+      .map(|increments| increments.max(1))
+      .unwrap_or(0);
+    // Last speed sent to each vibrator being ramped, so steps that convert to the same speed (such
+    // as the bottom of the range, which converts to 0.0) aren't sent twice.
+    let mut last_speeds: Vec<f64> = ramps
+      .iter()
+      .map(|(_, index, ..)| {
+        let range = self.scalars[*index].step_range();
+        self.convert_step_to_speed(self.scalars[*index].value().load(SeqCst), range)
+      })
+      .collect();
+    let mut commands = vec![];
+    for increment in 1..=increments {
+      let speeds: Vec<VibrateSubcommand> = ramps
+        .iter()
+        .zip(last_speeds.iter_mut())
+        .filter_map(|((vibrator, index, start, end), last_speed)| {
+          // The last command uses the target as given, in case converting back from the step lands
+          // on a slightly different speed.
+          let speed = if increment == increments {
+            target[*vibrator]
+          } else {
+            let step = start + (end - start) * increment as i64 / increments as i64;
+            self.convert_step_to_speed(step as u32, self.scalars[*index].step_range())
+          };
+          if speed == *last_speed {
+            return None;
+          }
+          *last_speed = speed;
+          Some(VibrateSubcommand::new(*vibrator as u32, speed))
+        })
+        .collect();
+      if !speeds.is_empty() {
+        commands.push((
+          duration * increment as u32 / increments as u32,
+          VibrateCmd::new(0, speeds),
+        ));
+      }
+    }
+    Ok(RampPlan { commands })
+  }
+
+  /// Stop message for all scalar actuators (vibrators, oscillators, etc), if the device has any.
+  pub fn scalar_stop_command(&self) -> Option<ButtplugDeviceCommandMessageUnion> {
+    self
+      .stop_commands
+      .iter()
+      .find(|x| matches!(x, ButtplugDeviceCommandMessageUnion::ScalarCmd(_)))
+      .cloned()
+  }
+
+  /// Stop message for all rotators, if the device has any.
+  pub fn rotate_stop_command(&self) -> Option<ButtplugDeviceCommandMessageUnion> {
+    self
+      .stop_commands
+      .iter()
+      .find(|x| matches!(x, ButtplugDeviceCommandMessageUnion::RotateCmd(_)))
+      .map(|x| self.refresh_stop_command(x))
+  }
+
+  /// Stop message for all linear actuators. Always None for now, as a linear movement ends on its
+  /// own once the device reaches the requested position, so no stop command is generated for it.
+  pub fn linear_stop_command(&self) -> Option<ButtplugDeviceCommandMessageUnion> {
+    self
+      .stop_commands
+      .iter()
+      .find(|x| matches!(x, ButtplugDeviceCommandMessageUnion::LinearCmd(_)))
+      .cloned()
+  }
+
+  // Speeds are scaled by the intensity cap on the way in, so undo that too.
+  fn convert_step_to_speed(&self, step: u32, range: &RangeInclusive<u32>) -> f64 {
+    let value = self.convert_from_step(step, range);
+    let value = if self.intensity_cap > 0.0 {
+      (value / self.intensity_cap).min(1.0)
+    } else {
+      value
+    };
+    value.powf(1.0 / self.speed_curve)
+  }
+
+  /// Commands that put the device back in its current state, built from what's been stored rather
+  /// than what's changed, for keepalive timers on devices that stop if they aren't sent commands
+  /// regularly. Only covers actuator types that have been sent a command, so a device that's never
+  /// been told to do anything gets nothing.
+  pub fn refresh_commands(&self) -> Vec<ButtplugDeviceCommandMessageUnion> {
+    let mut commands = vec![];
+    if self.sent_scalar.load(SeqCst) {
+      let subcommands = self
+        .scalars
+        .iter()
+        .enumerate()
+        .map(|(index, scalar)| {
+          ScalarSubcommand::new(
+            index as u32,
+            self.convert_step_to_speed(scalar.value().load(SeqCst), scalar.step_range()),
+            *scalar.actuator(),
+          )
+        })
+        .collect();
+      commands.push(ScalarCmd::new(0, subcommands).into());
+    }
+    if self.sent_rotation.load(SeqCst) {
+      let subcommands = self
+        .rotations
+        .iter()
+        .zip(self.rotation_step_ranges.iter())
+        .enumerate()
+        .map(|(index, ((speed, clockwise), range))| {
+          RotationSubcommand::new(
+            index as u32,
+            self.convert_step_to_speed(speed.load(SeqCst), range),
+            clockwise.load(SeqCst),
+          )
+        })
+        .collect();
+      commands.push(RotateCmd::new(0, subcommands).into());
+    }
+    if self.sent_linear.load(SeqCst) {
+      let subcommands = self
+        .linears
+        .iter()
+        .zip(self.linear_step_ranges.iter())
+        .enumerate()
+        .map(|(index, ((duration, position), range))| {
+          VectorSubcommand::new(
+            index as u32,
+            duration.load(SeqCst),
+            self.convert_from_step(position.load(SeqCst), range),
+          )
+        })
+        .collect();
+      commands.push(LinearCmd::new(0, subcommands).into());
+    }
+    commands
+  }
+
+  /// Cache the latest reading from a device sensor, so protocols that decode readings from
+  /// subscription notifications have somewhere to answer later sensor reads from.
+  pub fn update_sensor(&self, sensor_type: SensorType, value: i32) {
+    let mut readings = self
+      .sensor_readings
+      .lock()
+      .expect("Lock is never held across a panic");
+    match readings
+      .iter_mut()
+      .find(|(cached_type, _)| *cached_type == sensor_type)
+    {
+      Some((_, cached_value)) => *cached_value = value,
+      None => readings.push((sensor_type, value)),
+    }
+  }
+
+  /// Latest reading cached by [update_sensor](Self::update_sensor) for a sensor type, if any.
+  pub fn last_sensor(&self, sensor_type: SensorType) -> Option<i32> {
+    self
+      .sensor_readings
+      .lock()
+      .expect("Lock is never held across a panic")
+      .iter()
+      .find(|(cached_type, _)| *cached_type == sensor_type)
+      .map(|(_, value)| *value)
+  }
+
+  /// Regenerate the stop commands returned by [stop_commands](Self::stop_commands) from the
+  /// current feature set.
+  pub fn rebuild_stop_commands(&mut self) {
+    self.stop_commands.clear();
+    // Features configured with no actuators get no stop command, rather than an empty one.
+    if !self.scalars.is_empty() {
+      let subcommands = self
+        .scalars
+        .iter()
+        .enumerate()
+        .map(|(index, scalar)| ScalarSubcommand::new(index as u32, 0.0, *scalar.actuator()))
+        .collect();
+      self
+        .stop_commands
+        .push(ScalarCmd::new(0, subcommands).into());
+    }
+    if self.has_rotators() {
+      // Direction is ignored for 0.0 speeds in update_rotation, but protocols and clients can see
+      // these too, so use the configured default instead of picking one.
+      let subcommands = (0..self.rotations.len())
+        .map(|index| RotationSubcommand::new(index as u32, 0.0, self.stop_clockwise))
+        .collect();
+      self
+        .stop_commands
+        .push(RotateCmd::new(0, subcommands).into());
+    }
+  }
+
+  /// Replace the feature set with a new device configuration, for devices whose features can
+  /// change between connections (e.g. after a firmware update). Stored command state and disabled
+  /// actuators are reset, while behavior options like the rounding mode are kept.
+  pub fn reconfigure(
+    &mut self,
+    attributes: &ProtocolDeviceAttributes,
+  ) -> Result<(), ButtplugDeviceError> {
+    let mut builder = GenericCommandManagerBuilder::new(attributes);
+    builder
+      .rounding_mode(self.rounding_mode)
+      .min_nonzero_step(self.preserve_nonzero_speed)
+      .max_linear_duration(self.max_linear_duration)
+      .intensity_cap(self.intensity_cap)
+      .step_inclusive(self.step_inclusive);
+    if let Some(keepalive) = self.command_keepalive {
+      builder.command_keepalive(keepalive);
+    }
+    if let Some(device_label) = &self.device_label {
+      builder.device_label(device_label);
+    }
+    let max_step_delta = self.max_step_delta;
+    let coalesce_window = self.coalesce_window;
+    let linear_scalar_emulation = self.linear_scalar_emulation;
+    let dedup_enabled = self.dedup_enabled;
+    let stop_clockwise = self.stop_clockwise;
+    let speed_curve = self.speed_curve;
+    let inactivity_timeout = self.inactivity_timeout;
+    let vibration_queue_depth = self.vibration_queue_depth;
+    let rate_limits = [
+      ButtplugDeviceMessageType::ScalarCmd,
+      ButtplugDeviceMessageType::RotateCmd,
+      ButtplugDeviceMessageType::LinearCmd,
+    ]
+    .map(|msg_type| (msg_type, self.rate_limit(msg_type)));
+    *self = builder.finish()?;
+    self.max_step_delta = max_step_delta;
+    self.coalesce_window = coalesce_window;
+    self.linear_scalar_emulation = linear_scalar_emulation;
+    self.dedup_enabled = dedup_enabled;
+    self.set_stop_clockwise(stop_clockwise);
+    self.speed_curve = speed_curve;
+    self.inactivity_timeout = inactivity_timeout;
+    self.vibration_queue_depth = vibration_queue_depth;
+    for (msg_type, min_interval) in rate_limits {
+      if let Some(min_interval) = min_interval {
+        self.set_rate_limit(msg_type, min_interval)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Build a stop message that only targets a single feature, leaving any other features on the
+  /// device running. Returns None if the message type has no stop command, or the index is out of
+  /// range for the message type.
+  pub fn stop_command_for(
+    &self,
+    msg_type: ButtplugDeviceMessageType,
+    index: u32,
+  ) -> Option<ButtplugDeviceCommandMessageUnion> {
+    match msg_type {
+      ButtplugDeviceMessageType::ScalarCmd => {
+        let scalar = self.scalars.get(index as usize)?;
+        Some(
+          ScalarCmd::new(
+            0,
+            vec![ScalarSubcommand::new(index, 0.0, *scalar.actuator())],
+          )
+          .into(),
+        )
+      }
+      // VibrateCmd indexes only count vibrators, not every scalar actuator.
+      ButtplugDeviceMessageType::VibrateCmd => {
+        let vibrator_count = self
+          .scalars
+          .iter()
+          .filter(|x| *x.actuator() == ActuatorType::Vibrate)
+          .count();
+        if (index as usize) < vibrator_count {
+          Some(VibrateCmd::new(0, vec![VibrateSubcommand::new(index, 0.0)]).into())
+        } else {
+          None
+        }
+      }
+      ButtplugDeviceMessageType::RotateCmd => {
+        if (index as usize) < self.rotations.len() {
+          Some(
+            RotateCmd::new(
+              0,
+              vec![RotationSubcommand::new(
+                index,
+                0.0,
+                self.rotation_stop_direction(index as usize),
+              )],
+            )
+            .into(),
+          )
+        } else {
+          None
+        }
+      }
+      _ => None,
+    }
+  }
+
+  pub fn state_snapshot(&self) -> CommandManagerState {
+    CommandManagerState {
+      sent_scalar: self.sent_scalar.load(SeqCst),
+      sent_rotation: self.sent_rotation.load(SeqCst),
+      sent_linear: self.sent_linear.load(SeqCst),
+      scalars: self
+        .scalars
+        .iter()
+        .map(|x| x.value().load(SeqCst))
+        .collect(),
+      rotations: self
+        .rotations
+        .iter()
+        .map(|(speed, clockwise)| (speed.load(SeqCst), clockwise.load(SeqCst)))
+        .collect(),
+      linears: self
+        .linears
+        .iter()
+        .map(|(duration, position)| (duration.load(SeqCst), position.load(SeqCst)))
+        .collect(),
+    }
+  }
+
+  /// Load state from [state_snapshot](Self::state_snapshot), after which commands are deduplicated
+  /// as if the snapshotted values were the last ones sent. Fails without changing anything if the
+  /// snapshot doesn't match this manager's feature counts, or has steps outside of the current step
+  /// ranges (0, for stopped or never sent, is always allowed).
+  pub fn restore_state(&self, state: &CommandManagerState) -> Result<(), ButtplugDeviceError> {
+    for (count, state_count) in [
+      (self.scalars.len(), state.scalars.len()),
+      (self.rotations.len(), state.rotations.len()),
+      (self.linears.len(), state.linears.len()),
+    ] {
+      if count != state_count {
+        return Err(
+          self.label_device_error(ButtplugDeviceError::DeviceFeatureCountMismatch(
+            count as u32,
+            state_count as u32,
+          )),
+        );
+      }
+    }
+    let steps = self
+      .scalars
+      .iter()
+      .map(|scalar| scalar.step_range())
+      .zip(&state.scalars)
+      .map(|(range, value)| ("scalar", range, *value))
+      .chain(
+        self
+          .rotation_step_ranges
+          .iter()
+          .zip(&state.rotations)
+          .map(|(range, (speed, _))| ("rotation", range, *speed)),
+      )
+      .chain(
+        self
+          .linear_step_ranges
+          .iter()
+          .zip(&state.linears)
+          .map(|(range, (_, position))| ("linear", range, *position)),
+      );
+    for (feature_type, range, value) in steps {
+      if value != 0 && !range.contains(&value) {
+        return Err(
+          self.label_device_error(ButtplugDeviceError::DeviceConfigurationError(format!(
+            "Saved {} step {} is outside of the current step range {:?}.",
+            feature_type, value, range
+          ))),
+        );
+      }
+    }
+    for (scalar, value) in self.scalars.iter().zip(&state.scalars) {
+      scalar.value().store(*value, SeqCst);
+    }
+    for ((speed, clockwise), (state_speed, state_clockwise)) in
+      self.rotations.iter().zip(&state.rotations)
+    {
+      speed.store(*state_speed, SeqCst);
+      clockwise.store(*state_clockwise, SeqCst);
+    }
+    for ((duration, position), (state_duration, state_position)) in
+      self.linears.iter().zip(&state.linears)
+    {
+      duration.store(*state_duration, SeqCst);
+      position.store(*state_position, SeqCst);
+    }
+    self.sent_scalar.store(state.sent_scalar, SeqCst);
+    self.sent_rotation.store(state.sent_rotation, SeqCst);
+    self.sent_linear.store(state.sent_linear, SeqCst);
+    Ok(())
+  }
+
+  /// Clear all stored command state, so the next command of each type will always be emitted. Used
+  /// when the same manager outlives a device reconnection and cached values can no longer be trusted.
+  /// Queued vibration commands are dropped as well, along with the command history the keepalive,
+  /// inactivity watchdog, coalescing, and rate limits work from.
+  pub fn reset(&self) {
+    self
+      .vibration_queue
+      .lock()
+      .expect("Lock is never held across a panic")
+      .clear();
+    *self
+      .last_command_instant
+      .lock()
+      .expect("Lock is never held across a panic") = None;
+    *self
+      .last_update_instant
+      .lock()
+      .expect("Lock is never held across a panic") = None;
+    self
+      .pending_scalars
+      .lock()
+      .expect("Lock is never held across a panic")
+      .open_window = None;
+    for limit in [
+      &self.scalar_rate_limit,
+      &self.rotation_rate_limit,
+      &self.linear_rate_limit,
+    ]
+    .into_iter()
+    .flatten()
+    {
+      limit.clear();
+    }
+    self.sent_scalar.store(false, SeqCst);
+    self.sent_rotation.store(false, SeqCst);
+    self.sent_linear.store(false, SeqCst);
+    for scalar in &self.scalars {
+      scalar.value().store(0, SeqCst);
+    }
+    for (speed, clockwise) in &self.rotations {
+      speed.store(0, SeqCst);
+      clockwise.store(false, SeqCst);
+    }
+    for (duration, position) in &self.linears {
+      duration.store(0, SeqCst);
+      position.store(0, SeqCst);
+    }
+  }
+
+  /// Watch a hardware event stream, [resetting](Self::reset) whenever the hardware disconnects. A
+  /// disconnected device has stopped, so anything cached from before no longer matches it, and
+  /// would suppress the first commands sent once it's back. Runs until the event stream closes, so
+  /// it's usually spawned alongside the hardware. Managers that don't outlive their hardware don't
+  /// need this.
+  pub async fn reset_on_disconnect(&self, mut event_receiver: broadcast::Receiver<HardwareEvent>) {
+    loop {
+      match event_receiver.recv().await {
+        Ok(HardwareEvent::Disconnected(address, reason)) => {
+          debug!(
+            "Hardware {} disconnected ({:?}), resetting command manager state.",
+            address, reason
+          );
+          self.reset();
+        }
+        Ok(_) => continue,
+        // We may have missed a disconnect, so assume the worst. At most this costs resending a
+        // command the device is already running.
+        Err(broadcast::error::RecvError::Lagged(_)) => self.reset(),
+        Err(broadcast::error::RecvError::Closed) => break,
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{
+    merge_attributes,
+    CommandManagerState,
+    GenericCommandManager,
+    GenericCommandManagerBuilder,
+    GenericCommandResult,
+    Instant,
+    ProtocolDeviceAttributes,
+    RoundingMode,
+  };
+  use crate::{
+    core::{
+      errors::{ButtplugDeviceError, ButtplugError},
+      message::{
+        ActuatorType,
+        ButtplugDeviceMessageType,
+        Endpoint,
+        LinearCmd,
+        RotateCmd,
+        RotationSubcommand,
+        ScalarCmd,
+        ScalarSubcommand,
+        SensorType,
+        SingleMotorVibrateCmd,
+        StopDeviceCmd,
+        VectorSubcommand,
+        VibrateCmd,
+        VibrateSubcommand,
+      },
+    },
+    server::device::{
+      configuration::{ServerDeviceMessageAttributesBuilder, ServerGenericDeviceMessageAttributes},
+      hardware::{test_hardware::TestHardware, Hardware, HardwareEvent},
+      protocol::{aneros::Aneros, ProtocolHandler},
+    },
+    util::async_manager,
+  };
+  use std::{ops::RangeInclusive, sync::Arc, time::Duration};
+
+  #[test]
+  pub fn test_command_generator_vibration() {
+    let scalar_attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &scalar_attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let vibrate_msg = ScalarCmd::new(
+      0,
+      vec![
+        ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
+        ScalarSubcommand::new(1, 0.5, ActuatorType::Vibrate),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![
+        Some((ActuatorType::Vibrate, 10)),
+        Some((ActuatorType::Vibrate, 10))
+      ]
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![]
+    );
+    let vibrate_msg_2 = ScalarCmd::new(
+      0,
+      vec![
+        ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
+        ScalarSubcommand::new(1, 0.75, ActuatorType::Vibrate),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg_2, false)
+        .expect("Test, assuming infallible"),
+      vec![None, Some((ActuatorType::Vibrate, 15))]
+    );
+    let vibrate_msg_invalid = ScalarCmd::new(
+      0,
+      vec![ScalarSubcommand::new(2, 0.5, ActuatorType::Vibrate)],
+    );
+    assert!(mgr.update_scalar(&vibrate_msg_invalid, false).is_err());
+
+    assert_eq!(
+      mgr.scalars(),
+      vec![
+        Some((ActuatorType::Vibrate, 10)),
+        Some((ActuatorType::Vibrate, 15))
+      ]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_vibration_match_all() {
+    let scalar_attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &scalar_attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let vibrate_msg = ScalarCmd::new(
+      0,
+      vec![
+        ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
+        ScalarSubcommand::new(1, 0.5, ActuatorType::Vibrate),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg, true)
+        .expect("Test, assuming infallible"),
+      vec![
+        Some((ActuatorType::Vibrate, 10)),
+        Some((ActuatorType::Vibrate, 10))
+      ]
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg, true)
+        .expect("Test, assuming infallible"),
+      vec![]
+    );
+    let vibrate_msg_2 = ScalarCmd::new(
+      0,
+      vec![
+        ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
+        ScalarSubcommand::new(1, 0.75, ActuatorType::Vibrate),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg_2, true)
+        .expect("Test, assuming infallible"),
+      vec![
+        Some((ActuatorType::Vibrate, 10)),
+        Some((ActuatorType::Vibrate, 15))
+      ]
+    );
+    let vibrate_msg_invalid = ScalarCmd::new(
+      0,
+      vec![ScalarSubcommand::new(2, 0.5, ActuatorType::Vibrate)],
+    );
+    assert!(mgr.update_scalar(&vibrate_msg_invalid, false).is_err());
+
+    assert_eq!(
+      mgr.scalars(),
+      vec![
+        Some((ActuatorType::Vibrate, 10)),
+        Some((ActuatorType::Vibrate, 15))
+      ]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_vibration_step_range() {
+    let mut vibrate_attrs_1 = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(0, 20),
+      ActuatorType::Vibrate,
+    );
+    vibrate_attrs_1.set_step_limit(RangeInclusive::new(10, 15));
+    let mut vibrate_attrs_2 = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(0, 20),
+      ActuatorType::Vibrate,
+    );
+    vibrate_attrs_2.set_step_limit(RangeInclusive::new(10, 20));
+
+    let vibrate_attributes = ServerDeviceMessageAttributesBuilder::default()
+      .scalar_cmd(&[vibrate_attrs_1, vibrate_attrs_2])
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &vibrate_attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let vibrate_msg = ScalarCmd::new(
+      0,
+      vec![
+        ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
+        ScalarSubcommand::new(1, 0.5, ActuatorType::Vibrate),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![
+        Some((ActuatorType::Vibrate, 13)),
+        Some((ActuatorType::Vibrate, 15))
+      ]
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![]
+    );
+    let vibrate_msg_2 = ScalarCmd::new(
+      0,
+      vec![
+        ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
+        ScalarSubcommand::new(1, 0.75, ActuatorType::Vibrate),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg_2, false)
+        .expect("Test, assuming infallible"),
+      vec![None, Some((ActuatorType::Vibrate, 18))]
+    );
+    let vibrate_msg_invalid = ScalarCmd::new(
+      0,
+      vec![ScalarSubcommand::new(2, 0.5, ActuatorType::Vibrate)],
+    );
+    assert!(mgr.update_scalar(&vibrate_msg_invalid, false).is_err());
+
+    assert_eq!(
+      mgr.scalars(),
+      vec![
+        Some((ActuatorType::Vibrate, 13)),
+        Some((ActuatorType::Vibrate, 18))
+      ]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_rotation() {
+    let rotate_attributes = ServerDeviceMessageAttributesBuilder::default()
+      .rotators(2, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &rotate_attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+
+    let rotate_msg = RotateCmd::new(
+      0,
+      vec![
+        RotationSubcommand::new(0, 0.5, true),
+        RotationSubcommand::new(1, 0.5, true),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_rotation(&rotate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((10, true)), Some((10, true))]
+    );
+    assert_eq!(
+      mgr
+        .update_rotation(&rotate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![None, None]
+    );
+    let rotate_msg_2 = RotateCmd::new(
+      0,
+      vec![
+        RotationSubcommand::new(0, 0.5, true),
+        RotationSubcommand::new(1, 0.75, false),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_rotation(&rotate_msg_2, false)
+        .expect("Test, assuming infallible"),
+      vec![None, Some((15, false))]
+    );
+    let rotate_msg_3 = RotateCmd::new(
+      0,
+      vec![
+        RotationSubcommand::new(0, 0.75, false),
+        RotationSubcommand::new(1, 0.75, false),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_rotation(&rotate_msg_3, true)
+        .expect("Test, assuming infallible"),
+      vec![Some((15, false)), Some((15, false))]
+    );
+    let rotate_msg_invalid = RotateCmd::new(0, vec![RotationSubcommand::new(2, 0.5, true)]);
+    assert!(mgr.update_rotation(&rotate_msg_invalid, false).is_err());
+  }
+
+  #[test]
+  pub fn test_command_generator_linear() {
+    let linear_attributes = ServerDeviceMessageAttributesBuilder::default()
+      .linears(2, 100)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &linear_attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+
+    let linear_msg = LinearCmd::new(
+      0,
+      vec![
+        VectorSubcommand::new(0, 500, 0.5),
+        VectorSubcommand::new(1, 500, 0.5),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_linear(&linear_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((500, 50)), Some((500, 50))]
+    );
+    assert_eq!(
+      mgr
+        .update_linear(&linear_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![None, None]
+    );
+    let linear_msg_2 = LinearCmd::new(
+      0,
+      vec![
+        VectorSubcommand::new(0, 500, 0.5),
+        VectorSubcommand::new(1, 250, 0.75),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_linear(&linear_msg_2, false)
+        .expect("Test, assuming infallible"),
+      vec![None, Some((250, 75))]
+    );
+    let linear_msg_3 = LinearCmd::new(0, vec![VectorSubcommand::new(0, 100, 0.75)]);
+    assert_eq!(
+      mgr
+        .update_linear(&linear_msg_3, true)
+        .expect("Test, assuming infallible"),
+      vec![Some((100, 75)), Some((250, 75))]
+    );
+    let linear_msg_invalid = LinearCmd::new(0, vec![VectorSubcommand::new(2, 500, 0.5)]);
+    assert!(mgr.update_linear(&linear_msg_invalid, false).is_err());
+    let linear_msg_empty = LinearCmd::new(0, vec![]);
+    assert!(mgr.update_linear(&linear_msg_empty, false).is_err());
+  }
+
+  #[test]
+  pub fn test_command_generator_step_range_minimum() {
+    let mut vibrate_attrs = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(0, 10),
+      ActuatorType::Vibrate,
+    );
+    vibrate_attrs.set_step_limit(RangeInclusive::new(3, 10));
+    let mut linear_attrs = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(0, 100),
+      ActuatorType::Position,
+    );
+    linear_attrs.set_step_limit(RangeInclusive::new(3, 100));
+
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .scalar_cmd(&[vibrate_attrs])
+      .linear_cmd(&[linear_attrs])
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+
+    // Any nonzero speed should start at the minimum step, while 0.0 still turns the motor off.
+    let vibrate_msg = ScalarCmd::new(
+      0,
+      vec![ScalarSubcommand::new(0, 0.1, ActuatorType::Vibrate)],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 4))]
+    );
+    let vibrate_msg_2 = ScalarCmd::new(
+      0,
+      vec![ScalarSubcommand::new(0, 1.0, ActuatorType::Vibrate)],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg_2, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 10))]
+    );
+    let vibrate_msg_3 = ScalarCmd::new(
+      0,
+      vec![ScalarSubcommand::new(0, 0.0, ActuatorType::Vibrate)],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg_3, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 0))]
+    );
+
+    // Positions are mapped across the whole range, so 0.0 is the minimum step.
+    let linear_msg = LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 0.0)]);
+    assert_eq!(
+      mgr
+        .update_linear(&linear_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((500, 3))]
+    );
+    let linear_msg_2 = LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 1.0)]);
+    assert_eq!(
+      mgr
+        .update_linear(&linear_msg_2, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((500, 100))]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_reset() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .rotators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+
+    let vibrate_msg = ScalarCmd::new(
+      0,
+      vec![ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate)],
+    );
+    let rotate_msg = RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.5, true)]);
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 10))]
+    );
+    assert_eq!(
+      mgr
+        .update_rotation(&rotate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((10, true))]
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![]
+    );
+
+    mgr.reset();
+    assert_eq!(mgr.scalars(), vec![Some((ActuatorType::Vibrate, 0))]);
+
+    // After a reset, identical commands must go out again.
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 10))]
+    );
+    assert_eq!(
+      mgr
+        .update_rotation(&rotate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((10, true))]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_is_active() {
+    let scalar_attrs = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(0, 20),
+      ActuatorType::Vibrate,
+    );
+    let rotate_attrs = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(0, 20),
+      ActuatorType::Rotate,
+    );
+    let linear_attrs = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(0, 100),
+      ActuatorType::Position,
+    );
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .scalar_cmd(&[scalar_attrs])
+      .rotate_cmd(&[rotate_attrs])
+      .linear_cmd(&[linear_attrs])
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    assert!(!mgr.is_active());
+
+    // Scalars
+    mgr
+      .update_scalar(
+        &ScalarCmd::new(
+          0,
+          vec![ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate)],
+        ),
+        false,
+      )
+      .expect("Test, assuming infallible");
+    assert!(mgr.is_active());
+    mgr
+      .update_scalar(
+        &ScalarCmd::new(
+          0,
+          vec![ScalarSubcommand::new(0, 0.0, ActuatorType::Vibrate)],
+        ),
+        false,
+      )
+      .expect("Test, assuming infallible");
+    assert!(!mgr.is_active());
+
+    // Rotations
+    mgr
+      .update_rotation(
+        &RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.5, true)]),
+        false,
+      )
+      .expect("Test, assuming infallible");
+    assert!(mgr.is_active());
+    mgr
+      .update_rotation(
+        &RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.0, true)]),
+        false,
+      )
+      .expect("Test, assuming infallible");
+    assert!(!mgr.is_active());
+
+    // Linears stay active until reset, as we can't tell when they're done moving.
+    mgr
+      .update_linear(
+        &LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 0.5)]),
+        false,
+      )
+      .expect("Test, assuming infallible");
+    assert!(mgr.is_active());
+    mgr.reset();
+    assert!(!mgr.is_active());
+  }
+
+  #[test]
+  pub fn test_command_generator_scalar_mixed_actuators() {
+    let vibrate_attrs = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(0, 20),
+      ActuatorType::Vibrate,
+    );
+    let oscillate_attrs = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(0, 10),
+      ActuatorType::Oscillate,
+    );
+    let constrict_attrs = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(0, 4),
+      ActuatorType::Constrict,
+    );
+    let scalar_attributes = ServerDeviceMessageAttributesBuilder::default()
+      .scalar_cmd(&[vibrate_attrs, oscillate_attrs, constrict_attrs])
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &scalar_attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let scalar_msg = ScalarCmd::new(
+      0,
+      vec![
+        ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
+        ScalarSubcommand::new(1, 0.5, ActuatorType::Oscillate),
+        ScalarSubcommand::new(2, 0.5, ActuatorType::Constrict),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&scalar_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![
+        Some((ActuatorType::Vibrate, 10)),
+        Some((ActuatorType::Oscillate, 5)),
+        Some((ActuatorType::Constrict, 2))
+      ]
+    );
+    let scalar_msg_2 = ScalarCmd::new(
+      0,
+      vec![
+        ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
+        ScalarSubcommand::new(1, 1.0, ActuatorType::Oscillate),
+        ScalarSubcommand::new(2, 0.5, ActuatorType::Constrict),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&scalar_msg_2, false)
+        .expect("Test, assuming infallible"),
+      vec![None, Some((ActuatorType::Oscillate, 10)), None]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_feature_counts() {
+    let mut scalar_attrs = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(0, 20),
+      ActuatorType::Vibrate,
+    );
+    scalar_attrs.set_step_limit(RangeInclusive::new(2, 18));
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .scalar_cmd(&[scalar_attrs.clone(), scalar_attrs])
+      .rotators(1, 10)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    assert_eq!(mgr.scalar_count(), 2);
+    assert_eq!(mgr.rotator_count(), 1);
+    assert_eq!(mgr.linear_actuator_count(), 0);
+    assert_eq!(mgr.scalar_step_range(1), Some(&RangeInclusive::new(2, 18)));
+    assert_eq!(mgr.scalar_step_range(2), None);
+    assert_eq!(mgr.rotation_step_ranges(), &[RangeInclusive::new(0, 10)]);
+    assert!(mgr.linear_step_ranges().is_empty());
+  }
+
+  #[test]
+  pub fn test_command_generator_rounding_mode() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .rotators(1, 20)
+      .linears(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let vibrate_msg = ScalarCmd::new(
+      0,
+      vec![ScalarSubcommand::new(0, 0.99, ActuatorType::Vibrate)],
+    );
+    let rotate_msg = RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.99, true)]);
+    let linear_msg = LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 0.99)]);
+
+    // Rounding up is kept as the default, so existing devices get the same steps as always.
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    assert_eq!(mgr.rounding_mode(), RoundingMode::Ceil);
+
+    for (mode, expected) in [
+      (RoundingMode::Floor, 19),
+      (RoundingMode::Round, 20),
+      (RoundingMode::Ceil, 20),
+    ] {
+      let mut mgr =
+        GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+      mgr.set_rounding_mode(mode);
+      assert_eq!(
+        mgr
+          .update_scalar(&vibrate_msg, false)
+          .expect("Test, assuming infallible"),
+        vec![Some((ActuatorType::Vibrate, expected))]
+      );
+      assert_eq!(
+        mgr
+          .update_rotation(&rotate_msg, false)
+          .expect("Test, assuming infallible"),
+        vec![Some((expected, true))]
+      );
+      assert_eq!(
+        mgr
+          .update_linear(&linear_msg, false)
+          .expect("Test, assuming infallible"),
+        vec![Some((500, expected))]
+      );
+    }
+  }
+
+  #[test]
+  pub fn test_command_generator_preserve_nonzero_speed() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .rotators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    mgr.set_rounding_mode(RoundingMode::Floor);
+
+    let vibrate_msg = ScalarCmd::new(
+      0,
+      vec![ScalarSubcommand::new(0, 0.01, ActuatorType::Vibrate)],
+    );
+    let rotate_msg = RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.01, true)]);
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 0))]
+    );
+
+    mgr.set_preserve_nonzero_speed(true);
+    mgr.reset();
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 1))]
+    );
+    assert_eq!(
+      mgr
+        .update_rotation(&rotate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((1, true))]
+    );
+
+    // Values too small to register at all still get the minimum step.
+    mgr.set_rounding_mode(RoundingMode::Ceil);
+    let vibrate_msg_2 = ScalarCmd::new(
+      0,
+      vec![ScalarSubcommand::new(0, 0.000001, ActuatorType::Vibrate)],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg_2, false)
+        .expect("Test, assuming infallible"),
+      vec![]
+    );
+
+    let stop_msg = ScalarCmd::new(
+      0,
+      vec![ScalarSubcommand::new(0, 0.0, ActuatorType::Vibrate)],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&stop_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 0))]
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg_2, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 1))]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_scalar_detailed() {
+    let scalar_attrs = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(0, 20),
+      ActuatorType::Vibrate,
+    );
+    let mut limited_attrs = scalar_attrs.clone();
+    limited_attrs.set_step_limit(RangeInclusive::new(10, 20));
+    let scalar_attributes = ServerDeviceMessageAttributesBuilder::default()
+      .scalar_cmd(&[scalar_attrs, limited_attrs])
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &scalar_attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let vibrate_msg = ScalarCmd::new(
+      0,
+      vec![
+        ScalarSubcommand::new(0, 0.33, ActuatorType::Vibrate),
+        ScalarSubcommand::new(1, 0.33, ActuatorType::Vibrate),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar_detailed(&vibrate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![
+        Some((ActuatorType::Vibrate, 7, 0.35)),
+        Some((ActuatorType::Vibrate, 14, 0.4))
+      ]
+    );
+    let vibrate_msg_2 = ScalarCmd::new(
+      0,
+      vec![
+        ScalarSubcommand::new(0, 0.33, ActuatorType::Vibrate),
+        ScalarSubcommand::new(1, 0.0, ActuatorType::Vibrate),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar_detailed(&vibrate_msg_2, false)
+        .expect("Test, assuming infallible"),
+      vec![None, Some((ActuatorType::Vibrate, 0, 0.0))]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_invalid_step_range() {
+    let mut scalar_attrs = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(0, 20),
+      ActuatorType::Vibrate,
+    );
+    // Setters don't validate, so we can build a range that would underflow during conversion.
+    scalar_attrs.set_step_limit(RangeInclusive::new(15, 5));
+    let scalar_attributes = ServerDeviceMessageAttributesBuilder::default()
+      .scalar_cmd(&[scalar_attrs])
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &scalar_attributes);
+    assert!(GenericCommandManager::new(&device_attributes).is_err());
+
+    let rotate_attrs = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(20, 0),
+      ActuatorType::Rotate,
+    );
+    let rotate_attributes = ServerDeviceMessageAttributesBuilder::default()
+      .rotate_cmd(&[rotate_attrs])
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &rotate_attributes);
+    assert!(GenericCommandManager::new(&device_attributes).is_err());
+  }
+
+  #[test]
+  pub fn test_command_generator_stop_command_for() {
+    let vibrate_attrs = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(0, 20),
+      ActuatorType::Vibrate,
+    );
+    let constrict_attrs = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(0, 20),
+      ActuatorType::Constrict,
+    );
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .scalar_cmd(&[constrict_attrs, vibrate_attrs])
+      .rotators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+
+    assert_eq!(
+      mgr.stop_command_for(ButtplugDeviceMessageType::ScalarCmd, 0),
+      Some(
+        ScalarCmd::new(
+          0,
+          vec![ScalarSubcommand::new(0, 0.0, ActuatorType::Constrict)]
+        )
+        .into()
+      )
+    );
+    assert_eq!(
+      mgr.stop_command_for(ButtplugDeviceMessageType::VibrateCmd, 0),
+      Some(VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.0)]).into())
+    );
+    assert_eq!(
+      mgr.stop_command_for(ButtplugDeviceMessageType::RotateCmd, 0),
+      Some(RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.0, false)]).into())
+    );
+    assert!(mgr
+      .stop_command_for(ButtplugDeviceMessageType::ScalarCmd, 2)
+      .is_none());
+    assert!(mgr
+      .stop_command_for(ButtplugDeviceMessageType::VibrateCmd, 1)
+      .is_none());
+    assert!(mgr
+      .stop_command_for(ButtplugDeviceMessageType::RotateCmd, 1)
+      .is_none());
+    assert!(mgr
+      .stop_command_for(ButtplugDeviceMessageType::LinearCmd, 0)
+      .is_none());
+  }
+
+  #[test]
+  pub fn test_command_generator_out_of_range_values() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .rotators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+
+    for value in [-0.1, 1.5] {
+      let vibrate_msg = ScalarCmd::new(
+        0,
+        vec![
+          ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
+          ScalarSubcommand::new(1, value, ActuatorType::Vibrate),
+        ],
+      );
+      assert!(mgr.update_scalar(&vibrate_msg, false).is_err());
+      let rotate_msg = RotateCmd::new(0, vec![RotationSubcommand::new(0, value, true)]);
+      assert!(mgr.update_rotation(&rotate_msg, false).is_err());
+    }
+
+    // Nothing from the rejected commands should have been stored.
+    assert_eq!(
+      mgr.scalars(),
+      vec![
+        Some((ActuatorType::Vibrate, 0)),
+        Some((ActuatorType::Vibrate, 0))
+      ]
+    );
+  }
+  #[test]
+  pub fn test_command_generator_actuator_enabled() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .rotators(2, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    mgr
+      .set_actuator_enabled(ButtplugDeviceMessageType::VibrateCmd, 1, false)
+      .expect("Test, assuming infallible");
+    mgr
+      .set_actuator_enabled(ButtplugDeviceMessageType::RotateCmd, 0, false)
+      .expect("Test, assuming infallible");
+    assert!(mgr
+      .set_actuator_enabled(ButtplugDeviceMessageType::ScalarCmd, 2, false)
+      .is_err());
+    assert!(mgr
+      .set_actuator_enabled(ButtplugDeviceMessageType::LinearCmd, 0, false)
+      .is_err());
+
+    let vibrate_msg = |speed| {
+      ScalarCmd::new(
+        0,
+        vec![
+          ScalarSubcommand::new(0, speed, ActuatorType::Vibrate),
+          ScalarSubcommand::new(1, speed, ActuatorType::Vibrate),
+        ],
+      )
+    };
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(0.5), false)
+        .expect("Test, assuming infallible"),
+      vec![
+        Some((ActuatorType::Vibrate, 10)),
+        Some((ActuatorType::Vibrate, 0))
+      ]
+    );
+    // The muted motor stays at 0, so it never shows up as changed again.
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(0.75), false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 15)), None]
+    );
+
+    let rotate_msg = RotateCmd::new(
+      0,
+      vec![
+        RotationSubcommand::new(0, 0.5, true),
+        RotationSubcommand::new(1, 0.5, true),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_rotation(&rotate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((0, false)), Some((10, true))]
+    );
+    assert_eq!(
+      mgr
+        .update_rotation(&rotate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![None, None]
+    );
+
+    // Disabled actuators still get stopped. Stops keep each rotator's last direction.
+    assert_eq!(
+      mgr.stop_commands(),
+      vec![
+        vibrate_msg(0.0).into(),
+        RotateCmd::new(
+          0,
+          vec![
+            RotationSubcommand::new(0, 0.0, false),
+            RotationSubcommand::new(1, 0.0, true),
+          ],
+        )
+        .into()
+      ]
+    );
+
+    mgr
+      .set_actuator_enabled(ButtplugDeviceMessageType::VibrateCmd, 1, true)
+      .expect("Test, assuming infallible");
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(0.75), false)
+        .expect("Test, assuming infallible"),
+      vec![None, Some((ActuatorType::Vibrate, 15))]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_scalar_raw() {
+    let scalar_attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &scalar_attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let vibrate_msg = |speed_1, speed_2| {
+      ScalarCmd::new(
+        0,
+        vec![
+          ScalarSubcommand::new(0, speed_1, ActuatorType::Vibrate),
+          ScalarSubcommand::new(1, speed_2, ActuatorType::Vibrate),
+        ],
+      )
+    };
+
+    // Raw values are clamped to the step range.
+    assert_eq!(
+      mgr
+        .update_scalar_raw(&[Some(30), None], false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 20)), None]
+    );
+    // Normalized commands see the state left by the raw update.
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(1.0, 0.0), false)
+        .expect("Test, assuming infallible"),
+      vec![]
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(0.5, 0.5), false)
+        .expect("Test, assuming infallible"),
+      vec![
+        Some((ActuatorType::Vibrate, 10)),
+        Some((ActuatorType::Vibrate, 10))
+      ]
+    );
+    // And raw updates see the state left by normalized commands.
+    assert_eq!(
+      mgr
+        .update_scalar_raw(&[Some(10), Some(5)], true)
+        .expect("Test, assuming infallible"),
+      vec![
+        Some((ActuatorType::Vibrate, 10)),
+        Some((ActuatorType::Vibrate, 5))
+      ]
+    );
+    assert!(mgr
+      .update_scalar_raw(&[Some(1), Some(1), Some(1)], false)
+      .is_err());
+  }
+
+  #[test]
+  pub fn test_command_generator_oscillation() {
+    let scalar_attrs = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(0, 20),
+      ActuatorType::Oscillate,
+    );
+    let scalar_attributes = ServerDeviceMessageAttributesBuilder::default()
+      .scalar_cmd(&[scalar_attrs.clone(), scalar_attrs])
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &scalar_attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let oscillate_msg = ScalarCmd::new(
+      0,
+      vec![
+        ScalarSubcommand::new(0, 0.5, ActuatorType::Oscillate),
+        ScalarSubcommand::new(1, 0.5, ActuatorType::Oscillate),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&oscillate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![
+        Some((ActuatorType::Oscillate, 10)),
+        Some((ActuatorType::Oscillate, 10))
+      ]
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&oscillate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![]
+    );
+    let oscillate_msg_2 = ScalarCmd::new(
+      0,
+      vec![
+        ScalarSubcommand::new(0, 0.5, ActuatorType::Oscillate),
+        ScalarSubcommand::new(1, 0.75, ActuatorType::Oscillate),
+      ],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&oscillate_msg_2, false)
+        .expect("Test, assuming infallible"),
+      vec![None, Some((ActuatorType::Oscillate, 15))]
+    );
+    assert_eq!(
+      mgr.stop_commands(),
+      vec![ScalarCmd::new(
+        0,
+        vec![
+          ScalarSubcommand::new(0, 0.0, ActuatorType::Oscillate),
+          ScalarSubcommand::new(1, 0.0, ActuatorType::Oscillate),
+        ],
+      )
+      .into()]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_error_types() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .rotators(1, 20)
+      .linears(1, 100)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+
+    let empty_error = mgr
+      .update_scalar(&ScalarCmd::new(0, vec![]), false)
+      .expect_err("Test, assuming failure");
+    assert_eq!(
+      empty_error,
+      ButtplugDeviceError::EmptyCommand(ButtplugDeviceMessageType::ScalarCmd).into()
+    );
+    assert_eq!(
+      empty_error.to_string(),
+      "ScalarCmd has 0 commands, will not do anything."
+    );
+    assert_eq!(
+      mgr.update_rotation(&RotateCmd::new(0, vec![]), false),
+      Err(ButtplugDeviceError::EmptyCommand(ButtplugDeviceMessageType::RotateCmd).into())
+    );
+    assert_eq!(
+      mgr.update_linear(&LinearCmd::new(0, vec![]), false),
+      Err(ButtplugDeviceError::EmptyCommand(ButtplugDeviceMessageType::LinearCmd).into())
+    );
+
+    assert_eq!(
+      mgr.update_scalar(
+        &ScalarCmd::new(
+          0,
+          vec![ScalarSubcommand::new(1, 0.5, ActuatorType::Vibrate)]
+        ),
+        false
+      ),
+      Err(
+        ButtplugDeviceError::IndexOutOfRange {
+          requested: 1,
+          available: 1
+        }
+        .into()
+      )
+    );
+    assert_eq!(
+      mgr.update_rotation(
+        &RotateCmd::new(0, vec![RotationSubcommand::new(2, 0.5, true)]),
+        false
+      ),
+      Err(
+        ButtplugDeviceError::IndexOutOfRange {
+          requested: 2,
+          available: 1
+        }
+        .into()
+      )
+    );
+    assert_eq!(
+      mgr.update_linear(
+        &LinearCmd::new(0, vec![VectorSubcommand::new(3, 500, 0.5)]),
+        false
+      ),
+      Err(
+        ButtplugDeviceError::IndexOutOfRange {
+          requested: 3,
+          available: 1
+        }
+        .into()
+      )
+    );
+    assert_eq!(
+      ButtplugDeviceError::IndexOutOfRange {
+        requested: 3,
+        available: 1
+      }
+      .to_string(),
+      "Device only has 1 features, but was given an index of 3"
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_builder() {
+    let scalar_attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &scalar_attributes);
+    let vibrate_msg = |speed| {
+      ScalarCmd::new(
+        0,
+        vec![ScalarSubcommand::new(0, speed, ActuatorType::Vibrate)],
+      )
+    };
+
+    let mgr = GenericCommandManagerBuilder::new(&device_attributes)
+      .rounding_mode(RoundingMode::Floor)
+      .finish()
+      .expect("Test, assuming infallible");
+    assert_eq!(mgr.rounding_mode(), RoundingMode::Floor);
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(0.33), false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 6))]
+    );
+
+    let mgr = GenericCommandManagerBuilder::new(&device_attributes)
+      .rounding_mode(RoundingMode::Floor)
+      .min_nonzero_step(true)
+      .finish()
+      .expect("Test, assuming infallible");
+    assert!(mgr.preserve_nonzero_speed());
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(0.01), false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 1))]
+    );
+
+    // Single step ranges are only rejected when asked for.
+    let single_step_attrs = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(5, 5),
+      ActuatorType::Vibrate,
+    );
+    let single_step_attributes = ServerDeviceMessageAttributesBuilder::default()
+      .scalar_cmd(&[single_step_attrs])
+      .finish();
+    let single_step_device_attributes =
+      ProtocolDeviceAttributes::new("Whatever", &None, &single_step_attributes);
+    assert!(
+      GenericCommandManagerBuilder::new(&single_step_device_attributes)
+        .finish()
+        .is_ok()
+    );
+    assert!(
+      GenericCommandManagerBuilder::new(&single_step_device_attributes)
+        .strict_step_counts(true)
+        .finish()
+        .is_err()
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_linear_validation() {
+    let linear_attributes = ServerDeviceMessageAttributesBuilder::default()
+      .linears(1, 100)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &linear_attributes);
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    assert_eq!(mgr.max_linear_duration(), 60000);
+
+    assert!(mgr
+      .update_linear(
+        &LinearCmd::new(0, vec![VectorSubcommand::new(0, 0, 0.5)]),
+        false
+      )
+      .is_err());
+    assert!(mgr
+      .update_linear(
+        &LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 1.5)]),
+        false
+      )
+      .is_err());
+    assert!(mgr
+      .update_linear(
+        &LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, -0.5)]),
+        false
+      )
+      .is_err());
+
+    assert_eq!(
+      mgr
+        .update_linear(
+          &LinearCmd::new(0, vec![VectorSubcommand::new(0, 100000, 0.5)]),
+          false
+        )
+        .expect("Test, assuming infallible"),
+      vec![Some((60000, 50))]
+    );
+    mgr.set_max_linear_duration(1000);
+    assert_eq!(
+      mgr
+        .update_linear(
+          &LinearCmd::new(0, vec![VectorSubcommand::new(0, 5000, 0.5)]),
+          false
+        )
+        .expect("Test, assuming infallible"),
+      vec![Some((1000, 50))]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_state_snapshot() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .rotators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let vibrate_msg = ScalarCmd::new(
+      0,
+      vec![
+        ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
+        ScalarSubcommand::new(1, 0.25, ActuatorType::Vibrate),
+      ],
+    );
+    let rotate_msg = RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.5, true)]);
+    mgr
+      .update_scalar(&vibrate_msg, false)
+      .expect("Test, assuming infallible");
+    mgr
+      .update_rotation(&rotate_msg, false)
+      .expect("Test, assuming infallible");
+
+    let state = mgr.state_snapshot();
+    let serialized = serde_json::to_string(&state).expect("Test, assuming infallible");
+    let deserialized: CommandManagerState =
+      serde_json::from_str(&serialized).expect("Test, assuming infallible");
+    assert_eq!(state, deserialized);
+
+    let restored_mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    restored_mgr
+      .restore_state(&deserialized)
+      .expect("Test, assuming infallible");
+    assert_eq!(restored_mgr.state_snapshot(), state);
+    // The restored manager already considers these sent.
+    assert_eq!(
+      restored_mgr
+        .update_scalar(&vibrate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![]
+    );
+    assert_eq!(
+      restored_mgr
+        .update_rotation(&rotate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![None]
+    );
+
+    let scalar_only_attributes = ServerDeviceMessageAttributesBuilder::default()
+      .scalar_cmd(&[ServerGenericDeviceMessageAttributes::new(
+        "Test",
+        &RangeInclusive::new(0, 20),
+        ActuatorType::Vibrate,
+      )])
+      .finish();
+    let scalar_only_device_attributes =
+      ProtocolDeviceAttributes::new("Whatever", &None, &scalar_only_attributes);
+    let mismatched_mgr = GenericCommandManager::new(&scalar_only_device_attributes)
+      .expect("Test, assuming infallible");
+    assert!(mismatched_mgr.restore_state(&state).is_err());
+
+    // Steps that don't fit the current step ranges, say from a snapshot taken before the config
+    // changed, are rejected without restoring anything.
+    let fresh_mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let mut out_of_range_state = state.clone();
+    out_of_range_state.scalars[1] = 21;
+    assert!(matches!(
+      fresh_mgr.restore_state(&out_of_range_state),
+      Err(ButtplugDeviceError::DeviceConfigurationError(_))
+    ));
+    let mut out_of_range_state = state.clone();
+    out_of_range_state.rotations[0] = (100, true);
+    assert!(matches!(
+      fresh_mgr.restore_state(&out_of_range_state),
+      Err(ButtplugDeviceError::DeviceConfigurationError(_))
+    ));
+    assert_eq!(
+      fresh_mgr.state_snapshot(),
+      GenericCommandManager::new(&device_attributes)
+        .expect("Test, assuming infallible")
+        .state_snapshot()
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_reconfigure() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .rotators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    mgr.set_rounding_mode(RoundingMode::Floor);
+    assert_eq!(mgr.stop_commands().len(), 2);
+
+    // Firmware update dropped a vibrator and the rotator.
+    let shrunk_attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .finish();
+    let shrunk_device_attributes =
+      ProtocolDeviceAttributes::new("Whatever", &None, &shrunk_attributes);
+    mgr
+      .reconfigure(&shrunk_device_attributes)
+      .expect("Test, assuming infallible");
+    assert_eq!(mgr.scalar_count(), 1);
+    assert_eq!(mgr.rotator_count(), 0);
+    assert_eq!(mgr.rounding_mode(), RoundingMode::Floor);
+    assert_eq!(
+      mgr.stop_commands(),
+      vec![ScalarCmd::new(
+        0,
+        vec![ScalarSubcommand::new(0, 0.0, ActuatorType::Vibrate)]
+      )
+      .into()]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_scalar_only_stop_commands() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    assert_eq!(
+      mgr.scalar_stop_command(),
+      Some(
+        ScalarCmd::new(
+          0,
+          vec![
+            ScalarSubcommand::new(0, 0.0, ActuatorType::Vibrate),
+            ScalarSubcommand::new(1, 0.0, ActuatorType::Vibrate)
+          ]
+        )
+        .into()
+      )
+    );
+    assert!(mgr.rotate_stop_command().is_none());
+    assert!(mgr.linear_stop_command().is_none());
+    assert_eq!(mgr.stop_commands().len(), 1);
+  }
+
+  #[test]
+  pub fn test_command_generator_rotate_only_stop_commands() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .rotators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    assert_eq!(
+      mgr.rotate_stop_command(),
+      Some(RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.0, false)]).into())
+    );
+    assert!(mgr.scalar_stop_command().is_none());
+    assert!(mgr.linear_stop_command().is_none());
+    assert_eq!(mgr.stop_commands().len(), 1);
+  }
+
+  #[test]
+  pub fn test_command_generator_keepalive() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManagerBuilder::new(&device_attributes)
+      .command_keepalive(Duration::from_secs(5))
+      .finish()
+      .expect("Test, assuming infallible");
+    let cmd = ScalarCmd::new(
+      0,
+      vec![ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate)],
+    );
+    assert_eq!(
+      mgr.update_scalar(&cmd, false).expect("Test"),
+      vec![Some((ActuatorType::Vibrate, 10))]
+    );
+    // Within the keepalive interval, repeats are still suppressed.
+    assert_eq!(mgr.update_scalar(&cmd, false).expect("Test"), vec![]);
+    // Pretend the last command went out long enough ago that the device may have timed out.
+    mgr.set_last_command_instant(
+      Instant::now()
+        .checked_sub(Duration::from_secs(10))
+        .expect("Test, assuming infallible"),
+    );
+    assert_eq!(
+      mgr.update_scalar(&cmd, false).expect("Test"),
+      vec![Some((ActuatorType::Vibrate, 10))]
+    );
+    // Resending resets the interval.
+    assert_eq!(mgr.update_scalar(&cmd, false).expect("Test"), vec![]);
+  }
+
+  #[test]
+  pub fn test_command_generator_rotation_stop_keeps_direction() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .rotators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    assert_eq!(
+      mgr
+        .update_rotation(
+          &RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.5, true)]),
+          false
+        )
+        .expect("Test, assuming infallible"),
+      vec![Some((10, true))]
+    );
+    assert_eq!(
+      mgr
+        .update_rotation(
+          &RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.0, false)]),
+          false
+        )
+        .expect("Test, assuming infallible"),
+      vec![Some((0, true))]
+    );
+    // Once moving again, the requested direction is used.
+    assert_eq!(
+      mgr
+        .update_rotation(
+          &RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.5, false)]),
+          false
+        )
+        .expect("Test, assuming infallible"),
+      vec![Some((10, false))]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_intensity_cap() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .rotators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    mgr.set_intensity_cap(0.5);
+
+    let vibrate_msg = |speed| {
+      ScalarCmd::new(
+        0,
+        vec![ScalarSubcommand::new(0, speed, ActuatorType::Vibrate)],
+      )
+    };
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(1.0), false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 10))]
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(0.5), false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 5))]
+    );
+    assert_eq!(
+      mgr
+        .update_rotation(
+          &RotateCmd::new(0, vec![RotationSubcommand::new(0, 1.0, true)]),
+          false
+        )
+        .expect("Test, assuming infallible"),
+      vec![Some((10, true))]
+    );
+    // Raw steps can't get around the cap either.
+    assert_eq!(
+      mgr
+        .update_scalar_raw(&[Some(20)], false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 10))]
+    );
+
+    // Out of range caps are clamped.
+    mgr.set_intensity_cap(2.0);
+    assert_eq!(mgr.intensity_cap(), 1.0);
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(1.0), false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 20))]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_max_step_delta() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    mgr.set_max_step_delta(5);
+
+    let full_speed = ScalarCmd::new(
+      0,
+      vec![ScalarSubcommand::new(0, 1.0, ActuatorType::Vibrate)],
+    );
+    for step in [5, 10, 15, 20] {
+      assert_eq!(
+        mgr
+          .update_scalar(&full_speed, false)
+          .expect("Test, assuming infallible"),
+        vec![Some((ActuatorType::Vibrate, step))]
+      );
+    }
+    // Target reached, so there's nothing left to send.
+    assert_eq!(
+      mgr
+        .update_scalar(&full_speed, false)
+        .expect("Test, assuming infallible"),
+      vec![]
+    );
+    // Stopping isn't ramped.
+    assert_eq!(
+      mgr
+        .update_scalar(
+          &ScalarCmd::new(
+            0,
+            vec![ScalarSubcommand::new(0, 0.0, ActuatorType::Vibrate)]
+          ),
+          false
+        )
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 0))]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_command_generator_coalesce_window() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    mgr.set_coalesce_window(Some(Duration::from_millis(20)));
+
+    let vibrate_msg = |speed| {
+      ScalarCmd::new(
+        0,
+        vec![ScalarSubcommand::new(0, speed, ActuatorType::Vibrate)],
+      )
+    };
+    let (first, second, third) = (vibrate_msg(0.25), vibrate_msg(0.5), vibrate_msg(0.75));
+    let (first, second, third) = futures::join!(
+      mgr.update_scalar_coalesced(&first, false),
+      mgr.update_scalar_coalesced(&second, false),
+      mgr.update_scalar_coalesced(&third, false),
+    );
+    assert_eq!(
+      first.expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 15))]
+    );
+    assert_eq!(second.expect("Test, assuming infallible"), vec![]);
+    assert_eq!(third.expect("Test, assuming infallible"), vec![]);
+
+    // Invalid commands don't end up in the window.
+    assert!(mgr
+      .update_scalar_coalesced(&vibrate_msg(2.0), false)
+      .await
+      .is_err());
+    assert_eq!(
+      mgr
+        .update_scalar_coalesced(&vibrate_msg(0.75), false)
+        .await
+        .expect("Test, assuming infallible"),
+      vec![]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_command_generator_coalesce_window_dropped_opener() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    mgr.set_coalesce_window(Some(Duration::from_millis(20)));
+
+    // Drop the call that opened the window partway through it.
+    let first = ScalarCmd::new(
+      3,
+      vec![ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate)],
+    );
+    assert!(tokio::time::timeout(
+      Duration::from_millis(5),
+      mgr.update_scalar_coalesced(&first, false)
+    )
+    .await
+    .is_err());
+    // Inside the window, updates are still merged into what the dropped call held.
+    let second = ScalarCmd::new(
+      3,
+      vec![ScalarSubcommand::new(1, 0.25, ActuatorType::Vibrate)],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar_coalesced(&second, false)
+        .await
+        .expect("Test, assuming infallible"),
+      vec![]
+    );
+    // Once it should have closed, the next call picks everything up instead of being swallowed.
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    let third = ScalarCmd::new(
+      3,
+      vec![ScalarSubcommand::new(1, 0.75, ActuatorType::Vibrate)],
+    );
+    assert_eq!(
+      mgr
+        .update_scalar_coalesced(&third, false)
+        .await
+        .expect("Test, assuming infallible"),
+      vec![
+        Some((ActuatorType::Vibrate, 10)),
+        Some((ActuatorType::Vibrate, 15))
+      ]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_preview_scalar() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let initial_state = mgr.state_snapshot();
+    let vibrate_msg = |speed| {
+      ScalarCmd::new(
+        0,
+        vec![ScalarSubcommand::new(0, speed, ActuatorType::Vibrate)],
+      )
+    };
+    assert_eq!(
+      mgr
+        .preview_scalar(&vibrate_msg(0.5))
+        .expect("Test, assuming infallible"),
+      vec![10, 0]
+    );
+    assert_eq!(
+      mgr
+        .preview_scalar(&vibrate_msg(0.25))
+        .expect("Test, assuming infallible"),
+      vec![5, 0]
+    );
+    assert!(mgr.preview_scalar(&vibrate_msg(2.0)).is_err());
+    assert_eq!(mgr.state_snapshot(), initial_state);
+    // Nothing was marked as sent, so the real update goes out as normal.
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(0.5), false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 10)), None]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_scalar_as_linear() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .linears(1, 100)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let vibrate_msg = |speed| {
+      ScalarCmd::new(
+        0,
+        vec![ScalarSubcommand::new(0, speed, ActuatorType::Vibrate)],
+      )
+    };
+    // Opt-in only.
+    assert!(mgr.update_scalar_as_linear(&vibrate_msg(0.5)).is_err());
+
+    mgr.set_linear_scalar_emulation(true);
+    assert_eq!(
+      mgr
+        .update_scalar_as_linear(&vibrate_msg(0.5))
+        .expect("Test, assuming infallible"),
+      vec![
+        LinearCmd::new(0, vec![VectorSubcommand::new(0, 600, 1.0)]),
+        LinearCmd::new(0, vec![VectorSubcommand::new(0, 600, 0.0)]),
+      ]
+    );
+    // Faster speeds mean shorter strokes.
+    assert_eq!(
+      mgr
+        .update_scalar_as_linear(&vibrate_msg(1.0))
+        .expect("Test, assuming infallible")[0],
+      LinearCmd::new(0, vec![VectorSubcommand::new(0, 200, 1.0)])
+    );
+    assert!(mgr
+      .update_scalar_as_linear(&vibrate_msg(0.0))
+      .expect("Test, assuming infallible")
+      .is_empty());
+    assert!(mgr
+      .update_scalar_as_linear(&ScalarCmd::new(
+        0,
+        vec![ScalarSubcommand::new(1, 0.5, ActuatorType::Vibrate)]
+      ))
+      .is_err());
+  }
+
+  #[test]
+  pub fn test_command_generator_binary_actuator() {
+    let scalar_attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 1)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &scalar_attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let vibrate_msg = |speed| {
+      ScalarCmd::new(
+        0,
+        vec![ScalarSubcommand::new(0, speed, ActuatorType::Vibrate)],
+      )
+    };
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(0.3), false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 1))]
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(0.0), false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 0))]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_validate_command() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let initial_state = mgr.state_snapshot();
 
-    // Return the command vector for the protocol to turn into proprietary commands
-    Ok(None)
+    assert!(mgr
+      .validate_command(
+        &ScalarCmd::new(
+          0,
+          vec![ScalarSubcommand::new(1, 0.5, ActuatorType::Vibrate)]
+        )
+        .into()
+      )
+      .is_ok());
+    assert!(mgr
+      .validate_command(&VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 1.0)]).into())
+      .is_ok());
+    // Vibrator only device, so rotation isn't supported at all.
+    assert!(matches!(
+      mgr.validate_command(&RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.5, true)]).into()),
+      Err(ButtplugError::ButtplugDeviceError(
+        ButtplugDeviceError::MessageNotSupported(ButtplugDeviceMessageType::RotateCmd)
+      ))
+    ));
+    assert!(mgr
+      .validate_command(
+        &ScalarCmd::new(
+          0,
+          vec![ScalarSubcommand::new(2, 0.5, ActuatorType::Vibrate)]
+        )
+        .into()
+      )
+      .is_err());
+    assert!(mgr
+      .validate_command(&VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 1.5)]).into())
+      .is_err());
+    // Validation never touches state.
+    assert_eq!(mgr.state_snapshot(), initial_state);
   }
 
-  pub fn stop_commands(&self) -> Vec<ButtplugDeviceCommandMessageUnion> {
-    self.stop_commands.clone()
+  #[test]
+  pub fn test_command_generator_refresh_commands() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .rotators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    // Nothing to refresh until something has been sent.
+    assert!(mgr.refresh_commands().is_empty());
+
+    let vibrate_msg = ScalarCmd::new(
+      0,
+      vec![
+        ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
+        ScalarSubcommand::new(1, 0.25, ActuatorType::Vibrate),
+      ],
+    );
+    mgr
+      .update_scalar(&vibrate_msg, false)
+      .expect("Test, assuming infallible");
+    let rotate_msg = RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.75, true)]);
+    mgr
+      .update_rotation(&rotate_msg, false)
+      .expect("Test, assuming infallible");
+    // Sending the same thing again is deduplicated, but a refresh still reproduces it.
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![]
+    );
+    assert_eq!(
+      mgr.refresh_commands(),
+      vec![vibrate_msg.into(), rotate_msg.into()]
+    );
   }
-}
-/*
-#[cfg(test)]
-mod test {
-  use super::{GenericCommandManager, ProtocolDeviceAttributes};
-  use crate::{
-    core::message::{ActuatorType, RotateCmd, RotationSubcommand, ScalarCmd, ScalarSubcommand},
-    server::device::configuration::{
-      ServerDeviceMessageAttributesBuilder,
-      ServerGenericDeviceMessageAttributes,
-    },
-  };
-  use std::ops::RangeInclusive;
 
   #[test]
-  pub fn test_command_generator_vibration() {
-    let scalar_attrs = ServerGenericDeviceMessageAttributes::new(
+  pub fn test_command_generator_sensor_cache() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    assert_eq!(mgr.last_sensor(SensorType::Battery), None);
+    mgr.update_sensor(SensorType::Battery, 80);
+    assert_eq!(mgr.last_sensor(SensorType::Battery), Some(80));
+    // Newer readings replace older ones, and each sensor type is cached separately.
+    mgr.update_sensor(SensorType::Battery, 75);
+    mgr.update_sensor(SensorType::RSSI, -60);
+    assert_eq!(mgr.last_sensor(SensorType::Battery), Some(75));
+    assert_eq!(mgr.last_sensor(SensorType::RSSI), Some(-60));
+    assert_eq!(mgr.last_sensor(SensorType::Pressure), None);
+  }
+
+  #[test]
+  pub fn test_command_generator_no_step_features() {
+    // Feature attributes always carry a step range, so the closest thing to a feature without a
+    // step count is one whose range has no steps above 0. Updates should leave it stopped rather
+    // than panicking or sending steps the feature doesn't have.
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 0)
+      .rotators(1, 0)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManagerBuilder::new(&device_attributes)
+      .min_nonzero_step(true)
+      .finish()
+      .expect("Test, assuming infallible");
+    assert_eq!(
+      mgr
+        .update_scalar(
+          &ScalarCmd::new(
+            0,
+            vec![
+              ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
+              ScalarSubcommand::new(1, 1.0, ActuatorType::Vibrate),
+            ]
+          ),
+          false
+        )
+        .expect("Test, assuming infallible"),
+      vec![
+        Some((ActuatorType::Vibrate, 0)),
+        Some((ActuatorType::Vibrate, 0))
+      ]
+    );
+    // Once the first command has gone out, repeating it is deduplicated.
+    assert_eq!(
+      mgr
+        .update_scalar(
+          &ScalarCmd::new(
+            0,
+            vec![
+              ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
+              ScalarSubcommand::new(1, 1.0, ActuatorType::Vibrate),
+            ]
+          ),
+          false
+        )
+        .expect("Test, assuming infallible"),
+      vec![]
+    );
+    assert_eq!(
+      mgr
+        .update_rotation(
+          &RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.5, true)]),
+          false
+        )
+        .expect("Test, assuming infallible"),
+      vec![Some((0, false))]
+    );
+    // Strict mode refuses the configuration instead.
+    assert!(GenericCommandManagerBuilder::new(&device_attributes)
+      .strict_step_counts(true)
+      .finish()
+      .is_err());
+  }
+
+  #[test]
+  pub fn test_command_generator_set_step_count() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .rotators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let vibrate_msg = |speed| {
+      ScalarCmd::new(
+        0,
+        vec![ScalarSubcommand::new(0, speed, ActuatorType::Vibrate)],
+      )
+    };
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(0.5), false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 10))]
+    );
+
+    // The device turns out to have twice the steps. The same step on the new scale still goes out,
+    // since it means something different now.
+    mgr
+      .set_step_count(ButtplugDeviceMessageType::VibrateCmd, 0, 40)
+      .expect("Test, assuming infallible");
+    assert_eq!(mgr.scalar_step_range(0), Some(&RangeInclusive::new(0, 40)));
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(0.25), false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 10))]
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(0.5), false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 20))]
+    );
+
+    // Shrinking the range clamps what's stored, so refreshes stay in range.
+    mgr
+      .set_step_count(ButtplugDeviceMessageType::ScalarCmd, 0, 10)
+      .expect("Test, assuming infallible");
+    assert_eq!(mgr.state_snapshot().scalars(), &vec![10]);
+
+    assert!(mgr
+      .set_step_count(ButtplugDeviceMessageType::RotateCmd, 1, 10)
+      .is_err());
+    assert!(mgr
+      .set_step_count(ButtplugDeviceMessageType::LinearCmd, 0, 10)
+      .is_err());
+    assert!(mgr
+      .set_step_count(ButtplugDeviceMessageType::BatteryLevelCmd, 0, 10)
+      .is_err());
+  }
+
+  #[test]
+  pub fn test_command_generator_duplicate_indexes() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .rotators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let initial_state = mgr.state_snapshot();
+    let is_duplicate_error = |result: Result<_, ButtplugError>| {
+      matches!(
+        result,
+        Err(ButtplugError::ButtplugDeviceError(
+          ButtplugDeviceError::ProtocolRequirementError(_)
+        ))
+      )
+    };
+
+    assert!(is_duplicate_error(
+      mgr
+        .update_scalar(
+          &ScalarCmd::new(
+            0,
+            vec![
+              ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
+              ScalarSubcommand::new(0, 1.0, ActuatorType::Vibrate),
+            ]
+          ),
+          false
+        )
+        .map(|_| ())
+    ));
+    assert!(is_duplicate_error(
+      mgr.validate_command(
+        &VibrateCmd::new(
+          0,
+          vec![
+            VibrateSubcommand::new(0, 0.5),
+            VibrateSubcommand::new(0, 1.0)
+          ]
+        )
+        .into()
+      )
+    ));
+    assert!(is_duplicate_error(
+      mgr
+        .update_rotation(
+          &RotateCmd::new(
+            0,
+            vec![
+              RotationSubcommand::new(0, 0.5, true),
+              RotationSubcommand::new(0, 1.0, false),
+            ]
+          ),
+          false
+        )
+        .map(|_| ())
+    ));
+    // Nothing from the rejected commands was stored.
+    assert_eq!(mgr.state_snapshot(), initial_state);
+  }
+
+  #[test]
+  pub fn test_command_generator_stop_delta() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(3, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    mgr
+      .update_scalar(
+        &ScalarCmd::new(
+          0,
+          vec![
+            ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
+            ScalarSubcommand::new(2, 1.0, ActuatorType::Vibrate),
+          ],
+        ),
+        false,
+      )
+      .expect("Test, assuming infallible");
+    assert_eq!(
+      mgr.stop_delta(),
+      vec![
+        Some((ActuatorType::Vibrate, 0)),
+        None,
+        Some((ActuatorType::Vibrate, 0))
+      ]
+    );
+    // The stop is stored as sent, so there's nothing left to stop, and stopping again through a
+    // normal command is deduplicated.
+    assert_eq!(mgr.stop_delta(), vec![None, None, None]);
+    assert_eq!(
+      mgr
+        .update_scalar(
+          &ScalarCmd::new(
+            0,
+            vec![ScalarSubcommand::new(0, 0.0, ActuatorType::Vibrate)]
+          ),
+          false
+        )
+        .expect("Test, assuming infallible"),
+      vec![]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_rate_limit() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .rotators(2, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    mgr
+      .set_rate_limit(
+        ButtplugDeviceMessageType::ScalarCmd,
+        Duration::from_secs(60),
+      )
+      .expect("Test, assuming infallible");
+    mgr
+      .set_rate_limit(
+        ButtplugDeviceMessageType::RotateCmd,
+        Duration::from_secs(60),
+      )
+      .expect("Test, assuming infallible");
+    assert!(mgr
+      .set_rate_limit(
+        ButtplugDeviceMessageType::BatteryLevelCmd,
+        Duration::from_secs(60)
+      )
+      .is_err());
+    let vibrate_msg = |speed| {
+      ScalarCmd::new(
+        0,
+        vec![ScalarSubcommand::new(0, speed, ActuatorType::Vibrate)],
+      )
+    };
+
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(0.5), false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 10))]
+    );
+    // Too soon, so nothing goes out, but the latest target is kept.
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(0.75), false)
+        .expect("Test, assuming infallible"),
+      vec![]
+    );
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(1.0), false)
+        .expect("Test, assuming infallible"),
+      vec![]
+    );
+    // Once the interval is up, the latest target goes out, even though it's what's stored.
+    mgr.expire_rate_limits();
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(1.0), false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 20))]
+    );
+
+    // Rotation is limited separately, and a held back update to one rotator is sent along with
+    // the next update to any of them.
+    let rotate_msg = |index| RotateCmd::new(0, vec![RotationSubcommand::new(index, 0.5, true)]);
+    assert_eq!(
+      mgr
+        .update_rotation(&rotate_msg(0), false)
+        .expect("Test, assuming infallible"),
+      vec![Some((10, true)), None]
+    );
+    assert_eq!(
+      mgr
+        .update_rotation(&rotate_msg(1), false)
+        .expect("Test, assuming infallible"),
+      vec![None, None]
+    );
+    mgr.expire_rate_limits();
+    assert_eq!(
+      mgr
+        .update_rotation(&rotate_msg(0), false)
+        .expect("Test, assuming infallible"),
+      vec![Some((10, true)), Some((10, true))]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_debug_output() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    mgr
+      .update_scalar(
+        &ScalarCmd::new(
+          0,
+          vec![ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate)],
+        ),
+        false,
+      )
+      .expect("Test, assuming infallible");
+    let output = format!("{:?}", mgr);
+    assert!(output.contains("sent_scalar: true"));
+    assert!(output.contains("scalars: [(Vibrate, 10, 0..=20), (Vibrate, 0, 0..=20)]"));
+    assert!(output.contains("stop_command_count: 1"));
+  }
+
+  #[test]
+  pub fn test_command_generator_dedup_disabled() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .rotators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    assert!(mgr.dedup_enabled());
+    let vibrate_msg = ScalarCmd::new(
+      0,
+      vec![ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate)],
+    );
+    let rotate_msg = RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.5, true)]);
+    mgr
+      .update_scalar(&vibrate_msg, false)
+      .expect("Test, assuming infallible");
+    mgr
+      .update_rotation(&rotate_msg, false)
+      .expect("Test, assuming infallible");
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![]
+    );
+
+    // Without dedup, repeats go out, along with every other actuator of the same type.
+    mgr.set_dedup_enabled(false);
+    for _ in 0..2 {
+      assert_eq!(
+        mgr
+          .update_scalar(&vibrate_msg, false)
+          .expect("Test, assuming infallible"),
+        vec![
+          Some((ActuatorType::Vibrate, 10)),
+          Some((ActuatorType::Vibrate, 0))
+        ]
+      );
+      assert_eq!(
+        mgr
+          .update_rotation(&rotate_msg, false)
+          .expect("Test, assuming infallible"),
+        vec![Some((10, true))]
+      );
+    }
+  }
+
+  #[test]
+  pub fn test_command_generator_update_union() {
+    let constrict_attrs = ServerGenericDeviceMessageAttributes::new(
+      "Test",
+      &RangeInclusive::new(0, 10),
+      ActuatorType::Constrict,
+    );
+    let vibrate_attrs = ServerGenericDeviceMessageAttributes::new(
       "Test",
       &RangeInclusive::new(0, 20),
       ActuatorType::Vibrate,
     );
-    let scalar_attributes = ServerDeviceMessageAttributesBuilder::default()
-      .scalar_cmd(&vec![scalar_attrs.clone(), scalar_attrs])
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .scalar_cmd(&[constrict_attrs, vibrate_attrs])
+      .rotators(1, 20)
+      .linears(1, 100)
       .finish();
-    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &scalar_attributes);
-    let mgr = GenericCommandManager::new(&device_attributes);
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+
+    assert_eq!(
+      mgr
+        .update(
+          &ScalarCmd::new(
+            0,
+            vec![ScalarSubcommand::new(0, 0.5, ActuatorType::Constrict)]
+          )
+          .into(),
+          false
+        )
+        .expect("Test will not fail"),
+      GenericCommandResult::Scalar(vec![Some((ActuatorType::Constrict, 5)), None])
+    );
+    // Vibrator 0 is scalar feature 1 on this device.
+    assert_eq!(
+      mgr
+        .update(
+          &VibrateCmd::new(0, vec![VibrateSubcommand::new(0, 0.5)]).into(),
+          false
+        )
+        .expect("Test will not fail"),
+      GenericCommandResult::Scalar(vec![None, Some((ActuatorType::Vibrate, 10))])
+    );
+    assert_eq!(
+      mgr
+        .update(&SingleMotorVibrateCmd::new(0, 1.0).into(), false)
+        .expect("Test will not fail"),
+      GenericCommandResult::Scalar(vec![None, Some((ActuatorType::Vibrate, 20))])
+    );
+    assert_eq!(
+      mgr
+        .update(
+          &RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.5, true)]).into(),
+          false
+        )
+        .expect("Test will not fail"),
+      GenericCommandResult::Rotate(vec![Some((10, true))])
+    );
+    assert_eq!(
+      mgr
+        .update(
+          &LinearCmd::new(0, vec![VectorSubcommand::new(0, 500, 0.5)]).into(),
+          false
+        )
+        .expect("Test will not fail"),
+      GenericCommandResult::Linear(vec![Some((500, 50))])
+    );
+    assert!(matches!(
+      mgr.update(&StopDeviceCmd::new(0).into(), false),
+      Err(ButtplugError::ButtplugDeviceError(
+        ButtplugDeviceError::UnhandledCommand(_)
+      ))
+    ));
+  }
+
+  #[test]
+  pub fn test_command_generator_update_union_not_supported() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    assert!(matches!(
+      mgr.update(
+        &RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.5, true)]).into(),
+        false
+      ),
+      Err(ButtplugError::ButtplugDeviceError(
+        ButtplugDeviceError::MessageNotSupported(ButtplugDeviceMessageType::RotateCmd)
+      ))
+    ));
+    // Invalid commands are rejected before anything is stored.
+    assert!(mgr
+      .update(
+        &VibrateCmd::new(0, vec![VibrateSubcommand::new(1, 0.5)]).into(),
+        false
+      )
+      .is_err());
+    assert_eq!(mgr.state_snapshot().scalars(), &vec![0]);
+  }
+
+  #[test]
+  pub fn test_command_generator_rotation_stop_direction() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .rotators(2, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let stop = |clockwise_0, clockwise_1| {
+      RotateCmd::new(
+        0,
+        vec![
+          RotationSubcommand::new(0, 0.0, clockwise_0),
+          RotationSubcommand::new(1, 0.0, clockwise_1),
+        ],
+      )
+    };
+    assert_eq!(mgr.rotate_stop_command(), Some(stop(false, false).into()));
+    // Nothing sent yet, so the configured default is used.
+    mgr.set_stop_clockwise(true);
+    assert_eq!(mgr.rotate_stop_command(), Some(stop(true, true).into()));
+    assert_eq!(
+      mgr.stop_command_for(ButtplugDeviceMessageType::RotateCmd, 1),
+      Some(RotateCmd::new(0, vec![RotationSubcommand::new(1, 0.0, true)]).into())
+    );
+
+    mgr
+      .update_rotation(
+        &RotateCmd::new(
+          0,
+          vec![
+            RotationSubcommand::new(0, 0.5, false),
+            RotationSubcommand::new(1, 0.5, true),
+          ],
+        ),
+        false,
+      )
+      .expect("Test, assuming infallible");
+    // Once commanded, stops keep the direction each rotator was last sent.
+    assert_eq!(mgr.stop_commands(), vec![stop(false, true).into()]);
+    assert_eq!(
+      mgr.stop_command_for(ButtplugDeviceMessageType::RotateCmd, 0),
+      Some(RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.0, false)]).into())
+    );
+    // Applying the stop doesn't flip anything.
+    mgr
+      .update_rotation(
+        &RotateCmd::new(
+          0,
+          vec![
+            RotationSubcommand::new(0, 0.0, false),
+            RotationSubcommand::new(1, 0.0, true),
+          ],
+        ),
+        false,
+      )
+      .expect("Test, assuming infallible");
+    assert_eq!(
+      mgr.state_snapshot().rotations(),
+      &vec![(0, false), (0, true)]
+    );
+  }
+
+  #[test]
+  pub fn test_command_generator_speed_curve() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .rotators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
     let vibrate_msg = ScalarCmd::new(
       0,
-      vec![
-        ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
-        ScalarSubcommand::new(1, 0.5, ActuatorType::Vibrate),
-      ],
+      vec![ScalarSubcommand::new(0, 0.25, ActuatorType::Vibrate)],
+    );
+    let rotate_msg = RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.25, true)]);
+
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    assert_eq!(mgr.speed_curve(), 1.0);
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 5))]
+    );
+    assert_eq!(
+      mgr
+        .update_rotation(&rotate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((5, true))]
+    );
+
+    // 0.25 ^ 0.5 puts the same input halfway up the range.
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    mgr.set_speed_curve(0.5);
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 10))]
+    );
+    assert_eq!(
+      mgr
+        .update_rotation(&rotate_msg, false)
+        .expect("Test, assuming infallible"),
+      vec![Some((10, true))]
+    );
+    // Refreshes undo the curve, so they ask for what was originally requested.
+    assert_eq!(
+      mgr.refresh_commands(),
+      vec![vibrate_msg.into(), rotate_msg.into()]
+    );
+
+    mgr.set_speed_curve(0.0);
+    assert_eq!(mgr.speed_curve(), 1.0);
+  }
+
+  #[test]
+  pub fn test_command_generator_device_label() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let vibrate_msg = VibrateCmd::new(0, vec![VibrateSubcommand::new(2, 0.5)]);
+
+    // Without a label, errors are left as they are.
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    assert!(matches!(
+      mgr.validate_command(&vibrate_msg.clone().into()),
+      Err(ButtplugError::ButtplugDeviceError(
+        ButtplugDeviceError::IndexOutOfRange {
+          requested: 2,
+          available: 2
+        }
+      ))
+    ));
+
+    mgr.set_device_label(Some("Hush (test-address)".to_owned()));
+    assert_eq!(mgr.device_label(), Some("Hush (test-address)"));
+    let err = mgr
+      .validate_command(&vibrate_msg.into())
+      .expect_err("Test, index is out of range");
+    assert!(err.to_string().contains("Hush (test-address)"));
+    assert!(err
+      .to_string()
+      .contains("Device only has 2 features, but was given an index of 2"));
+    let err = mgr
+      .update_scalar(
+        &ScalarCmd::new(
+          0,
+          vec![ScalarSubcommand::new(5, 0.5, ActuatorType::Vibrate)],
+        ),
+        false,
+      )
+      .expect_err("Test, index is out of range");
+    assert!(err.to_string().contains("Hush (test-address)"));
+    let err = mgr
+      .set_actuator_enabled(ButtplugDeviceMessageType::RotateCmd, 0, false)
+      .expect_err("Test, device has no rotators");
+    assert!(err.to_string().contains("Hush (test-address)"));
+
+    // Labels set on the builder work the same way.
+    let mgr = GenericCommandManagerBuilder::new(&device_attributes)
+      .device_label("Edge")
+      .finish()
+      .expect("Test, assuming infallible");
+    let err = mgr
+      .update_rotation(
+        &RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.5, true)]),
+        false,
+      )
+      .expect_err("Test, device has no rotators");
+    assert!(err.to_string().starts_with("Edge: "));
+  }
+
+  #[test]
+  pub fn test_command_generator_match_all_partial_command() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .rotators(2, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let vibrate = |index, speed| {
+      mgr
+        .update(
+          &VibrateCmd::new(0, vec![VibrateSubcommand::new(index, speed)]).into(),
+          true,
+        )
+        .expect("Test, assuming infallible")
+    };
+
+    // Nothing has been sent to vibrator 0 yet, so it fills in as stopped.
+    assert_eq!(
+      vibrate(1, 0.5),
+      GenericCommandResult::Scalar(vec![
+        Some((ActuatorType::Vibrate, 0)),
+        Some((ActuatorType::Vibrate, 10))
+      ])
     );
+    // Vibrator 1 is left out, and is resent at its cached step instead of being zeroed.
     assert_eq!(
-      mgr
-        .update_scalar(&vibrate_msg, false)
-        .expect("Test, assuming infallible"),
-      vec![
-        Some((ActuatorType::Vibrate, 10)),
+      vibrate(0, 0.25),
+      GenericCommandResult::Scalar(vec![
+        Some((ActuatorType::Vibrate, 5)),
         Some((ActuatorType::Vibrate, 10))
-      ]
+      ])
     );
+    // A partial command that changes nothing still sends nothing.
+    assert_eq!(vibrate(1, 0.5), GenericCommandResult::Scalar(vec![]));
+    assert_eq!(mgr.state_snapshot().scalars(), &vec![5, 10]);
+
+    mgr
+      .update_rotation(
+        &RotateCmd::new(
+          0,
+          vec![
+            RotationSubcommand::new(0, 0.5, true),
+            RotationSubcommand::new(1, 0.5, false),
+          ],
+        ),
+        true,
+      )
+      .expect("Test, assuming infallible");
     assert_eq!(
       mgr
-        .update_scalar(&vibrate_msg, false)
+        .update_rotation(
+          &RotateCmd::new(0, vec![RotationSubcommand::new(1, 1.0, false)]),
+          true
+        )
         .expect("Test, assuming infallible"),
-      vec![]
+      vec![Some((10, true)), Some((20, false))]
     );
-    let vibrate_msg_2 = ScalarCmd::new(
-      0,
-      vec![
-        ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
-        ScalarSubcommand::new(1, 0.75, ActuatorType::Vibrate),
-      ],
+  }
+
+  #[test]
+  pub fn test_command_generator_no_actuators() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default().finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    assert!(!mgr.has_any_actuator());
+    assert!(mgr.stop_commands().is_empty());
+    assert!(matches!(
+      GenericCommandManagerBuilder::new(&device_attributes)
+        .require_actuators(true)
+        .finish(),
+      Err(ButtplugDeviceError::DeviceConfigurationError(_))
+    ));
+
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .linears(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManagerBuilder::new(&device_attributes)
+      .require_actuators(true)
+      .finish()
+      .expect("Test, assuming infallible");
+    assert!(mgr.has_any_actuator());
+  }
+
+  #[test]
+  pub fn test_command_generator_vibration_queue() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .scalar_cmd(&[
+        ServerGenericDeviceMessageAttributes::new(
+          "Oscillate",
+          &RangeInclusive::new(0, 20),
+          ActuatorType::Oscillate,
+        ),
+        ServerGenericDeviceMessageAttributes::new(
+          "Vibrate",
+          &RangeInclusive::new(0, 20),
+          ActuatorType::Vibrate,
+        ),
+      ])
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let vibrate = |speed| VibrateCmd::new(0, vec![VibrateSubcommand::new(0, speed)]);
+
+    assert_eq!(mgr.next_queued(), None);
+    for speed in [0.25, 0.25, 0.5] {
+      mgr
+        .enqueue_vibration(vibrate(speed))
+        .expect("Test, assuming infallible");
+    }
+    assert_eq!(mgr.queued_vibration_count(), 3);
+    // Results are by vibrator index, and repeats are deduplicated like any other update.
+    assert_eq!(mgr.next_queued(), Some(vec![Some(5)]));
+    assert_eq!(mgr.next_queued(), Some(vec![]));
+    assert_eq!(mgr.next_queued(), Some(vec![Some(10)]));
+    assert_eq!(mgr.next_queued(), None);
+    assert_eq!(mgr.state_snapshot().scalars(), &vec![0, 10]);
+
+    // Invalid commands are turned away at the door.
+    assert!(mgr
+      .enqueue_vibration(VibrateCmd::new(0, vec![VibrateSubcommand::new(1, 0.5)]))
+      .is_err());
+    assert_eq!(mgr.queued_vibration_count(), 0);
+
+    // A full queue drops the oldest command.
+    mgr.set_vibration_queue_depth(2);
+    for speed in [0.1, 0.75, 1.0] {
+      mgr
+        .enqueue_vibration(vibrate(speed))
+        .expect("Test, assuming infallible");
+    }
+    assert_eq!(mgr.queued_vibration_count(), 2);
+    assert_eq!(mgr.next_queued(), Some(vec![Some(15)]));
+    assert_eq!(mgr.next_queued(), Some(vec![Some(20)]));
+    assert_eq!(mgr.next_queued(), None);
+  }
+
+  #[test]
+  pub fn test_command_generator_zero_count_features() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .rotate_cmd(&[])
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    assert!(mgr.has_vibrators());
+    assert!(!mgr.has_rotators());
+    assert_eq!(mgr.rotate_stop_command(), None);
+    assert_eq!(
+      mgr.stop_commands(),
+      vec![ScalarCmd::new(
+        0,
+        vec![ScalarSubcommand::new(0, 0.0, ActuatorType::Vibrate)]
+      )
+      .into()]
     );
+    assert!(mgr
+      .update_rotation(
+        &RotateCmd::new(0, vec![RotationSubcommand::new(0, 0.5, true)]),
+        false
+      )
+      .is_err());
+
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .scalar_cmd(&[])
+      .rotators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    assert!(!mgr.has_vibrators());
+    assert!(mgr.has_rotators());
+    assert_eq!(mgr.scalar_stop_command(), None);
+    assert_eq!(mgr.stop_commands().len(), 1);
+  }
+
+  #[test]
+  pub fn test_command_generator_merge_attributes() {
+    let base = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .rotators(1, 20)
+      .finish();
+    let overrides = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 10)
+      .finish();
+    let merged = merge_attributes(&base, &overrides).expect("Test, assuming infallible");
+    // The override's step count wins, and anything it leaves out comes from the base.
+    assert_eq!(merged.scalar_cmd(), overrides.scalar_cmd());
+    assert_eq!(merged.rotate_cmd(), base.rotate_cmd());
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &merged);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
     assert_eq!(
       mgr
-        .update_scalar(&vibrate_msg_2, false)
+        .update_scalar(
+          &ScalarCmd::new(
+            0,
+            vec![ScalarSubcommand::new(0, 1.0, ActuatorType::Vibrate)]
+          ),
+          false
+        )
         .expect("Test, assuming infallible"),
-      vec![None, Some((ActuatorType::Vibrate, 15))]
-    );
-    let vibrate_msg_invalid = ScalarCmd::new(
-      0,
-      vec![ScalarSubcommand::new(2, 0.5, ActuatorType::Vibrate)],
+      vec![Some((ActuatorType::Vibrate, 10)), None]
     );
-    assert!(mgr.update_scalar(&vibrate_msg_invalid, false).is_err());
+    assert_eq!(mgr.rotator_count(), 1);
+
+    let overrides = ServerDeviceMessageAttributesBuilder::default()
+      .rotators(2, 10)
+      .finish();
+    assert!(matches!(
+      merge_attributes(&base, &overrides),
+      Err(ButtplugError::ButtplugDeviceError(
+        ButtplugDeviceError::DeviceConfigurationError(_)
+      ))
+    ));
+  }
+
+  #[test]
+  pub fn test_command_generator_supported_message_types() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 20)
+      .rotators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let message_types = mgr.supported_message_types();
+    assert!(message_types.contains(&ButtplugDeviceMessageType::VibrateCmd));
+    assert!(message_types.contains(&ButtplugDeviceMessageType::RotateCmd));
+    assert!(message_types.contains(&ButtplugDeviceMessageType::ScalarCmd));
+    assert!(!message_types.contains(&ButtplugDeviceMessageType::LinearCmd));
 
+    // Scalar actuators that aren't vibrators don't make a device vibrate.
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .scalar_cmd(&[ServerGenericDeviceMessageAttributes::new(
+        "Oscillate",
+        &RangeInclusive::new(0, 20),
+        ActuatorType::Oscillate,
+      )])
+      .linears(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
     assert_eq!(
-      mgr.scalars(),
+      mgr.supported_message_types(),
       vec![
-        Some((ActuatorType::Vibrate, 10)),
-        Some((ActuatorType::Vibrate, 15))
+        ButtplugDeviceMessageType::LinearCmd,
+        ButtplugDeviceMessageType::ScalarCmd
       ]
     );
   }
 
   #[test]
-  pub fn test_command_generator_vibration_match_all() {
-    let scalar_attrs = ServerGenericDeviceMessageAttributes::new(
-      "Test",
-      &RangeInclusive::new(0, 20),
-      ActuatorType::Vibrate,
-    );
-    let scalar_attributes = ServerDeviceMessageAttributesBuilder::default()
-      .scalar_cmd(&vec![scalar_attrs.clone(), scalar_attrs])
+  pub fn test_command_generator_inactivity_watchdog() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
       .finish();
-    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &scalar_attributes);
-    let mgr = GenericCommandManager::new(&device_attributes);
-    let vibrate_msg = ScalarCmd::new(
-      0,
-      vec![
-        ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
-        ScalarSubcommand::new(1, 0.5, ActuatorType::Vibrate),
-      ],
-    );
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    let vibrate_msg = |speed| {
+      ScalarCmd::new(
+        0,
+        vec![ScalarSubcommand::new(0, speed, ActuatorType::Vibrate)],
+      )
+    };
+    let timeout = Duration::from_secs(5);
+    let later = || Instant::now() + timeout + Duration::from_secs(1);
+
+    // Without a timeout, the watchdog never fires.
+    mgr
+      .update_scalar(&vibrate_msg(0.5), false)
+      .expect("Test, assuming infallible");
+    assert_eq!(mgr.poll_watchdog(later()), None);
+
+    mgr.set_inactivity_timeout(Some(timeout));
+    mgr
+      .update_scalar(&vibrate_msg(0.5), false)
+      .expect("Test, assuming infallible");
+    assert_eq!(mgr.poll_watchdog(Instant::now()), None);
+    assert_eq!(mgr.poll_watchdog(later()), Some(mgr.stop_commands()));
+    // Only once per quiet period.
+    assert_eq!(mgr.poll_watchdog(later()), None);
+
+    // Updates that don't change anything still count as activity.
+    mgr
+      .update_scalar(&vibrate_msg(0.5), false)
+      .expect("Test, assuming infallible");
+    assert_eq!(mgr.poll_watchdog(later()), Some(mgr.stop_commands()));
+
+    // Nothing to stop once the device is already stopped.
+    mgr
+      .update_scalar(&vibrate_msg(0.0), false)
+      .expect("Test, assuming infallible");
+    assert_eq!(mgr.poll_watchdog(later()), None);
+  }
+
+  #[test]
+  pub fn test_command_generator_step_inclusive() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .rotators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let vibrate_msg = |speed| {
+      ScalarCmd::new(
+        0,
+        vec![ScalarSubcommand::new(0, speed, ActuatorType::Vibrate)],
+      )
+    };
+
+    // Exclusive, the default, reaches the end of the range.
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    assert!(!mgr.step_inclusive());
     assert_eq!(
       mgr
-        .update_scalar(&vibrate_msg, true)
+        .update_scalar_detailed(&vibrate_msg(1.0), false)
         .expect("Test, assuming infallible"),
-      vec![
-        Some((ActuatorType::Vibrate, 10)),
-        Some((ActuatorType::Vibrate, 10))
-      ]
+      vec![Some((ActuatorType::Vibrate, 20, 1.0))]
     );
+
+    // Inclusive treats the end as a step count, so the top step is one below it.
+    let mgr = GenericCommandManagerBuilder::new(&device_attributes)
+      .step_inclusive(true)
+      .finish()
+      .expect("Test, assuming infallible");
+    assert!(mgr.step_inclusive());
     assert_eq!(
       mgr
-        .update_scalar(&vibrate_msg, true)
+        .update_scalar_detailed(&vibrate_msg(1.0), false)
         .expect("Test, assuming infallible"),
-      vec![]
+      vec![Some((ActuatorType::Vibrate, 19, 1.0))]
     );
-    let vibrate_msg_2 = ScalarCmd::new(
-      0,
-      vec![
-        ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
-        ScalarSubcommand::new(1, 0.75, ActuatorType::Vibrate),
-      ],
+    assert_eq!(
+      mgr
+        .update_scalar(&vibrate_msg(0.5), false)
+        .expect("Test, assuming infallible"),
+      vec![Some((ActuatorType::Vibrate, 10))]
     );
     assert_eq!(
       mgr
-        .update_scalar(&vibrate_msg_2, true)
+        .update_rotation(
+          &RotateCmd::new(0, vec![RotationSubcommand::new(0, 1.0, true)]),
+          false
+        )
         .expect("Test, assuming infallible"),
-      vec![
-        Some((ActuatorType::Vibrate, 10)),
-        Some((ActuatorType::Vibrate, 15))
-      ]
+      vec![Some((19, true))]
     );
-    let vibrate_msg_invalid = ScalarCmd::new(
-      0,
-      vec![ScalarSubcommand::new(2, 0.5, ActuatorType::Vibrate)],
+
+    // The option survives reconfiguring.
+    let mut mgr = mgr;
+    mgr
+      .reconfigure(&device_attributes)
+      .expect("Test, assuming infallible");
+    assert!(mgr.step_inclusive());
+  }
+
+  #[tokio::test]
+  async fn test_command_generator_reset_on_disconnect() {
+    let test_hardware = TestHardware::new("test-address");
+    let hardware = Hardware::new(
+      "Test",
+      "test-address",
+      &[Endpoint::Tx],
+      Box::new(test_hardware.clone()),
     );
-    assert!(mgr.update_scalar(&vibrate_msg_invalid, false).is_err());
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(2, 127)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Aneros", &None, &attributes);
+    let mgr =
+      Arc::new(GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible"));
+    {
+      let mgr = mgr.clone();
+      let event_receiver = hardware.event_stream();
+      async_manager::spawn(async move {
+        mgr.reset_on_disconnect(event_receiver).await;
+      });
+    }
+    let send_vibrate = || {
+      let mgr = mgr.clone();
+      let hardware = &hardware;
+      async move {
+        let commands = mgr
+          .update_scalar(
+            &ScalarCmd::new(
+              0,
+              vec![
+                ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
+                ScalarSubcommand::new(1, 1.0, ActuatorType::Vibrate),
+              ],
+            ),
+            false,
+          )
+          .expect("Test, assuming infallible");
+        if commands.is_empty() {
+          return;
+        }
+        for command in Aneros::default()
+          .handle_scalar_cmd(&commands)
+          .expect("Test, assuming infallible")
+        {
+          hardware
+            .parse_message(&command)
+            .await
+            .expect("Test, assuming infallible");
+        }
+      }
+    };
 
+    send_vibrate().await;
     assert_eq!(
-      mgr.scalars(),
+      test_hardware.written_data(),
+      vec![vec![0xF1, 64], vec![0xF2, 127]]
+    );
+    // The device is already running this, so nothing goes out.
+    test_hardware.clear_writes();
+    send_vibrate().await;
+    assert!(test_hardware.written_data().is_empty());
+
+    // The device stops when it disconnects. Once it's back, the same command has to go out again.
+    hardware
+      .disconnect()
+      .await
+      .expect("Test, assuming infallible");
+    while *mgr.state_snapshot().sent_scalar() {
+      tokio::task::yield_now().await;
+    }
+    test_hardware.inject_event(HardwareEvent::Reconnected("test-address".to_owned()));
+    send_vibrate().await;
+    assert_eq!(
+      test_hardware.written_data(),
+      vec![vec![0xF1, 64], vec![0xF2, 127]]
+    );
+
+    // Other events leave the cache alone.
+    test_hardware.clear_writes();
+    tokio::task::yield_now().await;
+    send_vibrate().await;
+    assert!(test_hardware.written_data().is_empty());
+  }
+
+  #[test]
+  pub fn test_command_generator_soft_stop() {
+    let attrs = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(3, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attrs);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    // Nothing running, nothing to ramp.
+    assert!(mgr.soft_stop(4).is_empty());
+
+    mgr
+      .update_scalar(
+        &ScalarCmd::new(
+          0,
+          vec![
+            ScalarSubcommand::new(0, 1.0, ActuatorType::Vibrate),
+            ScalarSubcommand::new(1, 0.5, ActuatorType::Vibrate),
+          ],
+        ),
+        false,
+      )
+      .expect("Test, assuming infallible");
+    let ramp = mgr.soft_stop(4);
+    assert_eq!(
+      ramp,
       vec![
-        Some((ActuatorType::Vibrate, 10)),
-        Some((ActuatorType::Vibrate, 15))
+        vec![Some(15), Some(8), None],
+        vec![Some(10), Some(5), None],
+        vec![Some(5), Some(3), None],
+        vec![Some(0), Some(0), None],
       ]
     );
+    for index in 0..2 {
+      let steps: Vec<u32> = ramp.iter().filter_map(|x| x[index]).collect();
+      assert!(steps.windows(2).all(|pair| pair[1] < pair[0]));
+      assert_eq!(steps.last(), Some(&0));
+    }
+    assert_eq!(mgr.state_snapshot().scalars(), &vec![0, 0, 0]);
+    // Already stopped.
+    assert!(mgr.soft_stop(4).is_empty());
+
+    // With more increments than steps to drop, each step is only sent once.
+    mgr
+      .update_scalar(
+        &ScalarCmd::new(
+          0,
+          vec![ScalarSubcommand::new(2, 0.5, ActuatorType::Vibrate)],
+        ),
+        false,
+      )
+      .expect("Test, assuming infallible");
+    let ramp = mgr.soft_stop(20);
+    assert_eq!(ramp.len(), 20);
+    let steps: Vec<u32> = ramp.iter().filter_map(|x| x[2]).collect();
+    assert_eq!(steps, (0..10).rev().collect::<Vec<u32>>());
   }
 
   #[test]
-  pub fn test_command_generator_vibration_step_range() {
-    let mut vibrate_attrs_1 = ServerGenericDeviceMessageAttributes::new(
-      "Test",
-      &RangeInclusive::new(0, 20),
-      ActuatorType::Vibrate,
-    );
-    vibrate_attrs_1.set_step_range(RangeInclusive::new(10, 15));
-    let mut vibrate_attrs_2 = ServerGenericDeviceMessageAttributes::new(
+  pub fn test_command_generator_soft_stop_step_limit() {
+    let mut vibrate_attrs = ServerGenericDeviceMessageAttributes::new(
       "Test",
       &RangeInclusive::new(0, 20),
       ActuatorType::Vibrate,
     );
-    vibrate_attrs_2.set_step_range(RangeInclusive::new(10, 20));
-
-    let vibrate_attributes = ServerDeviceMessageAttributesBuilder::default()
-      .scalar_cmd(&vec![vibrate_attrs_1, vibrate_attrs_2])
+    vibrate_attrs.set_step_limit(RangeInclusive::new(10, 20));
+    let attrs = ServerDeviceMessageAttributesBuilder::default()
+      .scalar_cmd(&[vibrate_attrs])
       .finish();
-    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &vibrate_attributes);
-    let mgr = GenericCommandManager::new(&device_attributes);
-    let vibrate_msg = ScalarCmd::new(
-      0,
-      vec![
-        ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
-        ScalarSubcommand::new(1, 0.5, ActuatorType::Vibrate),
-      ],
-    );
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attrs);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    mgr
+      .update_scalar(
+        &ScalarCmd::new(
+          0,
+          vec![ScalarSubcommand::new(0, 1.0, ActuatorType::Vibrate)],
+        ),
+        false,
+      )
+      .expect("Test, assuming infallible");
+    // The ramp stays within 10..=20 until the final stop, rather than sending steps under 10 that
+    // the device won't run at.
     assert_eq!(
-      mgr
-        .update_scalar(&vibrate_msg, false)
-        .expect("Test, assuming infallible"),
+      mgr.soft_stop(5),
       vec![
-        Some((ActuatorType::Vibrate, 13)),
-        Some((ActuatorType::Vibrate, 15))
+        vec![Some(18)],
+        vec![Some(16)],
+        vec![Some(14)],
+        vec![Some(12)],
+        vec![Some(0)],
       ]
     );
-    assert_eq!(
-      mgr
-        .update_scalar(&vibrate_msg, false)
-        .expect("Test, assuming infallible"),
-      vec![]
-    );
-    let vibrate_msg_2 = ScalarCmd::new(
-      0,
-      vec![
-        ScalarSubcommand::new(0, 0.5, ActuatorType::Vibrate),
-        ScalarSubcommand::new(1, 0.75, ActuatorType::Vibrate),
-      ],
-    );
-    assert_eq!(
-      mgr
-        .update_scalar(&vibrate_msg_2, false)
-        .expect("Test, assuming infallible"),
-      vec![None, Some((ActuatorType::Vibrate, 18))]
-    );
-    let vibrate_msg_invalid = ScalarCmd::new(
-      0,
-      vec![ScalarSubcommand::new(2, 0.5, ActuatorType::Vibrate)],
-    );
-    assert!(mgr.update_scalar(&vibrate_msg_invalid, false).is_err());
+  }
+
+  #[test]
+  pub fn test_command_generator_vibration_ramp() {
+    let attrs = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(3, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attrs);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    mgr
+      .update_scalar(
+        &ScalarCmd::new(
+          0,
+          vec![ScalarSubcommand::new(1, 0.5, ActuatorType::Vibrate)],
+        ),
+        false,
+      )
+      .expect("Test, assuming infallible");
 
+    // Vibrator 0 goes 0 -> 20 and sets the pace, vibrator 1 goes 10 -> 5 alongside it.
+    let plan = mgr
+      .update_vibration_ramp(&[1.0, 0.25], Duration::from_millis(1000))
+      .expect("Test, assuming infallible");
+    assert_eq!(plan.len(), 20);
+    let (first_time, first_cmd) = &plan.commands()[0];
+    assert_eq!(*first_time, Duration::from_millis(50));
+    assert_eq!(first_cmd.speeds(), &vec![VibrateSubcommand::new(0, 0.05)]);
+    let (last_time, last_cmd) = plan.commands().last().expect("Test, assuming infallible");
+    assert_eq!(*last_time, Duration::from_millis(1000));
     assert_eq!(
-      mgr.scalars(),
-      vec![
-        Some((ActuatorType::Vibrate, 13)),
-        Some((ActuatorType::Vibrate, 18))
+      last_cmd.speeds(),
+      &vec![
+        VibrateSubcommand::new(0, 1.0),
+        VibrateSubcommand::new(1, 0.25)
       ]
     );
+
+    let mut speeds: Vec<Vec<f64>> = vec![vec![], vec![]];
+    let mut last_time = Duration::ZERO;
+    for (time, cmd) in plan.clone() {
+      assert!(time > last_time);
+      last_time = time;
+      assert!(!cmd.speeds().is_empty());
+      for subcommand in cmd.speeds() {
+        speeds[subcommand.index() as usize].push(subcommand.speed());
+      }
+    }
+    assert_eq!(speeds[0].len(), 20);
+    assert!(speeds[0].windows(2).all(|pair| pair[1] > pair[0]));
+    assert_eq!(speeds[1].len(), 5);
+    assert!(speeds[1].windows(2).all(|pair| pair[1] < pair[0]));
+
+    // Nothing is stored until the commands are sent.
+    assert_eq!(mgr.state_snapshot().scalars(), &vec![0, 10, 0]);
+    for (_, cmd) in plan {
+      mgr
+        .update(&cmd.into(), false)
+        .expect("Test, assuming infallible");
+    }
+    assert_eq!(mgr.state_snapshot().scalars(), &vec![20, 5, 0]);
+
+    // Already there, nothing to do.
+    assert!(mgr
+      .update_vibration_ramp(&[1.0, 0.25], Duration::from_millis(1000))
+      .expect("Test, assuming infallible")
+      .is_empty());
+    // Targets are checked like any VibrateCmd.
+    assert!(mgr
+      .update_vibration_ramp(&[1.5], Duration::from_millis(1000))
+      .is_err());
+    assert!(mgr
+      .update_vibration_ramp(&[0.0, 0.0, 0.0, 0.0], Duration::from_millis(1000))
+      .is_err());
   }
 
   #[test]
-  pub fn test_command_generator_rotation() {
-    let rotate_attrs = ServerGenericDeviceMessageAttributes::new(
+  pub fn test_command_generator_vibration_ramp_step_limit() {
+    let mut vibrate_attrs = ServerGenericDeviceMessageAttributes::new(
       "Test",
       &RangeInclusive::new(0, 20),
-      ActuatorType::Rotate,
+      ActuatorType::Vibrate,
     );
-
-    let rotate_attributes = ServerDeviceMessageAttributesBuilder::default()
-      .rotate_cmd(&vec![rotate_attrs.clone(), rotate_attrs])
+    vibrate_attrs.set_step_limit(RangeInclusive::new(10, 20));
+    let attrs = ServerDeviceMessageAttributesBuilder::default()
+      .scalar_cmd(&[vibrate_attrs.clone(), vibrate_attrs])
       .finish();
-    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &rotate_attributes);
-    let mgr = GenericCommandManager::new(&device_attributes);
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attrs);
+    let mgr = GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
 
-    let rotate_msg = RotateCmd::new(
-      0,
-      vec![
-        RotationSubcommand::new(0, 0.5, true),
-        RotationSubcommand::new(1, 0.5, true),
-      ],
-    );
+    // Vibrator 0 goes from stopped to 20 and sets the pace, vibrator 1 goes from stopped to 15.
+    // Neither should be sent steps under 10, which would all come out as a stop.
+    let plan = mgr
+      .update_vibration_ramp(&[1.0, 0.5], Duration::from_millis(1000))
+      .expect("Test, assuming infallible");
+    assert_eq!(plan.len(), 10);
+    let mut speeds: Vec<Vec<f64>> = vec![vec![], vec![]];
+    for (_, cmd) in plan.clone() {
+      for subcommand in cmd.speeds() {
+        speeds[subcommand.index() as usize].push(subcommand.speed());
+      }
+    }
+    assert_eq!(speeds[0].len(), 10);
+    assert_eq!(speeds[1].len(), 5);
+    for vibrator_speeds in &speeds {
+      assert!(vibrator_speeds[0] > 0.0);
+      assert!(vibrator_speeds.windows(2).all(|pair| pair[1] > pair[0]));
+    }
+    for (_, cmd) in plan {
+      mgr
+        .update(&cmd.into(), false)
+        .expect("Test, assuming infallible");
+    }
+    assert_eq!(mgr.state_snapshot().scalars(), &vec![20, 15]);
+
+    // Back down, the only stop is the last command.
+    let plan = mgr
+      .update_vibration_ramp(&[0.0, 0.0], Duration::from_millis(1000))
+      .expect("Test, assuming infallible");
+    assert_eq!(plan.len(), 10);
+    let mut speeds: Vec<Vec<f64>> = vec![vec![], vec![]];
+    for (_, cmd) in plan {
+      for subcommand in cmd.speeds() {
+        speeds[subcommand.index() as usize].push(subcommand.speed());
+      }
+    }
+    for vibrator_speeds in &speeds {
+      assert!(vibrator_speeds.windows(2).all(|pair| pair[1] < pair[0]));
+      assert_eq!(
+        vibrator_speeds
+          .iter()
+          .filter(|speed| **speed == 0.0)
+          .count(),
+        1
+      );
+      assert_eq!(vibrator_speeds.last(), Some(&0.0));
+    }
+  }
+
+  #[test]
+  pub fn test_command_generator_reset_clears_history() {
+    let attributes = ServerDeviceMessageAttributesBuilder::default()
+      .vibrators(1, 20)
+      .finish();
+    let device_attributes = ProtocolDeviceAttributes::new("Whatever", &None, &attributes);
+    let mut mgr =
+      GenericCommandManager::new(&device_attributes).expect("Test, assuming infallible");
+    mgr
+      .set_rate_limit(
+        ButtplugDeviceMessageType::ScalarCmd,
+        Duration::from_secs(60),
+      )
+      .expect("Test, assuming infallible");
+    mgr.set_inactivity_timeout(Some(Duration::from_secs(1)));
+    let vibrate_msg = |speed| {
+      ScalarCmd::new(
+        0,
+        vec![ScalarSubcommand::new(0, speed, ActuatorType::Vibrate)],
+      )
+    };
     assert_eq!(
       mgr
-        .update_rotation(&rotate_msg, false)
+        .update_scalar(&vibrate_msg(0.5), false)
         .expect("Test, assuming infallible"),
-      vec![Some((10, true)), Some((10, true))]
+      vec![Some((ActuatorType::Vibrate, 10))]
     );
+    // Held back by the rate limit.
     assert_eq!(
       mgr
-        .update_rotation(&rotate_msg, false)
+        .update_scalar(&vibrate_msg(0.25), false)
         .expect("Test, assuming infallible"),
-      vec![None, None]
-    );
-    let rotate_msg_2 = RotateCmd::new(
-      0,
-      vec![
-        RotationSubcommand::new(0, 0.5, true),
-        RotationSubcommand::new(1, 0.75, false),
-      ],
+      vec![]
     );
+
+    mgr.reset();
+    // The watchdog has nothing to go on until the next update.
+    assert!(mgr
+      .poll_watchdog(Instant::now() + Duration::from_secs(10))
+      .is_none());
+    // The rate limit starts over too, so the first command after a reset goes straight out.
     assert_eq!(
       mgr
-        .update_rotation(&rotate_msg_2, false)
+        .update_scalar(&vibrate_msg(0.25), false)
         .expect("Test, assuming infallible"),
-      vec![None, Some((15, false))]
-    );
-    let rotate_msg_3 = RotateCmd::new(
-      0,
-      vec![
-        RotationSubcommand::new(0, 0.75, false),
-        RotationSubcommand::new(1, 0.75, false),
-      ],
+      vec![Some((ActuatorType::Vibrate, 5))]
     );
     assert_eq!(
       mgr
-        .update_rotation(&rotate_msg_3, true)
+        .update_scalar(&vibrate_msg(0.75), false)
         .expect("Test, assuming infallible"),
-      vec![Some((15, false)), Some((15, false))]
+      vec![]
     );
-    let rotate_msg_invalid = RotateCmd::new(0, vec![RotationSubcommand::new(2, 0.5, true)]);
-    assert!(mgr.update_rotation(&rotate_msg_invalid, false).is_err());
   }
+
   // TODO Write test for vibration stop generator
 }
-*/
@@ -92,7 +92,7 @@ impl ProtocolHandler for KGoalBoost {
             if sender.receiver_count() == 0 || stream_sensors.is_empty() {
               return;
             }
-            if let HardwareEvent::Notification(_, endpoint, data) = info {
+            if let HardwareEvent::Notification(_, endpoint, data, _) = info {
               if endpoint == Endpoint::RxPressure {
                 if data.len() < 7 {
                   // Not even sure how this would happen, error and continue on.
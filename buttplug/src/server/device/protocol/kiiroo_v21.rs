@@ -191,7 +191,7 @@ impl ProtocolHandler for KiirooV21 {
             if sender.receiver_count() == 0 || stream_sensors.is_empty() {
               return;
             }
-            if let HardwareEvent::Notification(_, endpoint, data) = info {
+            if let HardwareEvent::Notification(_, endpoint, data, _) = info {
               if endpoint == Endpoint::Rx {
                 if data.len() != 9 {
                   // Maybe not the Kiiroo Pearl 2.1?
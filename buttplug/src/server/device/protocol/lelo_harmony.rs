@@ -61,7 +61,7 @@ impl ProtocolInitializer for LeloHarmonyInitializer {
 
     loop {
       let event = event_receiver.recv().await;
-      if let Ok(HardwareEvent::Notification(_, _, n)) = event {
+      if let Ok(HardwareEvent::Notification(_, _, n, _)) = event {
         if n.iter().all(|b| *b == 0u8) {
           info!(
             "Lelo Harmony isn't authorised: Tap the device's power button to complete connection."
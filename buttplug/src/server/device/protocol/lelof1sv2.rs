@@ -63,7 +63,7 @@ impl ProtocolInitializer for LeloF1sV2Initializer {
 
     loop {
       let event = event_receiver.recv().await;
-      if let Ok(HardwareEvent::Notification(_, _, n)) = event {
+      if let Ok(HardwareEvent::Notification(_, _, n, _)) = event {
         if n.eq(&noauth) {
           info!(
             "Lelo F1s V2 isn't authorised: Tap the device's power button to complete connection."
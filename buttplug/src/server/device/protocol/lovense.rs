@@ -97,7 +97,7 @@ impl ProtocolIdentifier for LovenseIdentifier {
 
       select! {
         event = event_receiver.recv().fuse() => {
-          if let Ok(HardwareEvent::Notification(_, _, n)) = event {
+          if let Ok(HardwareEvent::Notification(_, _, n, _)) = event {
             let type_response = std::str::from_utf8(&n).map_err(|_| ButtplugDeviceError::ProtocolSpecificError("lovense".to_owned(), "Lovense device init got back non-UTF8 string.".to_owned()))?.to_owned();
             debug!("Lovense Device Type Response: {}", type_response);
             let ident = lovense_model_resolver(type_response);
@@ -340,7 +340,7 @@ impl ProtocolHandler for Lovense {
       write_fut.await?;
       while let Ok(event) = device_notification_receiver.recv().await {
         match event {
-          HardwareEvent::Notification(_, _, data) => {
+          HardwareEvent::Notification(_, _, data, _) => {
             if let Ok(data_str) = std::str::from_utf8(&data) {
               debug!("Lovense event received: {}", data_str);
               let len = data_str.len();
@@ -366,12 +366,15 @@ impl ProtocolHandler for Lovense {
               }
             }
           }
-          HardwareEvent::Disconnected(_) => {
+          HardwareEvent::Disconnected(..) => {
             return Err(ButtplugDeviceError::ProtocolSpecificError(
               "Lovense".to_owned(),
               "Lovense Device disconnected while getting Battery info.".to_owned(),
             ))
           }
+          HardwareEvent::Reconnecting(_)
+          | HardwareEvent::Reconnected(_)
+          | HardwareEvent::NotificationsDropped(..) => {}
         }
       }
       Err(ButtplugDeviceError::ProtocolSpecificError(
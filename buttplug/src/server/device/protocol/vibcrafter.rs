@@ -86,7 +86,7 @@ impl ProtocolInitializer for VibCrafterInitializer {
 
     loop {
       let event = event_receiver.recv().await;
-      if let Ok(HardwareEvent::Notification(_, _, n)) = event {
+      if let Ok(HardwareEvent::Notification(_, _, n, _)) = event {
         let decoded = decrypt(n);
         if decoded.eq("OK;") {
           debug!("VibCrafter authenticated!");
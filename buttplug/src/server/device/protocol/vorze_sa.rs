@@ -118,6 +118,12 @@ pub fn get_piston_speed(mut distance: f64, mut duration: f64) -> u8 {
   speed as u8
 }
 
+// Direction goes in the top bit, speed in the rest. Stopped rotators always send 0, since the
+// direction we're handed at speed 0 is just whatever was last used.
+fn rotate_data(speed: u32, clockwise: bool) -> u8 {
+  ((clockwise && speed != 0) as u8) << 7 | (speed as u8)
+}
+
 impl ProtocolHandler for VorzeSA {
   fn needs_full_command_set(&self) -> bool {
     true
@@ -148,7 +154,7 @@ impl ProtocolHandler for VorzeSA {
   ) -> Result<Vec<HardwareCommand>, ButtplugDeviceError> {
     if cmds.len() == 1 {
       if let Some((speed, clockwise)) = cmds[0] {
-        let data: u8 = (clockwise as u8) << 7 | (speed as u8);
+        let data = rotate_data(speed, clockwise);
         Ok(vec![HardwareWriteCmd::new(
           Endpoint::Tx,
           vec![self.device_type as u8, VorzeActions::Rotate as u8, data],
@@ -163,11 +169,11 @@ impl ProtocolHandler for VorzeSA {
       let mut data_right = 0u8;
       let mut changed = false;
       if let Some((speed, clockwise)) = cmds[0] {
-        data_left = (clockwise as u8) << 7 | (speed as u8);
+        data_left = rotate_data(speed, clockwise);
         changed = true;
       }
       if let Some((speed, clockwise)) = cmds[1] {
-        data_right = (clockwise as u8) << 7 | (speed as u8);
+        data_right = rotate_data(speed, clockwise);
         changed = true;
       }
       if changed {
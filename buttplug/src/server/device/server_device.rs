@@ -85,7 +85,7 @@ pub struct ServerDevice {
   // Legacy, should be removed once we hit message spec v4, and message fallback to v3 handled
   // within specific messages.
   attributes: ProtocolDeviceAttributes,
-  generic_command_manager: GenericCommandManager,
+  generic_command_manager: Arc<GenericCommandManager>,
   /// Unique identifier for the device
   #[getset(get = "pub")]
   identifier: UserDeviceIdentifier,
@@ -164,7 +164,7 @@ impl ServerDevice {
 
     // Check in the DeviceConfigurationManager to make sure we have attributes for this device.
     let attrs = if let Some(attrs) =
-      device_config_manager.device_definition(&identifier, &hardware.endpoints())
+      device_config_manager.device_definition(&identifier, hardware.endpoints())
     {
       attrs
     } else {
@@ -186,7 +186,7 @@ impl ServerDevice {
     let strategy = handler.keepalive_strategy();
 
     // We now have fully initialized hardware, return a server device.
-    let device = Self::new(identifier, handler, hardware, &attrs);
+    let device = Self::new(identifier, handler, hardware, &attrs)?;
 
     // If we need a keepalive with a packet replay, set this up via stopping the device on connect.
     if requires_keepalive
@@ -212,10 +212,19 @@ impl ServerDevice {
     handler: Arc<dyn ProtocolHandler>,
     hardware: Arc<Hardware>,
     definition: &UserDeviceDefinition,
-  ) -> Self {
+  ) -> Result<Self, ButtplugDeviceError> {
     let keepalive_packet = Arc::new(RwLock::new(None));
     let attributes = definition.clone().into();
-    let gcm = GenericCommandManager::new(&attributes);
+    let gcm = Arc::new(GenericCommandManager::new(&attributes)?);
+    // Hardware that reconnects keeps its device, so don't let state from before the disconnect
+    // suppress commands once it's back.
+    {
+      let gcm = gcm.clone();
+      let event_receiver = hardware.event_stream();
+      async_manager::spawn(async move {
+        gcm.reset_on_disconnect(event_receiver).await;
+      });
+    }
     // If we've gotten here, we know our hardware is connected. This means we can start the keepalive if it's required.
     if hardware.requires_keepalive()
       && !matches!(
@@ -261,7 +270,7 @@ impl ServerDevice {
       });
     }
 
-    Self {
+    Ok(Self {
       identifier,
       generic_command_manager: gcm,
       handler,
@@ -270,7 +279,7 @@ impl ServerDevice {
       attributes,
       definition: definition.clone(),
       raw_subscribed_endpoints: Arc::new(DashSet::new()),
-    }
+    })
   }
 
   /// Get the name of the device as set in the Device Configuration File.
@@ -318,8 +327,12 @@ impl ServerDevice {
       .filter_map(move |hardware_event| {
         let id = identifier.clone();
         match hardware_event {
-          HardwareEvent::Disconnected(_) => Some(ServerDeviceEvent::Disconnected(id)),
-          HardwareEvent::Notification(_address, endpoint, data) => {
+          HardwareEvent::Disconnected(..) => Some(ServerDeviceEvent::Disconnected(id)),
+          // The device is still ours while it's reconnecting, so there's nothing to tell anyone.
+          HardwareEvent::Reconnecting(_) | HardwareEvent::Reconnected(_) => None,
+          // Raw readings are passed on as they come, so there's nothing to resynchronize.
+          HardwareEvent::NotificationsDropped(..) => None,
+          HardwareEvent::Notification(_address, endpoint, data, _) => {
             // TODO Figure out how we're going to parse raw data into something sendable to the client.
             if raw_endpoints.contains(&endpoint) {
               Some(ServerDeviceEvent::Notification(
@@ -15,7 +15,12 @@ use buttplug::{
   },
   core::{
     errors::{ButtplugDeviceError, ButtplugError, ButtplugMessageError},
-    message::{self, ButtplugClientMessage, ClientDeviceMessageAttributes},
+    message::{
+      self,
+      ButtplugClientMessage,
+      ButtplugDeviceMessageType,
+      ClientDeviceMessageAttributes,
+    },
   },
   util::async_manager,
 };
@@ -166,7 +171,7 @@ async fn test_client_device_invalid_command() {
       .await
       .unwrap_err(),
     ButtplugClientError::ButtplugError(ButtplugError::ButtplugDeviceError(
-      ButtplugDeviceError::ProtocolRequirementError(..)
+      ButtplugDeviceError::EmptyCommand(ButtplugDeviceMessageType::ScalarCmd)
     ))
   ));
 }
@@ -10,6 +10,7 @@ use buttplug::{
   server::device::{
     configuration::ProtocolCommunicationSpecifier,
     hardware::{
+      DisconnectReason,
       Hardware,
       HardwareCommand,
       HardwareConnector,
@@ -181,7 +182,10 @@ impl TestDevice {
         match event {
           TestHardwareEvent::Disconnect => {
             event_sender_clone
-              .send(HardwareEvent::Disconnected(address_clone.clone()))
+              .send(HardwareEvent::Disconnected(
+                address_clone.clone(),
+                DisconnectReason::ClientClose,
+              ))
               .expect("Test");
           }
           TestHardwareEvent::Notifications(notifications) => {
@@ -192,6 +196,7 @@ impl TestDevice {
                     address_clone.clone(),
                     notification.endpoint,
                     notification.data.clone(),
+                    None,
                   ))
                   .expect("Test");
               }
@@ -253,7 +258,10 @@ impl HardwareInternal for TestDevice {
     let address = self.address.clone();
     async move {
       sender
-        .send(HardwareEvent::Disconnected(address))
+        .send(HardwareEvent::Disconnected(
+          address,
+          DisconnectReason::OwnerDropped,
+        ))
         .expect("Test");
       Ok(())
     }
@@ -312,7 +320,7 @@ impl HardwareInternal for TestDevice {
       return future::ready(Err(ButtplugDeviceError::InvalidEndpoint(msg.endpoint()))).boxed();
     }
     self.subscribed_endpoints.insert(msg.endpoint());
-    self.send_command((*msg).into())
+    self.send_command(msg.clone().into())
   }
 
   fn unsubscribe(